@@ -0,0 +1,191 @@
+//! The order of arguments in compile and link commands
+//!
+//! Every compile and link command is made of the groups in `ArgGroup`, always in the order
+//! they're declared, some of them empty:
+//!
+//! ```text
+//! <cxx> <compiler> <mode> <build> <before sources> <inputs> <after sources> <output> <link> <end>
+//! ```
+//!
+//! Raw arguments added using `Build::raw_arg` are placed in the group for their
+//! `ArgPosition`, in the order they were added. Archive commands don't use these groups and
+//! don't receive raw arguments
+
+use std::fmt;
+use std::process::Command;
+
+use crate::exec::shell_quote;
+
+/// Where a raw argument is placed in compile and link commands, see `Build::raw_arg`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ArgPosition {
+    /// After `build_args` and per-source flags, immediately before the sources, like `-x c++`
+    BeforeSources,
+
+    /// Immediately after the sources, objects and archives, like `-Wl,--end-group`
+    AfterSources,
+
+    /// After every other argument, including the Halide libraries and `ldflags`
+    End,
+}
+
+/// A group of arguments in a compile or link command, in the order they appear
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ArgGroup {
+    /// Language standard, include paths, warnings and `cxxflags`
+    Compiler,
+
+    /// Flags selecting what the command produces, like `-c`, `-shared` and `-MMD`
+    Mode,
+
+    /// `build_args` and per-source flags
+    Build,
+
+    BeforeSources,
+
+    /// Sources, `GenGen.cpp`, objects and archives
+    Inputs,
+
+    AfterSources,
+
+    /// `-o` and the output path
+    Output,
+
+    /// Halide libraries, `lib_dirs`, `libs`, `frameworks` and `ldflags`
+    Link,
+
+    End,
+}
+
+impl ArgGroup {
+    /// The name used when printing commands
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArgGroup::Compiler => "compiler",
+            ArgGroup::Mode => "mode",
+            ArgGroup::Build => "build",
+            ArgGroup::BeforeSources => "before sources",
+            ArgGroup::Inputs => "inputs",
+            ArgGroup::AfterSources => "after sources",
+            ArgGroup::Output => "output",
+            ArgGroup::Link => "link",
+            ArgGroup::End => "end",
+        }
+    }
+}
+
+/// The arguments of a command split into the groups they came from, see
+/// `Build::dump_command_groups`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandGroups {
+    pub program: String,
+
+    /// Non-empty groups in order
+    pub groups: Vec<(ArgGroup, Vec<String>)>,
+}
+
+/// Prints the program, then each group on its own line
+impl fmt::Display for CommandGroups {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.program)?;
+        for (group, args) in &self.groups {
+            let args: Vec<String> = args.iter().map(|arg| shell_quote(arg.as_ref())).collect();
+            writeln!(
+                f,
+                "  {:<15} {}",
+                format!("{}:", group.name()),
+                args.join(" ")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A command being built, with the index of the first argument of each group
+#[derive(Debug)]
+pub(crate) struct GroupedCommand {
+    pub(crate) cmd: Command,
+    starts: Vec<(ArgGroup, usize)>,
+}
+
+impl GroupedCommand {
+    /// Start with the arguments already in `cmd` as `group`
+    pub(crate) fn new(cmd: Command, group: ArgGroup) -> GroupedCommand {
+        GroupedCommand {
+            cmd,
+            starts: vec![(group, 0)],
+        }
+    }
+
+    /// Start `group`, returning the command to add its arguments to
+    pub(crate) fn group(&mut self, group: ArgGroup) -> &mut Command {
+        self.starts.push((group, self.cmd.get_args().len()));
+        &mut self.cmd
+    }
+
+    pub(crate) fn groups(&self) -> CommandGroups {
+        let args: Vec<String> = self
+            .cmd
+            .get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let groups = self
+            .starts
+            .iter()
+            .enumerate()
+            .map(|(i, (group, start))| {
+                let end = self.starts.get(i + 1).map_or(args.len(), |(_, end)| *end);
+                (*group, args[*start..end].to_vec())
+            })
+            .filter(|(_, args)| !args.is_empty())
+            .collect();
+        CommandGroups {
+            program: self.cmd.get_program().to_string_lossy().into_owned(),
+            groups,
+        }
+    }
+}
+
+/// Split a flags string like `CXXFLAGS` into arguments
+///
+/// Arguments are separated by whitespace. Single or double quotes group an argument
+/// containing spaces, like `"-IC:\Halide Stuff\include"`. Outside of quotes a backslash
+/// escapes a space or quote, as printed by pkg-config, and inside double quotes it escapes a
+/// double quote. Other backslashes are kept, so Windows paths don't need escaping
+pub fn split_flags(flags: &str) -> Vec<String> {
+    let mut args = vec![];
+    let mut arg = String::new();
+    let mut in_arg = false;
+    let mut quote = None;
+    let mut chars = flags.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', None)
+                if chars
+                    .peek()
+                    .is_some_and(|&next| next.is_whitespace() || next == '"' || next == '\'') =>
+            {
+                arg.extend(chars.next());
+            }
+            ('\\', Some('"')) if chars.peek() == Some(&'"') => {
+                arg.extend(chars.next());
+            }
+            (c, Some(q)) if c == q => quote = None,
+            (c, Some(_)) => arg.push(c),
+            ('"' | '\'', None) => quote = Some(c),
+            (c, None) if c.is_whitespace() => {
+                if in_arg {
+                    args.push(std::mem::take(&mut arg));
+                }
+                in_arg = false;
+                continue;
+            }
+            (c, None) => arg.push(c),
+        }
+        in_arg = true;
+    }
+    if in_arg {
+        args.push(arg);
+    }
+    args
+}
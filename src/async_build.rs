@@ -0,0 +1,361 @@
+//! Async build, run and source build steps, enabled by the `async` feature
+//!
+//! The steps run the same code as `Build::build_report`, `Build::run_report` and
+//! `Source::build` on Tokio's blocking thread pool, so the commands and checks can't differ
+//! from the blocking API. The returned `Task` streams the output of every command line by
+//! line, and stops the running command when it's dropped, so a step can be cancelled or
+//! given a deadline using `tokio::time::timeout`:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! let build = halide_build::Build::new("/opt/halide", "brighten").source_file("brighten.cpp");
+//! let mut task = build.build_async();
+//! while let Some(line) = task.next_line().await {
+//!     println!("{}", line.text);
+//! }
+//! let report = task.wait().await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Output is piped to be streamed, so programs that need the terminal, like a run step under
+//! a debugger, should use the blocking API, and `RunReport::resource_usage` is never set. The
+//! steps must be started from within a Tokio runtime
+
+use std::io;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::exec::{self, Executor, Stream};
+use crate::{Build, BuildReport, InterruptToken, RunReport, Source};
+
+/// A line written by a command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Line {
+    pub stream: Stream,
+
+    /// The line without its line ending, invalid UTF-8 is replaced
+    pub text: String,
+}
+
+/// A running step, see the module documentation
+///
+/// Dropping the task before `wait` returns stops the running command, the step then fails
+/// with `io::ErrorKind::Interrupted` after removing its temporary files
+#[derive(Debug)]
+pub struct Task<T> {
+    lines: mpsc::UnboundedReceiver<Line>,
+    handle: JoinHandle<io::Result<T>>,
+    token: InterruptToken,
+    finished: bool,
+}
+
+impl<T: Send + 'static> Task<T> {
+    /// Run `step` on the blocking thread pool with an executor that streams to the task
+    fn spawn(
+        executor: Arc<dyn Executor>,
+        step: impl FnOnce(Arc<dyn Executor>, InterruptToken) -> io::Result<T> + Send + 'static,
+    ) -> Task<T> {
+        let (tx, lines) = mpsc::unbounded_channel();
+        let token = InterruptToken::new();
+        let executor = Arc::new(Streaming {
+            inner: executor,
+            lines: tx,
+        });
+        let step_token = token.clone();
+        Task {
+            lines,
+            handle: tokio::task::spawn_blocking(move || step(executor, step_token)),
+            token,
+            finished: false,
+        }
+    }
+}
+
+impl<T> Task<T> {
+    /// The next line written by a command, `None` once the step has finished and every line
+    /// was read
+    pub async fn next_line(&mut self) -> Option<Line> {
+        self.lines.recv().await
+    }
+
+    /// Stop the running command, like dropping the task
+    pub fn interrupt(&self) {
+        self.token.interrupt()
+    }
+
+    /// Wait for the step to finish, lines that weren't read are discarded
+    pub async fn wait(mut self) -> io::Result<T> {
+        let res = (&mut self.handle).await;
+        self.finished = true;
+        res.unwrap_or_else(|e| Err(io::Error::other(e)))
+    }
+}
+
+impl<T> Drop for Task<T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.token.interrupt();
+        }
+    }
+}
+
+/// Sends the output of every command to a `Task`, passing it on to the caller when it asked
+/// for the stream
+#[derive(Debug)]
+struct Streaming {
+    inner: Arc<dyn Executor>,
+    lines: mpsc::UnboundedSender<Line>,
+}
+
+impl Executor for Streaming {
+    fn status(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<ExitStatus> {
+        self.status_with_lines(cmd, token, group, input, &[], &mut |_, _| ())
+    }
+
+    fn status_with_lines(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+        streams: &[Stream],
+        on_line: &mut dyn FnMut(Stream, &[u8]),
+    ) -> io::Result<ExitStatus> {
+        let all = [Stream::Stdout, Stream::Stderr];
+        self.inner
+            .status_with_lines(cmd, token, group, input, &all, &mut |stream, line| {
+                if streams.contains(&stream) {
+                    on_line(stream, line);
+                }
+                let _ = self.lines.send(Line {
+                    stream,
+                    text: exec::line_text(line).into_owned(),
+                });
+            })
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.inner.output(cmd)
+    }
+}
+
+impl Build<'static> {
+    /// Like `build_report`, see the module documentation
+    ///
+    /// A successful build allows `run` and `run_async` on this build, like `build`
+    pub fn build_async(&self) -> Task<BuildReport> {
+        let mut build = self.clone();
+        build.built = self.built.share();
+        Task::spawn(self.executor.clone(), move |executor, token| {
+            build.executor = executor;
+            build.interrupt = token;
+            build.build_report()
+        })
+    }
+
+    /// Like `run_report`, see the module documentation
+    pub fn run_async(&self) -> Task<RunReport> {
+        let mut build = self.clone();
+        Task::spawn(self.executor.clone(), move |executor, token| {
+            build.executor = executor;
+            build.interrupt = token;
+            build.run_report()
+        })
+    }
+}
+
+impl Source {
+    /// Like `build`, see the module documentation
+    ///
+    /// Events are sent to `events` as they are for `build`
+    pub fn build_async(&self) -> Task<bool> {
+        // The SCM backend isn't `Send` and isn't used to build, so the source is recreated on
+        // the blocking thread without it
+        let halide_path = self.halide_path.clone();
+        let repo = self.repo.clone();
+        let branch = self.branch.clone();
+        let make = self.make.clone();
+        let make_flags = self.make_flags.clone();
+        let (shallow, stash, jobs) = (self.shallow, self.stash, self.jobs);
+        let llvm_config = self.llvm_config.clone();
+        let patches = self.patches.clone();
+        let (events, tee) = (self.events.clone(), self.tee);
+        Task::spawn(self.executor.clone(), move |executor, interrupt| {
+            let source = Source {
+                halide_path,
+                repo,
+                branch,
+                make,
+                make_flags,
+                shallow,
+                stash,
+                jobs,
+                llvm_config,
+                patches,
+                scm: None,
+                interrupt,
+                events,
+                tee,
+                executor,
+            };
+            source.build()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::mpsc as std_mpsc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::exec::{Mock, Response};
+
+    fn build(dir: &Path, mock: &Mock) -> Build<'static> {
+        Build::new("/opt/halide", dir.join("brighten"))
+            .source_file("brighten.cpp")
+            .compiler("c++")
+            .with_image_io(false)
+            .atomic_outputs(false)
+            .with_executor(mock.clone())
+    }
+
+    /// Blocks every command until it's interrupted, then runs it using the mock, which fails
+    /// with `io::ErrorKind::Interrupted`, sending `started` and `released` to the test
+    #[derive(Debug)]
+    struct UntilInterrupted {
+        mock: Mock,
+        events: std_mpsc::SyncSender<&'static str>,
+    }
+
+    impl Executor for UntilInterrupted {
+        fn status(
+            &self,
+            cmd: &mut Command,
+            token: &InterruptToken,
+            group: bool,
+            input: Option<&[u8]>,
+        ) -> io::Result<ExitStatus> {
+            self.status_with_lines(cmd, token, group, input, &[], &mut |_, _| ())
+        }
+
+        fn status_with_lines(
+            &self,
+            cmd: &mut Command,
+            token: &InterruptToken,
+            group: bool,
+            input: Option<&[u8]>,
+            streams: &[Stream],
+            on_line: &mut dyn FnMut(Stream, &[u8]),
+        ) -> io::Result<ExitStatus> {
+            let _ = self.events.try_send("started");
+            while !token.is_interrupted() {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let _ = self.events.try_send("released");
+            self.mock
+                .status_with_lines(cmd, token, group, input, streams, on_line)
+        }
+
+        fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+            self.mock.output(cmd)
+        }
+    }
+
+    #[tokio::test]
+    async fn build_streams_lines_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new()
+            .on(
+                "c++",
+                Response::ok()
+                    .stdout("note: first\nnote: second\n")
+                    .stderr("warning: unused\n")
+                    .output_file("exe"),
+            )
+            .on("brighten", Response::ok().stdout("done"));
+        let build = build(dir.path(), &mock);
+        let mut task = build.build_async();
+
+        let mut lines = vec![];
+        while let Some(line) = task.next_line().await {
+            lines.push(line);
+        }
+        let report = task.wait().await.unwrap();
+        assert!(report.success);
+        assert_eq!(
+            lines,
+            [
+                (Stream::Stdout, "note: first"),
+                (Stream::Stdout, "note: second"),
+                (Stream::Stderr, "warning: unused"),
+            ]
+            .map(|(stream, text)| Line {
+                stream,
+                text: text.to_string()
+            })
+        );
+
+        // The shared build state allows running the output, a partial last line is kept
+        let mut task = build.run_async();
+        assert_eq!(
+            task.next_line().await,
+            Some(Line {
+                stream: Stream::Stdout,
+                text: "done".to_string()
+            })
+        );
+        assert_eq!(task.next_line().await, None);
+        assert!(task.wait().await.unwrap().success);
+    }
+
+    #[tokio::test]
+    async fn interrupt_stops_the_running_command() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let (events, received) = std_mpsc::sync_channel(2);
+        let build = build(dir.path(), &mock).with_executor(UntilInterrupted {
+            mock: mock.clone(),
+            events,
+        });
+
+        let task = build.build_async();
+        assert_eq!(received.recv().unwrap(), "started");
+        task.interrupt();
+        let err = task.wait().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(!build.built.get());
+    }
+
+    #[tokio::test]
+    async fn dropping_the_task_stops_the_step() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let (events, received) = std_mpsc::sync_channel(2);
+        let build = build(dir.path(), &mock).with_executor(UntilInterrupted {
+            mock: mock.clone(),
+            events,
+        });
+
+        let task = build.build_async();
+        assert_eq!(received.recv().unwrap(), "started");
+        drop(task);
+        assert_eq!(
+            received.recv_timeout(Duration::from_secs(10)),
+            Ok("released")
+        );
+        // The interrupted command never ran
+        assert!(mock.calls().is_empty());
+    }
+}
@@ -1,15 +1,27 @@
 use halide_build::*;
 
-use clap::{Arg, Command};
+use clap::{Arg, ArgMatches, Command};
 
+use std::borrow::Cow;
 use std::env;
-use std::io::Write;
+use std::ffi::OsString;
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
 use std::path::{Path, PathBuf};
 use std::process::exit;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
 
 static mut QUIET: bool = false;
 
+static mut VERBOSE: bool = false;
+
+/// Directory holding the executables built by `run`, `versions` or `compare`, removed before
+/// exiting unless `--keep` is set
+static RUN_DIR: Mutex<Option<tempfile::TempDir>> = Mutex::new(None);
+
+static INTERRUPT: OnceLock<InterruptToken> = OnceLock::new();
+
 macro_rules! log {
     ($fmt:expr, $($arg:tt)*) => {
         unsafe {
@@ -28,6 +40,202 @@ macro_rules! log {
     }
 }
 
+/// Redraw the progress line for `halide src`, with a progress bar when the percentage of
+/// the build is known
+fn progress_bar(step: SourceStep, elapsed: Duration, percent: Option<f32>) {
+    const WIDTH: usize = 40;
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+    let secs = elapsed.as_secs();
+    let spinner = SPINNER[(elapsed.as_millis() / 100) as usize % SPINNER.len()];
+    eprint!(
+        "\r\x1b[K{} {} {}:{:02}",
+        spinner,
+        step,
+        secs / 60,
+        secs % 60
+    );
+    if let Some(percent) = percent {
+        let percent = percent.clamp(0.0, 100.0);
+        let filled = (percent / 100.0 * WIDTH as f32) as usize;
+        eprint!(
+            " [{}{}] {:3.0}%",
+            "#".repeat(filled),
+            " ".repeat(WIDTH - filled),
+            percent
+        );
+    }
+    let _ = io::stderr().flush();
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(_: libc::c_int) {
+    if let Some(token) = INTERRUPT.get() {
+        token.interrupt();
+    }
+}
+
+/// Interrupt running child processes on SIGINT and SIGTERM, instead of exiting immediately, so
+/// temporary files can be cleaned up
+fn install_signal_handlers(token: InterruptToken) {
+    let _ = INTERRUPT.set(token);
+
+    #[cfg(unix)]
+    unsafe {
+        let handler = handle_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// Record whether the compiler, flags and Halide path came from flags, the environment or
+/// defaults
+fn record_provenance(build: &mut Build, matches: &ArgMatches, global: &ArgMatches) {
+    let settings = [
+        ("halide_path", global, "halide-path", "HALIDE_PATH"),
+        ("compiler", matches, "cxx", "CXX"),
+        ("cxxflags", matches, "cxxflags", "CXXFLAGS"),
+        ("ldflags", matches, "ldflags", "LDFLAGS"),
+    ];
+
+    for (setting, m, arg, var) in settings {
+        let provenance = if m.occurrences_of(arg) > 0 {
+            Provenance::CommandLine(arg.to_string())
+        } else if env::var_os(var).is_some() {
+            Provenance::Env(var.to_string())
+        } else {
+            Provenance::Default
+        };
+        build.set_provenance(setting, provenance);
+    }
+}
+
+/// Apply the toolchain file, then exit if the environment doesn't satisfy its pins or the
+/// Halide headers and library don't match
+///
+/// A compiler passed using `--cxx` takes precedence over the toolchain's compiler
+fn apply_toolchain<'a>(
+    mut build: Build<'a>,
+    toolchain: Option<&'a toolchain::Toolchain>,
+    matches: &ArgMatches,
+) -> Build<'a> {
+    if let Some(toolchain) = toolchain {
+        let cxx = match matches.occurrences_of("cxx") {
+            0 => None,
+            _ => build.cxx.take(),
+        };
+        build = build.with_toolchain(toolchain);
+        if cxx.is_some() {
+            build.cxx = cxx;
+        }
+    }
+
+    if let Err(e) = build.check() {
+        log!("{}", e);
+        exit(1)
+    }
+
+    build
+}
+
+/// Create a temporary directory for the executables of a command, see `RUN_DIR`
+fn run_dir(prefix: &str, keep: bool) -> PathBuf {
+    let dir = check(
+        tempfile::Builder::new().prefix(prefix).tempdir(),
+        "Error creating a directory for the executable",
+    );
+    if unsafe { VERBOSE } {
+        log!("Building in {}", dir.path().display());
+    }
+    let path = dir.path().to_path_buf();
+    if keep {
+        log!("Keeping {}", path.display());
+        let _ = dir.keep();
+    } else {
+        *RUN_DIR.lock().unwrap() = Some(dir);
+    }
+    path
+}
+
+/// Remove `RUN_DIR`, `exit` skips destructors
+fn remove_run_dir() {
+    if let Ok(mut dir) = RUN_DIR.lock() {
+        dir.take();
+    }
+}
+
+/// Unwrap a result, exiting with the conventional status code when interrupted
+fn check<T>(res: io::Result<T>, msg: impl Display) -> T {
+    match res {
+        Ok(x) => x,
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+            remove_run_dir();
+            log!("Interrupted");
+            exit(130)
+        }
+        Err(e) => {
+            remove_run_dir();
+            if let Some(failure) = e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<diagnostics::Failure>())
+            {
+                eprintln!("{}: {} failed", msg, failure.command);
+                eprintln!("Top diagnostics:");
+                for diagnostic in failure.top(5) {
+                    eprintln!("  {}", diagnostic);
+                }
+                exit(1)
+            }
+            if let Some(mismatch) = e
+                .get_ref()
+                .and_then(|e| e.downcast_ref::<golden::Mismatch>())
+            {
+                eprintln!("{}: {}", msg, mismatch);
+                exit(1)
+            }
+            panic!("{}: {}", msg, e)
+        }
+    }
+}
+
+/// Make generator arguments emit assembly, returning the path of the `.s` file, or `None`
+/// when the generator or output directory is missing
+fn emit_asm_args(args: &mut Vec<String>) -> Option<PathBuf> {
+    let value = |flag: &str| {
+        args.iter()
+            .position(|arg| arg == flag)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let output_dir = value("-o")?;
+    let function = value("-f").or_else(|| value("-g"))?;
+
+    match args.iter().position(|arg| arg == "-e") {
+        Some(i) if i + 1 < args.len() => {
+            if !args[i + 1]
+                .split(',')
+                .any(|emit| emit == Emit::Assembly.name())
+            {
+                args[i + 1].push(',');
+                args[i + 1].push_str(Emit::Assembly.name());
+            }
+        }
+        _ => args.extend(["-e".to_string(), Emit::Assembly.name().to_string()]),
+    }
+
+    Some(gen::emitted_file(output_dir, &function, Emit::Assembly))
+}
+
+/// Print the resource usage of a step as a JSON object on stderr
+fn print_resource_usage(step: &str, usage: Option<ResourceUsage>) {
+    if let Some(usage) = usage {
+        eprintln!(
+            "{}",
+            serde_json::json!({ "step": step, "resource_usage": usage })
+        );
+    }
+}
+
 fn relative_to_home<P: AsRef<Path>>(path: P) -> PathBuf {
     let home = PathBuf::from(env::var("HOME").expect("Cannot find HOME directory"));
     home.join(path.as_ref())
@@ -50,16 +258,490 @@ fn src_command<'a>() -> Command<'a> {
                 .help("Halide respository"),
         )
         .arg(
-            Arg::new("branch")
-                .long("branch")
-                .default_value("main")
-                .help("Halide source branch"),
+            Arg::new("branch")
+                .long("branch")
+                .default_value("main")
+                .help("Halide source branch"),
+        )
+        .arg(
+            Arg::new("stash")
+                .long("stash")
+                .help("Stash local changes before updating"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .short('j')
+                .long("jobs")
+                .takes_value(true)
+                .help("Number of parallel build jobs, defaults to the number of cores"),
+        )
+        .arg(
+            Arg::new("patch")
+                .long("patch")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Patch to apply before building, may be repeated"),
+        )
+        .arg(
+            Arg::new("llvm-config")
+                .long("llvm-config")
+                .env("LLVM_CONFIG")
+                .takes_value(true)
+                .help("llvm-config of the LLVM to build Halide with"),
+        )
+        .arg(
+            Arg::new("make-flags")
+                .multiple_occurrences(true)
+                .raw(true)
+                .takes_value(true)
+                .help("Arguments to make"),
+        )
+}
+
+fn build_command<'a>() -> Command<'a> {
+    Command::new("build")
+        .about("Build Halide source files")
+        .arg(
+            Arg::new("cxx")
+                .long("cxx")
+                .env("CXX")
+                .default_value("c++")
+                .help("Set c++ compiler"),
+        )
+        .arg(
+            Arg::new("cxxflags")
+                .env("CXXFLAGS")
+                .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ compile flags"),
+        )
+        .arg(
+            Arg::new("ldflags")
+                .env("LDFLAGS")
+                .long("ldflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ link flags"),
+        )
+        .arg(
+            Arg::new("name")
+                .required(true)
+                .help("Output executable name"),
+        )
+        .arg(
+            Arg::new("input")
+                .multiple_occurrences(true)
+                .required(true)
+                .help("Input files"),
+        )
+        .arg(
+            Arg::new("args")
+                .multiple_occurrences(true)
+                .raw(true)
+                .takes_value(true)
+                .help("Arguments to executable"),
+        )
+        .arg(
+            Arg::new("generator")
+                .long("generator")
+                .short('g')
+                .help("Link with GenGen.cpp"),
+        )
+        .arg(
+            Arg::new("gengen")
+                .long("gengen")
+                .takes_value(true)
+                .help("Generator main to link instead of GenGen.cpp from the Halide tools"),
+        )
+        .arg(
+            Arg::new("warnings")
+                .long("warnings")
+                .takes_value(true)
+                .possible_values(["none", "default", "strict"])
+                .help("Compiler warnings to report"),
+        )
+        .arg(
+            Arg::new("werror")
+                .long("werror")
+                .help("Treat compiler warnings as errors"),
+        )
+        .arg(
+            Arg::new("container")
+                .long("container")
+                .takes_value(true)
+                .help("Compile and run inside this Docker image, with the current directory mounted at /work"),
+        )
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .help("Print the effective configuration and exit without building"),
+        )
+        .arg(
+            Arg::new("dry-run").long("dry-run").help(
+                "Print the build commands, with their arguments grouped, without running them",
+            ),
+        )
+        .arg(
+            Arg::new("show-config")
+                .long("show-config")
+                .help("Print the resolved configuration and commands and exit without building"),
+        )
+        .arg(
+            Arg::new("strip")
+                .long("strip")
+                .help("Strip symbols from the executable"),
+        )
+        .arg(
+            Arg::new("link-map")
+                .long("link-map")
+                .takes_value(true)
+                .help("Write a linker map to this path"),
+        )
+        .arg(
+            Arg::new("llvm-system-libs")
+                .long("llvm-system-libs")
+                .help("Link the libraries reported by llvm-config with a static libHalide"),
+        )
+        .arg(
+            Arg::new("llvm-config")
+                .long("llvm-config")
+                .env("LLVM_CONFIG")
+                .takes_value(true)
+                .help("llvm-config used by --llvm-system-libs, passing it implies the flag"),
+        )
+        .arg(
+            Arg::new("object")
+                .long("object")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Prebuilt object file to link, may be repeated"),
+        )
+        .arg(
+            Arg::new("archive")
+                .long("archive")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Prebuilt static library to link, may be repeated"),
+        )
+        .arg(
+            Arg::new("no-image-io")
+                .long("no-image-io")
+                .help("Don't link libpng and libjpeg"),
+        )
+        .arg(
+            Arg::new("clean-output")
+                .long("clean-output")
+                .help("Remove an existing output before compiling"),
+        )
+        .arg(
+            Arg::new("resource-usage")
+                .long("resource-usage")
+                .help("Print the peak memory and CPU time of each step as JSON"),
+        )
+        .arg(
+            Arg::new("shared")
+                .long("shared")
+                .takes_value(true)
+                .help("Compile shared library"),
+        )
+}
+
+fn run_command<'a>() -> Command<'a> {
+    Command::new("run")
+        .about("Build and run Halide source files")
+        .arg(
+            Arg::new("cxx")
+                .long("cxx")
+                .env("CXX")
+                .default_value("c++")
+                .help("Set c++ compiler"),
+        )
+        .arg(
+            Arg::new("cxxflags")
+                .env("CXXFLAGS")
+                .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ compile flags"),
+        )
+        .arg(
+            Arg::new("ldflags")
+                .env("LDFLAGS")
+                .long("ldflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ link flags"),
+        )
+        .arg(
+            Arg::new("keep")
+                .long("keep")
+                .short('k')
+                .help("Keep generated executables"),
+        )
+        .arg(
+            Arg::new("generator")
+                .long("generator")
+                .short('g')
+                .help("Link with GenGen.cpp"),
+        )
+        .arg(
+            Arg::new("gengen")
+                .long("gengen")
+                .takes_value(true)
+                .help("Generator main to link instead of GenGen.cpp from the Halide tools"),
+        )
+        .arg(
+            Arg::new("warnings")
+                .long("warnings")
+                .takes_value(true)
+                .possible_values(["none", "default", "strict"])
+                .help("Compiler warnings to report"),
+        )
+        .arg(
+            Arg::new("werror")
+                .long("werror")
+                .help("Treat compiler warnings as errors"),
+        )
+        .arg(
+            Arg::new("container")
+                .long("container")
+                .takes_value(true)
+                .help("Compile and run inside this Docker image, with the current directory mounted at /work"),
+        )
+        .arg(Arg::new("emit-asm").long("emit-asm").help(
+            "Run the generator with -e assembly and print the path of the .s file, \
+                     implies --generator",
+        ))
+        .arg(
+            Arg::new("input")
+                .multiple_occurrences(true)
+                .required_unless_present("expr")
+                .help("Input files"),
+        )
+        .arg(
+            Arg::new("expr")
+                .long("expr")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Source code to compile, or - to read it from stdin"),
+        )
+        .arg(
+            Arg::new("args")
+                .multiple_occurrences(true)
+                .raw(true)
+                .takes_value(true)
+                .help("Arguments to executable"),
+        )
+        .arg(
+            Arg::new("under")
+                .long("under")
+                .takes_value(true)
+                .help("Wrapper command to start the executable with, like \"gdb --args\""),
+        )
+        .arg(
+            Arg::new("args-file")
+                .long("args-file")
+                .takes_value(true)
+                .help("File of arguments to the executable, passed before the others, # starts a comment line"),
+        )
+        .arg(
+            Arg::new("cwd")
+                .long("cwd")
+                .takes_value(true)
+                .help("Working directory for the executable"),
+        )
+        .arg(
+            Arg::new("stdin")
+                .long("stdin")
+                .takes_value(true)
+                .help("File to use as standard input for the executable"),
+        )
+        .arg(
+            Arg::new("nice")
+                .long("nice")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Niceness increment for the executable, Linux only"),
+        )
+        .arg(
+            Arg::new("isolate-cpu")
+                .long("isolate-cpu")
+                .takes_value(true)
+                .help("Run the executable on a single CPU with HL_NUM_THREADS=1, Linux only"),
+        )
+        .arg(
+            Arg::new("expect")
+                .long("expect")
+                .takes_value(true)
+                .help("Fail when the standard output differs from this golden file"),
+        )
+        .arg(
+            Arg::new("expect-ignore")
+                .long("expect-ignore")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Ignore output lines matching this regular expression, may be repeated"),
+        )
+        .arg(
+            Arg::new("expect-trim")
+                .long("expect-trim")
+                .help("Ignore whitespace at the end of output lines"),
+        )
+        .arg(
+            Arg::new("update-golden")
+                .long("update-golden")
+                .requires("expect")
+                .help("Rewrite the golden file with the output instead of comparing"),
+        )
+        .arg(
+            Arg::new("resource-usage")
+                .long("resource-usage")
+                .help("Print the peak memory and CPU time of each step as JSON"),
+        )
+        .arg(
+            Arg::new("object")
+                .long("object")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Prebuilt object file to link, may be repeated"),
+        )
+        .arg(
+            Arg::new("archive")
+                .long("archive")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("Prebuilt static library to link, may be repeated"),
+        )
+        .arg(
+            Arg::new("no-image-io")
+                .long("no-image-io")
+                .help("Don't link libpng and libjpeg"),
+        )
+        .arg(
+            Arg::new("shared")
+                .long("shared")
+                .takes_value(true)
+                .help("Compile shared library"),
+        )
+}
+
+fn check_command<'a>() -> Command<'a> {
+    Command::new("check")
+        .about("Check Halide source files for errors without compiling them")
+        .arg(
+            Arg::new("cxx")
+                .long("cxx")
+                .env("CXX")
+                .default_value("c++")
+                .help("Set c++ compiler"),
+        )
+        .arg(
+            Arg::new("cxxflags")
+                .env("CXXFLAGS")
+                .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ compile flags"),
+        )
+        .arg(
+            Arg::new("input")
+                .multiple_occurrences(true)
+                .required(true)
+                .help("Input files"),
+        )
+}
+
+fn new_command<'a>() -> Command<'a> {
+    Command::new("new")
+        .about("Create new Halide genertor")
+        .arg(Arg::new("path").required(true))
+        .arg(
+            Arg::new("template-file")
+                .long("template-file")
+                .takes_value(true)
+                .conflicts_with("template")
+                .help("Template with {{name}}, {{registered_name}}, {{input_type}} and {{dims}} placeholders"),
+        )
+        .arg(
+            Arg::new("template")
+                .long("template")
+                .takes_value(true)
+                .help("Name of a template in HALIDE_TEMPLATE_DIR"),
+        )
+}
+
+fn init_command<'a>() -> Command<'a> {
+    Command::new("init")
+        .about("Create a new Halide project")
+        .arg(Arg::new("dir").required(true))
+        .arg(
+            Arg::new("force")
+                .long("force")
+                .short('f')
+                .help("Overwrite files in a non-empty directory"),
+        )
+}
+
+fn versions_command<'a>() -> Command<'a> {
+    Command::new("versions")
+        .about("Build and run Halide source files against several Halide installations")
+        .arg(
+            Arg::new("halide")
+                .long("halide")
+                .short('H')
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .required(true)
+                .help("Path to a Halide installation, may be repeated"),
+        )
+        .arg(
+            Arg::new("cxx")
+                .long("cxx")
+                .env("CXX")
+                .default_value("c++")
+                .help("Set c++ compiler"),
+        )
+        .arg(
+            Arg::new("cxxflags")
+                .env("CXXFLAGS")
+                .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ compile flags"),
+        )
+        .arg(
+            Arg::new("ldflags")
+                .env("LDFLAGS")
+                .long("ldflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
+                .help("Set c++ link flags"),
+        )
+        .arg(
+            Arg::new("generator")
+                .long("generator")
+                .short('g')
+                .help("Link with GenGen.cpp"),
+        )
+        .arg(
+            Arg::new("input")
+                .multiple_occurrences(true)
+                .required(true)
+                .help("Input files"),
+        )
+        .arg(
+            Arg::new("args")
+                .multiple_occurrences(true)
+                .raw(true)
+                .takes_value(true)
+                .help("Arguments to executable"),
         )
 }
 
-fn build_command<'a>() -> Command<'a> {
-    Command::new("build")
-        .about("Build Halide source files")
+fn ninja_command<'a>() -> Command<'a> {
+    Command::new("ninja")
+        .about("Write a Ninja build file for the kernels in a project")
         .arg(
             Arg::new("cxx")
                 .long("cxx")
@@ -71,49 +753,103 @@ fn build_command<'a>() -> Command<'a> {
             Arg::new("cxxflags")
                 .env("CXXFLAGS")
                 .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
                 .help("Set c++ compile flags"),
         )
         .arg(
             Arg::new("ldflags")
                 .env("LDFLAGS")
                 .long("ldflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
                 .help("Set c++ link flags"),
         )
         .arg(
-            Arg::new("name")
-                .required(true)
-                .help("Output executable name"),
+            Arg::new("output")
+                .long("output")
+                .short('o')
+                .takes_value(true)
+                .help("Build file to write, defaults to build.ninja in the project directory"),
         )
         .arg(
-            Arg::new("input")
-                .multiple_occurrences(true)
-                .required(true)
-                .help("Input files"),
+            Arg::new("project")
+                .default_value(".")
+                .help("Project file or directory containing halide.toml"),
         )
+}
+
+fn gen_command<'a>() -> Command<'a> {
+    Command::new("gen")
+        .about("Build a generator and run it to emit a pipeline")
         .arg(
-            Arg::new("args")
-                .multiple_occurrences(true)
-                .raw(true)
+            Arg::new("cxx")
+                .long("cxx")
+                .env("CXX")
+                .default_value("c++")
+                .help("Set c++ compiler"),
+        )
+        .arg(
+            Arg::new("cxxflags")
+                .env("CXXFLAGS")
+                .long("cxxflags")
                 .takes_value(true)
-                .help("Arguments to executable"),
+                .allow_hyphen_values(true)
+                .help("Set c++ compile flags"),
+        )
+        .arg(
+            Arg::new("binary")
+                .long("binary")
+                .takes_value(true)
+                .help("Existing generator executable, only rebuilt when an input is newer"),
+        )
+        .arg(
+            Arg::new("under")
+                .long("under")
+                .takes_value(true)
+                .help("Wrapper command to start the generator with, like \"gdb --args\""),
+        )
+        .arg(
+            Arg::new("gengen")
+                .long("gengen")
+                .takes_value(true)
+                .help("Generator main to link instead of GenGen.cpp from the Halide tools"),
         )
         .arg(
             Arg::new("generator")
-                .long("generator")
                 .short('g')
-                .help("Link with GenGen.cpp"),
+                .takes_value(true)
+                .required(true)
+                .help("Name of the registered generator"),
         )
         .arg(
-            Arg::new("shared")
-                .long("shared")
+            Arg::new("function")
+                .short('f')
                 .takes_value(true)
-                .help("Compile shared library"),
+                .help("Name of the emitted function"),
+        )
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .takes_value(true)
+                .default_value(".")
+                .help("Directory for emitted files"),
         )
+        .arg(
+            Arg::new("emit")
+                .short('e')
+                .takes_value(true)
+                .help("Comma separated files to emit, like static_library,c_header"),
+        )
+        .arg(Arg::new("input").multiple_occurrences(true).help(
+            "Generator sources, found from the generator name when omitted, and \
+                     name=value generator parameters like target=host",
+        ))
 }
 
-fn run_command<'a>() -> Command<'a> {
-    Command::new("run")
-        .about("Build and run Halide source files")
+fn compare_command<'a>() -> Command<'a> {
+    Command::new("compare")
+        .about("Build and run two pipelines and compare the files they write")
         .arg(
             Arg::new("cxx")
                 .long("cxx")
@@ -125,51 +861,210 @@ fn run_command<'a>() -> Command<'a> {
             Arg::new("cxxflags")
                 .env("CXXFLAGS")
                 .long("cxxflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
                 .help("Set c++ compile flags"),
         )
         .arg(
             Arg::new("ldflags")
                 .env("LDFLAGS")
                 .long("ldflags")
+                .takes_value(true)
+                .allow_hyphen_values(true)
                 .help("Set c++ link flags"),
         )
         .arg(
             Arg::new("keep")
                 .long("keep")
                 .short('k')
-                .help("Keep generated executables"),
+                .help("Keep the executables and their outputs"),
         )
         .arg(
-            Arg::new("generator")
-                .long("generator")
-                .short('g')
-                .help("Link with GenGen.cpp"),
+            Arg::new("output-file")
+                .long("output-file")
+                .short('o')
+                .takes_value(true)
+                .required(true)
+                .help("File written by both executables, relative to their working directory"),
         )
         .arg(
-            Arg::new("input")
-                .multiple_occurrences(true)
+            Arg::new("tolerance")
+                .long("tolerance")
+                .takes_value(true)
+                .help("Largest allowed difference between elements, outputs must match exactly without it"),
+        )
+        .arg(
+            Arg::new("type")
+                .long("type")
+                .takes_value(true)
+                .default_value("float32")
+                .help("Element type used with --tolerance, like uint8 or float32"),
+        )
+        .arg(
+            Arg::new("reference")
                 .required(true)
-                .help("Input files"),
+                .help("Source file of the reference pipeline"),
+        )
+        .arg(
+            Arg::new("candidate")
+                .required(true)
+                .help("Source file of the pipeline being checked"),
         )
         .arg(
             Arg::new("args")
                 .multiple_occurrences(true)
                 .raw(true)
                 .takes_value(true)
-                .help("Arguments to executable"),
+                .help("Arguments to both executables"),
         )
+}
+
+fn manifest_command<'a>() -> Command<'a> {
+    Command::new("manifest")
+        .about("Print the pipelines recorded in a manifest")
         .arg(
-            Arg::new("shared")
-                .long("shared")
+            Arg::new("path")
+                .required(true)
+                .help("Manifest file or directory containing pipelines.json"),
+        )
+}
+
+fn info_command<'a>() -> Command<'a> {
+    Command::new("info")
+        .about("Print the resolved Halide paths, versions, compiler and target")
+        .arg(
+            Arg::new("json")
+                .long("json")
+                .help("Print a JSON object, see the info module for the schema"),
+        )
+}
+
+fn vendor_command<'a>() -> Command<'a> {
+    Command::new("vendor")
+        .about("Copy the headers, library and tools kernel builds need into a project")
+        .arg(
+            Arg::new("dest")
+                .long("dest")
                 .takes_value(true)
-                .help("Compile shared library"),
+                .required(true)
+                .help("Directory to copy into, must be empty or previously vendored"),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Check the vendored files against their manifest instead of copying"),
+        )
+        .arg(
+            Arg::new("no-autoschedulers")
+                .long("no-autoschedulers")
+                .help("Don't copy the autoscheduler plugins"),
         )
 }
 
-fn new_command<'a>() -> Command<'a> {
-    Command::new("new")
-        .about("Create new Halide genertor")
-        .arg(Arg::new("path").required(true))
+/// Arguments selecting the build an artifact is exported from, or imported for
+fn cache_build_args(cmd: Command) -> Command {
+    cmd.arg(
+        Arg::new("cxx")
+            .long("cxx")
+            .env("CXX")
+            .default_value("c++")
+            .help("Set c++ compiler"),
+    )
+    .arg(
+        Arg::new("cxxflags")
+            .env("CXXFLAGS")
+            .long("cxxflags")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("Set c++ compile flags"),
+    )
+    .arg(
+        Arg::new("ldflags")
+            .env("LDFLAGS")
+            .long("ldflags")
+            .takes_value(true)
+            .allow_hyphen_values(true)
+            .help("Set c++ link flags"),
+    )
+    .arg(
+        Arg::new("target")
+            .long("target")
+            .takes_value(true)
+            .help("Halide target the artifact is built for, defaults to host"),
+    )
+    .arg(
+        Arg::new("generator")
+            .long("generator")
+            .short('g')
+            .help("The artifact is a generator linked with GenGen.cpp"),
+    )
+    .arg(
+        Arg::new("input")
+            .multiple_occurrences(true)
+            .help("Input files the artifact is built from"),
+    )
+}
+
+/// The build described by the arguments added by `cache_build_args`
+fn cache_build<'a>(halide_path: &Path, m: &'a ArgMatches, output: &str) -> Build<'a> {
+    let mut build = Build::new(halide_path, output);
+    build.cxx = m.value_of("cxx").map(Cow::from);
+    build.cxxflags = m.value_of("cxxflags").map(Cow::from);
+    build.ldflags = m.value_of("ldflags").map(Cow::from);
+    build.target = m.value_of("target");
+    build.generator = m.is_present("generator");
+    build.src = m
+        .values_of("input")
+        .unwrap_or_default()
+        .map(PathBuf::from)
+        .collect();
+    build
+}
+
+fn cache_command<'a>() -> Command<'a> {
+    Command::new("cache")
+        .about("Share built artifacts between machines")
+        .subcommand(cache_build_args(
+            Command::new("export")
+                .about("Build an artifact, unless it's up to date, and write it and its fingerprint to an archive")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .takes_value(true)
+                        .required(true)
+                        .help("The built artifact"),
+                )
+                .arg(
+                    Arg::new("manifest")
+                        .long("manifest")
+                        .takes_value(true)
+                        .help("Manifest of the pipelines linked into the artifact"),
+                )
+                .arg(Arg::new("archive").required(true).help("Archive to write")),
+        ))
+        .subcommand(cache_build_args(
+            Command::new("import")
+                .about("Verify and extract an archive written by `halide cache export`")
+                .arg(
+                    Arg::new("dir")
+                        .long("dir")
+                        .takes_value(true)
+                        .default_value(".")
+                        .help("Directory to extract the artifact into"),
+                )
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .help("Import artifacts built for another Halide, target, host or inputs"),
+                )
+                .arg(Arg::new("archive").required(true).help("Archive to read")),
+        ))
+}
+
+fn doctor_command<'a>() -> Command<'a> {
+    Command::new("doctor")
+        .about("Check the Halide path, compiler, libraries and tools, with hints for fixing them")
 }
 
 fn main() {
@@ -182,6 +1077,11 @@ fn main() {
                 .short('q')
                 .help("Disable logging to stdout/stderr"),
         )
+        .arg(
+            Arg::new("verbose")
+                .short('v')
+                .help("Log more details, like where temporary files are written"),
+        )
         .arg(
             Arg::new("halide-path")
                 .short('p')
@@ -192,12 +1092,24 @@ fn main() {
         .subcommand(src_command())
         .subcommand(build_command())
         .subcommand(run_command())
-        .subcommand(new_command());
+        .subcommand(check_command())
+        .subcommand(new_command())
+        .subcommand(init_command())
+        .subcommand(versions_command())
+        .subcommand(ninja_command())
+        .subcommand(gen_command())
+        .subcommand(compare_command())
+        .subcommand(manifest_command())
+        .subcommand(info_command())
+        .subcommand(vendor_command())
+        .subcommand(cache_command())
+        .subcommand(doctor_command());
 
     let matches = app.clone().get_matches();
 
     unsafe {
         QUIET = matches.is_present("quiet");
+        VERBOSE = matches.is_present("verbose");
     }
 
     let halide_path = Path::new(
@@ -206,26 +1118,70 @@ fn main() {
             .expect("Invalid HALIDE_PATH"),
     );
 
+    let interrupt = InterruptToken::new();
+    install_signal_handlers(interrupt.clone());
+
+    let toolchain = Path::new(toolchain::FILE_NAME).exists().then(|| {
+        check(
+            toolchain::load(toolchain::FILE_NAME),
+            "Error loading toolchain file",
+        )
+    });
+
     if let Some(src) = matches.subcommand_matches("src") {
-        let source = Source {
-            halide_path: halide_path.to_owned(),
-            repo: src
-                .value_of("source")
-                .expect("Invalid source repository")
-                .to_string(),
-            branch: src
-                .value_of("branch")
-                .expect("Invalid branch name")
-                .to_string(),
-            make: src
-                .value_of("make")
-                .expect("Invalid make executable")
-                .to_string(),
-            make_flags: src
-                .values_of("make-flags")
-                .unwrap_or(clap::Values::default())
-                .map(|s| s.to_string())
-                .collect(),
+        let mut source = Source::new(halide_path);
+        source.repo = src
+            .value_of("source")
+            .expect("Invalid source repository")
+            .to_string();
+        source.branch = src
+            .value_of("branch")
+            .expect("Invalid branch name")
+            .to_string();
+        source.make = src
+            .value_of("make")
+            .expect("Invalid make executable")
+            .to_string();
+        source.make_flags = src
+            .values_of("make-flags")
+            .unwrap_or(clap::Values::default())
+            .map(|s| s.to_string())
+            .collect();
+        source.stash = src.is_present("stash");
+        source.jobs = src
+            .value_of("jobs")
+            .map(|n| n.parse().expect("Invalid number of jobs"));
+        source.llvm_config = src.value_of("llvm-config").map(PathBuf::from);
+        source.patches = src
+            .values_of("patch")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        source.interrupt = interrupt.clone();
+
+        // Output is passed through unchanged unless there's a terminal to draw progress on
+        let progress = unsafe { !QUIET } && io::stderr().is_terminal();
+        if progress {
+            // The latest build percentage, drawn on each tick
+            let percent = Mutex::new(None);
+            source.events = EventHandler::new(move |event| match event {
+                Event::SourceTick { step, elapsed } => {
+                    progress_bar(*step, *elapsed, *percent.lock().unwrap())
+                }
+                Event::SourceProgress {
+                    percent: Some(p), ..
+                } => *percent.lock().unwrap() = Some(*p),
+                Event::SourceProgress { line, .. } => {
+                    eprint!("\r\x1b[K");
+                    println!("{}", line)
+                }
+                event => log!("{}", event),
+            });
+        }
+        let clear_progress = || {
+            if progress {
+                eprint!("\r\x1b[K");
+            }
         };
 
         if halide_path.exists() {
@@ -233,8 +1189,22 @@ fn main() {
                 "Updating Halide source in {}",
                 halide_path.to_string_lossy()
             );
-            if !source.update().expect("Error updating git repository") {
-                log!("Failed to update git repository");
+            let updated = source.update();
+            clear_progress();
+            match updated {
+                Ok(scm::UpdateStatus::UpToDate) => log!("Halide source is up to date"),
+                Ok(scm::UpdateStatus::Updated) => log!("Halide source updated"),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                    log!("Interrupted");
+                    exit(130)
+                }
+                Err(e) => {
+                    log!("Failed to update git repository: {}", e);
+                    exit(1)
+                }
+            }
+            if let Err(e) = source.apply_patches() {
+                log!("{}", e);
                 exit(1)
             }
         } else {
@@ -242,13 +1212,28 @@ fn main() {
                 "Downloading Halide source to {}",
                 halide_path.to_string_lossy()
             );
-            if !source.download().expect("Error downloading git repository") {
+            let downloaded = source.download();
+            clear_progress();
+            if !check(downloaded, "Error downloading git repository") {
                 log!("Failed to clone git repository");
                 exit(1)
             }
         }
 
-        if !source.build().expect("Error building Halide source") {
+        // Catch an unsupported LLVM before a long build fails
+        match source.verify_llvm() {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => log!("Warning: {}", e),
+            Err(e) => {
+                log!("{}", e);
+                exit(1)
+            }
+        }
+
+        let built = source.build();
+        clear_progress();
+
+        if !check(built, "Error building Halide source") {
             log!("Halide build failed");
             exit(1)
         } else {
@@ -258,32 +1243,88 @@ fn main() {
             );
         }
     } else if let Some(b) = matches.subcommand_matches("build") {
-        let build = Build {
-            halide_path: halide_path.to_owned(),
-            cxx: b.value_of("cxx"),
-            cxxflags: b.value_of("cxxflags"),
-            ldflags: b.value_of("ldflags"),
-            output: PathBuf::from(b.value_of("name").expect("Invalid output path")),
-            src: b
-                .values_of("input")
-                .expect("Invalid input files")
-                .map(|x| PathBuf::from(x))
-                .collect(),
-            keep: true,
-            build_args: b
-                .values_of("args")
-                .unwrap_or(clap::Values::default())
-                .collect(),
-            run_args: vec![],
-            generator: b.is_present("generator"),
-        };
+        let mut build = Build::new(
+            halide_path,
+            b.value_of("name").expect("Invalid output path"),
+        );
+        build.cxx = b.value_of("cxx").map(Cow::from);
+        build.cxxflags = b.value_of("cxxflags").map(Cow::from);
+        build.ldflags = b.value_of("ldflags").map(Cow::from);
+        build.src = b
+            .values_of("input")
+            .expect("Invalid input files")
+            .map(PathBuf::from)
+            .collect();
+        build.keep = true;
+        build.build_args = b
+            .values_of("args")
+            .unwrap_or(clap::Values::default())
+            .map(OsString::from)
+            .collect();
+        build.generator = b.is_present("generator");
+        build.gengen = b.value_of("gengen").map(PathBuf::from);
+        if let Some(level) = b.value_of("warnings") {
+            build.warnings = WarningLevel::from_name(level).unwrap();
+        }
+        build.warnings_as_errors = b.is_present("werror");
+        if let Some(image) = b.value_of("container") {
+            build = build.in_container(check(Container::new(image), "Error setting up container"));
+        }
+        build.objects = b
+            .values_of("object")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        build.archives = b
+            .values_of("archive")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        build.strip = b.is_present("strip");
+        build.link_map = b.value_of("link-map").map(PathBuf::from);
+        if b.is_present("llvm-system-libs") || b.occurrences_of("llvm-config") > 0 {
+            build.llvm_config = Some(PathBuf::from(
+                b.value_of("llvm-config").unwrap_or("llvm-config"),
+            ));
+        }
+        build.clean_output = b.is_present("clean-output");
+        build.resource_usage = b.is_present("resource-usage");
+        if b.is_present("no-image-io") {
+            build.image_libs.clear();
+        }
+        build.interrupt = interrupt.clone();
+        build.events = EventHandler::new(|event| log!("{}", event));
+        record_provenance(&mut build, b, &matches);
+        let build = apply_toolchain(build, toolchain.as_ref(), b);
+
+        if b.is_present("explain") {
+            print!("{}", build.explain());
+            return;
+        }
+
+        if b.is_present("show-config") {
+            print!("{}", build.summary());
+            return;
+        }
+
+        if b.is_present("dry-run") {
+            for cmd in build.dump_command_groups() {
+                print!("{}", cmd);
+            }
+            return;
+        }
 
         log!("Compiling {:?} to {:?}", build.src, build.output);
-        if !build
-            .build()
-            .expect(format!("Error building {:?}", build.output).as_str())
-        {
+        let report = check(
+            build.build_report(),
+            format!("Error building {:?}", build.output),
+        );
+        print_resource_usage("build", report.resource_usage);
+        if !report.success {
             log!("Unable to build {:?}", build.output);
+            if let Some(advice) = build.layout_advice() {
+                log!("{}", advice);
+            }
             exit(1)
         }
 
@@ -291,90 +1332,548 @@ fn main() {
             let f = std::path::PathBuf::from(x);
             let f =
                 f.with_file_name(String::from("lib") + f.file_name().unwrap().to_str().unwrap());
-            let f = f.with_extension("so");
+            let f = shared_library::Platform::host().output_path(f.with_extension(""));
 
             log!("Building shared library: {} -> {}", x, f.display());
             compile_shared_library(b.value_of("cxx"), f.to_str().unwrap(), &[x])
                 .expect("Unable to compile shared library");
         }
     } else if let Some(b) = matches.subcommand_matches("run") {
-        let start = SystemTime::now();
-        let ts = start.duration_since(UNIX_EPOCH).unwrap();
-        let ms = ts.as_secs() * 1000 + ts.subsec_nanos() as u64 / 1000000;
-        let build = Build {
-            halide_path: halide_path.to_owned(),
-            cxx: b.value_of("cxx"),
-            cxxflags: b.value_of("cxxflags"),
-            ldflags: b.value_of("ldflags"),
-            output: PathBuf::from(format!("./halide-{}", ms)),
-            src: b
-                .values_of("input")
-                .expect("Invalid input files")
-                .map(|x| PathBuf::from(x))
-                .collect(),
-            keep: b.is_present("keep"),
-            run_args: b
-                .values_of("args")
+        let expr = b.value_of("expr").map(|code| match code {
+            "-" => check(
+                io::read_to_string(io::stdin()),
+                "Error reading source from stdin",
+            ),
+            code => code.to_string(),
+        });
+
+        let mut run_args: Vec<String> = vec![];
+        if let Some(path) = b.value_of("args-file") {
+            let contents = check(
+                std::fs::read_to_string(path),
+                format!("Error reading arguments from {}", path),
+            );
+            run_args.extend(parse_args_file(&contents));
+        }
+        run_args.extend(
+            b.values_of("args")
                 .unwrap_or(clap::Values::default())
-                .collect(),
-            build_args: vec![],
-            generator: b.is_present("generator"),
-        };
+                .map(String::from),
+        );
+        let asm = b.is_present("emit-asm").then(|| {
+            emit_asm_args(&mut run_args).unwrap_or_else(|| {
+                log!("--emit-asm requires the -g and -o generator arguments");
+                exit(1)
+            })
+        });
+
+        // Each run gets its own directory, so concurrent runs can't collide and nothing is
+        // left behind when a run fails
+        let dir = run_dir("halide-run-", b.is_present("keep"));
+        let mut build = Build::new(halide_path, dir.join("halide"));
+        build.cxx = b.value_of("cxx").map(Cow::from);
+        build.cxxflags = b.value_of("cxxflags").map(Cow::from);
+        build.ldflags = b.value_of("ldflags").map(Cow::from);
+        build.src = b
+            .values_of("input")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        if let Some(code) = &expr {
+            build = check(build.source_string("expr", code), "Invalid source");
+        }
+        build.keep = b.is_present("keep");
+        // A stale output from an earlier run must never be run after a failed build
+        build.clean_output = true;
+        build.resource_usage = b.is_present("resource-usage");
+        build.run_args = run_args.iter().map(OsString::from).collect();
+        build.run_under = b
+            .value_of("under")
+            .map(|under| under.split_whitespace().collect())
+            .unwrap_or_default();
+        build.generator = b.is_present("generator") || asm.is_some();
+        build.gengen = b.value_of("gengen").map(PathBuf::from);
+        if let Some(level) = b.value_of("warnings") {
+            build.warnings = WarningLevel::from_name(level).unwrap();
+        }
+        build.warnings_as_errors = b.is_present("werror");
+        if let Some(image) = b.value_of("container") {
+            build = build.in_container(check(Container::new(image), "Error setting up container"));
+        }
+        build.objects = b
+            .values_of("object")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        build.archives = b
+            .values_of("archive")
+            .unwrap_or(clap::Values::default())
+            .map(PathBuf::from)
+            .collect();
+        if b.is_present("no-image-io") {
+            build.image_libs.clear();
+        }
+        build.run_cwd = b.value_of("cwd").map(PathBuf::from);
+        if let Some(path) = b.value_of("stdin") {
+            build.stdin = StdinSource::File(PathBuf::from(path));
+        }
+        build.nice = b
+            .value_of("nice")
+            .map(|n| n.parse().expect("Invalid niceness"));
+        if let Some(golden) = b.value_of("expect") {
+            build = build
+                .expect_stdout(golden)
+                .expect_trim_trailing_whitespace(b.is_present("expect-trim"))
+                .update_golden(b.is_present("update-golden"));
+            for pattern in b
+                .values_of("expect-ignore")
+                .unwrap_or(clap::Values::default())
+            {
+                build = build.expect_ignore(pattern);
+            }
+        }
+        if let Some(cpu) = b.value_of("isolate-cpu") {
+            build.cpu_affinity = vec![cpu.parse().expect("Invalid CPU index")];
+            build
+                .run_vars
+                .push(("HL_NUM_THREADS".to_string(), "1".to_string()));
+        }
+        build.interrupt = interrupt.clone();
+        build.events = EventHandler::new(|event| log!("{}", event));
+        let build = apply_toolchain(build, toolchain.as_ref(), b);
 
         let output = build.output.to_owned();
 
         log!("Compiling {:?} to {:?}", build.src, output);
-        if !build
-            .build()
-            .expect(format!("Error building {:?}", build.src).as_str())
-        {
+        let report = check(
+            build.build_report(),
+            format!("Error building {:?}", build.src),
+        );
+        print_resource_usage("build", report.resource_usage);
+        if !report.success {
             log!("Failure building {:?}", build.src);
+            if let Some(advice) = build.layout_advice() {
+                log!("{}", advice);
+            }
+            remove_run_dir();
             exit(1)
         }
 
         log!("Running {:?}", build.output);
-        if !build
-            .run()
-            .expect(format!("Error running {:?}", build.output).as_str())
-        {
+        let report = check(
+            build.run_report(),
+            format!("Error running {:?}", build.output),
+        );
+        print_resource_usage("run", report.resource_usage);
+        // Output checked against a golden file is captured, so it's printed afterwards
+        if let Some(outcome) = &report.outcome {
+            let _ = io::stdout().write_all(&outcome.stdout);
+            let _ = io::stderr().write_all(&outcome.stderr);
+        }
+        if !report.success {
             log!("Failure while running {:?}", build.output);
+            remove_run_dir();
             exit(1)
         }
 
+        remove_run_dir();
+
+        if let Some(path) = asm {
+            println!("{}", path.display());
+        }
+
         if let Some(x) = b.value_of("shared") {
             let f = std::path::PathBuf::from(x);
             let f =
                 f.with_file_name(String::from("lib") + f.file_name().unwrap().to_str().unwrap());
-            let f = f.with_extension("so");
+            let f = shared_library::Platform::host().output_path(f.with_extension(""));
 
             log!("Building shared library: {} -> {}", x, f.display());
             compile_shared_library(b.value_of("cxx"), f.to_str().unwrap(), &[x])
                 .expect("Unable to compile shared library");
         }
+    } else if let Some(b) = matches.subcommand_matches("check") {
+        let mut build = Build::new(halide_path, "");
+        build.cxx = b.value_of("cxx").map(Cow::from);
+        build.cxxflags = b.value_of("cxxflags").map(Cow::from);
+        build.src = b
+            .values_of("input")
+            .expect("Invalid input files")
+            .map(PathBuf::from)
+            .collect();
+        build.interrupt = interrupt.clone();
+        let build = apply_toolchain(build, toolchain.as_ref(), b);
+
+        log!("Checking {:?}", build.src);
+        if !check(
+            build.syntax_check(),
+            format!("Error checking {:?}", build.src),
+        ) {
+            exit(1)
+        }
     } else if let Some(b) = matches.subcommand_matches("new") {
         let dest = b.value_of("path").unwrap();
+        let source = match (b.value_of("template-file"), b.value_of("template")) {
+            (Some(path), _) => check(std::fs::read_to_string(path), "Unable to read template"),
+            (None, Some(name)) => check(template::load(name), "Unable to load template"),
+            (None, None) => template::DEFAULT.to_string(),
+        };
+        let vars = template::TemplateVars::new("Filter", "filter");
+        let s = match template::render(&source, &vars) {
+            Ok(s) => s,
+            Err(e) => {
+                log!("Invalid template: {}", e);
+                exit(1)
+            }
+        };
         let mut f = std::fs::File::create(dest).expect("Unable to open output file");
-        let s = "
-#include <Halide.h>
-using namespace Halide;
+        if let Err(e) = f.write(s.as_bytes()) {
+            log!("Unable to write new file: {:?}", e);
+        }
+    } else if let Some(b) = matches.subcommand_matches("init") {
+        let dir = b.value_of("dir").unwrap();
+        if let Err(e) = project::init(dir, b.is_present("force")) {
+            log!("Unable to create project: {}", e);
+            exit(1)
+        }
+        log!("Created Halide project in {}", dir);
+    } else if let Some(b) = matches.subcommand_matches("ninja") {
+        let project_path = Path::new(b.value_of("project").unwrap());
+        let project = check(project::load(project_path), "Error loading project file");
+        if project.kernels.is_empty() {
+            log!("No kernels in {}", project_path.display());
+            exit(1)
+        }
 
-class Filter: public Generator<Filter> {
-public:
-    Var x, y, c;
-    Input<Buffer<float>> input{\"input\", 3};
-    Output<Buffer<float>> output{\"output\", 3};
-    void generate(){
+        // Paths in the build file are relative to the project directory, where ninja is run
+        let dir = match project_path.is_dir() {
+            true => project_path,
+            false => project_path.parent().unwrap_or(Path::new(".")),
+        };
+        let output = b
+            .value_of("output")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| dir.join("build.ninja"));
 
-    }
+        let halide_path = check(std::path::absolute(halide_path), "Invalid Halide path");
+        let mut base = Build::new(&halide_path, "");
+        base.cxx = b.value_of("cxx").map(Cow::from);
+        base.cxxflags = b.value_of("cxxflags").map(Cow::from);
+        base.ldflags = b.value_of("ldflags").map(Cow::from);
+        let base = apply_toolchain(base, toolchain.as_ref(), b);
 
-    void schedule(){
+        let mut ninja = ninja::NinjaWriter::new(&base);
+        let mut targets = vec![];
+        for kernel in &project.kernels {
+            let mut build = base.clone();
+            build.output = kernel
+                .output_dir()
+                .join(format!("{}.generator", kernel.name));
+            build.src = kernel.sources.clone();
+            build.target = kernel.target.as_deref();
 
-    }
-};
+            let mut gen = GenBuilder::new(build, kernel.generator_name(), kernel.output_dir())
+                .function_name(&kernel.name);
+            for (name, value) in &kernel.params {
+                gen = gen.param(name, value);
+            }
+            check(gen.validate(), format!("Invalid kernel {}", kernel.name));
 
-HALIDE_REGISTER_GENERATOR(Filter, filter);";
-        if let Err(e) = f.write(s.as_bytes()) {
-            log!("Unable to write new file: {:?}", e);
+            ninja.add_generator(&gen);
+            targets.extend(gen.emitted_files());
+        }
+        ninja.default(&targets);
+
+        check(ninja.write(&output), "Error writing build file");
+        log!("Wrote {}", output.display());
+    } else if let Some(b) = matches.subcommand_matches("versions") {
+        let dir = run_dir("halide-versions-", false);
+        let mut build = Build::new(halide_path, dir.join("halide"));
+        build.cxx = b.value_of("cxx").map(Cow::from);
+        build.cxxflags = b.value_of("cxxflags").map(Cow::from);
+        build.ldflags = b.value_of("ldflags").map(Cow::from);
+        build.src = b
+            .values_of("input")
+            .expect("Invalid input files")
+            .map(PathBuf::from)
+            .collect();
+        build.run_args = b
+            .values_of("args")
+            .unwrap_or(clap::Values::default())
+            .map(OsString::from)
+            .collect();
+        build.generator = b.is_present("generator");
+        build.interrupt = interrupt.clone();
+
+        let paths: Vec<&str> = b.values_of("halide").unwrap().collect();
+        let results = check(
+            compat::check(&build, &paths),
+            "Error checking Halide versions",
+        );
+        remove_run_dir();
+
+        print!("{}", compat::table(&results));
+        if !results.iter().all(|r| r.passed()) {
+            exit(1)
+        }
+    } else if let Some(g) = matches.subcommand_matches("gen") {
+        let generator = g.value_of("generator").unwrap();
+        let output_dir = g.value_of("output").unwrap();
+        let (params, src): (Vec<&str>, Vec<&str>) = g
+            .values_of("input")
+            .unwrap_or_default()
+            .partition(|arg| arg.contains('='));
+
+        let binary = g
+            .value_of("binary")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| Path::new(output_dir).join(format!("{}.generator", generator)));
+        let mut build = Build::new(halide_path, &binary).incremental(true);
+        build.cxx = g.value_of("cxx").map(Cow::from);
+        build.cxxflags = g.value_of("cxxflags").map(Cow::from);
+        build.src = src.iter().map(PathBuf::from).collect();
+        // Without sources, the generator is found by its name, like `blur_generator.cpp`
+        if build.src.is_empty() && !g.is_present("binary") {
+            build = check(
+                build.source_from_generator_name(".", generator),
+                "Unable to find the generator source",
+            );
+        }
+        build.gengen = g.value_of("gengen").map(PathBuf::from);
+        build.run_under = g
+            .value_of("under")
+            .map(|under| under.split_whitespace().collect())
+            .unwrap_or_default();
+        build.interrupt = interrupt.clone();
+        build.events = EventHandler::new(|event| log!("{}", event));
+
+        let mut gen = GenBuilder::new(build, generator, output_dir);
+        if g.is_present("binary") {
+            gen = gen.existing_binary(&binary);
+        }
+        if let Some(name) = g.value_of("function") {
+            gen = gen.function_name(name);
+        }
+        for name in g.value_of("emit").unwrap_or_default().split(',') {
+            match Emit::from_name(name) {
+                Some(emit) => gen = gen.emit(emit),
+                None if name.is_empty() => (),
+                None => {
+                    log!("Unknown emit kind {}", name);
+                    exit(1)
+                }
+            }
+        }
+        for param in params {
+            let (name, value) = param.split_once('=').unwrap();
+            match name {
+                "target" => gen.build.target = Some(value),
+                name => gen = gen.param(name, value),
+            }
+        }
+        gen.build = apply_toolchain(gen.build, toolchain.as_ref(), g);
+
+        if !check(
+            gen.build_then_emit(),
+            format!("Error running generator {}", generator),
+        ) {
+            log!("Unable to emit {}", gen.function());
+            exit(1)
+        }
+    } else if let Some(c) = matches.subcommand_matches("compare") {
+        let output_file = c.value_of("output-file").unwrap();
+        let tolerance = c
+            .value_of("tolerance")
+            .map(|t| t.parse::<f64>().expect("Invalid tolerance"));
+        let element = c.value_of("type").and_then(harness::ElementType::from_name);
+        let element = element.unwrap_or_else(|| {
+            log!("Unknown element type {}", c.value_of("type").unwrap());
+            exit(1)
+        });
+        let run_args: Vec<&str> = c.values_of("args").unwrap_or_default().collect();
+
+        let dir = run_dir("halide-compare-", c.is_present("keep"));
+
+        // Each executable runs in its own directory, so they can write the same file name
+        let mut outputs = vec![];
+        for name in ["reference", "candidate"] {
+            let src = c.value_of(name).unwrap();
+            let run_dir = dir.join(name);
+            check(
+                std::fs::create_dir_all(&run_dir),
+                format!("Error creating {}", run_dir.display()),
+            );
+
+            let mut build = Build::new(halide_path, run_dir.join("pipeline"))
+                .source_file(src)
+                .run_args(&run_args)
+                .run_cwd(&run_dir)
+                .run_capture_file(output_file);
+            build.cxx = c.value_of("cxx").map(Cow::from);
+            build.cxxflags = c.value_of("cxxflags").map(Cow::from);
+            build.ldflags = c.value_of("ldflags").map(Cow::from);
+            build.keep = true;
+            build.clean_output = true;
+            build.interrupt = interrupt.clone();
+            build.events = EventHandler::new(|event| log!("{}", event));
+            let build = apply_toolchain(build, toolchain.as_ref(), c);
+
+            log!("Compiling {} to {}", src, build.output.display());
+            if !check(build.build(), format!("Error building {}", src)) {
+                log!("Failure building {}", src);
+                remove_run_dir();
+                exit(1)
+            }
+
+            log!("Running {}", build.output.display());
+            let report = check(build.run_report(), format!("Error running {}", src));
+            if !report.success {
+                log!("Failure while running {}", src);
+                remove_run_dir();
+                exit(1)
+            }
+            outputs.extend(report.outputs);
+        }
+
+        let comparison = check(
+            compare::files(&outputs[0], &outputs[1], element, tolerance),
+            format!("Error comparing {}", output_file),
+        );
+        remove_run_dir();
+
+        println!("{}", comparison);
+        if !comparison.matches() {
+            exit(1)
+        }
+    } else if let Some(m) = matches.subcommand_matches("manifest") {
+        let path = m.value_of("path").unwrap();
+        let manifest = match manifest::load(path) {
+            Ok(m) => m,
+            Err(e) => {
+                log!("Unable to load manifest {}: {}", path, e);
+                exit(1)
+            }
+        };
+
+        for pipeline in &manifest.pipelines {
+            println!("{} (generator {})", pipeline.function, pipeline.generator);
+            println!("  targets: {}", pipeline.targets.join(", "));
+            if let Some(version) = &pipeline.halide_version {
+                println!("  halide: {}", version);
+            }
+            for arg in &pipeline.arguments {
+                println!("  argument: {} {}", arg.ty, arg.name);
+            }
+            for file in &pipeline.files {
+                println!("  file: {} {}", file.path.display(), file.hash);
+            }
+        }
+    } else if let Some(i) = matches.subcommand_matches("info") {
+        let info = Info::collect(&exec::System, halide_path);
+        if i.is_present("json") {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&info).expect("Unable to serialize info")
+            );
+        } else {
+            print!("{}", info);
+        }
+    } else if let Some(v) = matches.subcommand_matches("vendor") {
+        let dest = Path::new(v.value_of("dest").unwrap());
+        if v.is_present("verify") {
+            let tampering = check(vendor::verify(dest), "Error verifying vendored Halide");
+            for t in &tampering {
+                println!("{}", t);
+            }
+            if !tampering.is_empty() {
+                exit(1)
+            }
+            log!("{} matches {}", dest.display(), vendor::FILE_NAME);
+        } else {
+            let options =
+                vendor::VendorOptions::new().autoschedulers(!v.is_present("no-autoschedulers"));
+            let manifest = check(
+                vendor::create(halide_path, dest, &options),
+                "Error vendoring Halide",
+            );
+            log!(
+                "Vendored {} files ({:.1} MiB) of Halide {} into {}",
+                manifest.files.len(),
+                manifest.size() as f64 / (1024.0 * 1024.0),
+                manifest
+                    .halide_version
+                    .as_deref()
+                    .unwrap_or("(unknown version)"),
+                dest.display()
+            );
+        }
+    } else if let Some(c) = matches.subcommand_matches("cache") {
+        if let Some(e) = c.subcommand_matches("export") {
+            let mut build = cache_build(halide_path, e, e.value_of("output").unwrap());
+            build.incremental = true;
+            build.keep = true;
+            build.interrupt = interrupt.clone();
+            build.events = EventHandler::new(|event| log!("{}", event));
+            let build = apply_toolchain(build, toolchain.as_ref(), e);
+            let report = check(
+                build.build_report(),
+                format!("Error building {:?}", build.output),
+            );
+            if !report.success {
+                log!("Unable to build {:?}", build.output);
+                exit(1)
+            }
+
+            let manifest = e
+                .value_of("manifest")
+                .map(|path| check(manifest::load(path), "Error loading manifest"));
+            let archive = e.value_of("archive").unwrap();
+            let entry = check(
+                cache::export(&build, &report, manifest.as_ref(), archive),
+                "Error exporting artifact",
+            );
+            log!(
+                "Exported {} ({}) to {}",
+                build.output.display(),
+                entry.fingerprint,
+                archive
+            );
+        } else if let Some(i) = c.subcommand_matches("import") {
+            // The toolchain's flags are part of the fingerprint, the compiler isn't run
+            let mut build = cache_build(halide_path, i, "");
+            if let Some(toolchain) = &toolchain {
+                build = build.with_toolchain(toolchain);
+            }
+            let imported = check(
+                cache::import(
+                    i.value_of("archive").unwrap(),
+                    i.value_of("dir").unwrap(),
+                    &cache::Fingerprint::of(&build),
+                    i.is_present("force"),
+                ),
+                "Error importing artifact",
+            );
+            for mismatch in &imported.mismatches {
+                log!("warning: artifact {}", mismatch);
+            }
+            log!(
+                "Imported {} ({})",
+                imported.output().display(),
+                imported.entry.fingerprint
+            );
+        } else {
+            app.find_subcommand_mut("cache")
+                .unwrap()
+                .print_long_help()
+                .unwrap();
+        }
+    } else if matches.subcommand_matches("doctor").is_some() {
+        let checks = doctor::run(halide_path);
+        for check in &checks {
+            println!("{}", check);
+        }
+        if doctor::failed(&checks) {
+            exit(1)
         }
     } else {
         app.print_long_help().unwrap();
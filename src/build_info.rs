@@ -0,0 +1,354 @@
+//! Record the compiler used to build libHalide, so kernels built with an incompatible
+//! compiler can be detected
+//!
+//! `Source::build` writes `halide-build-info.json` next to the library, and `Build::check`
+//! compares it with the compiler used for the build. Mixing compilers, or C++ standard
+//! libraries like libstdc++ and libc++, causes confusing link errors. Prebuilt Halide
+//! releases don't have the file, so nothing is checked for them
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::args;
+use crate::exec::Executor;
+use crate::version::{self, CompilerFamily};
+use crate::{Layout, LayoutKind};
+
+/// Name of the file written next to libHalide
+pub const FILE_NAME: &str = "halide-build-info.json";
+
+/// Current `BuildInfo` schema version
+pub const VERSION: u32 = 1;
+
+/// C++ standard library used by a compiler
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stdlib {
+    /// GNU libstdc++
+    Libstdcxx,
+
+    /// LLVM libc++
+    Libcxx,
+
+    /// Microsoft STL
+    Msvc,
+
+    Unknown,
+}
+
+impl Stdlib {
+    /// The standard library used by a compiler of `family` with `flags`
+    ///
+    /// `-stdlib=` takes precedence, otherwise Clang uses libc++ on macOS and libstdc++
+    /// elsewhere
+    pub fn detect<S: AsRef<str>>(family: CompilerFamily, flags: &[S]) -> Stdlib {
+        for flag in flags.iter().rev() {
+            match flag.as_ref() {
+                "-stdlib=libc++" => return Stdlib::Libcxx,
+                "-stdlib=libstdc++" => return Stdlib::Libstdcxx,
+                _ => (),
+            }
+        }
+
+        match family {
+            CompilerFamily::Gcc => Stdlib::Libstdcxx,
+            CompilerFamily::Clang if cfg!(target_os = "macos") => Stdlib::Libcxx,
+            CompilerFamily::Clang => Stdlib::Libstdcxx,
+            CompilerFamily::Msvc => Stdlib::Msvc,
+            CompilerFamily::Unknown => Stdlib::Unknown,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stdlib::Libstdcxx => "libstdc++",
+            Stdlib::Libcxx => "libc++",
+            Stdlib::Msvc => "msvc",
+            Stdlib::Unknown => "unknown",
+        }
+    }
+}
+
+/// The compiler that built libHalide, or that builds a kernel
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// Schema version, see `VERSION`
+    pub version: u32,
+
+    /// Compiler command, like `g++` or `/usr/bin/clang++`
+    pub compiler: String,
+
+    /// `gcc`, `clang`, `msvc` or `unknown`
+    pub family: String,
+
+    /// `None` when the compiler couldn't be run or didn't report a version
+    pub compiler_version: Option<String>,
+
+    pub stdlib: Stdlib,
+
+    /// Compile flags
+    pub flags: Vec<String>,
+}
+
+impl BuildInfo {
+    /// Describe `compiler` used with `flags`, running it to find its family and version
+    pub fn detect(executor: &dyn Executor, compiler: &str, flags: Vec<String>) -> BuildInfo {
+        let family =
+            version::compiler_family(executor, compiler).unwrap_or(CompilerFamily::Unknown);
+        BuildInfo {
+            version: VERSION,
+            compiler: compiler.to_string(),
+            family: family.name().to_string(),
+            compiler_version: version::compiler_version(executor, compiler)
+                .ok()
+                .flatten()
+                .map(|v| v.to_string()),
+            stdlib: Stdlib::detect(family, &flags),
+            flags,
+        }
+    }
+
+    /// Describe the compiler used to build the Halide source at `halide_path`
+    ///
+    /// CMake builds are read from `CMakeCache.txt`, Makefile builds use `CXX` and `CXXFLAGS`
+    /// or the Makefile's default of `g++`
+    pub fn for_source(executor: &dyn Executor, halide_path: impl AsRef<Path>) -> BuildInfo {
+        let cache = fs::read_to_string(halide_path.as_ref().join("CMakeCache.txt"));
+        let (compiler, flags) = match cache {
+            Ok(cache) => (
+                cmake_cache_value(&cache, "CMAKE_CXX_COMPILER").unwrap_or("c++".to_string()),
+                cmake_cache_value(&cache, "CMAKE_CXX_FLAGS").unwrap_or_default(),
+            ),
+            Err(_) => (
+                env::var("CXX").unwrap_or("g++".to_string()),
+                env::var("CXXFLAGS").unwrap_or_default(),
+            ),
+        };
+        BuildInfo::detect(executor, &compiler, args::split_flags(&flags))
+    }
+
+    /// Read the build info from the library directory, `None` when there isn't one
+    pub fn read(lib_dir: impl AsRef<Path>) -> io::Result<Option<BuildInfo>> {
+        let path = lib_dir.as_ref().join(FILE_NAME);
+        let json = match fs::read_to_string(&path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_str(&json).map(Some).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid {}: {}", path.display(), e),
+            )
+        })
+    }
+
+    /// Write the build info to the library directory
+    pub fn write(&self, lib_dir: impl AsRef<Path>) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(lib_dir.as_ref().join(FILE_NAME), json)
+    }
+}
+
+/// The directory `Source::build` writes the build info to, where the library is built
+pub fn lib_dir(halide_path: impl AsRef<Path>) -> PathBuf {
+    let halide_path = halide_path.as_ref();
+    match LayoutKind::detect(halide_path) {
+        LayoutKind::CMakeBuild => Layout::build_dir(halide_path)
+            .map(|layout| layout.lib)
+            .unwrap_or_else(|_| halide_path.join("src")),
+        _ => Layout::install(halide_path).lib,
+    }
+}
+
+fn cmake_cache_value(cache: &str, name: &str) -> Option<String> {
+    cache.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        let key = key.split(':').next()?;
+        (key == name && !value.is_empty()).then(|| value.to_string())
+    })
+}
+
+/// A difference between the compiler that built libHalide and the one building a kernel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Family { halide: String, build: String },
+    Stdlib { halide: Stdlib, build: Stdlib },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Family { halide, build } => write!(
+                f,
+                "libHalide was built with {} but this build uses {}",
+                halide, build
+            ),
+            Mismatch::Stdlib { halide, build } => write!(
+                f,
+                "libHalide was built against {} but this build uses {}, which causes link errors",
+                halide.name(),
+                build.name()
+            ),
+        }
+    }
+}
+
+/// Classify the differences between the compiler that built libHalide and the one building
+/// a kernel, unknown families and standard libraries are never reported
+pub fn compare(halide: &BuildInfo, build: &BuildInfo) -> Vec<Mismatch> {
+    let mut mismatches = vec![];
+    let unknown = CompilerFamily::Unknown.name();
+    if halide.family != build.family && halide.family != unknown && build.family != unknown {
+        mismatches.push(Mismatch::Family {
+            halide: halide.family.clone(),
+            build: build.family.clone(),
+        });
+    }
+    if halide.stdlib != build.stdlib
+        && halide.stdlib != Stdlib::Unknown
+        && build.stdlib != Stdlib::Unknown
+    {
+        mismatches.push(Mismatch::Stdlib {
+            halide: halide.stdlib,
+            build: build.stdlib,
+        });
+    }
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+
+    fn info(family: &str, stdlib: Stdlib) -> BuildInfo {
+        BuildInfo {
+            version: VERSION,
+            compiler: "c++".to_string(),
+            family: family.to_string(),
+            compiler_version: None,
+            stdlib,
+            flags: vec![],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(BuildInfo::read(dir.path()).unwrap(), None);
+
+        let mock = Mock::new().on(
+            "clang++",
+            Response::ok().stdout("clang version 17.0.6\nTarget: x86_64-pc-linux-gnu\n"),
+        );
+        let flags = vec!["-O2".to_string(), "-stdlib=libc++".to_string()];
+        let detected = BuildInfo::detect(&mock, "clang++", flags.clone());
+        assert_eq!(
+            detected,
+            BuildInfo {
+                version: VERSION,
+                compiler: "clang++".to_string(),
+                family: "clang".to_string(),
+                compiler_version: Some("17.0.6".to_string()),
+                stdlib: Stdlib::Libcxx,
+                flags,
+            }
+        );
+        detected.write(dir.path()).unwrap();
+        assert_eq!(BuildInfo::read(dir.path()).unwrap(), Some(detected));
+
+        fs::write(dir.path().join(FILE_NAME), "{}").unwrap();
+        let err = BuildInfo::read(dir.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn source_compiler_from_cmake_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("CMakeCache.txt"),
+            "CMAKE_CXX_COMPILER:FILEPATH=/usr/bin/g++-13\nCMAKE_CXX_FLAGS:STRING=-O3 -g\n",
+        )
+        .unwrap();
+        let mock = Mock::new().on("g++-13", Response::ok().stdout("g++-13 (GCC) 13.2.0\n"));
+        let info = BuildInfo::for_source(&mock, dir.path());
+        assert_eq!(info.compiler, "/usr/bin/g++-13");
+        assert_eq!(info.family, "gcc");
+        assert_eq!(info.compiler_version.as_deref(), Some("13.2.0"));
+        assert_eq!(info.stdlib, Stdlib::Libstdcxx);
+        assert_eq!(info.flags, ["-O3", "-g"]);
+    }
+
+    #[test]
+    fn stdlib_flags_take_precedence() {
+        assert_eq!(
+            Stdlib::detect(CompilerFamily::Gcc, &["-stdlib=libc++"]),
+            Stdlib::Libcxx
+        );
+        assert_eq!(
+            Stdlib::detect(
+                CompilerFamily::Clang,
+                &["-stdlib=libc++", "-stdlib=libstdc++"]
+            ),
+            Stdlib::Libstdcxx
+        );
+        assert_eq!(
+            Stdlib::detect::<&str>(CompilerFamily::Msvc, &[]),
+            Stdlib::Msvc
+        );
+        assert_eq!(
+            Stdlib::detect::<&str>(CompilerFamily::Unknown, &[]),
+            Stdlib::Unknown
+        );
+    }
+
+    #[test]
+    fn mismatches() {
+        use Stdlib::*;
+
+        let family = |halide: &str, build: &str| Mismatch::Family {
+            halide: halide.to_string(),
+            build: build.to_string(),
+        };
+        let stdlib = |halide, build| Mismatch::Stdlib { halide, build };
+        for (halide, build, expected) in [
+            (info("gcc", Libstdcxx), info("gcc", Libstdcxx), vec![]),
+            (
+                info("gcc", Libstdcxx),
+                info("clang", Libstdcxx),
+                vec![family("gcc", "clang")],
+            ),
+            (
+                info("clang", Libstdcxx),
+                info("clang", Libcxx),
+                vec![stdlib(Libstdcxx, Libcxx)],
+            ),
+            (
+                info("gcc", Libstdcxx),
+                info("clang", Libcxx),
+                vec![family("gcc", "clang"), stdlib(Libstdcxx, Libcxx)],
+            ),
+            (info("unknown", Unknown), info("msvc", Msvc), vec![]),
+            (info("gcc", Libstdcxx), info("unknown", Unknown), vec![]),
+        ] {
+            assert_eq!(
+                compare(&halide, &build),
+                expected,
+                "{:?} {:?}",
+                halide,
+                build
+            );
+        }
+
+        assert_eq!(
+            stdlib(Libstdcxx, Libcxx).to_string(),
+            "libHalide was built against libstdc++ but this build uses libc++, which causes link errors"
+        );
+    }
+}
@@ -0,0 +1,744 @@
+//! Share built artifacts between machines, like a build farm and developer laptops
+//!
+//! `export` writes a tar archive containing the artifacts of a build and `halide-cache.json`,
+//! which lists the hash and size of each artifact, the manifest entries of the pipelines linked
+//! into it, and the fingerprint of the build: the Halide version, target and host it was built
+//! for, and a hash of its sources and flags. `import` checks the hashes and the fingerprint and
+//! extracts the artifacts with a dependency file listing the build's relative dependencies, so
+//! an incremental build of the same output on the importing machine is up to date until one of
+//! them changes. Builds using `stamp` are still rebuilt, since their fingerprint covers local
+//! paths
+//!
+//! ```text
+//! halide-cache.json
+//! files/libbrighten.a
+//! ```
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hash;
+use crate::manifest::{Manifest, Pipeline};
+use crate::version;
+use crate::{depfile, Build, BuildReport, OutputKind};
+
+/// Name of the entry listing the artifacts
+pub const FILE_NAME: &str = "halide-cache.json";
+
+/// Current `halide-cache.json` schema version
+pub const VERSION: u32 = 1;
+
+/// Directory of the artifacts in the archive
+const FILES_DIR: &str = "files/";
+
+/// Size of a tar header and of the blocks data is padded to
+const BLOCK: usize = 512;
+
+/// What an artifact was built for, artifacts are only imported when it matches
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// `None` when the headers don't define the version macros
+    pub halide_version: Option<String>,
+
+    /// Halide target, `host` by default
+    pub target: String,
+
+    /// Operating system and architecture of the machine that built it, like `linux-x86_64`
+    pub host: String,
+
+    /// Hash of the contents of the sources, objects and archives and of the flags, see
+    /// `inputs_hash`
+    #[serde(default)]
+    pub inputs: String,
+}
+
+impl Fingerprint {
+    /// The fingerprint of the artifacts `build` produces on this machine
+    pub fn of(build: &Build) -> Fingerprint {
+        Fingerprint {
+            halide_version: version::header_version(build.halide_layout().include)
+                .map(|v| v.to_string()),
+            target: build.target_string().unwrap_or_else(|| "host".to_string()),
+            host: format!("{}-{}", env::consts::OS, env::consts::ARCH),
+            inputs: inputs_hash(build),
+        }
+    }
+
+    /// Differences from `expected`, empty when the artifacts can be used
+    pub fn mismatches(&self, expected: &Fingerprint) -> Vec<String> {
+        let mut mismatches = vec![];
+        if self.halide_version != expected.halide_version {
+            let name = |v: &Option<String>| v.clone().unwrap_or_else(|| "unknown".to_string());
+            mismatches.push(format!(
+                "built with Halide {}, expected {}",
+                name(&self.halide_version),
+                name(&expected.halide_version)
+            ));
+        }
+        if self.target != expected.target {
+            mismatches.push(format!(
+                "built for target {}, expected {}",
+                self.target, expected.target
+            ));
+        }
+        if self.host != expected.host {
+            mismatches.push(format!(
+                "built on {}, expected {}",
+                self.host, expected.host
+            ));
+        }
+        if self.inputs != expected.inputs {
+            mismatches.push("built from different sources or flags".to_string());
+        }
+        mismatches
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Halide {}, target {}, host {}",
+            self.halide_version.as_deref().unwrap_or("unknown"),
+            self.target,
+            self.host
+        )
+    }
+}
+
+/// Hash the inputs that determine the contents of the artifacts, which don't depend on where
+/// the build runs
+///
+/// Paths are hashed by file name and contents, since checkouts and the Halide install are in
+/// different places on each machine. Missing files are hashed by name only
+pub fn inputs_hash(build: &Build) -> String {
+    let mut hasher = hash::Fnv::default();
+    hasher.write_str(&format!("{:?}", build.output_kind));
+    hasher.write_str(build.std());
+    hasher.write_str(&build.generator.to_string());
+    hasher.write_str(build.cxxflags.as_deref().unwrap_or_default());
+    hasher.write_str(build.ldflags.as_deref().unwrap_or_default());
+    for arg in &build.build_args {
+        hasher.write_str(&arg.to_string_lossy());
+    }
+    for (position, arg) in &build.raw_args {
+        hasher.write_str(&format!("{:?}", position));
+        hasher.write_str(arg);
+    }
+    for lib in build.libs.iter().chain(&build.frameworks) {
+        hasher.write_str(lib);
+    }
+    for feature in &build.target_features {
+        hasher.write_str(feature);
+    }
+
+    let mut src_flags: Vec<_> = build.src_flags.iter().collect();
+    src_flags.sort();
+    for (path, flags) in src_flags {
+        hasher.write_str(&path.to_string_lossy());
+        for flag in flags {
+            hasher.write_str(flag);
+        }
+    }
+
+    for path in build
+        .src
+        .iter()
+        .chain(&build.objects)
+        .chain(&build.archives)
+        .chain(&build.gengen)
+    {
+        hasher.write_str(&path.file_name().unwrap_or_default().to_string_lossy());
+        if let Ok(contents) = hash::file(path) {
+            hasher.write(&contents.to_le_bytes());
+        }
+    }
+    hash::hex(hasher.finish())
+}
+
+/// An artifact in the archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedFile {
+    /// File name, the artifacts are placed next to each other
+    pub path: String,
+
+    /// Content hash
+    pub hash: String,
+
+    /// Size in bytes
+    pub size: u64,
+
+    pub executable: bool,
+}
+
+/// The contents of `halide-cache.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    /// Schema version, see `VERSION`
+    pub version: u32,
+
+    pub fingerprint: Fingerprint,
+
+    /// The first file is the build's output
+    pub files: Vec<CachedFile>,
+
+    /// Manifest entries of the pipelines linked into the artifacts
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pipelines: Vec<Pipeline>,
+
+    /// Relative paths of the files the output depends on, written to the dependency file of
+    /// the imported output
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<PathBuf>,
+}
+
+/// The result of `import`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportedArtifact {
+    pub entry: CacheEntry,
+
+    /// Paths of the extracted files, the build's output first
+    pub paths: Vec<PathBuf>,
+
+    /// Fingerprint differences that were ignored because of `force`
+    pub mismatches: Vec<String>,
+}
+
+impl ImportedArtifact {
+    /// The imported build output
+    pub fn output(&self) -> &Path {
+        &self.paths[0]
+    }
+}
+
+/// The artifacts of a build, the output and the `.wasm` file of Emscripten executables
+fn artifacts(build: &Build) -> Vec<PathBuf> {
+    let mut paths = vec![build.output.clone()];
+    if build.emscripten && build.output_kind == OutputKind::Executable {
+        paths.push(build.output.with_extension("wasm"));
+    }
+    paths
+}
+
+/// The pipelines in `manifest` with an emitted file the build links, or that it produced
+fn linked_pipelines(build: &Build, manifest: &Manifest) -> Vec<Pipeline> {
+    let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_owned());
+    let linked: Vec<PathBuf> = build
+        .src
+        .iter()
+        .chain(&build.objects)
+        .chain(&build.archives)
+        .chain([&build.output])
+        .map(|path| absolute(path))
+        .collect();
+
+    manifest
+        .pipelines
+        .iter()
+        .filter(|pipeline| {
+            pipeline
+                .files
+                .iter()
+                .any(|file| linked.contains(&absolute(&file.path)))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Write the artifacts of a successful build to the archive `dest`
+///
+/// `report` is the report of building `build`, its relative dependencies are recorded for
+/// `import`. The entries of `manifest` for the pipelines the build links are included
+///
+/// Fails with `io::ErrorKind::InvalidInput` when the build failed or an artifact name is too
+/// long for a tar header
+pub fn export(
+    build: &Build,
+    report: &BuildReport,
+    manifest: Option<&Manifest>,
+    dest: impl AsRef<Path>,
+) -> io::Result<CacheEntry> {
+    if !report.success {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} was not built successfully", build.output.display()),
+        ));
+    }
+
+    let mut entries = vec![];
+    let mut files = vec![];
+    for path in artifacts(build) {
+        let name = path
+            .file_name()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Invalid output {}", path.display()),
+                )
+            })?
+            .to_string_lossy()
+            .into_owned();
+        let data = fs::read(&path)?;
+        let executable = is_executable(&path, build.output_kind);
+        files.push(CachedFile {
+            path: name.clone(),
+            hash: hash::hex(hash::bytes(&data)),
+            size: data.len() as u64,
+            executable,
+        });
+        entries.push((format!("{}{}", FILES_DIR, name), executable, data));
+    }
+
+    let entry = CacheEntry {
+        version: VERSION,
+        fingerprint: Fingerprint::of(build),
+        files,
+        pipelines: manifest
+            .map(|m| linked_pipelines(build, m))
+            .unwrap_or_default(),
+        dependencies: report
+            .dependencies
+            .iter()
+            .filter(|path| path.is_relative())
+            .cloned()
+            .collect(),
+    };
+    let json = serde_json::to_string_pretty(&entry).map_err(io::Error::other)?;
+    entries.insert(0, (FILE_NAME.to_string(), false, json.into_bytes()));
+
+    let dest = dest.as_ref();
+    let tmp = temp_path(dest);
+    let res = write_tar(&tmp, &entries).and_then(|()| crate::replace_file(&tmp, dest));
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res.map(|()| entry)
+}
+
+/// Extract the artifacts in the archive `src` into `out_dir`
+///
+/// Fails with `io::ErrorKind::InvalidData` when the archive is invalid, a hash or size doesn't
+/// match, or the fingerprint doesn't match `expected` and `force` isn't set. Nothing is
+/// written unless every artifact is valid
+pub fn import(
+    src: impl AsRef<Path>,
+    out_dir: impl AsRef<Path>,
+    expected: &Fingerprint,
+    force: bool,
+) -> io::Result<ImportedArtifact> {
+    let src = src.as_ref();
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut entries = read_tar(&fs::read(src)?)
+        .map_err(|e| invalid(format!("{} is not a valid archive: {}", src.display(), e)))?;
+
+    let json = entries
+        .iter()
+        .position(|(name, _)| name == FILE_NAME)
+        .map(|i| entries.remove(i).1)
+        .ok_or_else(|| invalid(format!("{} has no {}", src.display(), FILE_NAME)))?;
+    let entry: CacheEntry = serde_json::from_slice(&json)
+        .map_err(|e| invalid(format!("Invalid {} in {}: {}", FILE_NAME, src.display(), e)))?;
+    if entry.version > VERSION {
+        return Err(invalid(format!(
+            "{} was written by a newer version, schema {}",
+            src.display(),
+            entry.version
+        )));
+    }
+    if entry.files.is_empty() {
+        return Err(invalid(format!("{} has no artifacts", src.display())));
+    }
+
+    let mismatches = entry.fingerprint.mismatches(expected);
+    if !mismatches.is_empty() && !force {
+        return Err(invalid(format!(
+            "{} can't be used here, it was {}",
+            src.display(),
+            mismatches.join(", ")
+        )));
+    }
+
+    let mut data = vec![];
+    for file in &entry.files {
+        if !is_file_name(&file.path) {
+            return Err(invalid(format!("Invalid artifact name {:?}", file.path)));
+        }
+        let name = format!("{}{}", FILES_DIR, file.path);
+        let i = entries
+            .iter()
+            .position(|(n, _)| *n == name)
+            .ok_or_else(|| invalid(format!("{} is missing {}", src.display(), file.path)))?;
+        let bytes = entries.remove(i).1;
+        if bytes.len() as u64 != file.size || hash::hex(hash::bytes(&bytes)) != file.hash {
+            return Err(invalid(format!(
+                "Checksum mismatch for {} in {}",
+                file.path,
+                src.display()
+            )));
+        }
+        data.push(bytes);
+    }
+    if let Some((name, _)) = entries.first() {
+        return Err(invalid(format!(
+            "{} contains {}, which isn't listed in {}",
+            src.display(),
+            name,
+            FILE_NAME
+        )));
+    }
+
+    let out_dir = out_dir.as_ref();
+    fs::create_dir_all(out_dir)?;
+    let mut paths = vec![];
+    for (file, bytes) in entry.files.iter().zip(data) {
+        let path = out_dir.join(&file.path);
+        let tmp = temp_path(&path);
+        let res = write_file(&tmp, &bytes, file.executable)
+            .and_then(|()| crate::replace_file(&tmp, &path));
+        if res.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        res?;
+        paths.push(path);
+    }
+
+    // The sources the artifact was built from are the importing build's own, which only need
+    // to be older than the output. Dependencies outside the project, like the Halide headers,
+    // are covered by the fingerprint
+    depfile::write(depfile::path(&paths[0]), &paths[0], &entry.dependencies)?;
+
+    Ok(ImportedArtifact {
+        entry,
+        paths,
+        mismatches,
+    })
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp.{}", std::process::id()));
+    path.with_file_name(name)
+}
+
+/// Whether `name` is a single path component, so it can't be written outside `out_dir`
+fn is_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+    matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none()
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path, _kind: OutputKind) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .is_ok_and(|m| m.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(_path: &Path, kind: OutputKind) -> bool {
+    kind == OutputKind::Executable
+}
+
+fn write_file(path: &Path, data: &[u8], executable: bool) -> io::Result<()> {
+    fs::write(path, data)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = if executable { 0o755 } else { 0o644 };
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = executable;
+    Ok(())
+}
+
+/// Write a ustar archive of `(name, executable, data)` entries
+fn write_tar(path: &Path, entries: &[(String, bool, Vec<u8>)]) -> io::Result<()> {
+    let mut f = io::BufWriter::new(fs::File::create(path)?);
+    for (name, executable, data) in entries {
+        f.write_all(&tar_header(
+            name,
+            if *executable { 0o755 } else { 0o644 },
+            data.len(),
+        )?)?;
+        f.write_all(data)?;
+        f.write_all(&vec![0; padding(data.len())])?;
+    }
+    // The end of the archive is marked by two empty blocks
+    f.write_all(&[0; 2 * BLOCK])?;
+    f.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+fn padding(size: usize) -> usize {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+fn tar_header(name: &str, mode: u32, size: usize) -> io::Result<[u8; BLOCK]> {
+    if name.len() >= 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is too long for a tar archive", name),
+        ));
+    }
+
+    let mut header = [0u8; BLOCK];
+    let mut field = |offset: usize, value: &[u8]| {
+        header[offset..offset + value.len()].copy_from_slice(value);
+    };
+    field(0, name.as_bytes());
+    field(100, format!("{:07o}\0", mode).as_bytes());
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, b"00000000000\0");
+    field(156, b"0");
+    field(257, b"ustar\x0000");
+
+    // The checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let sum: u32 = header.iter().map(|&b| b as u32).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    Ok(header)
+}
+
+/// Parse an octal header field, which may be padded with spaces or NULs
+fn octal(field: &[u8]) -> io::Result<usize> {
+    let s = String::from_utf8_lossy(field);
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(s, 8).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid number {:?}", s),
+        )
+    })
+}
+
+/// The names and contents of the regular files in a tar archive
+fn read_tar(bytes: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let truncated = || io::Error::new(io::ErrorKind::InvalidData, "truncated");
+    let mut entries = vec![];
+    let mut offset = 0;
+    loop {
+        let header = bytes.get(offset..offset + BLOCK).ok_or_else(truncated)?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(entries);
+        }
+
+        let expected = octal(&header[148..156])?;
+        let sum: usize = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as usize)
+            .sum();
+        if sum != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "header checksum mismatch",
+            ));
+        }
+
+        let name_end = header[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let name = String::from_utf8_lossy(&header[..name_end]).into_owned();
+        let size = octal(&header[124..136])?;
+        offset += BLOCK;
+        let data = bytes.get(offset..offset + size).ok_or_else(truncated)?;
+        // Directories and links aren't written by `export`
+        if matches!(header[156], b'0' | 0) {
+            entries.push((name, data.to_vec()));
+        }
+        offset += size + padding(size);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::File;
+
+    /// A built executable and its source in `dir`
+    fn built(dir: &Path) -> Build<'static> {
+        fs::write(dir.join("brighten.cpp"), "int main() {}").unwrap();
+        let build =
+            Build::new("/opt/halide", dir.join("brighten")).source_file(dir.join("brighten.cpp"));
+        write_file(&build.output, b"\x7fELF", true).unwrap();
+        build
+    }
+
+    fn report() -> BuildReport {
+        BuildReport {
+            success: true,
+            dependencies: vec![
+                PathBuf::from("brighten.h"),
+                PathBuf::from("/opt/halide/include/Halide.h"),
+            ],
+            ..BuildReport::default()
+        }
+    }
+
+    fn pipeline(function: &str, path: &Path) -> Pipeline {
+        Pipeline {
+            function: function.to_string(),
+            symbol: None,
+            generator: function.to_string(),
+            sources: vec![],
+            targets: vec!["host".to_string()],
+            halide_version: None,
+            files: vec![File {
+                path: path.to_owned(),
+                hash: "0".to_string(),
+            }],
+            arguments: vec![],
+            staged_headers: vec![],
+        }
+    }
+
+    fn export_archive(dir: &Path) -> (Build<'static>, PathBuf, CacheEntry) {
+        let archive = dir.join("brighten.tar");
+        let build = built(dir);
+        let entry = export(&build, &report(), None, &archive).unwrap();
+        (build, archive, entry)
+    }
+
+    #[test]
+    fn archive_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = built(dir.path()).archive(dir.path().join("libblur.a"));
+        let manifest = Manifest {
+            pipelines: vec![
+                pipeline("blur", &dir.path().join("libblur.a")),
+                pipeline("sharpen", &dir.path().join("libsharpen.a")),
+            ],
+            ..Manifest::default()
+        };
+        let archive = dir.path().join("brighten.tar");
+        let entry = export(&build, &report(), Some(&manifest), &archive).unwrap();
+
+        let entries = read_tar(&fs::read(&archive).unwrap()).unwrap();
+        let names: Vec<&str> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, [FILE_NAME, "files/brighten"]);
+        assert_eq!(entries[1].1, b"\x7fELF");
+
+        let json: CacheEntry = serde_json::from_slice(&entries[0].1).unwrap();
+        assert_eq!(json, entry);
+        assert_eq!(entry.files[0].path, "brighten");
+        assert_eq!(entry.files[0].size, 4);
+        assert_eq!(entry.files[0].hash, hash::hex(hash::bytes(b"\x7fELF")));
+        assert!(entry.files[0].executable);
+        assert_eq!(entry.fingerprint, Fingerprint::of(&build));
+
+        // Only the pipelines linked into the artifact, and only relative dependencies
+        assert_eq!(entry.pipelines.len(), 1);
+        assert_eq!(entry.pipelines[0].function, "blur");
+        assert_eq!(entry.dependencies, [PathBuf::from("brighten.h")]);
+    }
+
+    #[test]
+    fn export_requires_a_successful_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = built(dir.path());
+        let err = export(
+            &build,
+            &BuildReport::default(),
+            None,
+            dir.path().join("a.tar"),
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!dir.path().join("a.tar").exists());
+    }
+
+    #[test]
+    fn import_extracts_the_artifacts() {
+        let dir = tempfile::tempdir().unwrap();
+        let (build, archive, entry) = export_archive(dir.path());
+        let out = dir.path().join("out");
+        let imported = import(&archive, &out, &Fingerprint::of(&build), false).unwrap();
+        assert_eq!(imported.entry, entry);
+        assert!(imported.mismatches.is_empty());
+        assert_eq!(imported.output(), out.join("brighten"));
+        assert_eq!(fs::read(imported.output()).unwrap(), b"\x7fELF");
+        #[cfg(unix)]
+        assert!(is_executable(imported.output(), OutputKind::Executable));
+
+        let deps = depfile::read(depfile::path(imported.output())).unwrap();
+        assert_eq!(deps, [PathBuf::from("brighten.h")]);
+    }
+
+    #[test]
+    fn import_verifies_hashes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (build, archive, _) = export_archive(dir.path());
+
+        // Same size, different contents
+        let mut entries: Vec<_> = read_tar(&fs::read(&archive).unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|(name, data)| (name, false, data))
+            .collect();
+        entries[1].2 = b"\x7fBAD".to_vec();
+        write_tar(&archive, &entries).unwrap();
+
+        let out = dir.path().join("out");
+        let err = import(&archive, &out, &Fingerprint::of(&build), true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("Checksum mismatch for brighten"));
+        assert!(!out.join("brighten").exists());
+
+        // Entries that aren't listed are rejected too
+        entries[1].2 = b"\x7fELF".to_vec();
+        entries.push(("files/extra".to_string(), false, vec![]));
+        write_tar(&archive, &entries).unwrap();
+        let err = import(&archive, &out, &Fingerprint::of(&build), true).unwrap_err();
+        assert!(err.to_string().contains("files/extra"));
+    }
+
+    #[test]
+    fn import_rejects_other_fingerprints() {
+        let dir = tempfile::tempdir().unwrap();
+        let (build, archive, _) = export_archive(dir.path());
+        let out = dir.path().join("out");
+
+        let expected = Fingerprint::of(&build.clone().target("x86-64-linux-avx2"));
+        let err = import(&archive, &out, &expected, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .contains("built for target host, expected x86-64-linux-avx2"));
+        assert!(!out.exists());
+
+        // Forcing the import reports the differences
+        let imported = import(&archive, &out, &expected, true).unwrap();
+        assert_eq!(imported.mismatches.len(), 1);
+        assert!(out.join("brighten").exists());
+    }
+
+    #[test]
+    fn inputs_are_fingerprinted() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = built(dir.path());
+        let fingerprint = Fingerprint::of(&build);
+        assert_eq!(Fingerprint::of(&build.clone()), fingerprint);
+
+        let flags = Fingerprint::of(&build.clone().cxxflags("-O3"));
+        assert_eq!(
+            flags.mismatches(&fingerprint),
+            ["built from different sources or flags"]
+        );
+
+        fs::write(dir.path().join("brighten.cpp"), "int main() { return 1; }").unwrap();
+        assert_ne!(Fingerprint::of(&build).inputs, fingerprint.inputs);
+
+        // Where the sources are checked out doesn't matter
+        let other = tempfile::tempdir().unwrap();
+        let moved = built(other.path());
+        fs::write(other.path().join("brighten.cpp"), "int main() {}").unwrap();
+        assert_eq!(Fingerprint::of(&moved), fingerprint);
+    }
+}
@@ -0,0 +1,262 @@
+//! Compare the outputs written by two pipelines, for example a reference schedule and an
+//! optimized one
+//!
+//! Outputs are compared byte for byte, or as little-endian elements of an `ElementType` when
+//! a tolerance is given
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::harness::ElementType;
+
+/// Read a little-endian element from the start of `bytes`, which must be at least
+/// `element.size()` bytes long
+fn read(element: ElementType, bytes: &[u8]) -> f64 {
+    match element {
+        ElementType::U8 => bytes[0] as f64,
+        ElementType::I8 => bytes[0] as i8 as f64,
+        ElementType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+        ElementType::I16 => i16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+        ElementType::U32 => u32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ElementType::I32 => i32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ElementType::F32 => f32::from_le_bytes(bytes[..4].try_into().unwrap()) as f64,
+        ElementType::F64 => f64::from_le_bytes(bytes[..8].try_into().unwrap()),
+    }
+}
+
+/// The result of comparing two outputs
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Comparison {
+    /// Sizes of the reference and candidate outputs in bytes
+    pub sizes: (usize, usize),
+
+    /// Number of elements that differ by more than the tolerance, or bytes that differ for
+    /// exact comparisons
+    pub mismatches: usize,
+
+    /// Index of the first mismatched element
+    pub first_mismatch: Option<usize>,
+
+    /// Largest absolute difference between elements, NaN when only one of them is NaN
+    pub max_difference: f64,
+}
+
+impl Comparison {
+    /// Returns true when the outputs are the same size and nothing differs by more than the
+    /// tolerance
+    pub fn matches(&self) -> bool {
+        self.sizes.0 == self.sizes.1 && self.mismatches == 0
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.sizes.0 != self.sizes.1 {
+            return write!(
+                f,
+                "outputs differ in size, {} bytes and {} bytes",
+                self.sizes.0, self.sizes.1
+            );
+        }
+        match self.first_mismatch {
+            None => write!(f, "outputs match, max difference {}", self.max_difference),
+            Some(index) => write!(
+                f,
+                "{} elements differ, first at index {}, max difference {}",
+                self.mismatches, index, self.max_difference
+            ),
+        }
+    }
+}
+
+/// Compare two buffers byte for byte
+pub fn exact(reference: &[u8], candidate: &[u8]) -> Comparison {
+    within(reference, candidate, ElementType::U8, 0.0)
+}
+
+/// Compare two buffers as little-endian `element`s, allowing each to differ by `tolerance`
+///
+/// Trailing bytes that don't make up a whole element are compared exactly. NaNs only match
+/// other NaNs, and infinities only match infinities of the same sign
+pub fn within(
+    reference: &[u8],
+    candidate: &[u8],
+    element: ElementType,
+    tolerance: f64,
+) -> Comparison {
+    let mut comparison = Comparison {
+        sizes: (reference.len(), candidate.len()),
+        ..Comparison::default()
+    };
+
+    let size = element.size();
+    let n = reference.len().min(candidate.len());
+    let whole = n - n % size;
+    for (index, (a, b)) in reference[..whole]
+        .chunks_exact(size)
+        .zip(candidate[..whole].chunks_exact(size))
+        .enumerate()
+    {
+        let (a, b) = (read(element, a), read(element, b));
+        // Equal infinities would otherwise differ by NaN
+        let difference = match (a.is_nan(), b.is_nan()) {
+            (true, true) => 0.0,
+            (false, false) if a == b => 0.0,
+            (false, false) => (a - b).abs(),
+            _ => f64::NAN,
+        };
+        if difference.is_nan() || difference > comparison.max_difference {
+            comparison.max_difference = difference;
+        }
+        if difference.is_nan() || difference > tolerance {
+            comparison.mismatches += 1;
+            comparison.first_mismatch.get_or_insert(index);
+        }
+    }
+
+    if reference[whole..n] != candidate[whole..n] {
+        comparison.mismatches += 1;
+        comparison.first_mismatch.get_or_insert(whole / size);
+    }
+    comparison
+}
+
+/// Read and compare two output files, exactly when `tolerance` is `None`
+pub fn files(
+    reference: impl AsRef<Path>,
+    candidate: impl AsRef<Path>,
+    element: ElementType,
+    tolerance: Option<f64>,
+) -> io::Result<Comparison> {
+    let reference = fs::read(reference)?;
+    let candidate = fs::read(candidate)?;
+    Ok(match tolerance {
+        Some(tolerance) => within(&reference, &candidate, element, tolerance),
+        None => exact(&reference, &candidate),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reference, candidate, tolerance, mismatches, first mismatch and max difference
+    type Case = (
+        &'static [f32],
+        &'static [f32],
+        f64,
+        usize,
+        Option<usize>,
+        f64,
+    );
+
+    fn f32s(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn tolerance() {
+        let table: &[Case] = &[
+            (&[1.0, 2.0], &[1.0, 2.0], 0.0, 0, None, 0.0),
+            (&[1.0, 2.0], &[1.0, 2.25], 0.5, 0, None, 0.25),
+            (&[1.0, 2.0], &[1.0, 2.25], 0.25, 0, None, 0.25),
+            (&[1.0, 2.0, 3.0], &[1.5, 2.0, 4.0], 0.25, 2, Some(0), 1.0),
+            (&[-1.0, 2.0], &[1.0, 2.0], 1.0, 1, Some(0), 2.0),
+            (&[f32::NAN, 1.0], &[f32::NAN, 1.0], 0.0, 0, None, 0.0),
+            (
+                &[1.0, f32::INFINITY],
+                &[1.0, f32::INFINITY],
+                0.0,
+                0,
+                None,
+                0.0,
+            ),
+        ];
+        for (reference, candidate, tolerance, mismatches, first, max) in table {
+            let comparison = within(
+                &f32s(reference),
+                &f32s(candidate),
+                ElementType::F32,
+                *tolerance,
+            );
+            let case = format!("{:?} {:?} {}", reference, candidate, tolerance);
+            assert_eq!(comparison.mismatches, *mismatches, "{}", case);
+            assert_eq!(comparison.first_mismatch, *first, "{}", case);
+            assert_eq!(comparison.max_difference, *max, "{}", case);
+            assert_eq!(comparison.matches(), *mismatches == 0, "{}", case);
+        }
+    }
+
+    #[test]
+    fn nan_only_matches_nan() {
+        let comparison = within(
+            &f32s(&[1.0, f32::NAN, 5.0]),
+            &f32s(&[1.0, 2.0, 100.0]),
+            ElementType::F32,
+            1000.0,
+        );
+        assert_eq!(comparison.mismatches, 1);
+        assert_eq!(comparison.first_mismatch, Some(1));
+        assert!(comparison.max_difference.is_nan());
+    }
+
+    #[test]
+    fn integer_elements() {
+        // Signed elements are compared by value rather than by their bytes
+        let reference = (-2i16).to_le_bytes();
+        let candidate = 1i16.to_le_bytes();
+        let comparison = within(&reference, &candidate, ElementType::I16, 3.0);
+        assert!(comparison.matches());
+        assert_eq!(comparison.max_difference, 3.0);
+        assert!(!within(&reference, &candidate, ElementType::U16, 3.0).matches());
+
+        let reference = [200u8, 10];
+        let candidate = [201u8, 10];
+        assert!(within(&reference, &candidate, ElementType::U8, 1.0).matches());
+        assert!(!exact(&reference, &candidate).matches());
+    }
+
+    #[test]
+    fn sizes_and_trailing_bytes() {
+        let mut reference = f32s(&[1.0]);
+        let mut candidate = f32s(&[1.0]);
+        reference.extend([1, 2]);
+        candidate.extend([1, 3]);
+        let comparison = within(&reference, &candidate, ElementType::F32, 1.0);
+        assert_eq!(comparison.mismatches, 1);
+        assert_eq!(comparison.first_mismatch, Some(1));
+
+        let comparison = exact(b"abc", b"abcd");
+        assert_eq!(comparison.sizes, (3, 4));
+        assert_eq!(comparison.mismatches, 0);
+        assert!(!comparison.matches());
+        assert_eq!(
+            comparison.to_string(),
+            "outputs differ in size, 3 bytes and 4 bytes"
+        );
+        assert_eq!(
+            exact(b"abcd", b"abxy").to_string(),
+            "2 elements differ, first at index 2, max difference 21"
+        );
+        assert_eq!(
+            exact(b"abc", b"abc").to_string(),
+            "outputs match, max difference 0"
+        );
+    }
+
+    #[test]
+    fn compare_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b) = (dir.path().join("a"), dir.path().join("b"));
+        fs::write(&a, f32s(&[1.0, 2.0])).unwrap();
+        fs::write(&b, f32s(&[1.0, 2.001])).unwrap();
+        assert!(!files(&a, &b, ElementType::F32, None).unwrap().matches());
+        assert!(files(&a, &b, ElementType::F32, Some(0.01))
+            .unwrap()
+            .matches());
+        let err = files(&a, dir.path().join("missing"), ElementType::F32, None).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
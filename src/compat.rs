@@ -0,0 +1,124 @@
+//! Build and run the same sources against several Halide installations
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::version::{halide_version, Version};
+use crate::Build;
+
+/// The outcome of building and running against one Halide installation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Passed,
+    BuildFailed,
+    RunFailed,
+    Error(String),
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Status::Passed => write!(f, "pass"),
+            Status::BuildFailed => write!(f, "build failed"),
+            Status::RunFailed => write!(f, "run failed"),
+            Status::Error(e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+/// The result for a single Halide installation
+#[derive(Debug, Clone)]
+pub struct CompatResult {
+    pub halide_path: PathBuf,
+    pub version: Option<Version>,
+    pub status: Status,
+}
+
+impl CompatResult {
+    pub fn passed(&self) -> bool {
+        self.status == Status::Passed
+    }
+}
+
+/// Build and run `build` against each Halide installation in turn
+///
+/// Each build uses its own output, named after the original with the index of the
+/// installation appended. Interruption stops the sweep and is returned as an error.
+pub fn check<P: AsRef<Path>>(build: &Build, halide_paths: &[P]) -> io::Result<Vec<CompatResult>> {
+    let mut results = vec![];
+
+    for (index, halide_path) in halide_paths.iter().enumerate() {
+        let halide_path = halide_path.as_ref();
+        let mut b = build.clone();
+        b.halide_path = halide_path.to_path_buf();
+
+        let mut name = build.output.file_name().unwrap_or_default().to_owned();
+        name.push(format!("-{}", index));
+        b.output = build.output.with_file_name(name);
+
+        let res = match b.build() {
+            Ok(true) => b.run().map(|ok| {
+                if ok {
+                    Status::Passed
+                } else {
+                    Status::RunFailed
+                }
+            }),
+            Ok(false) => Ok(Status::BuildFailed),
+            Err(e) => Err(e),
+        };
+
+        let status = match res {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => return Err(e),
+            Err(e) => Status::Error(e.to_string()),
+            Ok(status) => status,
+        };
+
+        results.push(CompatResult {
+            halide_path: halide_path.to_path_buf(),
+            version: halide_version(halide_path),
+            status,
+        });
+    }
+
+    Ok(results)
+}
+
+/// Format results as a table with one row per installation
+pub fn table(results: &[CompatResult]) -> String {
+    let rows: Vec<(String, String, String)> = results
+        .iter()
+        .map(|r| {
+            (
+                r.halide_path.display().to_string(),
+                r.version
+                    .map(|v| v.to_string())
+                    .unwrap_or_else(|| "unknown".to_string()),
+                r.status.to_string(),
+            )
+        })
+        .collect();
+
+    let path_width = rows.iter().map(|r| r.0.len()).chain([6]).max().unwrap_or(0);
+    let version_width = rows.iter().map(|r| r.1.len()).chain([7]).max().unwrap_or(0);
+
+    let mut s = format!(
+        "{:pw$}  {:vw$}  result\n",
+        "halide",
+        "version",
+        pw = path_width,
+        vw = version_width
+    );
+    for (path, version, status) in rows {
+        s.push_str(&format!(
+            "{:pw$}  {:vw$}  {}\n",
+            path,
+            version,
+            status,
+            pw = path_width,
+            vw = version_width
+        ));
+    }
+    s
+}
@@ -0,0 +1,233 @@
+//! Run build and run step commands inside a container with a pinned toolchain
+//!
+//! `Container` is an `Executor` that wraps every command in `docker run`, or another
+//! compatible program like `podman`:
+//!
+//! ```text
+//! docker run --rm -i -v <mount>:<workdir> -w <workdir> [-u <uid>:<gid>] [-e NAME=VALUE ...] <image> <command>
+//! ```
+//!
+//! Only `mount` is shared with the container. Arguments, environment variables and the
+//! working directory are rewritten by replacing the `mount` prefix with `workdir`, so every
+//! source, output and the Halide path must be inside `mount`, or exist at the same path in
+//! the image. Other paths are passed unchanged, and commands run from outside `mount` start
+//! in `workdir`. Setting `workdir` to the same path as
+//! `mount` disables the rewriting entirely, which also keeps paths in diagnostics identical
+//! to the host's.
+//!
+//! Checks made by the build itself, like whether a source exists or is newer than the
+//! output, still happen on the host.
+
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output};
+use std::sync::Arc;
+
+use crate::exec::{Executor, ResourceUsage, Stream, System};
+use crate::process::InterruptToken;
+
+/// Runs commands inside a container, see the module documentation
+#[derive(Debug, Clone)]
+pub struct Container {
+    /// Container image, like `ghcr.io/me/halide:16`
+    pub image: String,
+
+    /// Program used to start the container, defaults to `docker`
+    pub program: String,
+
+    /// Host directory shared with the container, defaults to the current directory
+    pub mount: PathBuf,
+
+    /// Where `mount` appears inside the container, defaults to `/work`
+    pub workdir: PathBuf,
+
+    /// User to run as, defaults to the current user on Unix so outputs aren't owned by root
+    pub user: Option<String>,
+
+    /// Extra arguments passed to `docker run` before the image
+    pub args: Vec<String>,
+
+    /// Runs the `docker run` commands
+    executor: Arc<dyn Executor>,
+}
+
+impl Container {
+    pub fn new(image: impl Into<String>) -> io::Result<Container> {
+        Ok(Container {
+            image: image.into(),
+            program: "docker".to_string(),
+            mount: std::env::current_dir()?,
+            workdir: PathBuf::from("/work"),
+            user: current_user(),
+            args: vec![],
+            executor: Arc::new(System),
+        })
+    }
+
+    pub fn program(mut self, program: impl Into<String>) -> Self {
+        self.program = program.into();
+        self
+    }
+
+    pub fn mount(mut self, dir: impl AsRef<Path>) -> Self {
+        self.mount = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn workdir(mut self, dir: impl AsRef<Path>) -> Self {
+        self.workdir = dir.as_ref().to_path_buf();
+        self
+    }
+
+    pub fn user(mut self, user: Option<String>) -> Self {
+        self.user = user;
+        self
+    }
+
+    pub fn arg(mut self, arg: impl Into<String>) -> Self {
+        self.args.push(arg.into());
+        self
+    }
+
+    /// Start containers using `executor` instead of starting child processes
+    pub(crate) fn with_executor(mut self, executor: Arc<dyn Executor>) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Rewrite host paths under `mount` to the same paths under `workdir`
+    pub fn map(&self, arg: &OsStr) -> OsString {
+        let mount = self.mount.to_string_lossy();
+        let arg_str = arg.to_string_lossy();
+        if mount.is_empty() || !arg_str.contains(mount.as_ref()) {
+            return arg.to_os_string();
+        }
+
+        // Only replace whole path components, so `/work-old` isn't mapped for `/work`
+        let workdir = self.workdir.to_string_lossy();
+        let mut out = String::new();
+        let mut rest = arg_str.as_ref();
+        while let Some(index) = rest.find(mount.as_ref()) {
+            let end = index + mount.len();
+            out.push_str(&rest[..index]);
+            match rest[end..].chars().next() {
+                None | Some('/') | Some('\\') | Some(':') | Some(' ') => out.push_str(&workdir),
+                Some(_) => out.push_str(&mount),
+            }
+            rest = &rest[end..];
+        }
+        out.push_str(rest);
+        OsString::from(out)
+    }
+
+    /// The `docker run` command running `cmd` in the container
+    pub fn command(&self, cmd: &Command) -> Command {
+        // Directories outside of `mount` don't exist in the container, `workdir` is used instead
+        let dir = match cmd.get_current_dir() {
+            Some(dir) => std::env::current_dir().map(|cwd| cwd.join(dir)),
+            None => std::env::current_dir(),
+        };
+        let workdir = match dir {
+            Ok(dir) => match dir.strip_prefix(&self.mount) {
+                Ok(rel) if rel.as_os_str().is_empty() => self.workdir.clone(),
+                Ok(rel) => self.workdir.join(rel),
+                Err(_) => self.workdir.clone(),
+            },
+            Err(_) => self.workdir.clone(),
+        };
+
+        let mut mount = self.mount.as_os_str().to_os_string();
+        mount.push(":");
+        mount.push(&self.workdir);
+
+        let mut docker = Command::new(&self.program);
+        docker
+            .arg("run")
+            .arg("--rm")
+            .arg("-i")
+            .arg("-v")
+            .arg(mount)
+            .arg("-w")
+            .arg(workdir);
+        if let Some(user) = &self.user {
+            docker.arg("-u").arg(user);
+        }
+        for (name, value) in cmd.get_envs() {
+            if let Some(value) = value {
+                let mut var = name.to_os_string();
+                var.push("=");
+                var.push(self.map(value));
+                docker.arg("-e").arg(var);
+            }
+        }
+        docker.args(&self.args).arg(&self.image);
+        docker.arg(self.map(cmd.get_program()));
+        for arg in cmd.get_args() {
+            docker.arg(self.map(arg));
+        }
+        docker
+    }
+}
+
+#[cfg(unix)]
+fn current_user() -> Option<String> {
+    // SAFETY: getuid and getgid can't fail
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    Some(format!("{}:{}", uid, gid))
+}
+
+#[cfg(not(unix))]
+fn current_user() -> Option<String> {
+    None
+}
+
+impl Executor for Container {
+    fn status(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<ExitStatus> {
+        self.executor
+            .status(&mut self.command(cmd), token, group, input)
+    }
+
+    fn status_with_usage(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+        // The usage would be that of the docker client, not the command
+        let status = self
+            .executor
+            .status(&mut self.command(cmd), token, group, input)?;
+        Ok((status, None))
+    }
+
+    fn status_with_lines(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+        streams: &[Stream],
+        on_line: &mut dyn FnMut(Stream, &[u8]),
+    ) -> io::Result<ExitStatus> {
+        self.executor.status_with_lines(
+            &mut self.command(cmd),
+            token,
+            group,
+            input,
+            streams,
+            on_line,
+        )
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.executor.output(&mut self.command(cmd))
+    }
+}
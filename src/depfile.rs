@@ -0,0 +1,195 @@
+//! Make-style dependency files, written by the compiler using `-MMD -MF <file>`
+//!
+//! The dependency file for an output is written next to it as `<output>.d`:
+//!
+//! ```text
+//! path/to/output: src/brighten.cpp \
+//!   include/my\ schedule.h
+//! ```
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Path of the dependency file for `output`
+pub fn path(output: impl AsRef<Path>) -> PathBuf {
+    let mut path = output.as_ref().as_os_str().to_owned();
+    path.push(".d");
+    PathBuf::from(path)
+}
+
+/// Parse the prerequisites of every rule in a dependency file
+///
+/// Handles line continuations, escaped spaces and `$$`, the phony targets written by `-MP`
+/// and Windows drive letters in paths. Duplicates are removed, keeping the first occurrence.
+pub fn parse(contents: &str) -> Vec<PathBuf> {
+    let mut deps: Vec<PathBuf> = vec![];
+
+    // Join continued lines, accepting both `\n` and `\r\n`
+    let joined = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+
+    for line in joined.lines() {
+        let mut in_prereqs = false;
+        for word in split_words(line) {
+            if !in_prereqs {
+                // The target list ends at the first word ending with an unescaped colon
+                if word == ":" || (word.ends_with(':') && !is_drive(&word)) {
+                    in_prereqs = true;
+                }
+                continue;
+            }
+
+            let path = PathBuf::from(word);
+            if !deps.contains(&path) {
+                deps.push(path);
+            }
+        }
+    }
+
+    deps
+}
+
+/// Read and parse a dependency file
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// Write a single rule listing the dependencies of `output`
+pub fn write(path: impl AsRef<Path>, output: &Path, deps: &[PathBuf]) -> io::Result<()> {
+    let mut s = escape(&output.to_string_lossy());
+    s.push(':');
+    for dep in deps {
+        s.push_str(" \\\n  ");
+        s.push_str(&escape(&dep.to_string_lossy()));
+    }
+    s.push('\n');
+    fs::write(path, s)
+}
+
+fn escape(path: &str) -> String {
+    path.replace('$', "$$").replace(' ', "\\ ")
+}
+
+/// Split a line into words, unescaping `\ `, `\#` and `$$`
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some(' ') | Some('#')) => {
+                word.push(chars.next().unwrap_or_default())
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            }
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+/// Windows paths like `C:` and `C:\foo.h` contain a colon that doesn't end the target list
+fn is_drive(word: &str) -> bool {
+    let bytes = word.as_bytes();
+    bytes.len() == 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<PathBuf> {
+        paths.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn escaped_spaces() {
+        assert_eq!(
+            parse("out/brighten: src/brighten.cpp include/my\\ schedule.h a\\#b.h $$HOME.h\n"),
+            paths(&[
+                "src/brighten.cpp",
+                "include/my schedule.h",
+                "a#b.h",
+                "$HOME.h"
+            ])
+        );
+    }
+
+    #[test]
+    fn line_continuations() {
+        let deps = paths(&["src/brighten.cpp", "include/a.h", "include/b.h"]);
+        assert_eq!(
+            parse("out: src/brighten.cpp \\\n  include/a.h \\\n  include/b.h\n"),
+            deps
+        );
+        assert_eq!(
+            parse("out: src/brighten.cpp \\\r\n  include/a.h \\\r\n  include/b.h\r\n"),
+            deps
+        );
+        // The target may be alone on its line
+        assert_eq!(
+            parse("out: \\\n  src/brighten.cpp include/a.h \\\n  include/b.h"),
+            deps
+        );
+    }
+
+    #[test]
+    fn multiple_targets_and_rules() {
+        // `-MP` adds a phony rule for every header, duplicates are removed
+        let contents = "out.o out.d: src/a.cpp include/a.h\n\
+                        \n\
+                        include/a.h:\n\
+                        other.o : src/b.cpp include/a.h\n";
+        assert_eq!(
+            parse(contents),
+            paths(&["src/a.cpp", "include/a.h", "src/b.cpp"])
+        );
+    }
+
+    #[test]
+    fn windows_drive_letters() {
+        let contents = "C:\\build\\brighten.o: C:\\src\\brighten.cpp \\\r\n  \
+                        C:\\Program\\ Files\\Halide\\include\\Halide.h \\\r\n  d:/include/a.h\r\n";
+        assert_eq!(
+            parse(contents),
+            paths(&[
+                "C:\\src\\brighten.cpp",
+                "C:\\Program Files\\Halide\\include\\Halide.h",
+                "d:/include/a.h",
+            ])
+        );
+
+        // A drive on its own isn't the end of the targets
+        assert_eq!(parse("C: out.o: a.cpp"), paths(&["a.cpp"]));
+    }
+
+    #[test]
+    fn write_round_trips() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("out.d");
+        let deps = paths(&["src/my brighten.cpp", "$HOME/a.h", "C:\\include\\b.h"]);
+        write(&file, Path::new("build/my out"), &deps).unwrap();
+        assert_eq!(read(&file).unwrap(), deps);
+    }
+
+    #[test]
+    fn path_appends_the_extension() {
+        assert_eq!(
+            path("build/brighten.o"),
+            PathBuf::from("build/brighten.o.d")
+        );
+    }
+}
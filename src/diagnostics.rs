@@ -0,0 +1,706 @@
+//! Extract Halide and compiler diagnostics from captured output
+//!
+//! Generators report errors in several forms depending on the Halide version, for example:
+//!
+//! ```text
+//! Error: Input buffer input is accessed at -1, which is before the min (0) in dimension 0
+//! Unhandled exception: Error: Generator brighten has no GeneratorParam named: scale
+//! Error at /src/brighten_generator.cpp:12:
+//! Can't call Func::realize on Func "f" because it has not yet been defined.
+//! ```
+//!
+//! Compiler diagnostics like `a.cpp:3:5: error: ...` are recognized too, along with the JSON
+//! diagnostics written by GCC 9 to 14 with `-fdiagnostics-format=json`
+
+use std::fmt;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::event::EventHandler;
+use crate::exec::{self, Executor, InterruptToken, Stream};
+use crate::version::{CompilerFamily, Version};
+
+/// Lines of context kept after a diagnostic
+const MAX_CONTEXT: usize = 8;
+
+/// Number of diagnostics included in a `Failure` message
+const TOP: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("error"),
+            Severity::Warning => f.write_str("warning"),
+        }
+    }
+}
+
+/// Where a compiler diagnostic points in the source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: u32,
+    pub column: Option<u32>,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.display(), self.line)?;
+        if let Some(column) = self.column {
+            write!(f, ":{}", column)?;
+        }
+        Ok(())
+    }
+}
+
+/// A diagnostic found in the output of a generator or compiler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+
+    /// Source location of compiler diagnostics, when the compiler reported one
+    pub location: Option<Location>,
+
+    /// Lines following the message, like a source location or compiler excerpt
+    pub context: Vec<String>,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(location) = &self.location {
+            write!(f, "{}: ", location)?;
+        }
+        write!(f, "{}: {}", self.severity, self.message)?;
+        for line in &self.context {
+            write!(f, "\n    {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the context of a diagnostic is collected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// Halide messages continue until a blank line
+    Halide,
+
+    /// Compiler excerpts are indented
+    Compiler,
+}
+
+/// Parse a `file:line:column` or `file:line` prefix of a compiler diagnostic
+fn location(prefix: &str) -> Option<Location> {
+    let (rest, last) = prefix.rsplit_once(':')?;
+    let last = last.parse().ok()?;
+    let (file, line, column) = match rest.rsplit_once(':') {
+        Some((file, line)) => match line.parse() {
+            Ok(line) => (file, line, Some(last)),
+            Err(_) => (rest, last, None),
+        },
+        None => (rest, last, None),
+    };
+    if file.is_empty() {
+        return None;
+    }
+    Some(Location {
+        file: PathBuf::from(file),
+        line,
+        column,
+    })
+}
+
+/// Recognize the first line of a diagnostic, returning the message, which may be empty when
+/// it's on the next line, and the location of compiler diagnostics
+fn classify(line: &str) -> Option<(Severity, Kind, String, Option<Location>)> {
+    let line = line.trim();
+
+    if let Some(rest) = line.strip_prefix("Unhandled exception:") {
+        let rest = rest.trim();
+        let rest = rest.strip_prefix("Error:").unwrap_or(rest);
+        return Some((Severity::Error, Kind::Halide, rest.trim().to_string(), None));
+    }
+
+    if let Some(rest) = line.strip_prefix("Error:") {
+        return Some((Severity::Error, Kind::Halide, rest.trim().to_string(), None));
+    }
+
+    if line.starts_with("Error at ") || line.starts_with("Internal Error at ") {
+        return Some((Severity::Error, Kind::Halide, String::new(), None));
+    }
+
+    if let Some(rest) = line.strip_prefix("Warning:") {
+        return Some((
+            Severity::Warning,
+            Kind::Halide,
+            rest.trim().to_string(),
+            None,
+        ));
+    }
+
+    if line.contains("Assertion failed") || line.contains("Assertion `") {
+        return Some((Severity::Error, Kind::Halide, line.to_string(), None));
+    }
+
+    for (pattern, severity) in [
+        (": fatal error: ", Severity::Error),
+        (": error: ", Severity::Error),
+        (": warning: ", Severity::Warning),
+    ] {
+        if let Some((prefix, message)) = line.split_once(pattern) {
+            // Lines without a location, like the linker's, are kept whole
+            return Some(match location(prefix) {
+                Some(location) => (
+                    severity,
+                    Kind::Compiler,
+                    message.trim().to_string(),
+                    Some(location),
+                ),
+                None => (severity, Kind::Compiler, line.to_string(), None),
+            });
+        }
+    }
+
+    None
+}
+
+/// Find the diagnostics in captured output, in the order they appear
+///
+/// Unrelated lines between diagnostics are ignored, so stdout and stderr can be interleaved
+pub fn parse(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut current: Option<(Kind, Diagnostic)> = None;
+
+    for line in output.lines() {
+        if let Some((severity, kind, message, location)) = classify(line) {
+            diagnostics.extend(current.take().map(|(_, d)| d));
+
+            // A location on its own line is kept as context for the message that follows
+            let mut context = vec![];
+            if message.is_empty() && kind == Kind::Halide && line.contains(" at ") {
+                context.push(line.trim().to_string());
+            }
+
+            current = Some((
+                kind,
+                Diagnostic {
+                    severity,
+                    message,
+                    location,
+                    context,
+                },
+            ));
+            continue;
+        }
+
+        let (kind, diagnostic) = match &mut current {
+            Some(current) => current,
+            None => continue,
+        };
+
+        let continues = match kind {
+            Kind::Halide => !line.trim().is_empty(),
+            Kind::Compiler => line.starts_with(' ') || line.starts_with('\t'),
+        };
+        if !continues {
+            if !diagnostic.message.is_empty() {
+                diagnostics.extend(current.take().map(|(_, d)| d));
+            }
+            continue;
+        }
+
+        if diagnostic.message.is_empty() {
+            diagnostic.message = line.trim().to_string();
+        } else if diagnostic.context.len() < MAX_CONTEXT {
+            diagnostic.context.push(line.trim_end().to_string());
+        }
+    }
+
+    diagnostics.extend(current.map(|(_, d)| d));
+    diagnostics.retain(|d| !d.message.is_empty());
+    diagnostics
+}
+
+/// A diagnostic in GCC's JSON format
+#[derive(Debug, Deserialize)]
+struct JsonDiagnostic {
+    kind: String,
+    message: String,
+    #[serde(default)]
+    locations: Vec<JsonLocation>,
+    #[serde(default)]
+    children: Vec<JsonDiagnostic>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonLocation {
+    caret: JsonPosition,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonPosition {
+    file: String,
+    line: u32,
+    column: Option<u32>,
+}
+
+impl JsonDiagnostic {
+    fn location(&self) -> Option<Location> {
+        self.locations.first().map(|loc| Location {
+            file: PathBuf::from(&loc.caret.file),
+            line: loc.caret.line,
+            column: loc.caret.column,
+        })
+    }
+
+    /// Errors and warnings, notes only appear as the context of their parent
+    fn to_diagnostic(&self) -> Option<Diagnostic> {
+        let severity = match self.kind.as_str() {
+            "error" | "fatal error" | "sorry, unimplemented" | "internal compiler error" => {
+                Severity::Error
+            }
+            "warning" => Severity::Warning,
+            _ => return None,
+        };
+        let context = self
+            .children
+            .iter()
+            .take(MAX_CONTEXT)
+            .map(|child| match child.location() {
+                Some(location) => format!("{}: {}: {}", location, child.kind, child.message),
+                None => format!("{}: {}", child.kind, child.message),
+            })
+            .collect();
+        Some(Diagnostic {
+            severity,
+            message: self.message.clone(),
+            location: self.location(),
+            context,
+        })
+    }
+}
+
+/// Find the diagnostics in the output of a compile or link command
+///
+/// Lines holding GCC's JSON diagnostics are parsed as JSON, the others as text, since
+/// linker errors are always text
+pub fn parse_compiler(output: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut text = String::new();
+    for line in output.lines() {
+        let json = line
+            .trim_start()
+            .starts_with('[')
+            .then(|| serde_json::from_str::<Vec<JsonDiagnostic>>(line).ok())
+            .flatten();
+        match json {
+            Some(json) => diagnostics.extend(json.iter().filter_map(JsonDiagnostic::to_diagnostic)),
+            None => {
+                text.push_str(line);
+                text.push('\n');
+            }
+        }
+    }
+    diagnostics.extend(parse(&text));
+    diagnostics
+}
+
+/// The flag selecting JSON diagnostics for a compiler, only GCC 9 to 14 support them
+///
+/// `version` is only called for GCC
+pub(crate) fn json_flag(
+    family: CompilerFamily,
+    version: impl FnOnce() -> Option<Version>,
+) -> Option<&'static str> {
+    if family != CompilerFamily::Gcc {
+        return None;
+    }
+    let major = version()?.major;
+    (9..=14)
+        .contains(&major)
+        .then_some("-fdiagnostics-format=json")
+}
+
+/// Returned when a generator fails and its output contains diagnostics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// The command that failed
+    pub command: String,
+
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Failure {
+    /// The errors, followed by the warnings
+    pub fn top(&self, n: usize) -> impl Iterator<Item = &Diagnostic> {
+        let errors = self
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error);
+        let warnings = self
+            .diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Warning);
+        errors.chain(warnings).take(n)
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed", self.command)?;
+        for diagnostic in self.top(TOP) {
+            write!(f, "\n  {}", diagnostic)?;
+        }
+        if self.diagnostics.len() > TOP {
+            write!(f, "\n  ({} more)", self.diagnostics.len() - TOP)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Run a generator, echoing its output while it's captured, or sending it to `tee` as events
+/// instead when it's given, and writing `input` to its standard input when given
+///
+/// Returns a `Failure` error when the command fails and its output contains diagnostics
+pub(crate) fn status(
+    executor: &dyn Executor,
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+    tee: Option<&EventHandler>,
+) -> io::Result<bool> {
+    let mut output = String::new();
+    let status = executor.status_with_lines(
+        cmd,
+        token,
+        group,
+        input,
+        &[Stream::Stdout, Stream::Stderr],
+        &mut |stream, line| {
+            match (tee, stream) {
+                (Some(events), _) => events.emit(exec::line_event(stream, line)),
+                (None, Stream::Stdout) => {
+                    let _ = io::stdout().write_all(line);
+                }
+                (None, Stream::Stderr) => {
+                    let _ = io::stderr().write_all(line);
+                }
+            }
+            output.push_str(&exec::line_text(line));
+            output.push('\n');
+        },
+    )?;
+
+    if status.success() {
+        return Ok(true);
+    }
+
+    let diagnostics = parse(&output);
+    if diagnostics.is_empty() {
+        return Ok(false);
+    }
+
+    Err(io::Error::other(Failure {
+        command: cmd.get_program().to_string_lossy().into_owned(),
+        diagnostics,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::event::Event;
+    use crate::exec::{Mock, Response};
+    use std::sync::{Arc, Mutex};
+
+    fn diagnostic(severity: Severity, message: &str, context: &[&str]) -> Diagnostic {
+        Diagnostic {
+            severity,
+            message: message.to_string(),
+            location: None,
+            context: context.iter().map(|line| line.to_string()).collect(),
+        }
+    }
+
+    fn at(file: &str, line: u32, column: Option<u32>) -> Option<Location> {
+        Some(Location {
+            file: PathBuf::from(file),
+            line,
+            column,
+        })
+    }
+
+    /// Halide 10 to 12 report the location of user errors before the message
+    #[test]
+    fn halide_12_transcript() {
+        let output = "\
+Generating brighten for target host
+Error at /src/brighten_generator.cpp:12:
+Can't call Func::realize on Func \"f\" because it has not yet been defined.
+
+Aborted (core dumped)
+";
+        assert_eq!(
+            parse(output),
+            [diagnostic(
+                Severity::Error,
+                "Can't call Func::realize on Func \"f\" because it has not yet been defined.",
+                &["Error at /src/brighten_generator.cpp:12:"],
+            )]
+        );
+    }
+
+    /// Newer versions throw exceptions, which GenGen reports before exiting
+    #[test]
+    fn halide_16_transcript() {
+        let output = "\
+Warning: Target feature avx512 is not supported by the host
+
+Unhandled exception: Error: Generator brighten has no GeneratorParam named: scale
+
+";
+        assert_eq!(
+            parse(output),
+            [
+                diagnostic(
+                    Severity::Warning,
+                    "Target feature avx512 is not supported by the host",
+                    &[],
+                ),
+                diagnostic(
+                    Severity::Error,
+                    "Generator brighten has no GeneratorParam named: scale",
+                    &[],
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn runtime_errors() {
+        let output = "\
+Error: Input buffer input is accessed at -1, which is before the min (0) in dimension 0
+Unhandled exception: Internal Error at /halide/src/CodeGen_LLVM.cpp:1120 triggered by user code at :
+Condition failed: t.lanes() == 1
+
+";
+        assert_eq!(
+            parse(output),
+            [
+                diagnostic(
+                    Severity::Error,
+                    "Input buffer input is accessed at -1, which is before the min (0) in dimension 0",
+                    &[],
+                ),
+                diagnostic(
+                    Severity::Error,
+                    "Internal Error at /halide/src/CodeGen_LLVM.cpp:1120 triggered by user code at :",
+                    &["Condition failed: t.lanes() == 1"],
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn assertions() {
+        let output = "\
+brighten.generator: /halide/src/Func.cpp:1220: Halide::Stage& Halide::Stage::split(): Assertion `dims.size() > 0' failed.
+Aborted (core dumped)
+";
+        assert_eq!(
+            parse(output),
+            [diagnostic(
+                Severity::Error,
+                "brighten.generator: /halide/src/Func.cpp:1220: Halide::Stage& Halide::Stage::split(): Assertion `dims.size() > 0' failed.",
+                &["Aborted (core dumped)"],
+            )]
+        );
+    }
+
+    /// Progress written to stdout ends up between the diagnostics on stderr
+    #[test]
+    fn interleaved_output() {
+        let output = "\
+[1/3] Lowering brighten
+
+Warning: Ignoring schedule for unused Func g
+
+[2/3] Compiling brighten
+brighten.cpp:3:5: warning: unused variable 'x' [-Wunused-variable]
+    int x;
+        ^
+[3/3] Linking brighten
+Error: Output buffer output has type uint8 but type of the Func is float32
+
+done
+";
+        let diagnostics = parse(output);
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(
+            diagnostics[0].message,
+            "Ignoring schedule for unused Func g"
+        );
+        assert_eq!(diagnostics[1].location, at("brighten.cpp", 3, Some(5)));
+        assert_eq!(diagnostics[1].context, ["    int x;", "        ^"]);
+        assert_eq!(
+            diagnostics[2],
+            diagnostic(
+                Severity::Error,
+                "Output buffer output has type uint8 but type of the Func is float32",
+                &[],
+            )
+        );
+    }
+
+    #[test]
+    fn compiler_text() {
+        let output = "\
+In file included from brighten.cpp:1:
+./brighten.h:4:10: fatal error: 'Halide.h' file not found
+    4 | #include \"Halide.h\"
+      |          ^~~~~~~~~~
+C:\\src\\brighten.cpp:7: error: expected ';'
+/usr/bin/ld: cannot find -lpng
+collect2: error: ld returned 1 exit status
+";
+        let diagnostics = parse(output);
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics[0].message, "'Halide.h' file not found");
+        assert_eq!(diagnostics[0].location, at("./brighten.h", 4, Some(10)));
+        assert_eq!(diagnostics[0].context.len(), 2);
+        assert_eq!(
+            diagnostics[1].location,
+            at("C:\\src\\brighten.cpp", 7, None)
+        );
+        assert_eq!(
+            diagnostics[2],
+            diagnostic(
+                Severity::Error,
+                "collect2: error: ld returned 1 exit status",
+                &[],
+            )
+        );
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "./brighten.h:4:10: error: 'Halide.h' file not found\n        4 | #include \"Halide.h\"\n          |          ^~~~~~~~~~"
+        );
+    }
+
+    #[test]
+    fn compiler_json() {
+        let output = r#"[{"kind": "error", "message": "'x' was not declared in this scope", "locations": [{"caret": {"file": "brighten.cpp", "line": 3, "column": 5}}], "children": [{"kind": "note", "message": "suggested alternative: 'y'", "locations": [{"caret": {"file": "brighten.cpp", "line": 2, "column": 9}}]}]}, {"kind": "note", "message": "ignored"}]
+/usr/bin/ld: brighten.o: undefined reference to `halide_malloc'
+collect2: error: ld returned 1 exit status
+"#;
+        let diagnostics = parse_compiler(output);
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(
+            diagnostics[0],
+            Diagnostic {
+                severity: Severity::Error,
+                message: "'x' was not declared in this scope".to_string(),
+                location: at("brighten.cpp", 3, Some(5)),
+                context: vec!["brighten.cpp:2:9: note: suggested alternative: 'y'".to_string()],
+            }
+        );
+        assert_eq!(
+            diagnostics[1].message,
+            "collect2: error: ld returned 1 exit status"
+        );
+
+        // Lines that only look like JSON are parsed as text
+        assert!(parse_compiler("[1/3] Compiling\n").is_empty());
+    }
+
+    #[test]
+    fn context_is_limited() {
+        let mut output = "Error: too many lines\n".to_string();
+        for i in 0..20 {
+            output.push_str(&format!("line {}\n", i));
+        }
+        let diagnostics = parse(&output);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].context.len(), MAX_CONTEXT);
+        assert!(parse("Error:\n\n").is_empty());
+    }
+
+    #[test]
+    fn failure_message() {
+        let failure = Failure {
+            command: "brighten.generator".to_string(),
+            diagnostics: vec![
+                diagnostic(Severity::Warning, "w1", &[]),
+                diagnostic(Severity::Error, "e1", &["context"]),
+                diagnostic(Severity::Warning, "w2", &[]),
+                diagnostic(Severity::Error, "e2", &[]),
+                diagnostic(Severity::Error, "e3", &[]),
+            ],
+        };
+        assert_eq!(
+            failure.to_string(),
+            "brighten.generator failed\n  error: e1\n    context\n  error: e2\n  error: e3\n  (2 more)"
+        );
+        let top: Vec<&str> = failure.top(4).map(|d| d.message.as_str()).collect();
+        assert_eq!(top, ["e1", "e2", "e3", "w1"]);
+    }
+
+    #[test]
+    fn generator_status() {
+        let mock = Mock::new()
+            .on(
+                "brighten.generator",
+                Response::exit(1)
+                    .stdout("Generating brighten\n")
+                    .stderr("Unhandled exception: Error: bad param\n"),
+            )
+            .on("brighten.generator", Response::exit(1).stderr("Segfault\n"))
+            .on(
+                "brighten.generator",
+                Response::ok().stderr("Warning: slow\n"),
+            );
+        let events = Arc::new(Mutex::new(vec![]));
+        let sink = events.clone();
+        let handler = EventHandler::new(move |event| sink.lock().unwrap().push(event.clone()));
+        let run = || {
+            status(
+                &mock,
+                &mut Command::new("brighten.generator"),
+                &InterruptToken::new(),
+                true,
+                None,
+                Some(&handler),
+            )
+        };
+
+        let e = run().unwrap_err();
+        let failure = e.get_ref().unwrap().downcast_ref::<Failure>().unwrap();
+        assert_eq!(failure.command, "brighten.generator");
+        assert_eq!(
+            failure.diagnostics,
+            [diagnostic(Severity::Error, "bad param", &[])]
+        );
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                Event::ChildStdoutLine("Generating brighten".to_string()),
+                Event::ChildStderrLine("Unhandled exception: Error: bad param".to_string()),
+            ]
+        );
+
+        // Failures without diagnostics, and warnings from successful runs, aren't errors
+        assert!(!run().unwrap());
+        assert!(run().unwrap());
+    }
+}
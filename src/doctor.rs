@@ -0,0 +1,267 @@
+//! Diagnose the environment used to build Halide kernels
+//!
+//! `halide doctor` prints the result of each check with a hint for fixing it, and fails when
+//! a check that prevents every build fails. Missing optional pieces, like the image libraries
+//! or git, are warnings
+
+use std::env;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::build_info::{self, BuildInfo};
+use crate::exec;
+use crate::info::Info;
+use crate::pkg_config;
+use crate::scm::GitCli;
+use crate::version;
+use crate::{Language, Layout, LayoutKind, Source};
+
+/// The result of a check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+
+    /// Some builds or commands won't work
+    Warn,
+
+    /// No kernel can be built
+    Fail,
+}
+
+/// A checked prerequisite
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+
+    /// What was found
+    pub detail: String,
+
+    /// How to fix a failed check
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, detail: impl Into<String>) -> Check {
+        Check {
+            name,
+            status: Status::Pass,
+            detail: detail.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(
+        name: &'static str,
+        status: Status,
+        detail: impl Into<String>,
+        hint: impl Into<String>,
+    ) -> Check {
+        Check {
+            name,
+            status,
+            detail: detail.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+/// `[ok] name: detail`, followed by the hint on its own line
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let status = match self.status {
+            Status::Pass => "[ok]  ",
+            Status::Warn => "[warn]",
+            Status::Fail => "[fail]",
+        };
+        write!(f, "{} {}: {}", status, self.name, self.detail)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether any check failed
+pub fn failed(checks: &[Check]) -> bool {
+    checks.iter().any(|check| check.status == Status::Fail)
+}
+
+/// Check everything needed to build kernels with the Halide at `halide_path`
+pub fn run(halide_path: impl AsRef<Path>) -> Vec<Check> {
+    let halide_path = halide_path.as_ref();
+    let kind = LayoutKind::detect(halide_path);
+    let layout = Layout::install(halide_path);
+    let info = Info::collect(&exec::System, halide_path);
+    let mut checks = vec![];
+
+    let from_env = env::var_os("HALIDE_PATH").is_some_and(|path| Path::new(&path) == halide_path);
+    let detail = format!(
+        "{}{} ({})",
+        halide_path.display(),
+        if from_env { " from HALIDE_PATH" } else { "" },
+        kind.name()
+    );
+    checks.push(match kind.advice(halide_path) {
+        _ if !halide_path.is_dir() => Check::problem(
+            "Halide path",
+            Status::Fail,
+            format!("{} does not exist", halide_path.display()),
+            "Set HALIDE_PATH or pass -p, or download and build Halide using `halide src`",
+        ),
+        None => Check::pass("Halide path", detail),
+        // These work once the build is told where to look
+        Some(advice) if matches!(kind, LayoutKind::Release | LayoutKind::CMakeBuild) => {
+            Check::problem("Halide path", Status::Warn, detail, advice)
+        }
+        Some(advice) => Check::problem("Halide path", Status::Fail, detail, advice),
+    });
+
+    let header = layout.include.join("Halide.h");
+    checks.push(match (&info.halide_version, header.is_file()) {
+        (_, false) => Check::problem(
+            "Halide.h",
+            Status::Fail,
+            format!("missing from {}", layout.include.display()),
+            "Point HALIDE_PATH or -p at a Halide install, or download one using `halide src`",
+        ),
+        (Some(version), true) => Check::pass("Halide.h", format!("version {}", version)),
+        (None, true) => Check::pass("Halide.h", "unknown version"),
+    });
+
+    checks.push(if info.libraries.is_empty() {
+        Check::problem(
+            "Halide library",
+            Status::Fail,
+            format!("missing from {}", layout.lib.display()),
+            "Build Halide using `halide src`, or install a binary release",
+        )
+    } else {
+        let names: Vec<&str> = info.libraries.iter().map(|l| l.name.as_str()).collect();
+        Check::pass("Halide library", names.join(", "))
+    });
+
+    if header.is_file() && !info.libraries.is_empty() {
+        checks.push(match version::Installed::read(&layout).check() {
+            Ok(()) => Check::pass("Halide install", "headers match the library"),
+            Err(stale) => Check::problem(
+                "Halide install",
+                Status::Fail,
+                stale.to_string(),
+                "Reinstall Halide so the headers and library come from the same build",
+            ),
+        });
+    }
+
+    checks.push(if layout.tools.join("GenGen.cpp").is_file() {
+        Check::pass("GenGen.cpp", layout.tools.display().to_string())
+    } else {
+        Check::problem(
+            "GenGen.cpp",
+            Status::Fail,
+            format!("missing from {}", layout.tools.display()),
+            "Generators can't be built, pass --gengen or use a complete Halide install",
+        )
+    });
+
+    checks.push(check_compiler(&info, &layout));
+    checks.extend(check_image_libs());
+
+    if matches!(
+        kind,
+        LayoutKind::SourceTree | LayoutKind::MakeBuild | LayoutKind::CMakeBuild
+    ) {
+        checks.push(check_llvm(halide_path));
+    }
+
+    checks.push(if GitCli::default().is_available() {
+        Check::pass("git", "found")
+    } else {
+        Check::problem(
+            "git",
+            Status::Warn,
+            "not found",
+            "Install git to download and update Halide using `halide src`",
+        )
+    });
+    checks
+}
+
+fn check_compiler(info: &Info, layout: &Layout) -> Check {
+    let var = Language::Cxx.compiler_var();
+    let compiler = &info.compiler;
+    let version = match &compiler.version {
+        Some(version) => version,
+        None => {
+            return Check::problem(
+                "C++ compiler",
+                Status::Fail,
+                format!("unable to run {}", compiler.name),
+                format!("Install a C++ compiler, or set {} to one", var),
+            )
+        }
+    };
+    let detail = format!("{} ({} {})", compiler.name, compiler.family, version);
+
+    let halide = match BuildInfo::read(&layout.lib) {
+        Ok(Some(halide)) => halide,
+        _ => return Check::pass("C++ compiler", detail),
+    };
+    let build = BuildInfo::detect(&exec::System, &compiler.name, vec![]);
+    let mismatches = build_info::compare(&halide, &build);
+    match mismatches.first() {
+        None => Check::pass("C++ compiler", detail),
+        Some(mismatch) => Check::problem(
+            "C++ compiler",
+            Status::Warn,
+            format!("{}, {}", detail, mismatch),
+            format!("Set {} to {} to match libHalide", var, halide.compiler),
+        ),
+    }
+}
+
+/// The image libraries linked by default for `Halide::Tools` image IO
+fn check_image_libs() -> Vec<Check> {
+    ["png", "jpeg"]
+        .iter()
+        .map(|lib| {
+            let found = pkg_config::package_names(lib)
+                .iter()
+                .find_map(|package| pkg_config::query(&exec::System, package));
+            match found {
+                Some(_) => Check::pass("image library", format!("lib{} found", lib)),
+                None => Check::problem(
+                    "image library",
+                    Status::Warn,
+                    format!("lib{} not found by pkg-config", lib),
+                    format!(
+                        "Install the lib{} development package, or build with --no-image-io",
+                        lib
+                    ),
+                ),
+            }
+        })
+        .collect()
+}
+
+/// Only checked for source checkouts, since installs don't need LLVM
+fn check_llvm(halide_path: &Path) -> Check {
+    let source = Source::new(halide_path);
+    let llvm_config = source.llvm_config_path();
+    match source.verify_llvm() {
+        Ok(()) => Check::pass("LLVM", llvm_config.display().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Check::problem(
+            "LLVM",
+            Status::Warn,
+            e.to_string(),
+            "Install LLVM or set LLVM_CONFIG to rebuild Halide from source",
+        ),
+        Err(e) => Check::problem(
+            "LLVM",
+            Status::Warn,
+            e.to_string(),
+            "Rebuilding Halide from source will fail with this LLVM",
+        ),
+    }
+}
@@ -0,0 +1,123 @@
+//! Notifications emitted while building and running
+
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::diagnostics::Diagnostic;
+
+/// Something that happened during a build or run that may be of interest to the caller
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Event {
+    /// A requested option could not be applied, but the step continued without it
+    Warning(String),
+
+    /// A line of output from a Halide source build, with the estimated progress in percent
+    /// when it can be determined from the line
+    SourceProgress { percent: Option<f32>, line: String },
+
+    /// Sent periodically while a long `Source` step is running, with the time since it
+    /// started
+    SourceTick { step: SourceStep, elapsed: Duration },
+
+    /// A compiler diagnostic, sent after each compile command when `structured_diagnostics`
+    /// is set
+    Diagnostic(Diagnostic),
+
+    /// The compiler picked from `Build::compilers`, with the reason each earlier one was
+    /// skipped
+    CompilerSelected {
+        compiler: String,
+        rejected: Vec<(String, String)>,
+    },
+
+    /// A patch from `Source::patches` was applied, or skipped since it was already applied
+    PatchApplied { patch: PathBuf, skipped: bool },
+
+    /// A line written to standard output by a command, sent when `Build::tee` or
+    /// `Source::tee` is set, without its line ending and with invalid UTF-8 replaced
+    ChildStdoutLine(String),
+
+    /// A line written to standard error by a command, like `ChildStdoutLine`
+    ChildStderrLine(String),
+}
+
+/// A step of downloading or building Halide source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceStep {
+    Download,
+    Update,
+    Build,
+}
+
+impl fmt::Display for SourceStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceStep::Download => f.write_str("Downloading"),
+            SourceStep::Update => f.write_str("Updating"),
+            SourceStep::Build => f.write_str("Building"),
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Event::Warning(msg) => write!(f, "warning: {}", msg),
+            Event::SourceProgress { line, .. } => f.write_str(line),
+            Event::SourceTick { step, elapsed } => {
+                write!(f, "{} ({}s)", step, elapsed.as_secs())
+            }
+            Event::Diagnostic(diagnostic) => write!(f, "{}", diagnostic),
+            Event::CompilerSelected { compiler, rejected } => {
+                write!(f, "Using compiler {}", compiler)?;
+                for (compiler, reason) in rejected {
+                    write!(f, ", skipped {} ({})", compiler, reason)?;
+                }
+                Ok(())
+            }
+            Event::PatchApplied {
+                patch,
+                skipped: false,
+            } => write!(f, "Applied patch {}", patch.display()),
+            Event::PatchApplied {
+                patch,
+                skipped: true,
+            } => write!(f, "Patch {} is already applied", patch.display()),
+            Event::ChildStdoutLine(line) | Event::ChildStderrLine(line) => f.write_str(line),
+        }
+    }
+}
+
+type Callback = dyn Fn(&Event) + Send + Sync;
+
+/// A callback that receives events, events are dropped when no callback is set
+#[derive(Clone, Default)]
+pub struct EventHandler(Option<Arc<Callback>>);
+
+impl EventHandler {
+    pub fn new(f: impl Fn(&Event) + Send + Sync + 'static) -> EventHandler {
+        EventHandler(Some(Arc::new(f)))
+    }
+
+    pub(crate) fn is_set(&self) -> bool {
+        self.0.is_some()
+    }
+
+    pub(crate) fn emit(&self, event: Event) {
+        if let Some(f) = &self.0 {
+            f(&event)
+        }
+    }
+}
+
+impl fmt::Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(_) => f.write_str("EventHandler(Some(..))"),
+            None => f.write_str("EventHandler(None)"),
+        }
+    }
+}
@@ -0,0 +1,934 @@
+//! Process execution used by builds, runs and source builds
+//!
+//! Commands are run through an `Executor`, `System` runs them as child processes. With the
+//! `testing` feature, `Mock` can be set using `Build::with_executor` or
+//! `Source::with_executor` to record commands and script their results without a compiler
+
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::process::{Command, ExitStatus, Output};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::event::{Event, EventHandler};
+use crate::process;
+pub use crate::process::{InterruptToken, Stream};
+
+/// Resources used by a child process and the children it waited for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ResourceUsage {
+    /// Peak resident set size in bytes
+    pub max_rss: u64,
+
+    #[serde(serialize_with = "seconds")]
+    pub user_time: Duration,
+
+    #[serde(serialize_with = "seconds")]
+    pub system_time: Duration,
+}
+
+fn seconds<S: serde::Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
+
+impl ResourceUsage {
+    #[cfg(unix)]
+    pub(crate) fn from_rusage(usage: &libc::rusage) -> ResourceUsage {
+        let time = |t: libc::timeval| {
+            Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64)
+        };
+
+        // Linux reports kilobytes, macOS reports bytes
+        let scale = if cfg!(target_vendor = "apple") {
+            1
+        } else {
+            1024
+        };
+        ResourceUsage {
+            max_rss: usage.ru_maxrss as u64 * scale,
+            user_time: time(usage.ru_utime),
+            system_time: time(usage.ru_stime),
+        }
+    }
+
+    /// Peak resident set size in MiB
+    pub fn max_rss_mib(&self) -> f64 {
+        self.max_rss as f64 / (1024.0 * 1024.0)
+    }
+
+    /// Combine the usage of commands run one after another, keeping the highest peak and
+    /// adding the CPU time
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.max_rss = self.max_rss.max(other.max_rss);
+        self.user_time += other.user_time;
+        self.system_time += other.system_time;
+    }
+}
+
+impl fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "peak memory {:.1} MiB, user {:.2}s, system {:.2}s",
+            self.max_rss_mib(),
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64()
+        )
+    }
+}
+
+/// The result of a command whose output was captured
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The command line, as returned by `command_line`
+    pub command: String,
+
+    pub status: ExitStatus,
+
+    /// Output as written by the command, without normalizing line endings or encoding
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+
+    pub duration: Duration,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        self.status.success()
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({}, {:.2}s)",
+            self.command,
+            self.status,
+            self.duration.as_secs_f64()
+        )
+    }
+}
+
+/// Quote an argument for a POSIX shell, arguments that don't need it are left alone
+pub(crate) fn shell_quote(arg: &OsStr) -> String {
+    let arg = arg.to_string_lossy();
+    let safe = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(safe) {
+        return arg.into_owned();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// The program and arguments of a command, quoted for the shell
+pub fn command_line(cmd: &Command) -> String {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Run a command to completion, writing `input` to its standard input when given and
+/// capturing its output as written
+pub(crate) fn capture(
+    executor: &dyn Executor,
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+) -> io::Result<Outcome> {
+    capture_with(executor, cmd, token, group, input, &mut |_, _| ())
+}
+
+/// Like `capture`, also sending each line to `events` as `Event::ChildStdoutLine` or
+/// `Event::ChildStderrLine` as soon as it's written
+///
+/// Lines of each stream arrive in order, lines of different streams in the order they were
+/// read. A last line without a line ending is sent when the command exits
+pub(crate) fn tee(
+    executor: &dyn Executor,
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+    events: &EventHandler,
+) -> io::Result<Outcome> {
+    capture_with(executor, cmd, token, group, input, &mut |stream, line| {
+        events.emit(line_event(stream, line))
+    })
+}
+
+/// The event for a line written by a child process
+pub(crate) fn line_event(stream: Stream, line: &[u8]) -> Event {
+    let line = line_text(line).into_owned();
+    match stream {
+        Stream::Stdout => Event::ChildStdoutLine(line),
+        Stream::Stderr => Event::ChildStderrLine(line),
+    }
+}
+
+/// A line passed to `Executor::status_with_lines` without its line ending, invalid UTF-8 is
+/// replaced
+pub(crate) fn line_text(line: &[u8]) -> Cow<'_, str> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    String::from_utf8_lossy(line)
+}
+
+fn capture_with(
+    executor: &dyn Executor,
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+    forward: &mut dyn FnMut(Stream, &[u8]),
+) -> io::Result<Outcome> {
+    let command = command_line(cmd);
+    let start = Instant::now();
+    let (mut stdout, mut stderr) = (vec![], vec![]);
+    let status = executor.status_with_lines(
+        cmd,
+        token,
+        group,
+        input,
+        &[Stream::Stdout, Stream::Stderr],
+        &mut |stream, line| {
+            forward(stream, line);
+            match stream {
+                Stream::Stdout => stdout.extend_from_slice(line),
+                Stream::Stderr => stderr.extend_from_slice(line),
+            }
+        },
+    )?;
+
+    Ok(Outcome {
+        command,
+        status,
+        stdout,
+        stderr,
+        duration: start.elapsed(),
+    })
+}
+
+/// Add the usage of a command to the total of the commands run before it
+pub(crate) fn add_usage(total: &mut Option<ResourceUsage>, usage: Option<ResourceUsage>) {
+    match (total.as_mut(), usage) {
+        (Some(total), Some(usage)) => total.add(&usage),
+        (None, usage) => *total = usage,
+        _ => (),
+    }
+}
+
+/// Fail with `io::ErrorKind::OutOfMemory` when a command was killed by `SIGKILL`, which is
+/// usually the out-of-memory killer or a CI memory limit
+pub(crate) fn check_killed(
+    cmd: &Command,
+    status: ExitStatus,
+    usage: Option<&ResourceUsage>,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if status.signal() == Some(libc::SIGKILL) {
+            let peak = usage
+                .map(|usage| format!(", peak memory {:.1} MiB", usage.max_rss_mib()))
+                .unwrap_or_default();
+            return Err(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                format!(
+                    "{} was killed by SIGKILL{}, it likely ran out of memory, try lowering \
+                     the parallelism or raising the memory limit",
+                    cmd.get_program().to_string_lossy(),
+                    peak
+                ),
+            ));
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (cmd, status, usage);
+
+    Ok(())
+}
+
+/// Runs commands on behalf of builds
+pub trait Executor: fmt::Debug + Send + Sync {
+    /// Run a command to completion, writing `input` to its standard input when given
+    ///
+    /// When `group` is set the command runs in its own process group on Unix, see
+    /// `InterruptToken`
+    fn status(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<ExitStatus>;
+
+    /// Like `status`, also returning the resources used by the command when they're
+    /// available
+    fn status_with_usage(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+        Ok((self.status(cmd, token, group, input)?, None))
+    }
+
+    /// Run a command to completion, writing `input` to its standard input when given, and
+    /// calling `on_line` with each line written to `streams`
+    ///
+    /// Lines are passed as written, including the line ending, so together they're the
+    /// command's output unchanged. The last line may not have one
+    fn status_with_lines(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+        streams: &[Stream],
+        on_line: &mut dyn FnMut(Stream, &[u8]),
+    ) -> io::Result<ExitStatus>;
+
+    /// Run a command to completion, capturing its output
+    fn output(&self, cmd: &mut Command) -> io::Result<Output>;
+}
+
+/// Runs commands as child processes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct System;
+
+impl Executor for System {
+    fn status(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<ExitStatus> {
+        process::status_with_input(cmd, token, group, input).map(|(status, _)| status)
+    }
+
+    fn status_with_usage(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+        process::status_with_input(cmd, token, group, input)
+    }
+
+    fn status_with_lines(
+        &self,
+        cmd: &mut Command,
+        token: &InterruptToken,
+        group: bool,
+        input: Option<&[u8]>,
+        streams: &[Stream],
+        on_line: &mut dyn FnMut(Stream, &[u8]),
+    ) -> io::Result<ExitStatus> {
+        process::status_with_lines(cmd, token, group, input, streams, on_line)
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        cmd.output()
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+pub use mock::{Call, Mock, Response};
+
+#[cfg(any(test, feature = "testing"))]
+mod mock {
+    use std::collections::VecDeque;
+    use std::ffi::OsStr;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::process::{Command, ExitStatus, Output};
+    use std::sync::{Arc, Mutex};
+
+    use super::{Executor, InterruptToken, Stream};
+
+    /// A command run by a `Mock`
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct Call {
+        pub program: String,
+        pub args: Vec<String>,
+
+        /// Variables set or removed on the command, `None` for removed variables
+        pub env: Vec<(String, Option<String>)>,
+
+        pub cwd: Option<PathBuf>,
+
+        /// Bytes written to standard input
+        pub input: Option<Vec<u8>>,
+
+        /// True when the command runs in its own process group
+        pub group: bool,
+    }
+
+    impl Call {
+        /// The value of an environment variable set on the command
+        pub fn env(&self, name: &str) -> Option<&str> {
+            self.env
+                .iter()
+                .rev()
+                .find(|(n, _)| n == name)
+                .and_then(|(_, value)| value.as_deref())
+        }
+
+        /// Returns true if `arg` was passed
+        pub fn has_arg(&self, arg: &str) -> bool {
+            self.args.iter().any(|a| a == arg)
+        }
+    }
+
+    /// The scripted result of a command
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Response {
+        pub code: i32,
+        pub stdout: String,
+        pub stderr: String,
+
+        /// Fail with `io::ErrorKind::Interrupted`, like an interrupted command
+        pub interrupted: bool,
+
+        /// Written to the path following `-o`, like the output of a compiler
+        pub output_file: Option<Vec<u8>>,
+    }
+
+    impl Response {
+        /// Exit successfully without output
+        pub fn ok() -> Response {
+            Response::default()
+        }
+
+        /// Exit with `code`
+        pub fn exit(code: i32) -> Response {
+            Response {
+                code,
+                ..Response::default()
+            }
+        }
+
+        /// Fail with `io::ErrorKind::Interrupted`
+        pub fn interrupted() -> Response {
+            Response {
+                interrupted: true,
+                ..Response::default()
+            }
+        }
+
+        pub fn stdout(mut self, s: impl Into<String>) -> Response {
+            self.stdout = s.into();
+            self
+        }
+
+        pub fn stderr(mut self, s: impl Into<String>) -> Response {
+            self.stderr = s.into();
+            self
+        }
+
+        /// Write `contents` to the path following `-o`, also when the command fails or is
+        /// interrupted, like a compiler leaving a partial output behind
+        pub fn output_file(mut self, contents: impl Into<Vec<u8>>) -> Response {
+            self.output_file = Some(contents.into());
+            self
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct State {
+        calls: Vec<Call>,
+        responses: Vec<(String, VecDeque<Response>)>,
+    }
+
+    /// Records commands instead of running them, returning scripted responses
+    ///
+    /// Responses are matched by program file name, like `c++` or `make`. When several
+    /// responses are given for a program they're returned in order, and the last one is
+    /// repeated. Commands without a response succeed without output. Clones share their
+    /// state, so a clone can be kept to inspect the calls after the mock is moved into a build
+    #[derive(Debug, Clone, Default)]
+    pub struct Mock(Arc<Mutex<State>>);
+
+    impl Mock {
+        pub fn new() -> Mock {
+            Mock::default()
+        }
+
+        /// Add a response for the next command running `program`
+        pub fn on(self, program: &str, response: Response) -> Mock {
+            {
+                let mut state = self.0.lock().unwrap();
+                match state.responses.iter_mut().find(|(p, _)| p == program) {
+                    Some((_, queue)) => queue.push_back(response),
+                    None => state
+                        .responses
+                        .push((program.to_string(), VecDeque::from([response]))),
+                }
+            }
+            self
+        }
+
+        /// The commands run so far
+        pub fn calls(&self) -> Vec<Call> {
+            self.0.lock().unwrap().calls.clone()
+        }
+
+        /// The commands run so far using `program`
+        pub fn calls_to(&self, program: &str) -> Vec<Call> {
+            self.calls()
+                .into_iter()
+                .filter(|call| program_name(&call.program) == program)
+                .collect()
+        }
+
+        fn call(
+            &self,
+            cmd: &Command,
+            token: &InterruptToken,
+            group: bool,
+            input: Option<&[u8]>,
+        ) -> io::Result<Response> {
+            if token.is_interrupted() {
+                return Err(crate::process::interrupted());
+            }
+
+            let lossy = |s: &OsStr| s.to_string_lossy().into_owned();
+            let call = Call {
+                program: lossy(cmd.get_program()),
+                args: cmd.get_args().map(lossy).collect(),
+                env: cmd
+                    .get_envs()
+                    .map(|(name, value)| (lossy(name), value.map(lossy)))
+                    .collect(),
+                cwd: cmd.get_current_dir().map(Path::to_path_buf),
+                input: input.map(<[u8]>::to_vec),
+                group,
+            };
+
+            let output = call
+                .args
+                .iter()
+                .position(|arg| arg == "-o")
+                .and_then(|i| call.args.get(i + 1))
+                .map(PathBuf::from);
+
+            let mut state = self.0.lock().unwrap();
+            let name = program_name(&call.program).to_string();
+            state.calls.push(call);
+
+            let response = match state.responses.iter_mut().find(|(p, _)| *p == name) {
+                Some((_, queue)) if queue.len() > 1 => queue.pop_front().unwrap(),
+                Some((_, queue)) => queue.front().cloned().unwrap_or_default(),
+                None => Response::ok(),
+            };
+            if let (Some(contents), Some(output)) = (&response.output_file, output) {
+                fs::write(output, contents)?;
+            }
+            if response.interrupted {
+                return Err(crate::process::interrupted());
+            }
+            Ok(response)
+        }
+    }
+
+    fn program_name(program: &str) -> &str {
+        Path::new(program)
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or(program)
+    }
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    impl Executor for Mock {
+        fn status(
+            &self,
+            cmd: &mut Command,
+            token: &InterruptToken,
+            group: bool,
+            input: Option<&[u8]>,
+        ) -> io::Result<ExitStatus> {
+            let response = self.call(cmd, token, group, input)?;
+            Ok(exit_status(response.code))
+        }
+
+        fn status_with_lines(
+            &self,
+            cmd: &mut Command,
+            token: &InterruptToken,
+            group: bool,
+            input: Option<&[u8]>,
+            streams: &[Stream],
+            on_line: &mut dyn FnMut(Stream, &[u8]),
+        ) -> io::Result<ExitStatus> {
+            let response = self.call(cmd, token, group, input)?;
+            for (stream, output) in [
+                (Stream::Stdout, &response.stdout),
+                (Stream::Stderr, &response.stderr),
+            ] {
+                if streams.contains(&stream) {
+                    output
+                        .split_inclusive('\n')
+                        .for_each(|line| on_line(stream, line.as_bytes()));
+                }
+            }
+            Ok(exit_status(response.code))
+        }
+
+        fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+            let response = self.call(cmd, &InterruptToken::new(), false, None)?;
+            Ok(Output {
+                status: exit_status(response.code),
+                stdout: response.stdout.into_bytes(),
+                stderr: response.stderr.into_bytes(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::process::Command;
+
+    use super::*;
+
+    fn run(mock: &Mock, cmd: &mut Command) -> io::Result<ExitStatus> {
+        mock.status(cmd, &InterruptToken::new(), true, None)
+    }
+
+    #[test]
+    fn mock_records_commands() {
+        let mock = Mock::new();
+        let mut cmd = Command::new("/usr/bin/c++");
+        cmd.args(["-c", "a b.cpp"])
+            .env("CXXFLAGS", "-O2")
+            .env_remove("LDFLAGS")
+            .current_dir("/tmp");
+        mock.status(&mut cmd, &InterruptToken::new(), false, Some(b"input"))
+            .unwrap();
+
+        let calls = mock.calls();
+        assert_eq!(calls.len(), 1);
+        let call = &calls[0];
+        assert_eq!(call.program, "/usr/bin/c++");
+        assert_eq!(call.args, ["-c", "a b.cpp"]);
+        assert_eq!(call.env("CXXFLAGS"), Some("-O2"));
+        assert_eq!(call.env("LDFLAGS"), None);
+        assert!(call.env.contains(&("LDFLAGS".to_string(), None)));
+        assert_eq!(call.cwd.as_deref(), Some("/tmp".as_ref()));
+        assert_eq!(call.input.as_deref(), Some(&b"input"[..]));
+        assert!(!call.group);
+        assert!(call.has_arg("a b.cpp"));
+        assert_eq!(mock.calls_to("c++").len(), 1);
+        assert!(mock.calls_to("make").is_empty());
+    }
+
+    #[test]
+    fn mock_responses_are_returned_in_order_and_the_last_is_repeated() {
+        let mock = Mock::new()
+            .on("c++", Response::exit(1))
+            .on("c++", Response::exit(2))
+            .on("c++", Response::ok());
+
+        let codes: Vec<Option<i32>> = (0..4)
+            .map(|_| run(&mock, &mut Command::new("c++")).unwrap().code())
+            .collect();
+        assert_eq!(codes, [Some(1), Some(2), Some(0), Some(0)]);
+
+        // Programs without a response succeed
+        assert!(run(&mock, &mut Command::new("ar")).unwrap().success());
+        assert_eq!(mock.calls().len(), 5);
+    }
+
+    #[test]
+    fn mock_clones_share_calls() {
+        let mock = Mock::new();
+        let executor: Box<dyn Executor> = Box::new(mock.clone());
+        executor
+            .status(
+                &mut Command::new("make"),
+                &InterruptToken::new(),
+                true,
+                None,
+            )
+            .unwrap();
+        assert_eq!(mock.calls_to("make").len(), 1);
+        assert!(mock.calls()[0].group);
+    }
+
+    #[test]
+    fn mock_output_and_lines() {
+        let mock = Mock::new().on(
+            "nm",
+            Response::exit(3).stdout("one\ntwo").stderr("warning\n"),
+        );
+        let output = mock.output(&mut Command::new("nm")).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+        assert_eq!(output.stdout, b"one\ntwo");
+        assert_eq!(output.stderr, b"warning\n");
+
+        let mut lines = vec![];
+        mock.status_with_lines(
+            &mut Command::new("nm"),
+            &InterruptToken::new(),
+            false,
+            Some(b"input"),
+            &[Stream::Stdout],
+            &mut |stream, line| lines.push((stream, line.to_vec())),
+        )
+        .unwrap();
+        assert_eq!(
+            lines,
+            [
+                (Stream::Stdout, b"one\n".to_vec()),
+                (Stream::Stdout, b"two".to_vec())
+            ]
+        );
+        assert_eq!(mock.calls()[1].input.as_deref(), Some(&b"input"[..]));
+    }
+
+    #[test]
+    fn mock_writes_output_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out.o");
+        let mock = Mock::new().on("c++", Response::exit(1).output_file("partial"));
+        let status = run(
+            &mock,
+            Command::new("c++")
+                .arg("-c")
+                .arg("a.cpp")
+                .arg("-o")
+                .arg(&output),
+        )
+        .unwrap();
+        assert!(!status.success());
+        assert_eq!(std::fs::read(&output).unwrap(), b"partial");
+    }
+
+    #[test]
+    fn mock_interrupts() {
+        let mock = Mock::new().on("make", Response::interrupted());
+        let err = run(&mock, &mut Command::new("make")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(mock.calls().len(), 1);
+
+        // Commands aren't started once the token is interrupted
+        let token = InterruptToken::new();
+        token.interrupt();
+        let err = mock
+            .status(&mut Command::new("c++"), &token, true, None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(mock.calls_to("c++").is_empty());
+    }
+
+    #[test]
+    fn capture_records_the_command_line() {
+        let mock = Mock::new().on("c++", Response::exit(1).stderr("error: oops\n"));
+        let outcome = capture(
+            &mock,
+            Command::new("c++").args(["-o", "out dir/a"]),
+            &InterruptToken::new(),
+            true,
+            None,
+        )
+        .unwrap();
+        assert_eq!(outcome.command, "c++ -o 'out dir/a'");
+        assert!(!outcome.success());
+        assert_eq!(outcome.stderr, b"error: oops\n");
+        assert!(outcome.stdout.is_empty());
+    }
+
+    #[test]
+    fn line_text_strips_the_line_ending() {
+        assert_eq!(line_text(b"a b\n"), "a b");
+        assert_eq!(line_text(b"crlf\r\n"), "crlf");
+        assert_eq!(line_text(b"last"), "last");
+        assert_eq!(line_text(b"\n"), "");
+        assert_eq!(line_text(b"bad \xff\n"), "bad \u{fffd}");
+    }
+
+    #[cfg(unix)]
+    fn sh(script: &str) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(script);
+        cmd
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_keeps_output_bytes() {
+        let outcome = capture(
+            &System,
+            &mut sh(r"printf 'crlf\r\nbinary \0\377\n'; printf 'no newline' >&2"),
+            &InterruptToken::new(),
+            false,
+            None,
+        )
+        .unwrap();
+        assert!(outcome.success());
+        assert_eq!(outcome.stdout, b"crlf\r\nbinary \0\xff\n");
+        assert_eq!(outcome.stderr, b"no newline");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn capture_writes_input() {
+        // Larger than a pipe buffer, so writing it on the calling thread would deadlock with
+        // the child writing its output
+        let input: Vec<u8> = (0..1 << 20).map(|i| (i % 251) as u8).collect();
+        let mut cmd = sh("cat");
+        cmd.stdin(std::process::Stdio::piped());
+        let outcome = capture(
+            &System,
+            &mut cmd,
+            &InterruptToken::new(),
+            false,
+            Some(&input),
+        )
+        .unwrap();
+        assert!(outcome.success());
+        assert!(outcome.stdout == input);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn lines_keep_partial_last_lines_and_stream_order() {
+        let mut lines = vec![];
+        let status = System
+            .status_with_lines(
+                &mut sh("printf 'a\\n'; printf 'e1\\n' >&2; printf 'b'; printf 'e2\\ne3' >&2"),
+                &InterruptToken::new(),
+                false,
+                None,
+                &[Stream::Stdout, Stream::Stderr],
+                &mut |stream, line| lines.push((stream, line.to_vec())),
+            )
+            .unwrap();
+        assert!(status.success());
+
+        let stream = |s: Stream| -> Vec<Vec<u8>> {
+            lines
+                .iter()
+                .filter(|(stream, _)| *stream == s)
+                .map(|(_, line)| line.clone())
+                .collect()
+        };
+        assert_eq!(stream(Stream::Stdout), [&b"a\n"[..], b"b"]);
+        assert_eq!(stream(Stream::Stderr), [&b"e1\n"[..], b"e2\n", b"e3"]);
+        assert_eq!(
+            line_event(Stream::Stdout, b"b"),
+            Event::ChildStdoutLine("b".to_string())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupt_stops_and_reaps_the_child() {
+        let dir = tempfile::tempdir().unwrap();
+        let pid_file = dir.path().join("pid");
+        let token = InterruptToken::new();
+        let interrupter = {
+            let token = token.clone();
+            let pid_file = pid_file.clone();
+            std::thread::spawn(move || {
+                while !pid_file.exists() {
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                token.interrupt();
+            })
+        };
+
+        let start = Instant::now();
+        let script = format!(
+            "echo $$ > '{}.tmp'; mv '{0}.tmp' '{0}'; exec sleep 30",
+            pid_file.display()
+        );
+        let err = System
+            .status(&mut sh(&script), &token, true, None)
+            .unwrap_err();
+        interrupter.join().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(start.elapsed() < process::INTERRUPT_GRACE_PERIOD);
+
+        // A zombie could still be signalled
+        let pid: libc::pid_t = std::fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(unsafe { libc::kill(pid, 0) }, -1);
+        assert_eq!(io::Error::last_os_error().raw_os_error(), Some(libc::ESRCH));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn usage_reports_peak_memory() {
+        // The shell holds 64 MiB read from `/dev/zero` in a variable
+        let mut cmd = sh("x=$(head -c 67108864 /dev/zero | tr '\\0' a); test ${#x} = 67108864");
+        let (status, usage) = System
+            .status_with_usage(&mut cmd, &InterruptToken::new(), false, None)
+            .unwrap();
+        assert!(status.success());
+        let usage = usage.unwrap();
+        assert!(usage.max_rss >= 64 << 20, "{}", usage);
+        assert!(check_killed(&cmd, status, Some(&usage)).is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn killed_commands_hint_at_memory() {
+        let mut cmd = sh("kill -KILL $$");
+        let (status, usage) = System
+            .status_with_usage(&mut cmd, &InterruptToken::new(), false, None)
+            .unwrap();
+        assert!(!status.success());
+        let err = check_killed(&cmd, status, usage.as_ref()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+        let msg = err.to_string();
+        assert!(
+            msg.starts_with("sh was killed by SIGKILL, peak memory "),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("ran out of memory"));
+
+        // Other signals aren't reported as running out of memory
+        let mut cmd = sh("kill -TERM $$");
+        let status = System
+            .status(&mut cmd, &InterruptToken::new(), false, None)
+            .unwrap();
+        assert!(check_killed(&cmd, status, None).is_ok());
+    }
+
+    #[test]
+    fn shell_quoting() {
+        assert_eq!(shell_quote("plain-arg=1.0".as_ref()), "plain-arg=1.0");
+        assert_eq!(shell_quote("".as_ref()), "''");
+        assert_eq!(shell_quote("a b".as_ref()), "'a b'");
+        assert_eq!(shell_quote("it's".as_ref()), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME".as_ref()), "'$HOME'");
+    }
+}
@@ -0,0 +1,101 @@
+//! Report the effective configuration of a build and where each setting came from
+
+use std::fmt;
+
+/// Where the value of a setting came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Provenance {
+    /// Built-in default
+    Default,
+
+    /// Read from an environment variable
+    Env(String),
+
+    /// Set using a builder method or field
+    Builder,
+
+    /// Set by a command line flag
+    CommandLine(String),
+
+    /// Applied from a toolchain file
+    Toolchain,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Provenance::Default => write!(f, "default"),
+            Provenance::Env(name) => write!(f, "env {}", name),
+            Provenance::Builder => write!(f, "builder"),
+            Provenance::CommandLine(flag) => write!(f, "flag --{}", flag),
+            Provenance::Toolchain => write!(f, "toolchain file"),
+        }
+    }
+}
+
+/// An effective setting
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub name: &'static str,
+    pub value: String,
+    pub provenance: Provenance,
+}
+
+/// The effective settings of a build, returned by `Build::explain`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Explanation {
+    pub settings: Vec<Setting>,
+}
+
+impl Explanation {
+    /// Find a setting by name
+    pub fn get(&self, name: &str) -> Option<&Setting> {
+        self.settings.iter().find(|s| s.name == name)
+    }
+
+    pub(crate) fn push(
+        &mut self,
+        name: &'static str,
+        value: impl Into<String>,
+        provenance: Provenance,
+    ) {
+        self.settings.push(Setting {
+            name,
+            value: value.into(),
+            provenance,
+        });
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name_width = self
+            .settings
+            .iter()
+            .map(|s| s.name.len())
+            .chain(["setting".len()])
+            .max()
+            .unwrap_or_default();
+        let value_width = self
+            .settings
+            .iter()
+            .map(|s| s.value.len())
+            .chain(["value".len()])
+            .max()
+            .unwrap_or_default();
+
+        writeln!(
+            f,
+            "{:name_width$}  {:value_width$}  source",
+            "setting", "value"
+        )?;
+        for s in &self.settings {
+            writeln!(
+                f,
+                "{:name_width$}  {:value_width$}  {}",
+                s.name, s.value, s.provenance
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,824 @@
+//! Build and run Halide generators to emit ahead-of-time compiled pipelines
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::harness::{self, BenchmarkOptions};
+use crate::metadata::{self, PipelineMetadata};
+use crate::mobile::MobileToolchain;
+use crate::{
+    diagnostics, hash, manifest, naming, validate, Build, Language, OutputKind, Platform,
+    TargetFeature,
+};
+
+/// Files that can be emitted by a generator, passed using `-e`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Emit {
+    Assembly,
+    Bitcode,
+    CHeader,
+    CSource,
+    CompilerLog,
+    CppStub,
+    Featurization,
+    FunctionInfoHeader,
+    LlvmAssembly,
+    Object,
+    PythonExtension,
+    PytorchWrapper,
+    Registration,
+    Schedule,
+    StaticLibrary,
+    Stmt,
+    StmtHtml,
+}
+
+impl Emit {
+    /// Files emitted by Halide when no `-e` flag is given
+    pub const DEFAULT: &'static [Emit] = &[Emit::StaticLibrary, Emit::CHeader, Emit::Registration];
+
+    pub const ALL: &'static [Emit] = &[
+        Emit::Assembly,
+        Emit::Bitcode,
+        Emit::CHeader,
+        Emit::CSource,
+        Emit::CompilerLog,
+        Emit::CppStub,
+        Emit::Featurization,
+        Emit::FunctionInfoHeader,
+        Emit::LlvmAssembly,
+        Emit::Object,
+        Emit::PythonExtension,
+        Emit::PytorchWrapper,
+        Emit::Registration,
+        Emit::Schedule,
+        Emit::StaticLibrary,
+        Emit::Stmt,
+        Emit::StmtHtml,
+    ];
+
+    /// Find the file kind named `name` by the generator's `-e` flag
+    pub fn from_name(name: &str) -> Option<Emit> {
+        Emit::ALL.iter().copied().find(|emit| emit.name() == name)
+    }
+
+    /// The name used by the generator's `-e` flag
+    pub fn name(&self) -> &'static str {
+        match self {
+            Emit::Assembly => "assembly",
+            Emit::Bitcode => "bitcode",
+            Emit::CHeader => "c_header",
+            Emit::CSource => "c_source",
+            Emit::CompilerLog => "compiler_log",
+            Emit::CppStub => "cpp_stub",
+            Emit::Featurization => "featurization",
+            Emit::FunctionInfoHeader => "function_info_header",
+            Emit::LlvmAssembly => "llvm_assembly",
+            Emit::Object => "object",
+            Emit::PythonExtension => "python_extension",
+            Emit::PytorchWrapper => "pytorch_wrapper",
+            Emit::Registration => "registration",
+            Emit::Schedule => "schedule",
+            Emit::StaticLibrary => "static_library",
+            Emit::Stmt => "stmt",
+            Emit::StmtHtml => "stmt_html",
+        }
+    }
+
+    /// The suffix Halide appends to the function name for this file
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Emit::Assembly => ".s",
+            Emit::Bitcode => ".bc",
+            Emit::CHeader => ".h",
+            Emit::CSource => ".halide_generated.cpp",
+            Emit::CompilerLog => ".halide_compiler_log",
+            Emit::CppStub => ".stub.h",
+            Emit::Featurization => ".featurization",
+            Emit::FunctionInfoHeader => ".function_info.h",
+            Emit::LlvmAssembly => ".ll",
+            Emit::Object if cfg!(windows) => ".obj",
+            Emit::Object => ".o",
+            Emit::PythonExtension => ".py.cpp",
+            Emit::PytorchWrapper => ".pytorch.h",
+            Emit::Registration => ".registration.cpp",
+            Emit::Schedule => ".schedule.h",
+            Emit::StaticLibrary if cfg!(windows) => ".lib",
+            Emit::StaticLibrary => ".a",
+            Emit::Stmt => ".stmt",
+            Emit::StmtHtml => ".stmt.html",
+        }
+    }
+}
+
+/// Halide headers needed by code that includes an emitted header
+pub(crate) const RUNTIME_HEADERS: &[&str] = &["HalideRuntime.h", "HalideBuffer.h"];
+
+/// Rewrite `#include <HalideRuntime.h>` style includes of the runtime headers to quoted
+/// includes, so they're found next to the including file
+pub fn rewrite_runtime_includes(header: &str) -> String {
+    let mut s = String::with_capacity(header.len());
+    for line in header.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let rewritten = trimmed
+            .strip_prefix("#include")
+            .map(str::trim_start)
+            .and_then(|rest| rest.strip_prefix('<'))
+            .and_then(|rest| rest.split_once('>'))
+            .filter(|(name, _)| RUNTIME_HEADERS.contains(name));
+        match rewritten {
+            Some((name, rest)) => {
+                s.push_str(&line[..line.len() - trimmed.len()]);
+                s.push_str(&format!("#include \"{}\"{}", name, rest));
+            }
+            None => s.push_str(line),
+        }
+    }
+    s
+}
+
+/// Parse the registered generator names from the error a generator prints when it's run
+/// without `-g`, like:
+///
+/// ```text
+/// Either -g <name> or -r must be specified; available Generators are:
+///     brighten
+///     blur
+/// ```
+///
+/// Returns `None` when the output doesn't contain the list, and an empty list for `<none>`
+pub fn parse_generator_list(output: &str) -> Option<Vec<String>> {
+    let mut lines = output.lines();
+    lines.find(|line| line.to_lowercase().contains("available generators"))?;
+
+    Some(
+        lines
+            .take_while(|line| line.starts_with([' ', '\t']) && !line.trim().is_empty())
+            .map(str::trim)
+            .filter(|name| *name != "<none>")
+            .map(str::to_string)
+            .collect(),
+    )
+}
+
+/// File names of a generator's source, `{}` is replaced with the generator name
+///
+/// Each name is tried with `.cpp` and `.cc`, see `find_generator_source`
+pub const GENERATOR_SOURCE_PATTERNS: &[&str] =
+    &["{}_generator", "{}.generator", "{}_gen", "{}Generator"];
+
+/// Find the source of the generator `name` in `dir` using `GENERATOR_SOURCE_PATTERNS`
+///
+/// `<name>.cpp` is only used when no file matches a pattern, since it's usually the program
+/// calling the pipeline. Fails with `io::ErrorKind::NotFound` when no file matches and with
+/// `io::ErrorKind::InvalidInput` when several do
+pub fn find_generator_source(dir: impl AsRef<Path>, name: &str) -> io::Result<PathBuf> {
+    let dir = dir.as_ref();
+    let candidates = |patterns: &[&str]| -> Vec<PathBuf> {
+        patterns
+            .iter()
+            .flat_map(|pattern| {
+                let stem = pattern.replace("{}", name);
+                ["cpp", "cc"].map(|ext| dir.join(format!("{}.{}", stem, ext)))
+            })
+            .filter(|path| path.is_file())
+            .collect()
+    };
+
+    let mut found = candidates(GENERATOR_SOURCE_PATTERNS);
+    if found.is_empty() {
+        found = candidates(&["{}"]);
+    }
+    match &found[..] {
+        [path] => Ok(path.clone()),
+        [] => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "No source for generator {} in {}, expected a file like {}_generator.cpp",
+                name,
+                dir.display(),
+                name
+            ),
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "Several sources for generator {}: {}",
+                name,
+                found
+                    .iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        )),
+    }
+}
+
+/// The path of the file a generator emits for `function` in `output_dir`
+pub fn emitted_file(output_dir: impl AsRef<Path>, function: &str, emit: Emit) -> PathBuf {
+    // Emitted files are named without the namespace
+    let base = function.rsplit("::").next().unwrap_or_default();
+    output_dir
+        .as_ref()
+        .join(format!("{}{}", base, emit.extension()))
+}
+
+/// GenBuilder builds a generator executable and runs it to emit a pipeline
+#[derive(Debug)]
+pub struct GenBuilder<'a> {
+    /// Builds the generator executable, `build.output` is the generator path
+    pub build: Build<'a>,
+
+    /// Name of the registered generator, passed using `-g`
+    pub generator_name: String,
+
+    /// Directory for emitted files, passed using `-o`
+    pub output_dir: PathBuf,
+
+    /// Name of the emitted function, passed using `-f`, defaults to the generator name
+    pub function_name: Option<String>,
+
+    /// Files to emit, Halide's defaults are used when empty
+    pub emit: Vec<Emit>,
+
+    /// Generator parameters, passed as `name=value`
+    pub params: Vec<(String, String)>,
+
+    /// Manifest file to record emitted pipelines in
+    pub manifest: Option<PathBuf>,
+
+    /// Copy `HalideRuntime.h` and `HalideBuffer.h` along with the emitted headers in
+    /// `stage_headers`
+    pub include_runtime_headers: bool,
+
+    /// `build.output` is a generator built elsewhere, it's only compiled when one of the
+    /// sources is newer
+    pub existing_binary: bool,
+}
+
+impl<'a> GenBuilder<'a> {
+    /// Create a new generator builder, `build` is used to compile the generator executable
+    pub fn new(
+        build: Build<'a>,
+        generator_name: impl Into<String>,
+        output_dir: impl AsRef<Path>,
+    ) -> GenBuilder<'a> {
+        GenBuilder {
+            build: build.generator(true).keep(true),
+            generator_name: generator_name.into(),
+            output_dir: output_dir.as_ref().to_path_buf(),
+            function_name: None,
+            emit: vec![],
+            params: vec![],
+            manifest: None,
+            include_runtime_headers: false,
+            existing_binary: false,
+        }
+    }
+
+    pub fn function_name(mut self, name: impl Into<String>) -> Self {
+        self.function_name = Some(name.into());
+        self
+    }
+
+    pub fn emit(mut self, emit: Emit) -> Self {
+        self.emit.push(emit);
+        self
+    }
+
+    pub fn param(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.params.push((name.into(), value.into()));
+        self
+    }
+
+    /// Record emitted pipelines in a manifest, usually `pipelines.json` in the output directory
+    pub fn manifest(mut self, path: impl AsRef<Path>) -> Self {
+        self.manifest = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn include_runtime_headers(mut self, x: bool) -> Self {
+        self.include_runtime_headers = x;
+        self
+    }
+
+    /// Run an existing generator executable, skipping the compile step unless one of the
+    /// build's sources is newer than `path`
+    pub fn existing_binary(mut self, path: impl AsRef<Path>) -> Self {
+        self.build.output = path.as_ref().to_path_buf();
+        self.existing_binary = true;
+        self
+    }
+
+    /// Emit the pipeline for an Android or iOS device, the generator is still built for the
+    /// host
+    pub fn mobile(mut self, toolchain: &MobileToolchain) -> Self {
+        self.build.target = Some(toolchain.halide_target());
+        self
+    }
+
+    /// The name of the emitted function, possibly including a C++ namespace
+    pub fn function(&self) -> &str {
+        self.function_name
+            .as_deref()
+            .unwrap_or(&self.generator_name)
+    }
+
+    /// The target passed to the generator, defaults to `host`
+    pub fn target(&self) -> String {
+        self.build
+            .target_string()
+            .unwrap_or_else(|| "host".to_string())
+    }
+
+    /// Paths of the files the generator will emit
+    pub fn emitted_files(&self) -> Vec<PathBuf> {
+        let emit = if self.emit.is_empty() {
+            Emit::DEFAULT
+        } else {
+            &self.emit
+        };
+
+        emit.iter()
+            .map(|e| emitted_file(&self.output_dir, self.function(), *e))
+            .collect()
+    }
+
+    /// Check the generator, function and parameter names, and that the output directory and
+    /// manifest are inside the build's sandbox when one is set
+    ///
+    /// Called before the generator is run, see `Build::validate` for the checks made when
+    /// building it
+    pub fn validate(&self) -> io::Result<()> {
+        validate::generator_name(&self.generator_name)?;
+        if let Some(name) = &self.function_name {
+            validate::function_name(name)?;
+        }
+        for (name, _) in &self.params {
+            validate::param_name(name)?;
+        }
+
+        self.build.validate()?;
+        if let Some(root) = &self.build.sandbox {
+            for path in [&self.output_dir].into_iter().chain(&self.manifest) {
+                validate::within(root, path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the emitted headers into the include directory `dir`, returning the staged paths
+    ///
+    /// Files are only written when their contents change, so their mtimes stay stable. When
+    /// `include_runtime_headers` is set the Halide runtime headers are staged too, and
+    /// `#include <HalideRuntime.h>` style references are rewritten to find them next to the
+    /// including header. Staged headers are recorded in the manifest when one is set.
+    pub fn stage_headers(&self, dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+        let dir = dir.as_ref();
+        if let Some(root) = &self.build.sandbox {
+            validate::within(root, dir)?;
+        }
+        fs::create_dir_all(dir)?;
+
+        let mut headers: Vec<PathBuf> = self
+            .emitted_files()
+            .into_iter()
+            .filter(|path| path.extension() == Some("h".as_ref()) && path.exists())
+            .collect();
+        if self.include_runtime_headers {
+            let include = self.build.halide_layout().include;
+            headers.extend(RUNTIME_HEADERS.iter().map(|name| include.join(name)));
+        }
+
+        let mut staged = vec![];
+        for src in headers {
+            let mut contents = fs::read(&src)?;
+            if self.include_runtime_headers {
+                contents =
+                    rewrite_runtime_includes(&String::from_utf8_lossy(&contents)).into_bytes();
+            }
+
+            let dest = dir.join(src.file_name().unwrap_or_default());
+            let unchanged = dest.exists() && hash::file(&dest)? == hash::bytes(&contents);
+            if !unchanged {
+                fs::write(&dest, &contents)?;
+            }
+            staged.push(dest);
+        }
+
+        if let Some(path) = &self.manifest {
+            let mut m = manifest::load(path).or_else(|e| match e.kind() {
+                io::ErrorKind::NotFound => Ok(manifest::Manifest::default()),
+                _ => Err(e),
+            })?;
+            let mut pipeline = match m.get(self.function()) {
+                Some(pipeline) => pipeline.clone(),
+                None => manifest::Pipeline::from_generator(self)?,
+            };
+            pipeline.staged_headers = staged
+                .iter()
+                .map(|path| {
+                    Ok(manifest::File {
+                        hash: hash::hex(hash::file(path)?),
+                        path: path.clone(),
+                    })
+                })
+                .collect::<io::Result<_>>()?;
+            m.insert(pipeline);
+            manifest::write(path, &m)?;
+        }
+
+        Ok(staged)
+    }
+
+    /// Compile the generator executable, unless an existing binary is current
+    pub fn build_generator(&self) -> io::Result<bool> {
+        if self.existing_binary {
+            if self.binary_is_current() {
+                return Ok(true);
+            }
+            if self.build.src.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Generator {} does not exist", self.build.output.display()),
+                ));
+            }
+        }
+        self.build.build()
+    }
+
+    /// Returns true when the existing binary is newer than the build's inputs
+    fn binary_is_current(&self) -> bool {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        let binary = match modified(&self.build.output) {
+            Some(time) => time,
+            None => return false,
+        };
+
+        self.build
+            .src
+            .iter()
+            .chain(&self.build.objects)
+            .chain(&self.build.archives)
+            .all(|input| modified(input).is_some_and(|time| time <= binary))
+    }
+
+    /// The command used to run the generator
+    ///
+    /// The generator is started under `build.run_under` when it's set
+    pub fn emit_command(&self) -> Command {
+        self.emit_command_in(&self.output_dir)
+    }
+
+    fn emit_command_in(&self, dir: &Path) -> Command {
+        let mut cmd = self.build.wrapped_command(&self.build.output);
+        cmd.arg("-g").arg(&self.generator_name).arg("-o").arg(dir);
+
+        if let Some(name) = &self.function_name {
+            cmd.arg("-f").arg(name);
+        }
+
+        if !self.emit.is_empty() {
+            let emit: Vec<&str> = self.emit.iter().map(Emit::name).collect();
+            cmd.arg("-e").arg(emit.join(","));
+        }
+
+        cmd.arg(format!("target={}", self.target()));
+        for (name, value) in &self.params {
+            cmd.arg(format!("{}={}", name, value));
+        }
+
+        self.build.run_env().apply(&mut cmd);
+        cmd
+    }
+
+    /// Run the generator, writing the emitted files to `output_dir`
+    ///
+    /// With `build.atomic_outputs` the files are emitted into a temporary directory inside
+    /// `output_dir` and only moved into place when the generator succeeds. The manifest is
+    /// updated once the files are in place
+    pub fn run_generator(&self) -> io::Result<bool> {
+        self.validate()?;
+        self.check_collision()?;
+        fs::create_dir_all(&self.output_dir)?;
+
+        let success = if self.build.atomic_outputs {
+            let staging =
+                self.output_dir
+                    .join(format!(".{}.tmp.{}", self.function(), std::process::id()));
+            fs::create_dir_all(&staging)?;
+            let result = self.emit_into(&staging).and_then(|success| {
+                if success {
+                    for entry in fs::read_dir(&staging)? {
+                        let entry = entry?;
+                        crate::replace_file(
+                            &entry.path(),
+                            &self.output_dir.join(entry.file_name()),
+                        )?;
+                    }
+                }
+                Ok(success)
+            });
+            let _ = fs::remove_dir_all(&staging);
+            result?
+        } else {
+            self.emit_into(&self.output_dir)?
+        };
+
+        if success {
+            self.record_manifest()?;
+        }
+        Ok(success)
+    }
+
+    fn emit_into(&self, dir: &Path) -> io::Result<bool> {
+        // Wrappers like debuggers need the terminal, so their output isn't captured and they
+        // stay in the terminal's process group
+        let success = if self.build.run_under.is_empty() {
+            diagnostics::status(
+                &*self.build.executor,
+                &mut self.emit_command_in(dir),
+                &self.build.interrupt,
+                true,
+                None,
+                self.build.tee.then_some(&self.build.events),
+            )?
+        } else {
+            self.build
+                .executor
+                .status(
+                    &mut self.emit_command_in(dir),
+                    &self.build.interrupt,
+                    false,
+                    None,
+                )?
+                .success()
+        };
+
+        Ok(success)
+    }
+
+    /// Record the emitted files in the manifest, when one is set
+    fn record_manifest(&self) -> io::Result<()> {
+        let path = match &self.manifest {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut m = manifest::load(path).or_else(|e| match e.kind() {
+            io::ErrorKind::NotFound => Ok(manifest::Manifest::default()),
+            _ => Err(e),
+        })?;
+        m.insert(manifest::Pipeline::from_generator(self)?);
+        manifest::write(path, &m)
+    }
+
+    /// Fail if the manifest records a different generator emitting a function with the same
+    /// name, since its files would be overwritten and the symbols would collide at link time
+    fn check_collision(&self) -> io::Result<()> {
+        let m = match &self.manifest {
+            Some(path) => match manifest::load(path) {
+                Ok(m) => m,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e),
+            },
+            None => return Ok(()),
+        };
+
+        let existing = match m.get(self.function()) {
+            Some(existing) => existing,
+            None => return Ok(()),
+        };
+        if existing.generator == self.generator_name
+            && (existing.sources.is_empty() || existing.sources == self.build.src)
+        {
+            return Ok(());
+        }
+
+        let sources = |sources: &[PathBuf]| {
+            sources
+                .iter()
+                .map(|src| src.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "Function {} in {} is already emitted by generator {} ({}), generator {} ({}) would overwrite it, use a different function name",
+                self.function(),
+                self.output_dir.display(),
+                existing.generator,
+                sources(&existing.sources),
+                self.generator_name,
+                sources(&self.build.src)
+            ),
+        ))
+    }
+
+    /// Path of the runtime object written by `emit_runtime`
+    pub fn runtime_path(&self, name: &str) -> PathBuf {
+        self.output_dir
+            .join(format!("{}{}", name, Emit::Object.extension()))
+    }
+
+    /// Emit the Halide runtime for the target as a standalone object file named `name`
+    ///
+    /// Pipelines emitted as C source don't contain the runtime, so it's linked separately
+    pub fn emit_runtime(&self, name: &str) -> io::Result<bool> {
+        validate::function_name(name)?;
+        self.build.validate()?;
+        fs::create_dir_all(&self.output_dir)?;
+
+        let mut cmd = self.build.wrapped_command(&self.build.output);
+        cmd.arg("-r")
+            .arg(name)
+            .arg("-o")
+            .arg(&self.output_dir)
+            .arg("-e")
+            .arg(Emit::Object.name())
+            .arg(format!("target={}", self.target()));
+        self.build.run_env().apply(&mut cmd);
+
+        // Like `run_generator`, wrappers stay in the terminal's process group
+        let group = self.build.run_under.is_empty();
+        let status = self
+            .build
+            .executor
+            .status(&mut cmd, &self.build.interrupt, group, None)?;
+        Ok(status.success())
+    }
+
+    /// The build for a static library containing the pipeline emitted with `Emit::CSource`
+    /// and the runtime emitted by `emit_runtime`, written to `lib<function>_c.a` in the output
+    /// directory
+    ///
+    /// The library doesn't depend on libHalide. The source is compiled as C, using `CC` or
+    /// `cc`, so the generator's compiler and flags aren't reused. Use `language(Language::Cxx)`
+    /// on the returned build for Halide versions whose C backend emits C++
+    pub fn c_source_library(&self, runtime: &str) -> Build<'a> {
+        let base = self.function().rsplit("::").next().unwrap_or_default();
+        let source = self
+            .output_dir
+            .join(format!("{}{}", base, Emit::CSource.extension()));
+
+        let mut build = Build::new(
+            &self.build.halide_path,
+            self.output_dir.join(naming::artifact_name(
+                OutputKind::StaticLibrary,
+                &format!("{}_c", base),
+                Platform::host(),
+            )),
+        )
+        .source_file(source)
+        .object(self.runtime_path(runtime))
+        .output_kind(OutputKind::StaticLibrary)
+        .language(Language::C)
+        .with_image_io(false)
+        .keep(true)
+        .with_interrupt_token(self.build.interrupt.clone());
+        build.executor = self.build.executor.clone();
+        build.sandbox = self.build.sandbox.clone();
+        build
+    }
+
+    /// Write a benchmark program for the emitted pipeline to the output directory, returning
+    /// the build for the benchmark executable
+    ///
+    /// The pipeline must already have been emitted as a static library with a C header
+    pub fn benchmark_harness(&self, opts: &BenchmarkOptions) -> io::Result<Build<'a>> {
+        let pipeline = manifest::Pipeline::from_generator(self)?;
+        let source = harness::benchmark_main(&pipeline, opts).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Benchmark buffers must have between 1 and 4 dimensions",
+            )
+        })?;
+
+        let base = self.function().rsplit("::").next().unwrap_or_default();
+        let source_path = self.output_dir.join(format!("{}_benchmark.cpp", base));
+        fs::write(&source_path, source)?;
+
+        let mut build = Build::new(
+            &self.build.halide_path,
+            self.output_dir.join(format!("{}_benchmark", base)),
+        )
+        .source_file(source_path)
+        .archive(
+            self.output_dir
+                .join(format!("{}{}", base, Emit::StaticLibrary.extension())),
+        )
+        .output_kind(OutputKind::Executable)
+        .keep(true)
+        .with_interrupt_token(self.build.interrupt.clone());
+        build.cxx = self.build.cxx.clone();
+        build.cxxflags = self.build.cxxflags.clone();
+        build.ldflags = self.build.ldflags.clone();
+        build.libs = self.build.libs.clone();
+        build.lib_dirs = self.build.lib_dirs.clone();
+        build.frameworks = self.build.frameworks.clone();
+        Ok(build)
+    }
+
+    /// Compile a benchmark program for the emitted pipeline, linked with its static library
+    pub fn build_benchmark_harness(&self, opts: &BenchmarkOptions) -> io::Result<bool> {
+        self.benchmark_harness(opts)?.build()
+    }
+
+    /// Read the metadata embedded in the emitted pipeline by compiling and running a probe
+    /// program that calls `<function>_metadata()`
+    ///
+    /// The pipeline must already have been emitted as a static library with a C header. When
+    /// the target has the `no_runtime` feature, a runtime is emitted for the probe using
+    /// `emit_runtime`
+    pub fn pipeline_metadata(&self) -> io::Result<PipelineMetadata> {
+        let header = emitted_file(&self.output_dir, self.function(), Emit::CHeader);
+        let archive = emitted_file(&self.output_dir, self.function(), Emit::StaticLibrary);
+        for (path, emit) in [(&header, Emit::CHeader), (&archive, Emit::StaticLibrary)] {
+            if !path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "{} not found, emit the pipeline with Emit::{:?}",
+                        path.display(),
+                        emit
+                    ),
+                ));
+            }
+        }
+
+        let base = self.function().rsplit("::").next().unwrap_or_default();
+        let source_path = self.output_dir.join(format!("{}_metadata_probe.cpp", base));
+        fs::write(
+            &source_path,
+            metadata::probe_source(self.function(), &header.to_string_lossy()),
+        )?;
+
+        let mut build = Build::new(
+            &self.build.halide_path,
+            self.output_dir.join(format!("{}_metadata_probe", base)),
+        )
+        .source_file(source_path)
+        .archive(archive)
+        .output_kind(OutputKind::Executable)
+        .capture(true)
+        .keep(true)
+        .with_interrupt_token(self.build.interrupt.clone());
+        build.cxx = self.build.cxx.clone();
+        build.cxxflags = self.build.cxxflags.clone();
+        build.ldflags = self.build.ldflags.clone();
+        build.libs = self.build.libs.clone();
+        build.lib_dirs = self.build.lib_dirs.clone();
+        build.frameworks = self.build.frameworks.clone();
+        build.executor = self.build.executor.clone();
+
+        let no_runtime = TargetFeature::NoRuntime.name();
+        if self
+            .target()
+            .split('-')
+            .any(|feature| feature == no_runtime)
+        {
+            let runtime = format!("{}_metadata_runtime", base);
+            if !self.emit_runtime(&runtime)? {
+                return Err(io::Error::other(format!(
+                    "Unable to emit a runtime for the metadata probe of {}",
+                    self.function()
+                )));
+            }
+            build = build.object(self.runtime_path(&runtime));
+        }
+
+        if !build.build()? {
+            return Err(io::Error::other(format!(
+                "Unable to compile the metadata probe for {}",
+                self.function()
+            )));
+        }
+
+        let report = build.run_report()?;
+        match report.outcome {
+            Some(outcome) if outcome.success() => {
+                metadata::parse(&String::from_utf8_lossy(&outcome.stdout))
+            }
+            Some(outcome) => Err(io::Error::other(format!(
+                "Metadata probe for {} failed: {}",
+                self.function(),
+                String::from_utf8_lossy(&outcome.stderr).trim()
+            ))),
+            None => Err(io::Error::other(format!(
+                "Metadata probe for {} was not run",
+                self.function()
+            ))),
+        }
+    }
+
+    /// Compile the generator, then run it to emit the pipeline
+    pub fn build_then_emit(&self) -> io::Result<bool> {
+        self.validate()?;
+        self.check_collision()?;
+        Ok(self.build_generator()? && self.run_generator()?)
+    }
+}
@@ -0,0 +1,437 @@
+//! Compare the output of a run with a golden file
+//!
+//! Both sides are normalized before comparing, see `Normalize`. A mismatch is returned as a
+//! `Mismatch` inside an `io::Error` with `io::ErrorKind::InvalidData`, holding a unified diff
+//! of the normalized output
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use regex_lite::Regex;
+
+/// Number of diff lines kept in a `Mismatch`
+pub const MAX_DIFF_LINES: usize = 40;
+
+/// Lines of context around each change in a diff
+const CONTEXT: usize = 3;
+
+/// Larger inputs are diffed without looking for common lines, after removing the common
+/// prefix and suffix
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// What the standard output of a run is expected to be
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expect {
+    /// Equal to the contents of a file, after normalizing
+    Golden(PathBuf),
+
+    /// Matches a regular expression, see the `regex-lite` crate for the syntax
+    Regex(String),
+}
+
+/// How output is normalized before comparing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Normalize {
+    /// Remove whitespace at the end of each line
+    pub trim_trailing_whitespace: bool,
+
+    /// Remove lines matching any of these regular expressions, like timestamps
+    pub ignore: Vec<String>,
+}
+
+impl Normalize {
+    /// The lines of `text` to compare, line endings are ignored
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` when an `ignore` pattern is invalid
+    pub fn lines(&self, text: &str) -> io::Result<Vec<String>> {
+        let ignore = self
+            .ignore
+            .iter()
+            .map(|pattern| compile(pattern))
+            .collect::<io::Result<Vec<Regex>>>()?;
+        Ok(text
+            .lines()
+            .map(|line| {
+                let line = line.trim_end_matches('\r');
+                if self.trim_trailing_whitespace {
+                    line.trim_end()
+                } else {
+                    line
+                }
+            })
+            .filter(|line| !ignore.iter().any(|re| re.is_match(line)))
+            .map(str::to_string)
+            .collect())
+    }
+}
+
+fn compile(pattern: &str) -> io::Result<Regex> {
+    Regex::new(pattern).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid pattern {:?}: {}", pattern, e),
+        )
+    })
+}
+
+/// The output didn't match the expectation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    /// What was expected, the golden file path or the pattern
+    pub expected: String,
+
+    /// Unified diff from the golden file to the output, or the start of the output when
+    /// matching a pattern, truncated to `MAX_DIFF_LINES` lines
+    pub diff: String,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Output doesn't match {}\n{}", self.expected, self.diff)
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Compare `stdout` with `expect`
+///
+/// When `update` is set a golden file is rewritten with `stdout` instead, patterns are still
+/// checked
+pub fn check(
+    expect: &Expect,
+    normalize: &Normalize,
+    stdout: &[u8],
+    update: bool,
+) -> io::Result<()> {
+    let stdout = String::from_utf8_lossy(stdout);
+    let mismatch = match expect {
+        Expect::Golden(path) if update => return fs::write(path, stdout.as_bytes()),
+        Expect::Golden(path) => compare_golden(path, normalize, &stdout)?,
+        Expect::Regex(pattern) => {
+            let lines = normalize.lines(&stdout)?;
+            if compile(pattern)?.is_match(&lines.join("\n")) {
+                None
+            } else {
+                Some(Mismatch {
+                    expected: format!("pattern {:?}", pattern),
+                    diff: truncate(lines, MAX_DIFF_LINES),
+                })
+            }
+        }
+    };
+
+    match mismatch {
+        Some(mismatch) => Err(io::Error::new(io::ErrorKind::InvalidData, mismatch)),
+        None => Ok(()),
+    }
+}
+
+fn compare_golden(
+    path: &Path,
+    normalize: &Normalize,
+    stdout: &str,
+) -> io::Result<Option<Mismatch>> {
+    let golden = fs::read_to_string(path).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Unable to read golden file {}: {}", path.display(), e),
+        )
+    })?;
+    let expected = normalize.lines(&golden)?;
+    let actual = normalize.lines(stdout)?;
+    if expected == actual {
+        return Ok(None);
+    }
+
+    let diff = unified_diff(&expected, &actual, &path.display().to_string(), "stdout");
+    Ok(Some(Mismatch {
+        expected: path.display().to_string(),
+        diff: truncate(diff.lines().map(str::to_string).collect(), MAX_DIFF_LINES),
+    }))
+}
+
+fn truncate(lines: Vec<String>, max: usize) -> String {
+    let mut s = lines
+        .iter()
+        .take(max)
+        .cloned()
+        .collect::<Vec<_>>()
+        .join("\n");
+    if lines.len() > max {
+        s.push_str(&format!("\n({} more lines)", lines.len() - max));
+    }
+    s
+}
+
+/// A line of a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// Pair the lines of `a` and `b` using their longest common subsequence
+fn diff_ops<'a>(a: &'a [String], b: &'a [String]) -> Vec<(Op, &'a str)> {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let suffix = a[prefix..]
+        .iter()
+        .rev()
+        .zip(b[prefix..].iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (ma, mb) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    let mut ops: Vec<(Op, &str)> = a[..prefix].iter().map(|l| (Op::Same, l.as_str())).collect();
+    if ma.len() * mb.len() > MAX_DIFF_CELLS {
+        ops.extend(ma.iter().map(|l| (Op::Removed, l.as_str())));
+        ops.extend(mb.iter().map(|l| (Op::Added, l.as_str())));
+    } else {
+        // lcs[i][j] is the length of the common subsequence of ma[i..] and mb[j..]
+        let mut lcs = vec![vec![0usize; mb.len() + 1]; ma.len() + 1];
+        for i in (0..ma.len()).rev() {
+            for j in (0..mb.len()).rev() {
+                lcs[i][j] = if ma[i] == mb[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    lcs[i + 1][j].max(lcs[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < ma.len() || j < mb.len() {
+            if i < ma.len() && j < mb.len() && ma[i] == mb[j] {
+                ops.push((Op::Same, &ma[i]));
+                i += 1;
+                j += 1;
+            } else if j == mb.len() || (i < ma.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+                ops.push((Op::Removed, &ma[i]));
+                i += 1;
+            } else {
+                ops.push((Op::Added, &mb[j]));
+                j += 1;
+            }
+        }
+    }
+    ops.extend(a[a.len() - suffix..].iter().map(|l| (Op::Same, l.as_str())));
+    ops
+}
+
+/// A unified diff from `a` to `b`, with `CONTEXT` lines around each change, empty when they're
+/// equal
+pub fn unified_diff(a: &[String], b: &[String], a_name: &str, b_name: &str) -> String {
+    let ops = diff_ops(a, b);
+    let changed: Vec<usize> = (0..ops.len()).filter(|&i| ops[i].0 != Op::Same).collect();
+    if changed.is_empty() {
+        return String::new();
+    }
+
+    // Group changes whose context overlaps into hunks of op indices
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for &i in &changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(ops.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut s = format!("--- {}\n+++ {}\n", a_name, b_name);
+    for (start, end) in hunks {
+        // Line numbers where the hunk starts in `a` and `b`
+        let a_start = ops[..start]
+            .iter()
+            .filter(|(op, _)| *op != Op::Added)
+            .count();
+        let b_start = ops[..start]
+            .iter()
+            .filter(|(op, _)| *op != Op::Removed)
+            .count();
+        let hunk = &ops[start..end];
+        let a_len = hunk.iter().filter(|(op, _)| *op != Op::Added).count();
+        let b_len = hunk.iter().filter(|(op, _)| *op != Op::Removed).count();
+
+        // Empty ranges are numbered from the line before them
+        let a_first = if a_len == 0 { a_start } else { a_start + 1 };
+        let b_first = if b_len == 0 { b_start } else { b_start + 1 };
+        s.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            a_first, a_len, b_first, b_len
+        ));
+        for (op, line) in hunk {
+            let prefix = match op {
+                Op::Same => ' ',
+                Op::Removed => '-',
+                Op::Added => '+',
+            };
+            s.push(prefix);
+            s.push_str(line);
+            s.push('\n');
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn golden(dir: &Path, contents: &str) -> Expect {
+        let path = dir.join("brighten.golden");
+        fs::write(&path, contents).unwrap();
+        Expect::Golden(path)
+    }
+
+    fn mismatch(res: io::Result<()>) -> Mismatch {
+        let e = res.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        e.into_inner()
+            .unwrap()
+            .downcast::<Mismatch>()
+            .map(|m| *m)
+            .unwrap()
+    }
+
+    #[test]
+    fn golden_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = golden(dir.path(), "min 0\nmax 255\n");
+        let normalize = Normalize::default();
+        assert!(check(&expect, &normalize, b"min 0\nmax 255\n", false).is_ok());
+
+        // Line endings and a missing final newline are ignored
+        assert!(check(&expect, &normalize, b"min 0\r\nmax 255", false).is_ok());
+    }
+
+    #[test]
+    fn golden_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = golden(dir.path(), "a\nb\nc\nd\ne\nf\ng\nh\n");
+        let m = mismatch(check(
+            &expect,
+            &Normalize::default(),
+            b"a\nb\nc\nd\nE\nf\ng\nh\n",
+            false,
+        ));
+        let path = dir.path().join("brighten.golden").display().to_string();
+        assert_eq!(m.expected, path);
+        assert_eq!(
+            m.diff,
+            format!(
+                "--- {}\n+++ stdout\n@@ -2,7 +2,7 @@\n b\n c\n d\n-e\n+E\n f\n g\n h",
+                path
+            )
+        );
+    }
+
+    #[test]
+    fn golden_mismatch_is_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = golden(dir.path(), "");
+        let stdout: String = (0..100).map(|i| format!("{}\n", i)).collect();
+        let m = mismatch(check(
+            &expect,
+            &Normalize::default(),
+            stdout.as_bytes(),
+            false,
+        ));
+        let lines: Vec<&str> = m.diff.lines().collect();
+        assert_eq!(lines.len(), MAX_DIFF_LINES + 1);
+        assert_eq!(lines[2], "@@ -0,0 +1,100 @@");
+        assert_eq!(
+            lines[MAX_DIFF_LINES],
+            format!("({} more lines)", 100 + 3 - MAX_DIFF_LINES)
+        );
+    }
+
+    #[test]
+    fn missing_golden_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = Expect::Golden(dir.path().join("missing.golden"));
+        let e = check(&expect, &Normalize::default(), b"", false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().contains("missing.golden"));
+    }
+
+    #[test]
+    fn update_rewrites_the_golden_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let expect = golden(dir.path(), "old\n");
+        let stdout = b"new\r\n\xff\n";
+        assert!(check(&expect, &Normalize::default(), stdout, true).is_ok());
+        let path = dir.path().join("brighten.golden");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\r\n\u{fffd}\n");
+
+        // The updated file matches the same output
+        assert!(check(&expect, &Normalize::default(), stdout, false).is_ok());
+
+        // Update mode creates missing files
+        let expect = Expect::Golden(dir.path().join("created.golden"));
+        assert!(check(&expect, &Normalize::default(), b"x\n", true).is_ok());
+        assert!(check(&expect, &Normalize::default(), b"x\n", false).is_ok());
+    }
+
+    #[test]
+    fn patterns() {
+        let expect = Expect::Regex(r"(?m)^took \d+ms$".to_string());
+        let normalize = Normalize::default();
+        assert!(check(&expect, &normalize, b"start\ntook 12ms\n", false).is_ok());
+
+        // Patterns are checked in update mode too
+        let m = mismatch(check(&expect, &normalize, b"start\ntook ms\n", true));
+        assert_eq!(m.expected, format!("pattern {:?}", r"(?m)^took \d+ms$"));
+        assert_eq!(m.diff, "start\ntook ms");
+
+        let e = check(&Expect::Regex("(".to_string()), &normalize, b"", false).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn normalize() {
+        let normalize = Normalize {
+            trim_trailing_whitespace: true,
+            ignore: vec![r"^time: ".to_string()],
+        };
+        assert_eq!(
+            normalize.lines("a  \r\ntime: 12:00\nb\t\n").unwrap(),
+            ["a", "b"]
+        );
+        assert_eq!(Normalize::default().lines("a  \r\n").unwrap(), ["a  "]);
+
+        let dir = tempfile::tempdir().unwrap();
+        let expect = golden(dir.path(), "a\nb\n");
+        assert!(check(&expect, &normalize, b"a \ntime: 1\nb\n", false).is_ok());
+
+        let invalid = Normalize {
+            ignore: vec!["[".to_string()],
+            ..Normalize::default()
+        };
+        assert_eq!(
+            invalid.lines("").unwrap_err().kind(),
+            io::ErrorKind::InvalidInput
+        );
+    }
+
+    #[test]
+    fn diffs() {
+        let lines = |s: &str| s.lines().map(str::to_string).collect::<Vec<_>>();
+        assert_eq!(unified_diff(&lines("a\nb"), &lines("a\nb"), "a", "b"), "");
+        assert_eq!(
+            unified_diff(&lines("a\nb\nc"), &lines("b\nc\nd"), "x", "y"),
+            "--- x\n+++ y\n@@ -1,3 +1,3 @@\n-a\n b\n c\n+d\n"
+        );
+
+        // Changes far apart are separate hunks
+        let a: Vec<String> = (0..20).map(|i| i.to_string()).collect();
+        let mut b = a.clone();
+        b[1] = "x".to_string();
+        b[18] = "y".to_string();
+        let diff = unified_diff(&a, &b, "a", "b");
+        let hunks: Vec<&str> = diff.lines().filter(|l| l.starts_with("@@")).collect();
+        assert_eq!(hunks, ["@@ -1,5 +1,5 @@", "@@ -16,5 +16,5 @@"]);
+    }
+}
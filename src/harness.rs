@@ -0,0 +1,249 @@
+//! Generate C++ programs that benchmark emitted pipelines
+//!
+//! The generated program calls the pipeline using `Halide::Tools::benchmark` from
+//! `halide_benchmark.h` and prints the timing as a single JSON object:
+//!
+//! ```json
+//! {"function": "brighten", "samples": 10, "iterations": 10, "seconds_per_iteration": 0.00012}
+//! ```
+
+use std::fmt::Write;
+
+use crate::manifest::{ArgumentKind, Pipeline};
+
+/// Element types supported for benchmark buffers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ElementType {
+    #[default]
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    F32,
+    F64,
+}
+
+impl ElementType {
+    /// The C++ type name
+    pub fn c_type(&self) -> &'static str {
+        match self {
+            ElementType::U8 => "uint8_t",
+            ElementType::U16 => "uint16_t",
+            ElementType::U32 => "uint32_t",
+            ElementType::I8 => "int8_t",
+            ElementType::I16 => "int16_t",
+            ElementType::I32 => "int32_t",
+            ElementType::F32 => "float",
+            ElementType::F64 => "double",
+        }
+    }
+
+    /// Parse a Halide style type name like `uint8` or `float32`, or a Rust style name like
+    /// `u8` or `f32`
+    pub fn from_name(name: &str) -> Option<ElementType> {
+        match name {
+            "u8" | "uint8" => Some(ElementType::U8),
+            "u16" | "uint16" => Some(ElementType::U16),
+            "u32" | "uint32" => Some(ElementType::U32),
+            "i8" | "int8" => Some(ElementType::I8),
+            "i16" | "int16" => Some(ElementType::I16),
+            "i32" | "int32" => Some(ElementType::I32),
+            "f32" | "float32" | "float" => Some(ElementType::F32),
+            "f64" | "float64" | "double" => Some(ElementType::F64),
+            _ => None,
+        }
+    }
+
+    /// Size of an element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            ElementType::U8 | ElementType::I8 => 1,
+            ElementType::U16 | ElementType::I16 => 2,
+            ElementType::U32 | ElementType::I32 | ElementType::F32 => 4,
+            ElementType::F64 => 8,
+        }
+    }
+
+    fn is_float(&self) -> bool {
+        matches!(self, ElementType::F32 | ElementType::F64)
+    }
+}
+
+/// Options for a generated benchmark
+#[derive(Debug, Clone, PartialEq)]
+pub struct BenchmarkOptions {
+    /// Extents of every buffer argument, between 1 and 4 dimensions
+    pub extents: Vec<usize>,
+
+    /// Element type of every buffer argument
+    pub element_type: ElementType,
+
+    /// Values passed for scalar arguments by name, scalars default to 0
+    pub scalars: Vec<(String, String)>,
+
+    /// Number of samples taken by `Halide::Tools::benchmark`
+    pub samples: u64,
+
+    /// Number of iterations per sample
+    pub iterations: u64,
+
+    /// Seed for the deterministic input data
+    pub seed: u32,
+}
+
+impl Default for BenchmarkOptions {
+    fn default() -> Self {
+        BenchmarkOptions {
+            extents: vec![1024, 1024],
+            element_type: ElementType::U8,
+            scalars: vec![],
+            samples: 10,
+            iterations: 10,
+            seed: 0,
+        }
+    }
+}
+
+impl BenchmarkOptions {
+    pub fn extents(mut self, extents: &[usize]) -> Self {
+        self.extents = extents.to_vec();
+        self
+    }
+
+    pub fn element_type(mut self, ty: ElementType) -> Self {
+        self.element_type = ty;
+        self
+    }
+
+    pub fn scalar(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.scalars.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn samples(mut self, samples: u64) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn iterations(mut self, iterations: u64) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    pub fn seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Generate a C++ `main` that benchmarks `pipeline`
+///
+/// The program includes the emitted header, so it should be compiled from the directory
+/// containing it. Every buffer argument is allocated with the same extents and element type
+/// and filled with deterministic data.
+///
+/// Returns `None` if `extents` doesn't have between 1 and 4 dimensions
+pub fn benchmark_main(pipeline: &Pipeline, opts: &BenchmarkOptions) -> Option<String> {
+    if opts.extents.is_empty() || opts.extents.len() > 4 {
+        return None;
+    }
+
+    let base = pipeline.function.rsplit("::").next().unwrap_or_default();
+    let ty = opts.element_type.c_type();
+    let extents = opts
+        .extents
+        .iter()
+        .map(usize::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    // The fill uses a small LCG, so the data is identical on every platform
+    let fill = if opts.element_type.is_float() {
+        format!("({})(state >> 16) / 65536", ty)
+    } else {
+        format!("({})(state >> 16)", ty)
+    };
+
+    let mut s = String::new();
+    let _ = writeln!(s, "// Generated by halide-build, do not edit");
+    let _ = writeln!(s, "#include <cstdint>");
+    let _ = writeln!(s, "#include <cstdio>");
+    let _ = writeln!(s);
+    let _ = writeln!(s, "#include \"HalideBuffer.h\"");
+    let _ = writeln!(s, "#include \"halide_benchmark.h\"");
+    let _ = writeln!(s, "#include \"{}.h\"", base);
+    let _ = writeln!(s);
+    let _ = writeln!(s, "int main() {{");
+    let _ = writeln!(s, "    uint32_t state = {}u;", opts.seed);
+
+    // Arguments are prefixed so they can't collide with the harness's own variables
+    let mut args = vec![];
+    for arg in &pipeline.arguments {
+        let var = format!("arg_{}", arg.name);
+        match arg.kind {
+            ArgumentKind::Buffer => {
+                let _ = writeln!(
+                    s,
+                    "    Halide::Runtime::Buffer<{}> {}({});",
+                    ty, var, extents
+                );
+                let _ = writeln!(
+                    s,
+                    "    {}.for_each_value([&]({} &v) {{ state = state * 1103515245u + 12345u; v = {}; }});",
+                    var, ty, fill
+                );
+                args.push(format!("{}.raw_buffer()", var));
+            }
+            ArgumentKind::Scalar => {
+                let value = opts
+                    .scalars
+                    .iter()
+                    .find(|(name, _)| *name == arg.name)
+                    .map(|(_, value)| value.as_str())
+                    .unwrap_or("0");
+                let _ = writeln!(s, "    {} {} = {};", arg.ty, var, value);
+                args.push(var);
+            }
+        }
+    }
+
+    let _ = writeln!(s);
+    let _ = writeln!(s, "    int error = 0;");
+    let _ = writeln!(
+        s,
+        "    double seconds = Halide::Tools::benchmark({}, {}, [&]() {{",
+        opts.samples, opts.iterations
+    );
+    let _ = writeln!(
+        s,
+        "        error |= {}({});",
+        pipeline.function,
+        args.join(", ")
+    );
+    for arg in &pipeline.arguments {
+        if arg.kind == ArgumentKind::Buffer {
+            let _ = writeln!(s, "        arg_{}.device_sync();", arg.name);
+        }
+    }
+    let _ = writeln!(s, "    }});");
+    let _ = writeln!(s);
+    let _ = writeln!(s, "    if (error != 0) {{");
+    let _ = writeln!(
+        s,
+        "        fprintf(stderr, \"{} returned %d\\n\", error);",
+        pipeline.function
+    );
+    let _ = writeln!(s, "        return 1;");
+    let _ = writeln!(s, "    }}");
+    let _ = writeln!(s);
+    let _ = writeln!(
+        s,
+        "    printf(\"{{\\\"function\\\": \\\"{}\\\", \\\"samples\\\": {}, \\\"iterations\\\": {}, \\\"seconds_per_iteration\\\": %g}}\\n\", seconds);",
+        pipeline.function, opts.samples, opts.iterations
+    );
+    let _ = writeln!(s, "    return 0;");
+    let _ = writeln!(s, "}}");
+    Some(s)
+}
@@ -0,0 +1,122 @@
+//! Stable content hashing
+//!
+//! `std::hash::DefaultHasher` is not guaranteed to be stable across Rust releases, so hashes
+//! that are written to disk use 64-bit FNV-1a instead
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const PRIME: u64 = 0x100000001b3;
+
+/// 64-bit FNV-1a hasher
+#[derive(Debug, Clone, Copy)]
+pub struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(OFFSET_BASIS)
+    }
+}
+
+impl Fnv {
+    /// Add bytes to the hash
+    pub fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+
+    /// Add a length-prefixed string to the hash, so adjacent strings can't run together
+    pub fn write_str(&mut self, s: &str) {
+        self.write(&(s.len() as u64).to_le_bytes());
+        self.write(s.as_bytes());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Hash the contents of a file
+pub fn file(path: impl AsRef<Path>) -> io::Result<u64> {
+    let mut f = File::open(path)?;
+    let mut hasher = Fnv::default();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = f.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    Ok(hasher.finish())
+}
+
+/// Hash a byte slice, matching `file` for the same contents
+pub fn bytes(bytes: &[u8]) -> u64 {
+    let mut hasher = Fnv::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+/// Format a hash as a fixed-width hex string
+pub fn hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_hashes() {
+        // Reference values for 64-bit FNV-1a, hashes on disk depend on them never changing
+        assert_eq!(hex(bytes(b"")), "cbf29ce484222325");
+        assert_eq!(hex(bytes(b"a")), "af63dc4c8601ec8c");
+        assert_eq!(hex(bytes(b"foobar")), "85944171f73967e8");
+    }
+
+    #[test]
+    fn files_match_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("brighten.a");
+
+        // Larger than the read buffer
+        let contents: Vec<u8> = (0..20000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        assert_eq!(file(&path).unwrap(), bytes(&contents));
+
+        let mut hasher = Fnv::default();
+        hasher.write(&contents[..100]);
+        hasher.write(&contents[100..]);
+        assert_eq!(hasher.finish(), bytes(&contents));
+
+        assert_eq!(
+            file(dir.path().join("missing")).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn strings_are_length_prefixed() {
+        let hash = |strs: &[&str]| {
+            let mut hasher = Fnv::default();
+            for s in strs {
+                hasher.write_str(s);
+            }
+            hasher.finish()
+        };
+        assert_ne!(hash(&["-O", "3"]), hash(&["-O3"]));
+        assert_ne!(hash(&["", "a"]), hash(&["a", ""]));
+        assert_eq!(hash(&["-O3"]), hash(&["-O3"]));
+    }
+
+    #[test]
+    fn hex_is_fixed_width() {
+        assert_eq!(hex(0), "0000000000000000");
+        assert_eq!(hex(u64::MAX), "ffffffffffffffff");
+    }
+}
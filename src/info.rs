@@ -0,0 +1,200 @@
+//! Describe a Halide installation and the compiler that would be used with it
+//!
+//! `halide info --json` prints an `Info` for build scripts written in other languages, the
+//! object always has a `version` field, which is bumped when fields are renamed or removed:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "halide_path": "/opt/halide",
+//!   "layout": "install",
+//!   "include": "/opt/halide/include",
+//!   "lib": "/opt/halide/lib",
+//!   "tools": "/opt/halide/tools",
+//!   "libraries": [{ "name": "libHalide.so.16", "kind": "shared" }],
+//!   "halide_version": "16.0.0",
+//!   "compiler": { "name": "c++", "family": "gcc", "version": "12.2.0" },
+//!   "target": "x86-64-linux"
+//! }
+//! ```
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::exec::Executor;
+use crate::version::{self, CompilerFamily};
+use crate::{Language, Layout, LayoutKind};
+
+/// Current `Info` schema version
+pub const VERSION: u32 = 1;
+
+/// How a Halide library can be linked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LibraryKind {
+    Shared,
+    Static,
+}
+
+/// A Halide library found in the library directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Library {
+    /// File name, like `libHalide.so.16` or `Halide.lib`
+    pub name: String,
+    pub kind: LibraryKind,
+}
+
+/// The C++ compiler selected by `CXX`, or the default
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Compiler {
+    pub name: String,
+
+    /// `gcc`, `clang`, `msvc` or `unknown`
+    pub family: String,
+
+    /// `None` when the compiler couldn't be run or didn't report a version
+    pub version: Option<String>,
+}
+
+/// Resolved paths and versions of a Halide installation
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Info {
+    /// Schema version, see `VERSION`
+    pub version: u32,
+
+    pub halide_path: PathBuf,
+
+    /// What the Halide path looks like, see `LayoutKind::name`
+    pub layout: String,
+
+    pub include: PathBuf,
+    pub lib: PathBuf,
+    pub tools: PathBuf,
+    pub libraries: Vec<Library>,
+
+    /// Read from the Halide headers
+    pub halide_version: Option<String>,
+
+    pub compiler: Compiler,
+
+    /// Halide target string of the host, without features
+    pub target: String,
+}
+
+impl Info {
+    /// Describe the installation at `halide_path`, running the compiler using `executor`
+    ///
+    /// Missing pieces are reported as empty or `None` rather than errors
+    pub fn collect(executor: &dyn Executor, halide_path: impl AsRef<Path>) -> Info {
+        let halide_path = halide_path.as_ref();
+        let layout = Layout::install(halide_path);
+        let cxx = env::var(Language::Cxx.compiler_var())
+            .unwrap_or_else(|_| Language::Cxx.default_compiler().to_string());
+
+        Info {
+            version: VERSION,
+            halide_path: halide_path.to_path_buf(),
+            layout: LayoutKind::detect(halide_path).name().to_string(),
+            libraries: libraries(&layout.lib),
+            halide_version: version::halide_version(halide_path).map(|v| v.to_string()),
+            compiler: Compiler {
+                family: version::compiler_family(executor, &cxx)
+                    .unwrap_or(CompilerFamily::Unknown)
+                    .name()
+                    .to_string(),
+                version: version::compiler_version(executor, &cxx)
+                    .ok()
+                    .flatten()
+                    .map(|v| v.to_string()),
+                name: cxx,
+            },
+            target: host_target(),
+            include: layout.include,
+            lib: layout.lib,
+            tools: layout.tools,
+        }
+    }
+}
+
+/// One field per line
+impl fmt::Display for Info {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unknown = |value: &Option<String>| value.clone().unwrap_or("unknown".to_string());
+        writeln!(f, "halide path: {}", self.halide_path.display())?;
+        writeln!(f, "layout:      {}", self.layout)?;
+        writeln!(f, "include:     {}", self.include.display())?;
+        writeln!(f, "lib:         {}", self.lib.display())?;
+        writeln!(f, "tools:       {}", self.tools.display())?;
+        for library in &self.libraries {
+            let kind = match library.kind {
+                LibraryKind::Shared => "shared",
+                LibraryKind::Static => "static",
+            };
+            writeln!(f, "library:     {} ({})", library.name, kind)?;
+        }
+        writeln!(f, "version:     {}", unknown(&self.halide_version))?;
+        writeln!(
+            f,
+            "compiler:    {} ({} {})",
+            self.compiler.name,
+            self.compiler.family,
+            unknown(&self.compiler.version)
+        )?;
+        writeln!(f, "target:      {}", self.target)
+    }
+}
+
+/// The Halide libraries in `lib`, sorted by name
+fn libraries(lib: &Path) -> Vec<Library> {
+    let mut libraries: Vec<Library> = fs::read_dir(lib)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stem = name.strip_prefix("lib").unwrap_or(&name);
+            if !stem.starts_with("Halide.") {
+                return None;
+            }
+            let kind = if name.ends_with(".a") || name.ends_with(".lib") {
+                LibraryKind::Static
+            } else if name.contains(".so") || name.ends_with(".dylib") || name.ends_with(".dll") {
+                LibraryKind::Shared
+            } else {
+                return None;
+            };
+            Some(Library { name, kind })
+        })
+        .collect();
+    libraries.sort_by(|a, b| a.name.cmp(&b.name));
+    libraries
+}
+
+/// The Halide target of the machine this was compiled for, like `x86-64-linux`, or `host`
+/// when the architecture or OS has no Halide name
+pub fn host_target() -> String {
+    let arch = match env::consts::ARCH {
+        "x86_64" => "x86-64",
+        "x86" => "x86-32",
+        "aarch64" => "arm-64",
+        "arm" => "arm-32",
+        "riscv64" => "riscv-64",
+        "wasm32" => "wasm-32",
+        "powerpc64" => "powerpc-64",
+        _ => return "host".to_string(),
+    };
+    let os = match env::consts::OS {
+        "linux" => "linux",
+        "macos" => "osx",
+        "windows" => "windows",
+        "android" => "android",
+        "ios" => "ios",
+        "freebsd" => "freebsd",
+        _ => return "host".to_string(),
+    };
+    format!("{}-{}", arch, os)
+}
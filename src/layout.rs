@@ -0,0 +1,332 @@
+//! Locate the Halide headers, libraries and tools
+//!
+//! An installed Halide has `include`, `lib` and `tools` directories. An uninstalled CMake
+//! build tree keeps the generated headers in `include`, the library in `src` and uses the
+//! tools from the source tree, which is found using `CMakeCache.txt`
+//!
+//! `LayoutKind` guesses what kind of directory a Halide path points at, so errors about
+//! missing pieces can say where to find them
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Names of the Halide library in a CMake build tree
+const LIBRARY_NAMES: &[&str] = &[
+    "libHalide.so",
+    "libHalide.dylib",
+    "libHalide.a",
+    "Halide.lib",
+];
+
+/// Files checked by `LayoutKind::detect`, relative to the Halide path
+const PROBES: &[&str] = &[
+    "halide-vendor.json",
+    "include/Halide.h",
+    "tools/GenGen.cpp",
+    "share/Halide/tools/GenGen.cpp",
+    "CMakeCache.txt",
+    "CMakeLists.txt",
+    "Makefile",
+    "src/Func.h",
+];
+
+/// What a Halide path looks like, based on the files in it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LayoutKind {
+    /// `include`, `lib` and `tools` directories, as expected by `Layout::install`
+    Install,
+
+    /// A copy made by `vendor::create`, laid out like an install
+    Vendored,
+
+    /// A binary release, which keeps the tools in `share/Halide/tools`
+    Release,
+
+    /// A CMake build tree, see `Layout::build_dir`
+    CMakeBuild,
+
+    /// A source checkout built in place using the Makefile, which is laid out like an install
+    MakeBuild,
+
+    /// A source checkout that hasn't been built
+    SourceTree,
+
+    Unknown,
+}
+
+impl LayoutKind {
+    /// Classify a directory from the paths in it, relative to the directory and using `/`
+    /// as the separator, only the paths in `PROBES` are considered
+    pub fn classify<S: AsRef<str>>(paths: &[S]) -> LayoutKind {
+        let has = |path: &str| paths.iter().any(|p| p.as_ref() == path);
+
+        if has("halide-vendor.json") {
+            LayoutKind::Vendored
+        } else if has("CMakeCache.txt") {
+            LayoutKind::CMakeBuild
+        } else if has("include/Halide.h") && has("tools/GenGen.cpp") {
+            if has("Makefile") && has("src/Func.h") {
+                LayoutKind::MakeBuild
+            } else {
+                LayoutKind::Install
+            }
+        } else if has("share/Halide/tools/GenGen.cpp") {
+            LayoutKind::Release
+        } else if has("src/Func.h") || has("CMakeLists.txt") {
+            LayoutKind::SourceTree
+        } else {
+            LayoutKind::Unknown
+        }
+    }
+
+    /// Classify the directory at `halide_path`
+    pub fn detect(halide_path: impl AsRef<Path>) -> LayoutKind {
+        let halide_path = halide_path.as_ref();
+        let present: Vec<&str> = PROBES
+            .iter()
+            .copied()
+            .filter(|path| halide_path.join(path).exists())
+            .collect();
+        LayoutKind::classify(&present)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LayoutKind::Install => "install",
+            LayoutKind::Vendored => "vendored distribution",
+            LayoutKind::Release => "binary release",
+            LayoutKind::CMakeBuild => "CMake build tree",
+            LayoutKind::MakeBuild => "Makefile build",
+            LayoutKind::SourceTree => "unbuilt source checkout",
+            LayoutKind::Unknown => "unknown",
+        }
+    }
+
+    /// How to use a Halide path of this kind, `None` when it can be used as is
+    pub fn advice(&self, halide_path: impl AsRef<Path>) -> Option<String> {
+        let halide_path = halide_path.as_ref();
+        match self {
+            LayoutKind::Install | LayoutKind::Vendored | LayoutKind::MakeBuild => None,
+            LayoutKind::Release => Some(format!(
+                "{} looks like a binary release, GenGen.cpp is at {}, set gengen_path or pass --gengen",
+                halide_path.display(),
+                halide_path.join("share/Halide/tools/GenGen.cpp").display()
+            )),
+            LayoutKind::CMakeBuild => Some(format!(
+                "{} looks like a CMake build tree, use Build::with_halide_from_build_dir or install it",
+                halide_path.display()
+            )),
+            LayoutKind::SourceTree => Some(format!(
+                "{} looks like a Halide source checkout that hasn't been built, build it using make or `halide src`",
+                halide_path.display()
+            )),
+            LayoutKind::Unknown => Some(format!(
+                "{} doesn't look like a Halide install, it should contain include/Halide.h and tools/GenGen.cpp",
+                halide_path.display()
+            )),
+        }
+    }
+}
+
+/// Directories containing the Halide headers, library and tools
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    /// Contains `Halide.h` and the runtime headers
+    pub include: PathBuf,
+
+    /// Contains the Halide library
+    pub lib: PathBuf,
+
+    /// Contains `GenGen.cpp` and `halide_image_io.h`
+    pub tools: PathBuf,
+}
+
+impl Layout {
+    /// The layout of a Halide install or distribution
+    pub fn install(halide_path: impl AsRef<Path>) -> Layout {
+        let halide_path = halide_path.as_ref();
+        Layout {
+            include: halide_path.join("include"),
+            lib: halide_path.join("lib"),
+            tools: halide_path.join("tools"),
+        }
+    }
+
+    /// Find the headers, library and tools of an uninstalled CMake build in `build_dir`
+    ///
+    /// Fails with `io::ErrorKind::NotFound`, listing the locations that were checked, when
+    /// any of them is missing
+    pub fn build_dir(build_dir: impl AsRef<Path>) -> io::Result<Layout> {
+        let build_dir = build_dir.as_ref();
+        let source_dir = cmake_source_dir(build_dir)
+            .or_else(|| build_dir.parent().map(Path::to_path_buf))
+            .unwrap_or_else(|| build_dir.to_path_buf());
+
+        let include = probe(
+            "Halide.h",
+            &[
+                build_dir.join("include"),
+                build_dir.join("src").join("include"),
+            ],
+            |dir| dir.join("Halide.h").is_file(),
+        )?;
+
+        let mut lib_dirs = vec![build_dir.join("src"), build_dir.join("lib")];
+        for config in ["Release", "RelWithDebInfo", "Debug"] {
+            lib_dirs.push(build_dir.join("src").join(config));
+        }
+        let lib = probe("the Halide library", &lib_dirs, |dir| {
+            LIBRARY_NAMES.iter().any(|name| dir.join(name).is_file())
+        })?;
+
+        let tools = probe(
+            "GenGen.cpp",
+            &[build_dir.join("tools"), source_dir.join("tools")],
+            |dir| dir.join("GenGen.cpp").is_file(),
+        )?;
+
+        Ok(Layout {
+            include,
+            lib,
+            tools,
+        })
+    }
+}
+
+/// The first of `dirs` where `found` is true
+fn probe(what: &str, dirs: &[PathBuf], found: impl Fn(&Path) -> bool) -> io::Result<PathBuf> {
+    if let Some(dir) = dirs.iter().find(|dir| found(dir)) {
+        return Ok(dir.clone());
+    }
+
+    let checked: Vec<String> = dirs.iter().map(|dir| dir.display().to_string()).collect();
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("Unable to find {}, checked {}", what, checked.join(", ")),
+    ))
+}
+
+/// Read the Halide source directory from `CMakeCache.txt`
+fn cmake_source_dir(build_dir: &Path) -> Option<PathBuf> {
+    let cache = fs::read_to_string(build_dir.join("CMakeCache.txt")).ok()?;
+    ["Halide_SOURCE_DIR:", "CMAKE_HOME_DIRECTORY:"]
+        .iter()
+        .find_map(|key| {
+            cache
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.split_once('='))
+                .map(|(_, value)| PathBuf::from(value.trim()))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory containing `files`
+    fn tree(files: &[&str]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            let path = dir.path().join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn install() {
+        let dir = tree(&["include/Halide.h", "lib/libHalide.so", "tools/GenGen.cpp"]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::Install);
+        assert_eq!(LayoutKind::Install.advice(dir.path()), None);
+    }
+
+    #[test]
+    fn source_build() {
+        let dir = tree(&[
+            "Makefile",
+            "CMakeLists.txt",
+            "src/Func.h",
+            "include/Halide.h",
+            "tools/GenGen.cpp",
+        ]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::MakeBuild);
+
+        let dir = tree(&[
+            "Makefile",
+            "CMakeLists.txt",
+            "src/Func.h",
+            "tools/GenGen.cpp",
+        ]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::SourceTree);
+    }
+
+    #[test]
+    fn cmake_build() {
+        let source = tree(&["CMakeLists.txt", "src/Func.h", "tools/GenGen.cpp"]);
+        let build = tree(&["include/Halide.h", "src/Release/libHalide.a"]);
+        fs::write(
+            build.path().join("CMakeCache.txt"),
+            format!(
+                "CMAKE_BUILD_TYPE:STRING=Release\nHalide_SOURCE_DIR:STATIC={}\n",
+                source.path().display()
+            ),
+        )
+        .unwrap();
+        assert_eq!(LayoutKind::detect(build.path()), LayoutKind::CMakeBuild);
+        assert_eq!(
+            Layout::build_dir(build.path()).unwrap(),
+            Layout {
+                include: build.path().join("include"),
+                lib: build.path().join("src/Release"),
+                tools: source.path().join("tools"),
+            }
+        );
+    }
+
+    #[test]
+    fn release_tarball() {
+        let dir = tree(&[
+            "include/Halide.h",
+            "lib/libHalide.so",
+            "share/Halide/tools/GenGen.cpp",
+        ]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::Release);
+        assert!(LayoutKind::Release
+            .advice(dir.path())
+            .unwrap()
+            .contains("share/Halide/tools/GenGen.cpp"));
+    }
+
+    #[test]
+    fn vendored() {
+        let dir = tree(&[
+            "halide-vendor.json",
+            "include/Halide.h",
+            "lib/libHalide.so",
+            "tools/GenGen.cpp",
+        ]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::Vendored);
+    }
+
+    #[test]
+    fn unrecognised() {
+        let dir = tree(&["README.md", "lib/libHalide.so"]);
+        assert_eq!(LayoutKind::detect(dir.path()), LayoutKind::Unknown);
+        assert_eq!(
+            LayoutKind::detect(dir.path().join("missing")),
+            LayoutKind::Unknown
+        );
+        assert!(LayoutKind::Unknown
+            .advice(dir.path())
+            .unwrap()
+            .contains("include/Halide.h and tools/GenGen.cpp"));
+
+        let err = Layout::build_dir(dir.path()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .starts_with("Unable to find Halide.h, checked "));
+    }
+}
@@ -1,97 +1,902 @@
 //! halide-build is used to compile [Halide](https://github.com/halide/halide) kernels
 
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::env;
-use std::fs::remove_file;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::fs::{remove_file, File};
 use std::io;
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
+
+pub mod args;
+#[cfg(feature = "async")]
+pub mod async_build;
+pub mod build_info;
+pub mod cache;
+pub mod compare;
+pub mod compat;
+pub mod container;
+pub mod depfile;
+pub mod diagnostics;
+pub mod doctor;
+pub mod event;
+pub mod exec;
+pub mod explain;
+pub mod gen;
+pub mod golden;
+pub mod harness;
+mod hash;
+pub mod info;
+pub mod layout;
+pub mod manifest;
+pub mod metadata;
+pub mod mobile;
+pub mod naming;
+pub mod ninja;
+pub mod pkg_config;
+mod process;
+pub mod progress;
+pub mod project;
+pub mod run_env;
+pub mod rungen;
+pub mod runtime_headers;
+pub mod scm;
+pub mod shared_library;
+pub mod stamp;
+pub mod sweep;
+pub mod system_libs;
+pub mod template;
+pub mod toolchain;
+pub mod validate;
+pub mod vendor;
+pub mod version;
+pub mod warnings;
+pub mod winpath;
+
+#[cfg(test)]
+mod tests;
+
+pub use args::{ArgGroup, ArgPosition, CommandGroups};
+pub use build_info::BuildInfo;
+pub use container::Container;
+pub use diagnostics::Diagnostic;
+pub use event::{Event, EventHandler, SourceStep};
+pub use exec::{Outcome, ResourceUsage};
+pub use explain::{Explanation, Provenance};
+pub use gen::{Emit, GenBuilder};
+pub use info::Info;
+pub use layout::{Layout, LayoutKind};
+pub use metadata::PipelineMetadata;
+pub use process::InterruptToken;
+pub use run_env::RunEnv;
+pub use rungen::MultiRunGen;
+pub use shared_library::{Platform, SharedLibrary};
+pub use warnings::WarningLevel;
+
+use args::GroupedCommand;
 
 static CARGO_LINK_SEARCH: &str = "cargo:rustc-link-search=native=";
 static CARGO_LINK_LIB: &str = "cargo:rustc-link-lib=";
+static CARGO_RERUN_IF_CHANGED: &str = "cargo:rerun-if-changed=";
+
+/// How often `Event::SourceTick` is sent
+const SOURCE_TICK: Duration = Duration::from_millis(100);
+
+/// How a library is linked, used as the `kind=` prefix of `cargo:rustc-link-lib`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Static,
+    Dylib,
+    Framework,
+}
+
+impl LinkKind {
+    /// The name used by Cargo for this kind
+    pub fn name(&self) -> &'static str {
+        match self {
+            LinkKind::Static => "static",
+            LinkKind::Dylib => "dylib",
+            LinkKind::Framework => "framework",
+        }
+    }
+
+    /// Infer the kind of a library from its file extension
+    pub fn from_path(path: impl AsRef<Path>) -> Option<LinkKind> {
+        let name = path.as_ref().file_name()?.to_str()?;
+        if name.ends_with(".a") || name.ends_with(".lib") {
+            Some(LinkKind::Static)
+        } else if name.ends_with(".so") || name.contains(".so.") || name.ends_with(".dylib") {
+            Some(LinkKind::Dylib)
+        } else {
+            None
+        }
+    }
+}
+
+/// Ask Cargo to rerun the build script when a file changes
+pub fn rerun_if_changed(path: impl AsRef<Path>) {
+    println!("{}{}", CARGO_RERUN_IF_CHANGED, path.as_ref().display());
+}
+
+/// Split the contents of an arguments file into arguments
+///
+/// Arguments are separated by whitespace or newlines, and lines starting with `#` are
+/// comments
+pub fn parse_args_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .map(String::from)
+        .collect()
+}
 
 /// Link a library, specified by path and name
 pub fn link_lib(path: Option<&str>, name: &str) {
+    link_lib_kind(path, name, None)
+}
+
+/// Link a library, specified by path, name and kind
+///
+/// When `kind` is `None` Cargo decides how the library is linked
+pub fn link_lib_kind(path: Option<&str>, name: &str, kind: Option<LinkKind>) {
     if let Some(path) = path {
         println!("{}{}", CARGO_LINK_SEARCH, path);
     }
 
-    println!("{}{}", CARGO_LINK_LIB, name);
+    match kind {
+        Some(kind) => println!("{}{}={}", CARGO_LINK_LIB, kind.name(), name),
+        None => println!("{}{}", CARGO_LINK_LIB, name),
+    }
 }
 
 /// Link a library, specified by filename
+///
+/// The kind is inferred from the extension, `.a` is linked statically and `.so` or
+/// `.dylib` dynamically
 pub fn link<P: AsRef<std::path::Path>>(filename: P) {
-    let mut filename = filename.as_ref().to_path_buf();
-    let name = filename.file_stem().expect("Invalid filename");
-    let s = String::from(name.to_str().expect("Invalid filename"));
-    let mut tmp: &str = &s;
+    let filename = winpath::strip_verbatim(filename);
+    let kind = LinkKind::from_path(&filename);
+    let s = filename
+        .file_name()
+        .expect("Invalid filename")
+        .to_string_lossy();
+
+    // Strip versioned suffixes like `.so.16`, then the extension
+    let mut name: &str = match s.find(".so.") {
+        Some(i) => &s[..i],
+        None => &s,
+    };
+    for ext in [".a", ".lib", ".so", ".dylib"] {
+        if let Some(stripped) = name.strip_suffix(ext) {
+            name = stripped;
+            break;
+        }
+    }
 
-    if let Some(s) = s.strip_prefix("lib") {
-        tmp = &s[3..]
+    // Versioned macOS libraries are named like `libHalide.16.dylib`
+    if s.ends_with(".dylib") {
+        while let Some((base, version)) = name.rsplit_once('.') {
+            if version.is_empty() || !version.bytes().all(|b| b.is_ascii_digit()) {
+                break;
+            }
+            name = base;
+        }
     }
 
-    if s.ends_with(".a") {
-        tmp = &tmp[..tmp.len() - 2];
-    } else if s.ends_with(".so") {
-        tmp = &tmp[..tmp.len() - 3];
-    } else if s.ends_with(".dylib") {
-        tmp = &tmp[..tmp.len() - 6];
+    // MSVC libraries aren't prefixed with `lib`
+    if !s.ends_with(".lib") {
+        if let Some(stripped) = name.strip_prefix("lib") {
+            name = stripped;
+        }
     }
 
-    filename.pop();
-    link_lib(filename.to_str(), tmp);
+    // Cargo directives end at the newline, spaces and other characters are passed unchanged
+    let dir = filename
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| dir.to_string_lossy());
+    link_lib_kind(dir.as_deref(), name, kind);
 }
 
 /// Compile a shared library using the C++ compiler
+///
+/// The platform's extension is added when `output` has none, see `SharedLibrary` to set the
+/// soname or install name
 pub fn compile_shared_library(
     compiler: Option<&str>,
     output: &str,
     args: &[&str],
 ) -> Result<bool, std::io::Error> {
-    let cxx = std::env::var("CXX").unwrap_or_else(|_| "c++".to_owned());
-    let mut cmd = Command::new(compiler.unwrap_or(&cxx));
+    shared_library(compiler, output, args).compile()
+}
+
+/// Like `compile_shared_library`, running the compiler using `executor`
+#[cfg(any(test, feature = "testing"))]
+pub fn compile_shared_library_with(
+    executor: impl exec::Executor + 'static,
+    compiler: Option<&str>,
+    output: &str,
+    args: &[&str],
+) -> Result<bool, std::io::Error> {
+    shared_library(compiler, output, args)
+        .with_executor(executor)
+        .compile()
+}
+
+/// Rename `from` over `to`, copying when they're on different devices
+///
+/// `fs::rename` replaces an existing `to` on every platform, on Windows it fails when `to` is
+/// an executable that's still running
+pub(crate) fn replace_file(from: &Path, to: &Path) -> io::Result<()> {
+    match std::fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_replace(from, to),
+        res => res,
+    }
+}
+
+/// Copy `from` to a temporary file next to `to`, then rename it over `to`, so `to` is never
+/// left partially written
+fn copy_replace(from: &Path, to: &Path) -> io::Result<()> {
+    let mut tmp = to.as_os_str().to_owned();
+    tmp.push(format!(".tmp.{}", std::process::id()));
+    let tmp = PathBuf::from(tmp);
+
+    let res = std::fs::copy(from, &tmp).and_then(|_| std::fs::rename(&tmp, to));
+    if res.is_err() {
+        let _ = remove_file(&tmp);
+        return res;
+    }
+    remove_file(from)
+}
+
+/// The directory containing a framework, searched using `-F`
+fn framework_dir(framework: &Path) -> PathBuf {
+    match framework.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+fn shared_library<'a>(
+    compiler: Option<&'a str>,
+    output: &str,
+    args: &[&'a str],
+) -> SharedLibrary<'a> {
+    let mut lib = SharedLibrary::new(output).args(args);
+    lib.compiler = compiler;
+    lib
+}
+
+/// The kind of artifact produced by a build
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputKind {
+    /// Compile and link an executable
+    #[default]
+    Executable,
+
+    /// Compile a single source file to an object file, without linking
+    Object,
+
+    /// Compile each source file to an object file and archive them using `ar`
+    StaticLibrary,
+
+    /// Compile and link a shared library
+    SharedLibrary,
+}
+
+/// GPU backends supported by Halide
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuBackend {
+    Cuda,
+    OpenCL,
+    Metal,
+    D3D12,
+}
+
+impl GpuBackend {
+    /// The Halide target feature enabling this backend
+    pub fn target_feature(&self) -> &'static str {
+        match self {
+            GpuBackend::Cuda => "cuda",
+            GpuBackend::OpenCL => "opencl",
+            GpuBackend::Metal => "metal",
+            GpuBackend::D3D12 => "d3d12compute",
+        }
+    }
+
+    /// Libraries needed by this backend on the current platform
+    pub fn libs(&self) -> &'static [&'static str] {
+        match self {
+            GpuBackend::Cuda => &["cuda"],
+            GpuBackend::OpenCL if cfg!(target_os = "macos") => &[],
+            GpuBackend::OpenCL => &["OpenCL"],
+            GpuBackend::Metal => &[],
+            GpuBackend::D3D12 if cfg!(windows) => &["d3d12"],
+            GpuBackend::D3D12 => &[],
+        }
+    }
+
+    /// Frameworks needed by this backend on the current platform
+    pub fn frameworks(&self) -> &'static [&'static str] {
+        match self {
+            GpuBackend::OpenCL if cfg!(target_os = "macos") => &["OpenCL"],
+            GpuBackend::Metal if cfg!(target_os = "macos") => &["Metal", "Foundation"],
+            _ => &[],
+        }
+    }
+}
+
+/// Halide target features, see `Build::with_feature`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TargetFeature {
+    /// Enable the runtime's debug checks and logging
+    Debug,
+    /// Add a `user_context` argument to the emitted function
+    UserContext,
+    NoAsserts,
+    NoBoundsQuery,
+    /// Don't include the runtime in the emitted object
+    NoRuntime,
+    Profile,
+    TraceAll,
+    StrictFloat,
+    /// Allow buffers larger than 2GB
+    LargeBuffers,
+    /// Mangle the emitted function name as C++
+    CPlusPlusNameMangling,
+    Cuda,
+    OpenCL,
+    Metal,
+    D3D12Compute,
+    Vulkan,
+    SSE41,
+    AVX,
+    AVX2,
+    AVX512,
+    FMA,
+    F16C,
+    ArmDotProd,
+    ArmFp16,
+    SVE2,
+    WasmSimd128,
+}
+
+impl TargetFeature {
+    /// The name of the feature in a target string
+    pub fn name(&self) -> &'static str {
+        match self {
+            TargetFeature::Debug => "debug",
+            TargetFeature::UserContext => "user_context",
+            TargetFeature::NoAsserts => "no_asserts",
+            TargetFeature::NoBoundsQuery => "no_bounds_query",
+            TargetFeature::NoRuntime => "no_runtime",
+            TargetFeature::Profile => "profile",
+            TargetFeature::TraceAll => "trace_all",
+            TargetFeature::StrictFloat => "strict_float",
+            TargetFeature::LargeBuffers => "large_buffers",
+            TargetFeature::CPlusPlusNameMangling => "c_plus_plus_name_mangling",
+            TargetFeature::Cuda => "cuda",
+            TargetFeature::OpenCL => "opencl",
+            TargetFeature::Metal => "metal",
+            TargetFeature::D3D12Compute => "d3d12compute",
+            TargetFeature::Vulkan => "vulkan",
+            TargetFeature::SSE41 => "sse41",
+            TargetFeature::AVX => "avx",
+            TargetFeature::AVX2 => "avx2",
+            TargetFeature::AVX512 => "avx512",
+            TargetFeature::FMA => "fma",
+            TargetFeature::F16C => "f16c",
+            TargetFeature::ArmDotProd => "arm_dot_prod",
+            TargetFeature::ArmFp16 => "arm_fp16",
+            TargetFeature::SVE2 => "sve2",
+            TargetFeature::WasmSimd128 => "wasm_simd128",
+        }
+    }
+}
+
+impl std::fmt::Display for TargetFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// CPU architectures for universal binaries on macOS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arch {
+    Arm64,
+    X86_64,
+}
+
+impl Arch {
+    /// The name used by `-arch` and `lipo`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Arch::Arm64 => "arm64",
+            Arch::X86_64 => "x86_64",
+        }
+    }
+}
+
+/// Source language of a build
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    Cxx,
+    C,
+}
+
+impl Language {
+    /// Environment variable selecting the compiler
+    pub fn compiler_var(&self) -> &'static str {
+        match self {
+            Language::Cxx => "CXX",
+            Language::C => "CC",
+        }
+    }
+
+    /// Compiler used when `compiler_var` isn't set
+    pub fn default_compiler(&self) -> &'static str {
+        match self {
+            Language::Cxx => "c++",
+            Language::C => "cc",
+        }
+    }
+
+    /// The language standard
+    pub fn std(&self) -> &'static str {
+        match self {
+            Language::Cxx => "c++17",
+            Language::C => "c11",
+        }
+    }
+
+    /// The language of a source file, from its extension
+    pub fn from_path(path: impl AsRef<Path>) -> Language {
+        match path.as_ref().extension() {
+            Some(ext) if ext == "c" => Language::C,
+            _ => Language::Cxx,
+        }
+    }
+}
+
+/// Standard input for the run step
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum StdinSource {
+    /// Inherit standard input from the current process
+    #[default]
+    Inherit,
+
+    /// Read from an empty input
+    Null,
+
+    /// Read from a file
+    File(PathBuf),
+
+    /// Read from a buffer
+    Bytes(Vec<u8>),
+}
+
+type PreRunFn = dyn FnMut(&Path) -> io::Result<()> + Send;
+
+/// A hook called with the output path before the run step
+#[derive(Clone)]
+pub struct PreRunHook(Arc<Mutex<PreRunFn>>);
+
+impl PreRunHook {
+    pub fn new(f: impl FnMut(&Path) -> io::Result<()> + Send + 'static) -> PreRunHook {
+        PreRunHook(Arc::new(Mutex::new(f)))
+    }
+
+    fn call(&self, path: &Path) -> io::Result<()> {
+        let mut f = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        f(path)
+    }
+}
+
+impl std::fmt::Debug for PreRunHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("PreRunHook(..)")
+    }
+}
+
+type PostBuildFn = dyn Fn(&Path) -> io::Result<()> + Send + Sync;
+
+/// A hook called with the output path after a successful build, see `Build::post_build`
+#[derive(Clone)]
+pub enum PostBuildHook {
+    Fn(Arc<PostBuildFn>),
+
+    /// A program and its arguments, with `{output}` replaced by the output path
+    Command(String, Vec<String>),
+}
+
+impl std::fmt::Debug for PostBuildHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PostBuildHook::Fn(_) => f.write_str("PostBuildHook::Fn(..)"),
+            PostBuildHook::Command(program, args) => f
+                .debug_tuple("PostBuildHook::Command")
+                .field(program)
+                .field(args)
+                .finish(),
+        }
+    }
+}
+
+/// The result of a build step
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildReport {
+    /// True when the output was built, or was already up to date
+    pub success: bool,
+
+    /// True when nothing was compiled because the output was already up to date
+    pub up_to_date: bool,
+
+    /// Files the output depends on, read from `<output>.d` for incremental builds
+    pub dependencies: Vec<PathBuf>,
+
+    /// Peak memory and CPU time of the compile commands when `resource_usage` is set, `None`
+    /// on platforms without `wait4`
+    pub resource_usage: Option<ResourceUsage>,
+
+    /// The compile commands that were run and their output when `capture` is set, ending
+    /// with the failing command when the build fails
+    pub outcomes: Vec<Outcome>,
+
+    /// Diagnostics reported by the compile commands when `structured_diagnostics` is set
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The result of a run step
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunReport {
+    pub success: bool,
+
+    /// Peak memory and CPU time of the output when `resource_usage` is set, `None` on
+    /// platforms without `wait4` and for generators
+    pub resource_usage: Option<ResourceUsage>,
+
+    /// The command and its output when `capture` is set
+    pub outcome: Option<Outcome>,
+
+    /// The files declared using `run_capture_file`, written by a successful run
+    pub outputs: Vec<PathBuf>,
+}
 
-    cmd.arg("-std=c++17");
-    let res = cmd
-        .arg("-shared")
-        .arg("-o")
-        .arg(output)
-        .args(args)
-        .status()?;
-    Ok(res.success())
+/// Whether a build succeeded, clones start with the same value
+#[derive(Debug, Default)]
+struct Built(Arc<AtomicBool>);
+
+impl Built {
+    fn get(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, x: bool) {
+        self.0.store(x, Ordering::SeqCst)
+    }
+
+    /// A flag that's set together with this one, unlike a clone
+    #[cfg(feature = "async")]
+    fn share(&self) -> Built {
+        Built(self.0.clone())
+    }
+}
+
+impl Clone for Built {
+    fn clone(&self) -> Self {
+        Built(Arc::new(AtomicBool::new(self.get())))
+    }
 }
 
+/// Flags found for the image libraries, and the libraries they were found for
+type ImageFlags = (Vec<String>, pkg_config::Flags);
+
+/// The output of `<compiler> --version`, and the compiler it's from
+type CompilerVersionOutput = (String, String);
+
+/// System libraries found for a static libHalide, and the symbol hints they were found with
+type SystemLibs = (Vec<(String, String)>, Option<Vec<String>>);
+
+type LlvmLibs = (PathBuf, Option<Vec<String>>);
+
 /// Build stores the required context for building a Halide kernel
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Build<'a> {
     /// Path to halide source
     pub halide_path: PathBuf,
 
+    /// Locations of the Halide headers, library and tools, defaults to the `include`, `lib`
+    /// and `tools` directories of `halide_path`
+    pub layout: Option<Layout>,
+
     /// Input files
     pub src: Vec<PathBuf>,
 
+    /// Sources passed as strings, with the contents written to their path in `src` before
+    /// building
+    pub inline_sources: Vec<(PathBuf, String)>,
+
+    /// Extra compile flags for individual input files
+    pub src_flags: HashMap<PathBuf, Vec<&'a str>>,
+
     /// Output file
     pub output: PathBuf,
 
     /// C++ compiler
-    pub cxx: Option<&'a str>,
+    pub cxx: Option<Cow<'a, str>>,
+
+    /// Compilers to try in order, the first that exists and compiles a test program is used
+    /// instead of `cxx`, see `compilers`
+    pub compilers: Vec<&'a str>,
 
     /// C++ compile time flags
-    pub cxxflags: Option<&'a str>,
+    pub cxxflags: Option<Cow<'a, str>>,
 
     /// C++ link time flags
-    pub ldflags: Option<&'a str>,
+    pub ldflags: Option<Cow<'a, str>>,
 
     /// Extra arguments to build step
-    pub build_args: Vec<&'a str>,
+    pub build_args: Vec<OsString>,
+
+    /// Arguments placed at a specific position in compile and link commands, see `args`
+    pub raw_args: Vec<(ArgPosition, &'a str)>,
+
+    /// Prebuilt object files, linked after the sources or added to static libraries
+    pub objects: Vec<PathBuf>,
+
+    /// Prebuilt static libraries, linked after the sources and before the Halide libraries
+    pub archives: Vec<PathBuf>,
+
+    /// Extra library search paths, added after the Halide libraries
+    pub lib_dirs: Vec<PathBuf>,
+
+    /// Extra libraries to link, added after the Halide libraries
+    pub libs: Vec<&'a str>,
+
+    /// Extra macOS frameworks to link, added after the Halide libraries
+    pub frameworks: Vec<&'a str>,
+
+    /// Image libraries used by `Halide::Tools::load_image` and `save_image`, linked after
+    /// libHalide
+    pub image_libs: Vec<&'a str>,
+
+    /// Find the include and library directories of `image_libs` using pkg-config, enabled by
+    /// default
+    pub pkg_config: bool,
+
+    /// Symbol prefixes and the libraries defining them, used with
+    /// `system_libs::DEFAULT_HINTS` to link a static libHalide
+    pub symbol_lib_hints: Vec<(String, String)>,
+
+    /// `llvm-config` used to find the libraries of a static libHalide instead of its symbols,
+    /// see `llvm_system_libs`
+    pub llvm_config: Option<PathBuf>,
+
+    /// Architectures to build a universal binary for, only supported on macOS
+    pub archs: Vec<Arch>,
+
+    /// Base Halide target, defaults to `host` when target features are added
+    pub target: Option<&'a str>,
+
+    /// Halide target features appended to the base target
+    pub target_features: Vec<&'a str>,
 
     /// Extra arguments to run step
-    pub run_args: Vec<&'a str>,
+    pub run_args: Vec<OsString>,
+
+    /// Arguments read by `run_args_file`, passed after `run_args`
+    pub file_run_args: Vec<String>,
+
+    /// Wrapper command the run step is started with, like `gdb --args`, see `run_under`
+    pub run_under: Vec<&'a str>,
+
+    /// Extra environment variables for the run step
+    pub run_vars: Vec<(String, String)>,
+
+    /// Directories prepended to `PATH` for the run step, so the output can find helper tools
+    pub run_paths: Vec<PathBuf>,
+
+    /// Working directory for the run step, defaults to the current directory
+    pub run_cwd: Option<PathBuf>,
+
+    /// Files the run step must write, see `run_capture_file`
+    pub run_outputs: Vec<PathBuf>,
+
+    /// Standard input for the run step
+    pub stdin: StdinSource,
+
+    /// Niceness increment for the run step, only supported on Linux
+    pub nice: Option<i32>,
+
+    /// CPUs the run step is allowed to run on, only supported on Linux
+    pub cpu_affinity: Vec<usize>,
+
+    /// Called with the output path before the run step, for example to strip or sign the
+    /// executable
+    pub pre_run: Option<PreRunHook>,
+
+    /// Called in order with the output path after a successful build, for example to sign or
+    /// pack it
+    pub post_build: Vec<PostBuildHook>,
 
     /// Keep executable when finished running
     pub keep: bool,
 
+    /// Run the output even if it wasn't built successfully by this build
+    pub force: bool,
+
+    /// Remove an existing output before compiling, so a failed build can't leave a stale
+    /// output that's run afterwards
+    pub clean_output: bool,
+
+    /// Fail instead of warning when the output isn't named conventionally for its kind, see
+    /// `naming`
+    pub strict_naming: bool,
+
+    /// Fail `check` instead of warning when libHalide was built with a different compiler
+    /// family or C++ standard library, see `build_info`
+    pub strict_compiler: bool,
+
+    /// Collect the peak memory and CPU time of the compile and run commands, see
+    /// `BuildReport` and `RunReport`
+    pub resource_usage: bool,
+
+    /// Capture the output of the compile and run commands in `BuildReport::outcomes` and
+    /// `RunReport::outcome` instead of passing it through
+    pub capture: bool,
+
+    /// Send each line written by the compile, run and generator commands to `events` while
+    /// it's captured, see `tee`
+    pub tee: bool,
+
+    /// Check the standard output of the run step, which captures it, see `golden`
+    pub expect_stdout: Option<golden::Expect>,
+
+    /// How output is normalized before checking `expect_stdout`
+    pub expect_normalize: golden::Normalize,
+
+    /// Rewrite the golden file of `expect_stdout` with the output instead of checking it
+    pub update_golden: bool,
+
     /// Include Halide generator header
     pub generator: bool,
+
+    /// Source of the generator `main`, defaults to `GenGen.cpp` in the tools directory
+    pub gengen: Option<PathBuf>,
+
+    /// Kind of artifact to produce
+    pub output_kind: OutputKind,
+
+    /// Source language, selects the default compiler and language standard
+    pub language: Language,
+
+    /// Write `<output>.stamp` after a successful build
+    pub stamp: bool,
+
+    /// Strip symbols from executables when linking
+    pub strip: bool,
+
+    /// Write a linker map to this path, showing which archive member defines each symbol
+    pub link_map: Option<PathBuf>,
+
+    /// Warnings reported by the compiler, see `warnings`
+    pub warnings: WarningLevel,
+
+    /// Turn warnings into errors using `-Werror`, or `/WX` for MSVC
+    pub warnings_as_errors: bool,
+
+    /// Compile C++ using `-fno-rtti`, to match a libHalide built without RTTI
+    ///
+    /// Most Halide builds have RTTI enabled, and generators using a libHalide built with it
+    /// fail to link when this is set
+    pub no_rtti: bool,
+
+    /// Compile C++ using `-fno-exceptions`, to match a libHalide built without exceptions
+    pub no_exceptions: bool,
+
+    /// Enable C++ modules and header units, compiling with `-std=c++20` and `-fmodules` for
+    /// Clang or `-fmodules-ts` for GCC 11 and later, other compilers fail the build
+    pub modules: bool,
+
+    /// Module map passed using `-fmodule-map-file=` for Clang or `-fmodule-mapper=` for GCC
+    /// when `modules` is set
+    pub module_map: Option<PathBuf>,
+
+    /// Link libstdc++ and libgcc statically using `-static-libstdc++ -static-libgcc`, so
+    /// executables don't depend on the host's libstdc++ version
+    ///
+    /// Only applies to GCC and Clang on Linux and other ELF platforms, libHalide is still
+    /// linked as configured
+    pub static_stdcxx: bool,
+
+    /// Compile to `<output>.tmp.<pid>` and rename it over the output only on success, so a
+    /// failed build never leaves a truncated or stale output behind, defaults to true
+    ///
+    /// Generators emit into a temporary directory in the output directory the same way.
+    /// Ignored for Emscripten, which writes files next to the output
+    pub atomic_outputs: bool,
+
+    /// Capture compiler output and report the diagnostics in it as `Event::Diagnostic` and in
+    /// `BuildReport::diagnostics`, GCC 9 to 14 are asked for JSON diagnostics, other compilers
+    /// are parsed on a best-effort basis
+    ///
+    /// The compiler output isn't shown unless `capture` is also set, then it's in
+    /// `BuildReport::outcomes`
+    pub structured_diagnostics: bool,
+
+    /// Keep colored compiler diagnostics when output isn't a terminal, ignored when
+    /// `NO_COLOR` is set
+    pub colorize_diagnostics: bool,
+
+    /// Compile to WebAssembly using Emscripten, set by `emscripten`
+    pub emscripten: bool,
+
+    /// Cross-compile for an Android or iOS device, set by `mobile`
+    pub mobile: Option<mobile::MobileToolchain>,
+
+    /// `Halide.framework` to link instead of libHalide, on macOS it's also found in
+    /// `halide_path`
+    pub halide_framework: Option<PathBuf>,
+
+    /// Write a `<output>.d` dependency file and skip the build when the output is newer than
+    /// the files it depends on
+    pub incremental: bool,
+
+    /// Avoid embedding build paths, timestamps and random seeds in the output
+    pub reproducible: bool,
+
+    /// Value of `SOURCE_DATE_EPOCH` for reproducible builds, defaults to the
+    /// `SOURCE_DATE_EPOCH` environment variable or 0
+    pub source_date_epoch: Option<u64>,
+
+    /// Directory that sources, outputs and other files used by the build must stay inside,
+    /// checked by `validate`
+    pub sandbox: Option<PathBuf>,
+
+    /// Used to interrupt the build and run steps
+    pub interrupt: InterruptToken,
+
+    /// Receives events from the build and run steps
+    pub events: EventHandler,
+
+    /// Runs the build and run commands
+    pub(crate) executor: Arc<dyn exec::Executor>,
+
+    /// Set when the last build succeeded
+    built: Built,
+
+    /// The compiler picked from `compilers` by the first build
+    selected_compiler: OnceLock<String>,
+
+    /// Where commands write the output when `atomic_outputs` is set
+    staging: Option<PathBuf>,
+
+    /// The flags found for `image_libs`, and the libraries they were found for
+    image_flags: Arc<Mutex<Option<ImageFlags>>>,
+
+    /// The compiler's `--version` output, see `compiler_version_output`
+    compiler_version_output: Arc<Mutex<Option<CompilerVersionOutput>>>,
+
+    /// The system libraries found for a static libHalide, see `halide_system_libs`
+    system_libs: Arc<Mutex<Option<SystemLibs>>>,
+
+    /// The flags reported by `llvm_config`, and the `llvm-config` they were reported by
+    llvm_libs: Arc<Mutex<Option<LlvmLibs>>>,
+
+    /// Pins checked by `check`
+    pub toolchain: Option<toolchain::Toolchain>,
+
+    /// Where settings came from, with the value they had at the time, used by `explain`
+    pub provenance: HashMap<&'static str, (Provenance, String)>,
 }
 
 impl<'a> Build<'a> {
@@ -101,17 +906,124 @@ impl<'a> Build<'a> {
         output: Q,
     ) -> Build<'a> {
         Build {
-            halide_path: halide_path.as_ref().to_path_buf(),
+            halide_path: winpath::strip_verbatim(halide_path),
+            layout: None,
             src: vec![],
+            inline_sources: vec![],
+            src_flags: HashMap::new(),
             output: output.as_ref().to_path_buf(),
             cxx: None,
+            compilers: vec![],
             cxxflags: None,
             ldflags: None,
             build_args: vec![],
+            raw_args: vec![],
+            objects: vec![],
+            archives: vec![],
+            lib_dirs: vec![],
+            libs: vec![],
+            frameworks: vec![],
+            image_libs: vec!["png", "jpeg"],
+            symbol_lib_hints: vec![],
+            llvm_config: None,
+            pkg_config: true,
+            archs: vec![],
+            target: None,
+            target_features: vec![],
             run_args: vec![],
+            file_run_args: vec![],
+            run_under: vec![],
+            run_vars: vec![],
+            run_paths: vec![],
+            run_cwd: None,
+            run_outputs: vec![],
+            stdin: StdinSource::Inherit,
+            nice: None,
+            cpu_affinity: vec![],
+            pre_run: None,
+            post_build: vec![],
             keep: false,
+            force: false,
+            clean_output: false,
+            strict_naming: false,
+            strict_compiler: false,
+            resource_usage: false,
+            capture: false,
+            tee: false,
+            expect_stdout: None,
+            expect_normalize: golden::Normalize::default(),
+            update_golden: false,
             generator: false,
+            gengen: None,
+            output_kind: OutputKind::Executable,
+            language: Language::Cxx,
+            stamp: false,
+            strip: false,
+            link_map: None,
+            warnings: WarningLevel::Default,
+            warnings_as_errors: false,
+            no_rtti: false,
+            no_exceptions: false,
+            modules: false,
+            module_map: None,
+            static_stdcxx: false,
+            atomic_outputs: true,
+            structured_diagnostics: false,
+            colorize_diagnostics: false,
+            emscripten: false,
+            mobile: None,
+            halide_framework: None,
+            incremental: false,
+            reproducible: false,
+            source_date_epoch: None,
+            sandbox: None,
+            interrupt: InterruptToken::new(),
+            events: EventHandler::default(),
+            executor: Arc::new(exec::System),
+            built: Built::default(),
+            selected_compiler: OnceLock::new(),
+            staging: None,
+            image_flags: Arc::default(),
+            compiler_version_output: Arc::default(),
+            system_libs: Arc::default(),
+            llvm_libs: Arc::default(),
+            toolchain: None,
+            provenance: HashMap::new(),
+        }
+    }
+
+    /// Create a new build with the given output, configured from the environment
+    ///
+    /// `halide_path` is read from `HALIDE_PATH`, falling back to `$HOME/halide`, and
+    /// `cxx`, `cxxflags` and `ldflags` are read from `CXX`, `CXXFLAGS` and `LDFLAGS`
+    pub fn from_env<P: AsRef<std::path::Path>>(output: P) -> Build<'a> {
+        let halide_path = env::var_os("HALIDE_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| {
+                env::var_os("HOME")
+                    .map(PathBuf::from)
+                    .unwrap_or_default()
+                    .join("halide")
+            });
+
+        let mut build = Build::new(halide_path, output);
+        build.cxx = env::var("CXX").ok().map(Cow::Owned);
+        build.cxxflags = env::var("CXXFLAGS").ok().map(Cow::Owned);
+        build.ldflags = env::var("LDFLAGS").ok().map(Cow::Owned);
+
+        for (name, var) in [
+            ("halide_path", "HALIDE_PATH"),
+            ("compiler", "CXX"),
+            ("cxxflags", "CXXFLAGS"),
+            ("ldflags", "LDFLAGS"),
+        ] {
+            if env::var_os(var).is_some() {
+                build.set_provenance(name, Provenance::Env(var.to_string()));
+            } else if name == "halide_path" {
+                build.set_provenance(name, Provenance::Default);
+            }
         }
+        build
     }
 
     pub fn source_file(mut self, src: impl AsRef<std::path::Path>) -> Self {
@@ -119,118 +1031,2701 @@ impl<'a> Build<'a> {
         self
     }
 
-    pub fn build_arg(mut self, src: &'a str) -> Self {
-        self.build_args.push(src.as_ref());
-        self
+    /// Add the source of the generator `name` in `dir`, see `gen::find_generator_source`
+    pub fn source_from_generator_name(self, dir: impl AsRef<Path>, name: &str) -> io::Result<Self> {
+        let src = gen::find_generator_source(dir, name)?;
+        Ok(self.source_file(src))
     }
 
-    pub fn build_args(mut self, src: impl AsRef<[&'a str]>) -> Self {
-        self.build_args.extend(src.as_ref());
-        self
+    /// Add a source from a string
+    ///
+    /// The code is written next to the output as `<name>-<hash>.cpp`, so identical code reuses
+    /// the same file, and compiler messages refer to `name`. The file is removed after
+    /// building unless `keep` or `incremental` is set.
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` when `name` contains a path separator or is
+    /// `..`, since the file must stay next to the output
+    pub fn source_string(mut self, name: &str, code: &str) -> io::Result<Self> {
+        validate::file_name("source", name)?;
+        let stem = name.strip_suffix(".cpp").unwrap_or(name);
+        let mut hasher = hash::Fnv::default();
+        hasher.write_str(code);
+
+        let path = self
+            .work_dir()
+            .join(format!("{}-{}.cpp", stem, hash::hex(hasher.finish())));
+        let contents = format!("#line 1 \"{}\"\n{}", name.replace('"', "\\\""), code);
+
+        self.src.push(path.clone());
+        self.inline_sources.push((path, contents));
+        Ok(self)
     }
 
-    pub fn run_arg(mut self, src: &'a str) -> Self {
-        self.run_args.push(src.as_ref());
-        self
+    /// Directory for intermediate files, the directory containing the output
+    fn work_dir(&self) -> PathBuf {
+        match self.output.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        }
+    }
+
+    /// Write inline sources that are missing or out of date, leaving others untouched so
+    /// their mtime is preserved
+    fn write_inline_sources(&self) -> io::Result<()> {
+        for (path, contents) in &self.inline_sources {
+            if std::fs::read_to_string(path).ok().as_ref() != Some(contents) {
+                std::fs::write(path, contents)?;
+            }
+        }
+        Ok(())
     }
 
-    pub fn run_args(mut self, src: impl AsRef<[&'a str]>) -> Self {
-        self.run_args.extend(src.as_ref());
+    /// Add a source file that is compiled with extra flags, separately from the other sources
+    pub fn source_file_with_flags(
+        mut self,
+        src: impl AsRef<std::path::Path>,
+        flags: impl AsRef<[&'a str]>,
+    ) -> Self {
+        let src = src.as_ref().to_owned();
+        self.src_flags
+            .entry(src.clone())
+            .or_default()
+            .extend(flags.as_ref());
+        self.src.push(src);
         self
     }
 
-    pub fn ldflags(mut self, flags: &'a str) -> Self {
-        self.ldflags = Some(flags);
+    pub fn build_arg(mut self, src: impl AsRef<OsStr>) -> Self {
+        self.build_args.push(src.as_ref().to_os_string());
         self
     }
 
-    pub fn cxxflags(mut self, flags: &'a str) -> Self {
-        self.cxxflags = Some(flags);
+    pub fn build_args<S: AsRef<OsStr>>(mut self, src: impl IntoIterator<Item = S>) -> Self {
+        self.build_args
+            .extend(src.into_iter().map(|arg| arg.as_ref().to_os_string()));
         self
     }
 
-    pub fn compiler(mut self, name: &'a str) -> Self {
-        self.cxx = Some(name);
+    /// Add an argument at `position` in every compile and link command
+    ///
+    /// Arguments at the same position keep the order they're added in, see `args` for the
+    /// order of the groups
+    pub fn raw_arg(mut self, position: ArgPosition, arg: &'a str) -> Self {
+        self.raw_args.push((position, arg));
         self
     }
 
-    pub fn keep(mut self, x: bool) -> Self {
-        self.keep = x;
+    pub fn run_arg(mut self, src: impl AsRef<OsStr>) -> Self {
+        self.run_args.push(src.as_ref().to_os_string());
         self
     }
 
-    pub fn generator(mut self, x: bool) -> Self {
-        self.generator = x;
+    pub fn run_args<S: AsRef<OsStr>>(mut self, src: impl IntoIterator<Item = S>) -> Self {
+        self.run_args
+            .extend(src.into_iter().map(|arg| arg.as_ref().to_os_string()));
         self
     }
 
-    /// Execute the build step
-    pub fn build(&self) -> io::Result<bool> {
-        let cxx_default = env::var("CXX").unwrap_or_else(|_| "c++".to_string());
-        let mut cmd = Command::new(self.cxx.unwrap_or(cxx_default.as_str()));
+    /// Read arguments for the run step from a file, see `parse_args_file` for the format
+    ///
+    /// The arguments are passed after `run_args`
+    pub fn run_args_file(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let contents = std::fs::read_to_string(path)?;
+        self.file_run_args.extend(parse_args_file(&contents));
+        Ok(self)
+    }
 
-        cmd.arg("-std=c++17");
-        cmd.args(&["-I", &self.halide_path.join("include").to_string_lossy()])
-            .args(&["-I", &self.halide_path.join("tools").to_string_lossy()]);
+    /// Start the run step, or the generator, using a wrapper like `["gdb", "--args"]`,
+    /// `["valgrind", "--leak-check=full"]` or `["perf", "record"]`
+    ///
+    /// The wrapper receives the run environment, and the output is kept until it exits. Output
+    /// isn't captured when a wrapper is set, even with `capture`, since debuggers need the
+    /// terminal
+    pub fn run_under(mut self, wrapper: impl AsRef<[&'a str]>) -> Self {
+        self.run_under = wrapper.as_ref().to_vec();
+        self
+    }
 
-        if let Some(flags) = &self.cxxflags {
-            cmd.args(flags.split(' '));
-        }
+    /// Set an environment variable for the run step
+    pub fn run_var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.run_vars.push((name.into(), value.into()));
+        self
+    }
 
-        if self.generator {
-            cmd.arg(
-                &self
-                    .halide_path
-                    .join("tools")
-                    .join("GenGen.cpp")
-                    .to_string_lossy()
-                    .as_ref(),
-            );
-        }
+    /// Add a directory to the `PATH` of the run step, ahead of the inherited `PATH`
+    ///
+    /// Directories are searched in the order they're added
+    pub fn append_to_run_env_path(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.run_paths.push(path.as_ref().to_owned());
+        self
+    }
 
-        cmd.args(&self.build_args);
+    /// Set the working directory for the run step
+    pub fn run_cwd(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.run_cwd = Some(path.as_ref().to_owned());
+        self
+    }
 
-        let tinfo = std::env::var("TERMINFO").unwrap_or_else(|_| "-lncurses".to_string());
+    /// Declare a file the run step writes, like an output image passed in `run_args`
+    ///
+    /// A successful run fails with `io::ErrorKind::NotFound` when the file wasn't created or
+    /// modified, otherwise its path is in `RunReport::outputs`. Relative paths are resolved
+    /// against `run_cwd`
+    pub fn run_capture_file(mut self, path: impl AsRef<Path>) -> Self {
+        self.run_outputs.push(path.as_ref().to_path_buf());
+        self
+    }
 
-        cmd.args(&self.src)
-            .args(&["-o", &self.output.to_string_lossy()])
-            .args(&[
-                "-L",
-                &self.halide_path.join("lib").to_string_lossy(),
-                "-lHalide",
-                "-lpng",
-                "-ljpeg",
-                "-lpthread",
-                &tinfo,
-                "-ldl",
-                "-lz",
-            ]);
+    /// The paths of `run_outputs`, as seen by the run step
+    fn run_output_paths(&self) -> Vec<PathBuf> {
+        self.run_outputs
+            .iter()
+            .map(|path| match &self.run_cwd {
+                Some(cwd) => cwd.join(path),
+                None => path.clone(),
+            })
+            .collect()
+    }
 
-        if let Some(flags) = &self.ldflags {
-            cmd.args(flags.split(' '));
+    /// Fail when a file in `run_outputs` wasn't written, `before` holds the modification
+    /// times from before the run
+    fn check_run_outputs(&self, before: &[Option<SystemTime>]) -> io::Result<Vec<PathBuf>> {
+        let paths = self.run_output_paths();
+        for (path, before) in paths.iter().zip(before) {
+            let modified = path.metadata().and_then(|m| m.modified()).ok();
+            if modified.is_none() || modified == *before {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "{} ran successfully but didn't write {}",
+                        self.output.display(),
+                        path.display()
+                    ),
+                ));
+            }
         }
+        Ok(paths)
+    }
 
-        cmd.status().map(|status| status.success())
+    /// Set the standard input for the run step
+    pub fn stdin(mut self, stdin: StdinSource) -> Self {
+        self.stdin = stdin;
+        self
     }
 
-    /// Execute the run step
-    pub fn run(&self) -> io::Result<bool> {
+    /// Write `bytes` to the standard input of the run step, also when its output is captured
+    /// using `capture`, `tee` or `expect_stdout`
+    ///
+    /// The input is written from a separate thread, so large inputs can't deadlock with a
+    /// child that writes its output before reading
+    pub fn run_stdin(self, bytes: impl Into<Vec<u8>>) -> Self {
+        self.stdin(StdinSource::Bytes(bytes.into()))
+    }
+
+    /// Lower the priority of the run step by `increment`
+    pub fn nice(mut self, increment: i32) -> Self {
+        self.nice = Some(increment);
+        self
+    }
+
+    /// Restrict the run step to the given CPUs
+    pub fn cpu_affinity(mut self, cpus: &[usize]) -> Self {
+        self.cpu_affinity = cpus.to_vec();
+        self
+    }
+
+    /// Call `f` with the output path after a successful build, before running it
+    ///
+    /// The run step is aborted if `f` returns an error
+    pub fn pre_run(mut self, f: impl FnMut(&Path) -> io::Result<()> + Send + 'static) -> Self {
+        self.pre_run = Some(PreRunHook::new(f));
+        self
+    }
+
+    /// Call `f` with the output path after each successful build, hooks are called in the
+    /// order they're added
+    ///
+    /// The build fails if `f` returns an error. Hooks aren't called when an incremental build
+    /// is up to date
+    pub fn post_build(
+        mut self,
+        f: impl Fn(&Path) -> io::Result<()> + Send + Sync + 'static,
+    ) -> Self {
+        self.post_build.push(PostBuildHook::Fn(Arc::new(f)));
+        self
+    }
+
+    /// Run `program` after each successful build, like `post_build`, with `{output}` in
+    /// `args` replaced by the output path
+    ///
+    /// The build fails if the command exits unsuccessfully
+    pub fn post_build_command<S: Into<String>>(
+        mut self,
+        program: impl Into<String>,
+        args: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.post_build.push(PostBuildHook::Command(
+            program.into(),
+            args.into_iter().map(Into::into).collect(),
+        ));
+        self
+    }
+
+    /// Add a prebuilt object file
+    pub fn object(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.objects.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Add a prebuilt object file, like `object`, it's linked after the sources without being
+    /// compiled
+    pub fn extra_object(self, path: impl AsRef<std::path::Path>) -> Self {
+        self.object(path)
+    }
+
+    /// Add several prebuilt object files
+    pub fn extra_objects<P: AsRef<std::path::Path>>(
+        mut self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> Self {
+        self.objects
+            .extend(paths.into_iter().map(|path| path.as_ref().to_owned()));
+        self
+    }
+
+    /// Add a prebuilt static library, like a pipeline emitted by a generator
+    pub fn archive(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.archives.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Add a library search path
+    pub fn extra_lib_dir(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.lib_dirs.push(path.as_ref().to_owned());
+        self
+    }
+
+    /// Add a library to link, by name without the `-l` prefix
+    pub fn extra_lib(mut self, name: &'a str) -> Self {
+        self.libs.push(name);
+        self
+    }
+
+    /// Add a macOS framework to link
+    pub fn extra_framework(mut self, name: &'a str) -> Self {
+        self.frameworks.push(name);
+        self
+    }
+
+    /// Build a universal binary for the given architectures, ignored with a warning on
+    /// platforms other than macOS
+    pub fn arch(mut self, archs: &[Arch]) -> Self {
+        self.archs = archs.to_vec();
+        self
+    }
+
+    /// Set the base Halide target, for example `host` or `x86-64-linux`
+    pub fn target(mut self, target: &'a str) -> Self {
+        self.target = Some(target);
+        self
+    }
+
+    /// Add a Halide target feature
+    pub fn target_feature(mut self, feature: &'a str) -> Self {
+        self.target_features.push(feature);
+        self
+    }
+
+    /// Add a Halide target feature, features that were already added are ignored
+    ///
+    /// GPU features don't add the backend's libraries, use `gpu` for that
+    pub fn with_feature(mut self, feature: TargetFeature) -> Self {
+        if !self.target_features.contains(&feature.name()) {
+            self.target_features.push(feature.name());
+        }
+        self
+    }
+
+    /// Enable a GPU backend, adding its target feature and runtime libraries
+    pub fn gpu(mut self, backend: GpuBackend) -> Self {
+        self.target_features.push(backend.target_feature());
+        self.libs.extend(backend.libs());
+        self.frameworks.extend(backend.frameworks());
+        self
+    }
+
+    /// The full target string, if a target or target features have been set
+    ///
+    /// When running, the target is passed to generators as `target=...` and to JIT programs
+    /// using `HL_JIT_TARGET`
+    pub fn target_string(&self) -> Option<String> {
+        if self.target.is_none() && self.target_features.is_empty() {
+            return None;
+        }
+
+        let mut target = self.target.unwrap_or("host").to_string();
+        for feature in &self.target_features {
+            target.push('-');
+            target.push_str(feature);
+        }
+        Some(target)
+    }
+
+    pub fn ldflags(mut self, flags: impl Into<Cow<'a, str>>) -> Self {
+        self.ldflags = Some(flags.into());
+        self
+    }
+
+    /// Link libpng and libjpeg for `Halide::Tools` image IO, enabled by default
+    pub fn with_image_io(mut self, x: bool) -> Self {
+        self.image_libs = if x { vec!["png", "jpeg"] } else { vec![] };
+        self
+    }
+
+    /// Replace the image libraries, for example `["png16", "turbojpeg"]`
+    pub fn image_libs(mut self, libs: &[&'a str]) -> Self {
+        self.image_libs = libs.to_vec();
+        self
+    }
+
+    pub fn pkg_config(mut self, x: bool) -> Self {
+        self.pkg_config = x;
+        self
+    }
+
+    /// Link `lib` when a static libHalide uses a symbol starting with `symbol_prefix`
+    ///
+    /// A hint for a prefix that's already known adds an alternative, linked when the compiler
+    /// can't find the earlier ones
+    pub fn symbol_lib_hint(
+        mut self,
+        symbol_prefix: impl Into<String>,
+        lib: impl Into<String>,
+    ) -> Self {
+        self.symbol_lib_hints
+            .push((symbol_prefix.into(), lib.into()));
+        self
+    }
+
+    /// Link the LLVM libraries and system libraries reported by `llvm-config --libs
+    /// --system-libs` with a static libHalide, instead of finding them from its symbols
+    ///
+    /// `LLVM_CONFIG` selects the `llvm-config`, see `llvm_config` to set it directly
+    pub fn llvm_system_libs(mut self, x: bool) -> Self {
+        self.llvm_config = x.then(|| {
+            env::var_os("LLVM_CONFIG")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| PathBuf::from("llvm-config"))
+        });
+        self
+    }
+
+    /// Like `llvm_system_libs`, using the `llvm-config` at `path`
+    pub fn llvm_config(mut self, path: impl AsRef<Path>) -> Self {
+        self.llvm_config = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn cxxflags(mut self, flags: impl Into<Cow<'a, str>>) -> Self {
+        self.cxxflags = Some(flags.into());
+        self
+    }
+
+    /// Use an uninstalled Halide CMake build in `build_dir`, see `Layout::build_dir`
+    pub fn with_halide_from_build_dir(mut self, build_dir: impl AsRef<Path>) -> io::Result<Self> {
+        self.layout = Some(Layout::build_dir(&build_dir)?);
+        self.halide_path = build_dir.as_ref().to_path_buf();
+        Ok(self)
+    }
+
+    /// The locations of the Halide headers, library and tools
+    ///
+    /// When linking a framework the headers are in the framework and the library is the
+    /// directory containing it
+    pub fn halide_layout(&self) -> Layout {
+        if let Some(layout) = &self.layout {
+            return layout.clone();
+        }
+
+        let mut layout = Layout::install(&self.halide_path);
+        if let Some(framework) = self.framework() {
+            layout.include = framework.join("Headers");
+            let tools = framework.join("Resources").join("tools");
+            if tools.is_dir() {
+                layout.tools = tools;
+            }
+            layout.lib = framework_dir(&framework);
+        }
+        layout
+    }
+
+    /// The Halide framework, set by `halide_framework` or found in `halide_path` on macOS
+    ///
+    /// `halide_path` may be the framework itself, or contain it directly or in `lib`
+    fn framework(&self) -> Option<PathBuf> {
+        if self.halide_framework.is_some() || !cfg!(target_os = "macos") {
+            return self.halide_framework.clone();
+        }
+
+        if self.halide_path.file_name() == Some("Halide.framework".as_ref()) {
+            return Some(self.halide_path.clone());
+        }
+        [
+            self.halide_path.join("Halide.framework"),
+            self.halide_path.join("lib").join("Halide.framework"),
+        ]
+        .into_iter()
+        .find(|path| path.is_dir())
+    }
+
+    /// How to fix `halide_path` when it doesn't look like an install, see `LayoutKind`
+    ///
+    /// Always `None` when a layout was set
+    pub fn layout_advice(&self) -> Option<String> {
+        if self.layout.is_some() {
+            return None;
+        }
+        LayoutKind::detect(&self.halide_path).advice(&self.halide_path)
+    }
+
+    pub fn compiler(mut self, name: &'a str) -> Self {
+        self.cxx = Some(Cow::Borrowed(name));
+        self
+    }
+
+    /// Try each compiler in order, like `["clang++", "g++-12", "c++"]`, using the first one
+    /// that exists and compiles a test program
+    ///
+    /// The compiler is picked by the first `build` and reused by later builds, it's reported
+    /// using `Event::CompilerSelected`
+    pub fn compilers(mut self, names: &[&'a str]) -> Self {
+        self.compilers = names.to_vec();
+        self
+    }
+
+    pub fn keep(mut self, x: bool) -> Self {
+        self.keep = x;
+        self
+    }
+
+    /// Allow `run` to execute an output that wasn't built by this build
+    pub fn force(mut self, x: bool) -> Self {
+        self.force = x;
+        self
+    }
+
+    pub fn clean_output(mut self, x: bool) -> Self {
+        self.clean_output = x;
+        self
+    }
+
+    pub fn resource_usage(mut self, x: bool) -> Self {
+        self.resource_usage = x;
+        self
+    }
+
+    pub fn capture(mut self, x: bool) -> Self {
+        self.capture = x;
+        self
+    }
+
+    /// Capture the output of the compile and run commands like `capture`, also sending each
+    /// line to `events` as `Event::ChildStdoutLine` or `Event::ChildStderrLine` as soon as
+    /// it's written
+    ///
+    /// Generator output is sent to `events` instead of the terminal. Output of commands
+    /// started under `run_under` isn't captured or sent
+    pub fn tee(mut self, x: bool) -> Self {
+        self.tee = x;
+        self
+    }
+
+    /// Run a command capturing its output, sending it to `events` when `tee` is set
+    fn capture_command(
+        &self,
+        cmd: &mut Command,
+        group: bool,
+        input: Option<&[u8]>,
+    ) -> io::Result<Outcome> {
+        if self.tee {
+            exec::tee(
+                &*self.executor,
+                cmd,
+                &self.interrupt,
+                group,
+                input,
+                &self.events,
+            )
+        } else {
+            exec::capture(&*self.executor, cmd, &self.interrupt, group, input)
+        }
+    }
+
+    /// Fail the run step when its standard output differs from the contents of `golden`
+    pub fn expect_stdout(mut self, golden: impl AsRef<Path>) -> Self {
+        self.expect_stdout = Some(golden::Expect::Golden(golden.as_ref().to_path_buf()));
+        self
+    }
+
+    /// Fail the run step when its standard output doesn't match `pattern`
+    pub fn expect_stdout_regex(mut self, pattern: impl Into<String>) -> Self {
+        self.expect_stdout = Some(golden::Expect::Regex(pattern.into()));
+        self
+    }
+
+    /// Ignore whitespace at the end of lines when checking `expect_stdout`
+    pub fn expect_trim_trailing_whitespace(mut self, x: bool) -> Self {
+        self.expect_normalize.trim_trailing_whitespace = x;
+        self
+    }
+
+    /// Ignore lines matching `pattern` when checking `expect_stdout`, like timestamps
+    pub fn expect_ignore(mut self, pattern: impl Into<String>) -> Self {
+        self.expect_normalize.ignore.push(pattern.into());
+        self
+    }
+
+    pub fn update_golden(mut self, x: bool) -> Self {
+        self.update_golden = x;
+        self
+    }
+
+    pub fn generator(mut self, x: bool) -> Self {
+        self.generator = x;
+        self
+    }
+
+    /// Use `path` as the generator `main` instead of `GenGen.cpp` from the Halide tools
+    pub fn gengen_path(mut self, path: impl AsRef<std::path::Path>) -> Self {
+        self.gengen = Some(path.as_ref().to_owned());
+        self
+    }
+
+    pub fn output_kind(mut self, kind: OutputKind) -> Self {
+        self.output_kind = kind;
+        self
+    }
+
+    pub fn strict_naming(mut self, x: bool) -> Self {
+        self.strict_naming = x;
+        self
+    }
+
+    pub fn strict_compiler(mut self, x: bool) -> Self {
+        self.strict_compiler = x;
+        self
+    }
+
+    /// Rename the output using the platform's convention for `output_kind`, like
+    /// `libfilter.a` for a static library named `filter`, so set the kind first
+    pub fn normalize_output(mut self) -> Self {
+        self.output = naming::normalize(self.output_kind, &self.output, Platform::host());
+        self
+    }
+
+    pub fn language(mut self, language: Language) -> Self {
+        self.language = language;
+        self
+    }
+
+    pub fn stamp(mut self, x: bool) -> Self {
+        self.stamp = x;
+        self
+    }
+
+    pub fn strip(mut self, x: bool) -> Self {
+        self.strip = x;
+        self
+    }
+
+    pub fn link_map(mut self, path: impl AsRef<Path>) -> Self {
+        self.link_map = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn warnings(mut self, level: WarningLevel) -> Self {
+        self.warnings = level;
+        self
+    }
+
+    pub fn warnings_as_errors(mut self, x: bool) -> Self {
+        self.warnings_as_errors = x;
+        self
+    }
+
+    /// Disable RTTI, see `no_rtti` for when it breaks linking
+    pub fn no_rtti(mut self, x: bool) -> Self {
+        self.no_rtti = x;
+        self
+    }
+
+    pub fn no_exceptions(mut self, x: bool) -> Self {
+        self.no_exceptions = x;
+        self
+    }
+
+    /// Enable C++ modules, optionally with a module map, see `modules`
+    pub fn modules(mut self, enable: bool, map_path: Option<&Path>) -> Self {
+        self.modules = enable;
+        self.module_map = map_path.map(Path::to_path_buf);
+        self
+    }
+
+    pub fn static_stdcxx(mut self, x: bool) -> Self {
+        self.static_stdcxx = x;
+        self
+    }
+
+    pub fn atomic_outputs(mut self, x: bool) -> Self {
+        self.atomic_outputs = x;
+        self
+    }
+
+    pub fn structured_diagnostics(mut self, x: bool) -> Self {
+        self.structured_diagnostics = x;
+        self
+    }
+
+    pub fn colorize_diagnostics(mut self, x: bool) -> Self {
+        self.colorize_diagnostics = x;
+        self
+    }
+
+    /// Compile to WebAssembly using Emscripten's `em++`
+    ///
+    /// Executables are written as `<output>.js` and `<output>.wasm`, and are run using
+    /// `node`. The Halide target defaults to `wasm-32-wasmrt`, and the native Halide
+    /// libraries aren't linked, so pipelines should be emitted ahead of time for that target
+    /// and added using `archive`
+    pub fn emscripten(mut self) -> Self {
+        self.emscripten = true;
+        self.cxx = Some(Cow::Borrowed("em++"));
+        if self.target.is_none() {
+            self.target = Some("wasm-32-wasmrt");
+        }
+        if self.output_kind == OutputKind::Executable {
+            self.output.set_extension("js");
+        }
+        self
+    }
+
+    /// Cross-compile for an Android or iOS device using its compiler and sysroot
+    ///
+    /// The Halide target defaults to the device's target and the native Halide libraries
+    /// aren't linked, so pipelines should be emitted ahead of time using `GenBuilder::mobile`
+    /// and added using `archive`. The output can't be run on the host
+    pub fn mobile(mut self, toolchain: &mobile::MobileToolchain) -> Self {
+        self.cxx = Some(Cow::Owned(toolchain.compiler().display().to_string()));
+        if self.target.is_none() {
+            self.target = Some(toolchain.halide_target());
+        }
+        self.mobile = Some(toolchain.clone());
+        self
+    }
+
+    /// Link Halide as a macOS framework, `path` is the `Halide.framework` directory
+    ///
+    /// The headers are used from the framework, and executables find it using `@rpath`
+    pub fn halide_framework(mut self, path: impl AsRef<Path>) -> Self {
+        self.halide_framework = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn incremental(mut self, x: bool) -> Self {
+        self.incremental = x;
+        self
+    }
+
+    pub fn reproducible(mut self, x: bool) -> Self {
+        self.reproducible = x;
+        self
+    }
+
+    pub fn source_date_epoch(mut self, epoch: u64) -> Self {
+        self.source_date_epoch = Some(epoch);
+        self
+    }
+
+    /// Share an interrupt token with other builds
+    pub fn with_interrupt_token(mut self, token: InterruptToken) -> Self {
+        self.interrupt = token;
+        self
+    }
+
+    /// Set a callback to receive events from the build and run steps
+    pub fn on_event(mut self, f: impl Fn(&Event) + Send + Sync + 'static) -> Self {
+        self.events = EventHandler::new(f);
+        self
+    }
+
+    /// Run commands using `executor` instead of starting child processes
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_executor(mut self, executor: impl exec::Executor + 'static) -> Self {
+        self.executor = Arc::new(executor);
+        self
+    }
+
+    /// Run the compile, link and run commands inside `container`, see `container::Container`
+    /// for how paths are shared with it
+    ///
+    /// Set the executor first when using `with_executor`, it's used to start the container
+    pub fn in_container(mut self, container: Container) -> Self {
+        self.executor = Arc::new(container.with_executor(self.executor.clone()));
+        self
+    }
+
+    /// Apply a toolchain description
+    ///
+    /// A pinned compiler replaces the current compiler and the toolchain's target features are
+    /// added, but `cxxflags` and `ldflags` are only used when they haven't already been set
+    pub fn with_toolchain(mut self, toolchain: &'a toolchain::Toolchain) -> Self {
+        if let Some(cxx) = &toolchain.compiler.cxx {
+            self.cxx = Some(Cow::Borrowed(cxx));
+            self.set_provenance("compiler", Provenance::Toolchain);
+        }
+
+        if self.cxxflags.is_none() && toolchain.cxxflags.is_some() {
+            self.cxxflags = toolchain.cxxflags.as_deref().map(Cow::Borrowed);
+            self.set_provenance("cxxflags", Provenance::Toolchain);
+        }
+
+        if self.ldflags.is_none() && toolchain.ldflags.is_some() {
+            self.ldflags = toolchain.ldflags.as_deref().map(Cow::Borrowed);
+            self.set_provenance("ldflags", Provenance::Toolchain);
+        }
+
+        for feature in &toolchain.target_features {
+            if !self.target_features.contains(&feature.as_str()) {
+                self.target_features.push(feature);
+            }
+        }
+        if !toolchain.target_features.is_empty() && self.target.is_none() {
+            self.set_provenance("target", Provenance::Toolchain);
+        }
+
+        self.toolchain = Some(toolchain.clone());
+        self
+    }
+
+    /// Record where a setting came from, for settings reported by `explain`
+    ///
+    /// The provenance only applies while the setting keeps its current value, later changes
+    /// are reported as coming from the builder
+    pub fn set_provenance(&mut self, name: &'static str, provenance: Provenance) {
+        if let Some(value) = self.setting_value(name) {
+            self.provenance.insert(name, (provenance, value));
+        }
+    }
+
+    /// The current value of a setting that tracks provenance, `None` when it isn't set
+    fn setting_value(&self, name: &str) -> Option<String> {
+        match name {
+            "halide_path" => Some(self.halide_path.display().to_string()),
+            "compiler" => self.cxx.as_ref().map(|cxx| cxx.to_string()),
+            "cxxflags" => self.cxxflags.as_ref().map(|flags| flags.to_string()),
+            "ldflags" => self.ldflags.as_ref().map(|flags| flags.to_string()),
+            "target" => self.target_string(),
+            "work_dir" => self.run_cwd.as_ref().map(|dir| dir.display().to_string()),
+            _ => None,
+        }
+    }
+
+    fn provenance_of(&self, name: &str, unset: Provenance) -> Provenance {
+        let value = match self.setting_value(name) {
+            Some(value) => value,
+            None => return unset,
+        };
+
+        match self.provenance.get(name) {
+            Some((provenance, recorded)) if *recorded == value => provenance.clone(),
+            _ => Provenance::Builder,
+        }
+    }
+
+    /// Report every effective setting and where it came from
+    pub fn explain(&self) -> Explanation {
+        let mut explanation = Explanation::default();
+        let from_env = |var: &str| match env::var_os(var) {
+            Some(_) => Provenance::Env(var.to_string()),
+            None => Provenance::Default,
+        };
+
+        let halide = self.provenance_of("halide_path", Provenance::Default);
+        explanation.push(
+            "halide_path",
+            self.halide_path.display().to_string(),
+            halide.clone(),
+        );
+        explanation.push(
+            "compiler",
+            self.compiler_name(),
+            self.provenance_of("compiler", from_env(self.language.compiler_var())),
+        );
+        explanation.push(
+            "std",
+            self.std(),
+            if self.modules {
+                Provenance::Builder
+            } else {
+                Provenance::Default
+            },
+        );
+        explanation.push(
+            "include_dirs",
+            format!(
+                "{} {}",
+                self.halide_layout().include.display(),
+                self.halide_layout().tools.display()
+            ),
+            halide.clone(),
+        );
+        explanation.push(
+            "cxxflags",
+            self.setting_value("cxxflags").unwrap_or_default(),
+            self.provenance_of("cxxflags", Provenance::Default),
+        );
+        explanation.push(
+            "ldflags",
+            self.setting_value("ldflags").unwrap_or_default(),
+            self.provenance_of("ldflags", Provenance::Default),
+        );
+
+        let mut lib_dirs = vec![self.halide_layout().lib.display().to_string()];
+        lib_dirs.extend(self.lib_dirs.iter().map(|dir| dir.display().to_string()));
+        explanation.push(
+            "lib_dirs",
+            lib_dirs.join(" "),
+            if self.lib_dirs.is_empty() {
+                halide
+            } else {
+                Provenance::Builder
+            },
+        );
+
+        let mut libs = self.default_libs();
+        libs.extend(self.libs.iter().map(|lib| format!("-l{}", lib)));
+        explanation.push("libs", libs.join(" "), self.libs_provenance());
+
+        explanation.push(
+            "target",
+            self.target_string().unwrap_or_else(|| "host".to_string()),
+            self.provenance_of("target", Provenance::Default),
+        );
+        explanation.push(
+            "work_dir",
+            self.setting_value("work_dir").unwrap_or_else(|| {
+                env::current_dir()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default()
+            }),
+            self.provenance_of("work_dir", Provenance::Default),
+        );
+        explanation.push(
+            "output",
+            self.output.display().to_string(),
+            Provenance::Builder,
+        );
+        explanation
+    }
+
+    /// Where the libraries linked by `default_libs` and `libs` came from, following the same
+    /// order as `default_libs`
+    fn libs_provenance(&self) -> Provenance {
+        if !self.libs.is_empty() || self.image_libs != ["png", "jpeg"] {
+            return Provenance::Builder;
+        }
+
+        if let (Some(llvm_config), Some(_)) = (&self.llvm_config, self.llvm_libs()) {
+            return match env::var_os("LLVM_CONFIG") {
+                Some(var) if *llvm_config == Path::new(&var) => {
+                    Provenance::Env("LLVM_CONFIG".to_string())
+                }
+                _ => Provenance::Builder,
+            };
+        }
+
+        // Libraries found from the symbols of a static libHalide, or the fallback list, where
+        // only `-lncurses` can be replaced
+        if self.halide_system_libs().is_none() && env::var_os("TERMINFO").is_some() {
+            Provenance::Env("TERMINFO".to_string())
+        } else {
+            Provenance::Default
+        }
+    }
+
+    /// A multi-line description of what `build` and `run` will do
+    ///
+    /// Lists the output, sources and run arguments, the settings from `explain`, and the
+    /// commands from `dump_commands` and the run step, so it can't drift from the build
+    pub fn summary(&self) -> String {
+        let mut s = String::new();
+        let kind = match self.output_kind {
+            OutputKind::Executable => "executable",
+            OutputKind::Object => "object",
+            OutputKind::StaticLibrary => "static library",
+            OutputKind::SharedLibrary => "shared library",
+        };
+        let list = |items: Vec<String>| {
+            if items.is_empty() {
+                "(none)".to_string()
+            } else {
+                items.join(" ")
+            }
+        };
+
+        let _ = writeln!(s, "output:   {} ({})", self.output.display(), kind);
+        let _ = writeln!(
+            s,
+            "language: {}",
+            match self.language {
+                Language::C => "c",
+                Language::Cxx => "c++",
+            }
+        );
+        let _ = writeln!(
+            s,
+            "sources:  {}",
+            list(
+                self.src
+                    .iter()
+                    .map(|src| src.display().to_string())
+                    .collect()
+            )
+        );
+        let _ = writeln!(
+            s,
+            "run args: {}",
+            list(
+                self.run_args
+                    .iter()
+                    .map(|arg| exec::shell_quote(arg))
+                    .chain(
+                        self.file_run_args
+                            .iter()
+                            .map(|arg| exec::shell_quote(arg.as_ref()))
+                    )
+                    .collect()
+            )
+        );
+        let _ = writeln!(s);
+        let _ = write!(s, "{}", self.explain());
+
+        let _ = writeln!(s);
+        let _ = writeln!(s, "commands:");
+        for cmd in self.dump_commands() {
+            let _ = writeln!(s, "  {}", exec::command_line(&cmd));
+        }
+        if self.output_kind == OutputKind::Executable {
+            match self.run_command() {
+                Ok((cmd, _)) => {
+                    let _ = writeln!(s, "  {}", exec::command_line(&cmd));
+                }
+                Err(e) => {
+                    let _ = writeln!(s, "  (run step unavailable: {})", e);
+                }
+            }
+        }
+        s
+    }
+
+    /// Check that the Halide headers and library are from the same build, that libHalide was
+    /// built with a compatible compiler, and that the environment satisfies the toolchain
+    /// pins
+    ///
+    /// Problems are returned as an `io::ErrorKind::InvalidData` error wrapping a
+    /// `version::Stale` or a `toolchain::CheckError`. Compiler mismatches are reported using
+    /// `Event::Warning`, or as an `InvalidData` error when `strict_compiler` is set
+    pub fn check(&self) -> io::Result<()> {
+        version::Installed::read(&self.halide_layout())
+            .check()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.check_compiler()?;
+
+        let toolchain = match &self.toolchain {
+            Some(toolchain) => toolchain,
+            None => return Ok(()),
+        };
+
+        let mismatches =
+            toolchain.mismatches(&*self.executor, &self.compiler_name(), &self.halide_path)?;
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            toolchain::CheckError(mismatches),
+        ))
+    }
+
+    /// Compare the compiler with the one recorded in `halide-build-info.json`, there's nothing
+    /// to compare when Halide wasn't built by `Source::build`
+    fn check_compiler(&self) -> io::Result<()> {
+        let halide = match BuildInfo::read(&self.halide_layout().lib)? {
+            Some(info) => info,
+            None => return Ok(()),
+        };
+
+        let flags = self
+            .cxxflags
+            .iter()
+            .flat_map(|flags| args::split_flags(flags))
+            .chain(
+                self.build_args
+                    .iter()
+                    .filter_map(|arg| arg.to_str())
+                    .map(str::to_string),
+            )
+            .collect();
+        let build = BuildInfo::detect(&*self.executor, &self.compiler_name(), flags);
+        let mismatches = build_info::compare(&halide, &build);
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        if self.strict_compiler {
+            let messages: Vec<String> = mismatches.iter().map(|m| m.to_string()).collect();
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                messages.join(", "),
+            ));
+        }
+        for mismatch in mismatches {
+            self.events.emit(Event::Warning(mismatch.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Require the files used by the build and run steps to be inside `root`
+    pub fn sandbox(mut self, root: impl AsRef<std::path::Path>) -> Self {
+        self.sandbox = Some(root.as_ref().to_path_buf());
+        self
+    }
+
+    /// Check the target string, and that the files used by the build and run steps are
+    /// inside the sandbox when one is set
+    ///
+    /// Called before any process is started by `build` and `run`, errors have kind
+    /// `InvalidInput` and wrap a `validate::Error`
+    pub fn validate(&self) -> io::Result<()> {
+        if let Some(target) = self.target_string() {
+            validate::target(&target)?;
+        }
+
+        let root = match &self.sandbox {
+            Some(root) => root,
+            None => return Ok(()),
+        };
+
+        let stdin = match &self.stdin {
+            StdinSource::File(path) => Some(path),
+            _ => None,
+        };
+        for path in self
+            .src
+            .iter()
+            .chain(self.src_flags.keys())
+            .chain(&self.objects)
+            .chain(&self.archives)
+            .chain([&self.output])
+            .chain(&self.run_cwd)
+            .chain(stdin)
+        {
+            validate::within(root, path)?;
+        }
+        Ok(())
+    }
+
+    /// Get a token that can be used to interrupt the build and run steps from another thread
+    pub fn interrupt_token(&self) -> InterruptToken {
+        self.interrupt.clone()
+    }
+
+    /// Hash of the compiler, flags and inputs used to build the output
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = hash::Fnv::default();
+        for cmd in self.dump_commands() {
+            hasher.write_str(&cmd.get_program().to_string_lossy());
+            for arg in cmd.get_args() {
+                hasher.write_str(&arg.to_string_lossy());
+            }
+            for (key, value) in cmd.get_envs() {
+                hasher.write_str(&key.to_string_lossy());
+                hasher.write_str(&value.unwrap_or_default().to_string_lossy());
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Execute the build step
+    pub fn build(&self) -> io::Result<bool> {
+        self.build_report().map(|report| report.success)
+    }
+
+    /// Execute the build step, skipping it for incremental builds that are up to date
+    pub fn build_report(&self) -> io::Result<BuildReport> {
+        self.built.set(false);
+        self.validate()?;
+        self.check_naming()?;
+        self.write_inline_sources()?;
+        if !self.compilers.is_empty() && self.selected_compiler.get().is_none() {
+            self.select_compiler()?;
+        }
+
+        if self.incremental && self.is_up_to_date() {
+            self.built.set(true);
+            return Ok(BuildReport {
+                success: true,
+                up_to_date: true,
+                dependencies: depfile::read(depfile::path(&self.output))?,
+                resource_usage: None,
+                outcomes: vec![],
+                diagnostics: vec![],
+            });
+        }
+
+        if self.strip {
+            self.check_strip();
+        }
+
+        if self.static_stdcxx && cfg!(target_os = "macos") {
+            self.events.emit(Event::Warning(
+                "static_stdcxx isn't supported on macOS and is ignored".to_string(),
+            ));
+        }
+
+        if cfg!(windows) {
+            self.check_path_lengths();
+        }
+
+        if !self.archs.is_empty() {
+            self.check_archs()?;
+        }
+
+        if self.modules {
+            self.check_modules()?;
+        }
+
+        if self.emscripten {
+            self.check_emscripten()?;
+        }
+
+        if let Some(toolchain) = &self.mobile {
+            toolchain.check()?;
+        }
+
+        for path in self.objects.iter().chain(&self.archives) {
+            if !path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Prebuilt input {} does not exist", path.display()),
+                ));
+            }
+        }
+
+        if self.generator && self.links() && !self.gengen_file().is_file() {
+            let advice = match &self.gengen {
+                None => self.layout_advice(),
+                Some(_) => None,
+            };
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "Generator main {} does not exist, {}",
+                    self.gengen_file().display(),
+                    advice.unwrap_or_else(|| {
+                        "set gengen_path for Halide versions that keep it elsewhere".to_string()
+                    })
+                ),
+            ));
+        }
+
+        if self.clean_output {
+            match remove_file(&self.output) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+
+        let mut resource_usage = None;
+        let mut outcomes = vec![];
+        let mut diagnostics = vec![];
+        let res = self.compile(&mut resource_usage, &mut outcomes, &mut diagnostics);
+        if !self.keep && !self.incremental {
+            for (path, _) in &self.inline_sources {
+                let _ = remove_file(path);
+            }
+        }
+
+        let success = match res {
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {
+                if !self.atomic() {
+                    let _ = remove_file(&self.output);
+                }
+                return Err(e);
+            }
+            res => res?,
+        };
+        if success {
+            self.run_post_build()?;
+        }
+        if success && self.stamp {
+            self.write_stamp()?;
+        }
+        self.built.set(success);
+
+        let dependencies = if success && self.incremental {
+            depfile::read(depfile::path(&self.output))?
+        } else {
+            vec![]
+        };
+
+        Ok(BuildReport {
+            success,
+            up_to_date: false,
+            dependencies,
+            resource_usage: resource_usage.filter(|_| self.resource_usage),
+            outcomes,
+            diagnostics,
+        })
+    }
+
+    /// Parse and type-check the sources with `-fsyntax-only`, without compiling or linking
+    ///
+    /// Sources with extra flags are checked separately, the others are checked together
+    pub fn syntax_check(&self) -> io::Result<bool> {
+        self.validate()?;
+        self.write_inline_sources()?;
+
+        let res = self
+            .syntax_check_commands()
+            .into_iter()
+            .try_fold(true, |success, mut cmd| match success {
+                true => self.status(&mut cmd, &mut None, &mut vec![], &mut vec![]),
+                false => Ok(false),
+            });
+
+        if !self.keep && !self.incremental {
+            for (path, _) in &self.inline_sources {
+                let _ = remove_file(path);
+            }
+        }
+        res
+    }
+
+    /// The commands run by `syntax_check`
+    pub fn syntax_check_commands(&self) -> Vec<Command> {
+        let command = |flags: &[&str], srcs: &[&PathBuf]| {
+            let mut cmd = GroupedCommand::new(self.compiler_command(), ArgGroup::Compiler);
+            cmd.group(ArgGroup::Mode).arg("-fsyntax-only");
+            cmd.group(ArgGroup::Build)
+                .args(&self.build_args)
+                .args(flags);
+            self.add_sources(&mut cmd, srcs);
+            cmd.group(ArgGroup::End)
+                .args(self.raw_args_at(ArgPosition::End));
+            cmd.cmd
+        };
+
+        let (flagged, plain): (Vec<&PathBuf>, Vec<&PathBuf>) = self
+            .src
+            .iter()
+            .partition(|src| self.src_flags.contains_key(*src));
+
+        let mut commands = vec![];
+        if !plain.is_empty() {
+            commands.push(command(&[], &plain));
+        }
+        for src in flagged {
+            commands.push(command(&self.src_flags[src], &[src]));
+        }
+        commands
+    }
+
+    /// Write a Ninja build file that builds the output, instead of building it directly
+    ///
+    /// Inline sources are written, since Ninja reads them from disk
+    pub fn write_ninja(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.validate()?;
+        self.write_inline_sources()?;
+
+        let mut ninja = ninja::NinjaWriter::new(self);
+        ninja.add_build(self);
+        ninja.default(std::slice::from_ref(&self.output));
+        ninja.write(path)
+    }
+
+    /// Ask Cargo to rerun the build script when a source file, or a header listed in the
+    /// dependency file of an incremental build, changes
+    pub fn rerun_if_changed(&self) {
+        let deps = depfile::read(depfile::path(&self.output)).unwrap_or_default();
+        for path in self
+            .src
+            .iter()
+            .chain(deps.iter().filter(|dep| !self.src.contains(dep)))
+        {
+            rerun_if_changed(path);
+        }
+    }
+
+    /// Returns true when the output exists and is newer than its sources and the files listed
+    /// in its dependency file
+    ///
+    /// When `stamp` is set the fingerprint in the stamp file must also match, so changes to
+    /// the compiler or flags cause a rebuild
+    pub fn is_up_to_date(&self) -> bool {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+
+        let output = match modified(&self.output) {
+            Some(time) => time,
+            None => return false,
+        };
+
+        let deps = match depfile::read(depfile::path(&self.output)) {
+            Ok(deps) => deps,
+            Err(_) => return false,
+        };
+
+        if self.stamp {
+            match stamp::read(stamp::path(&self.output)) {
+                Ok(stamp) if stamp.fingerprint == hash::hex(self.fingerprint()) => (),
+                _ => return false,
+            }
+        }
+
+        self.src
+            .iter()
+            .chain(&self.objects)
+            .chain(&self.archives)
+            .chain(&deps)
+            .all(|dep| modified(dep).is_some_and(|time| time <= output))
+    }
+
+    /// Content hash of the output, which can be compared across machines for reproducible builds
+    pub fn output_hash(&self) -> io::Result<String> {
+        hash::file(&self.output).map(hash::hex)
+    }
+
+    /// Write the stamp file for the output, returns true when the stamp changed
+    pub fn write_stamp(&self) -> io::Result<bool> {
+        let stamp = stamp::Stamp {
+            output: self.output.clone(),
+            hash: self.output_hash()?,
+            fingerprint: hash::hex(self.fingerprint()),
+        };
+        stamp::write(stamp::path(&self.output), &stamp)
+    }
+
+    /// The command that produces the output, without running it
+    ///
+    /// When sources are compiled separately this is the final link or archive command, the
+    /// commands compiling each object are returned by `dump_commands`
+    pub fn dump_command(&self) -> Command {
+        self.dump_commands()
+            .pop()
+            .expect("A build always has at least one command")
+    }
+
+    /// Every command run by the build step, in order, without running them
+    pub fn dump_commands(&self) -> Vec<Command> {
+        self.grouped_commands()
+            .into_iter()
+            .map(|grouped| grouped.cmd)
+            .collect()
+    }
+
+    /// Every command run by the build step with its arguments split into groups, showing
+    /// where `raw_args` and the other settings end up
+    pub fn dump_command_groups(&self) -> Vec<CommandGroups> {
+        self.grouped_commands()
+            .iter()
+            .map(GroupedCommand::groups)
+            .collect()
+    }
+
+    fn grouped_commands(&self) -> Vec<GroupedCommand> {
+        if !self.uses_objects() {
+            return vec![self.single_command()];
+        }
+
+        let objects = self.intermediate_objects();
+        let mut commands: Vec<GroupedCommand> = self
+            .object_sources()
+            .into_iter()
+            .zip(&objects)
+            .map(|((src, flags), object)| {
+                let mut cmd = GroupedCommand::new(self.compiler_command(), ArgGroup::Compiler);
+                let mode = cmd.group(ArgGroup::Mode).arg("-c");
+                if self.incremental {
+                    mode.arg("-MMD").arg("-MF").arg(object.with_extension("d"));
+                }
+                cmd.group(ArgGroup::Build)
+                    .args(&self.build_args)
+                    .args(flags);
+                self.add_sources(&mut cmd, [&src]);
+                cmd.group(ArgGroup::Output).arg("-o").arg(object);
+                cmd.group(ArgGroup::End)
+                    .args(self.raw_args_at(ArgPosition::End));
+                cmd
+            })
+            .collect();
+
+        if self.output_kind == OutputKind::StaticLibrary {
+            let mut members = objects;
+            members.extend(self.objects.iter().cloned());
+            commands.push(self.archive_command(&members));
+        } else {
+            let mut cmd = GroupedCommand::new(self.compiler_command(), ArgGroup::Compiler);
+            if self.output_kind == OutputKind::SharedLibrary {
+                cmd.group(ArgGroup::Mode).arg("-shared");
+            }
+            cmd.group(ArgGroup::Build).args(&self.build_args);
+            self.add_sources(
+                &mut cmd,
+                objects.iter().chain(&self.objects).chain(&self.archives),
+            );
+            self.add_output(&mut cmd);
+            commands.push(cmd);
+        }
+
+        commands
+    }
+
+    /// `raw_args` at `position`, in the order they were added
+    fn raw_args_at(&self, position: ArgPosition) -> impl Iterator<Item = &'a str> + '_ {
+        self.raw_args
+            .iter()
+            .filter(move |(p, _)| *p == position)
+            .map(|(_, arg)| *arg)
+    }
+
+    /// Add `inputs` surrounded by the raw arguments placed before and after the sources
+    ///
+    /// C sources without a `.c` extension, like the `.halide_generated.cpp` files emitted by
+    /// `Emit::CSource`, are marked as C, since the compiler picks the language from the
+    /// extension
+    fn add_sources<P: AsRef<std::ffi::OsStr>>(
+        &self,
+        cmd: &mut GroupedCommand,
+        inputs: impl IntoIterator<Item = P>,
+    ) {
+        cmd.group(ArgGroup::BeforeSources)
+            .args(self.raw_args_at(ArgPosition::BeforeSources));
+        let group = cmd.group(ArgGroup::Inputs);
+        for input in inputs {
+            let path = Path::new(input.as_ref());
+            if self.language == Language::C
+                && Language::from_path(path) != Language::C
+                && self.src.iter().any(|src| src == path)
+            {
+                group.arg("-x").arg("c").arg(path).arg("-x").arg("none");
+            } else {
+                group.arg(path);
+            }
+        }
+        cmd.group(ArgGroup::AfterSources)
+            .args(self.raw_args_at(ArgPosition::AfterSources));
+    }
+
+    /// The path commands write the output to, see `atomic_outputs`
+    fn command_output(&self) -> &Path {
+        self.staging.as_deref().unwrap_or(&self.output)
+    }
+
+    fn atomic(&self) -> bool {
+        self.atomic_outputs && !self.emscripten
+    }
+
+    /// Add the output, link arguments and raw arguments placed at the end
+    fn add_output(&self, cmd: &mut GroupedCommand) {
+        cmd.group(ArgGroup::Output)
+            .arg("-o")
+            .arg(self.command_output());
+        self.link_args(cmd.group(ArgGroup::Link));
+        cmd.group(ArgGroup::End)
+            .args(self.raw_args_at(ArgPosition::End));
+    }
+
+    /// Returns true when sources are compiled to objects separately, then linked or archived
+    fn uses_objects(&self) -> bool {
+        // The compiler only writes the dependencies of the last source to a single `-MF` file
+        self.output_kind == OutputKind::StaticLibrary
+            || (self.links()
+                && (!self.src_flags.is_empty()
+                    || (self.incremental && self.object_sources().len() > 1)))
+    }
+
+    /// Returns true when the output is linked, rather than archived or left as an object
+    fn links(&self) -> bool {
+        matches!(
+            self.output_kind,
+            OutputKind::Executable | OutputKind::SharedLibrary
+        )
+    }
+
+    /// Compile and link using a single compiler invocation
+    fn single_command(&self) -> GroupedCommand {
+        let mut cmd = GroupedCommand::new(self.compiler_command(), ArgGroup::Compiler);
+
+        let mode = cmd.group(ArgGroup::Mode);
+        if self.incremental {
+            mode.arg("-MMD").arg("-MF").arg(depfile::path(&self.output));
+        }
+
+        if self.output_kind == OutputKind::Object {
+            mode.arg("-c");
+            let build = cmd.group(ArgGroup::Build).args(&self.build_args);
+            for src in &self.src {
+                if let Some(flags) = self.src_flags.get(src) {
+                    build.args(flags);
+                }
+            }
+            self.add_sources(&mut cmd, &self.src);
+            cmd.group(ArgGroup::Output)
+                .arg("-o")
+                .arg(self.command_output());
+            cmd.group(ArgGroup::End)
+                .args(self.raw_args_at(ArgPosition::End));
+            return cmd;
+        }
+
+        if self.output_kind == OutputKind::SharedLibrary {
+            mode.arg("-shared");
+        }
+
+        cmd.group(ArgGroup::Build).args(&self.build_args);
+
+        // Prebuilt inputs come before the Halide libraries, since pipelines depend on them
+        let gengen = self.generator.then(|| self.gengen_file());
+        self.add_sources(
+            &mut cmd,
+            gengen
+                .iter()
+                .chain(&self.src)
+                .chain(&self.objects)
+                .chain(&self.archives),
+        );
+        self.add_output(&mut cmd);
+        cmd
+    }
+
+    /// Sources that are compiled to separate objects, with their extra flags
+    fn object_sources(&self) -> Vec<(PathBuf, &[&'a str])> {
+        let mut sources: Vec<(PathBuf, &[&'a str])> = self
+            .src
+            .iter()
+            .map(|src| {
+                let flags = self.src_flags.get(src).map(Vec::as_slice).unwrap_or(&[]);
+                (src.clone(), flags)
+            })
+            .collect();
+
+        if self.generator && self.output_kind == OutputKind::Executable {
+            sources.push((self.gengen_file(), &[]));
+        }
+
+        sources
+    }
+
+    /// Paths of the intermediate objects, when sources are compiled separately
+    fn intermediate_objects(&self) -> Vec<PathBuf> {
+        self.object_sources()
+            .iter()
+            .enumerate()
+            .map(|(index, (src, _))| self.object_path(index, src))
+            .collect()
+    }
+
+    /// Run the compile commands, writing to a staging path that replaces the output on
+    /// success when `atomic_outputs` is set
+    fn compile(
+        &self,
+        usage: &mut Option<ResourceUsage>,
+        outcomes: &mut Vec<Outcome>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<bool> {
+        if !self.atomic() {
+            return self.run_commands(usage, outcomes, diagnostics);
+        }
+
+        let mut staging = self.output.as_os_str().to_owned();
+        staging.push(format!(".tmp.{}", std::process::id()));
+        let staged = Build {
+            staging: Some(PathBuf::from(staging)),
+            ..self.clone()
+        };
+        let staging = staged.command_output();
+
+        let result = staged.run_commands(usage, outcomes, diagnostics);
+        if !matches!(result, Ok(true)) {
+            let _ = remove_file(staging);
+            return result;
+        }
+        replace_file(staging, &self.output)?;
+
+        // The compiler names the staging path as the target of a single command's depfile
+        if self.incremental && !self.uses_objects() {
+            let path = depfile::path(&self.output);
+            depfile::write(&path, &self.output, &depfile::read(&path)?)?;
+        }
+        Ok(true)
+    }
+
+    fn run_commands(
+        &self,
+        usage: &mut Option<ResourceUsage>,
+        outcomes: &mut Vec<Outcome>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<bool> {
+        if self.output_kind == OutputKind::StaticLibrary {
+            // `ar r` only replaces members, so start from an empty archive
+            let _ = remove_file(self.command_output());
+        }
+
+        let mut result = Ok(true);
+        for mut cmd in self.dump_commands() {
+            result = self.status(&mut cmd, usage, outcomes, diagnostics);
+            if !matches!(result, Ok(true)) {
+                break;
+            }
+        }
+
+        if self.uses_objects() && self.incremental && matches!(result, Ok(true)) {
+            result = self.merge_depfiles().map(|_| true);
+        }
+
+        if self.uses_objects() && !self.keep {
+            for object in self.intermediate_objects() {
+                let _ = remove_file(object.with_extension("d"));
+                let _ = remove_file(object);
+            }
+        }
+
+        result
+    }
+
+    /// Warn about paths the compiler and linker may be unable to open, since they don't
+    /// support extended-length paths
+    fn check_path_lengths(&self) {
+        let layout = self.halide_layout();
+        for path in self
+            .src
+            .iter()
+            .chain(&self.objects)
+            .chain(&self.archives)
+            .chain([&self.output, &layout.include, &layout.lib])
+        {
+            let path = std::path::absolute(path).unwrap_or_else(|_| path.clone());
+            if winpath::exceeds_max_path(&path) {
+                self.events.emit(Event::Warning(format!(
+                    "{} is longer than {} characters, the compiler may be unable to open it, \
+                     use a shorter directory or enable long paths",
+                    path.display(),
+                    winpath::MAX_PATH
+                )));
+            }
+        }
+    }
+
+    /// Fail unless the compiler supports modules for the language
+    fn check_modules(&self) -> io::Result<()> {
+        let cxx = self.compiler_name();
+        let supported = match self.compiler_family()? {
+            version::CompilerFamily::Clang => true,
+            version::CompilerFamily::Gcc => {
+                self.language == Language::Cxx
+                    && self.compiler_version()?.is_some_and(|v| v.major >= 11)
+            }
+            _ => false,
+        };
+        if supported {
+            return Ok(());
+        }
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "{} doesn't support C++ modules, use Clang or GCC 11 or later",
+                cxx
+            ),
+        ))
+    }
+
+    /// Make sure libHalide contains every requested architecture, since the link would
+    /// otherwise fail for the missing slices
+    fn check_archs(&self) -> io::Result<()> {
+        if !cfg!(target_os = "macos") {
+            self.events.emit(Event::Warning(
+                "arch is only supported on macOS and is ignored".to_string(),
+            ));
+            return Ok(());
+        }
+
+        if !self.links() {
+            return Ok(());
+        }
+
+        let lib = self.halide_layout().lib.join("libHalide.dylib");
+        let output = self
+            .executor
+            .output(Command::new("lipo").arg("-archs").arg(&lib))?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!(
+                "Unable to read architectures of {}",
+                lib.display()
+            )));
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let available: Vec<&str> = stdout.split_whitespace().collect();
+        let missing: Vec<&str> = self
+            .archs
+            .iter()
+            .map(Arch::name)
+            .filter(|arch| !available.contains(arch))
+            .collect();
+        if !missing.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} does not contain {}, build a universal libHalide first",
+                    lib.display(),
+                    missing.join(", ")
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Warn when the output isn't named conventionally for its kind, or fail when
+    /// `strict_naming` is set
+    fn check_naming(&self) -> io::Result<()> {
+        let platform = Platform::host();
+        if self.emscripten
+            || self.mobile.is_some()
+            || naming::is_conventional(self.output_kind, &self.output, platform)
+        {
+            return Ok(());
+        }
+
+        let expected = naming::normalize(self.output_kind, &self.output, platform);
+        let message = format!(
+            "{} is not named like {}, expected {}",
+            self.output.display(),
+            naming::describe(self.output_kind),
+            expected.display()
+        );
+        if self.strict_naming {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        self.events.emit(Event::Warning(message));
+        Ok(())
+    }
+
+    /// Call the post-build hooks in order, stopping at the first error
+    fn run_post_build(&self) -> io::Result<()> {
+        for (index, hook) in self.post_build.iter().enumerate() {
+            let res = match hook {
+                PostBuildHook::Fn(f) => f(&self.output),
+                PostBuildHook::Command(program, args) => {
+                    let output = self.output.to_string_lossy();
+                    let mut cmd = Command::new(program);
+                    cmd.args(args.iter().map(|arg| arg.replace("{output}", &output)));
+                    let status = self
+                        .executor
+                        .status(&mut cmd, &self.interrupt, true, None)?;
+                    match status.success() {
+                        true => Ok(()),
+                        false => Err(io::Error::other(format!(
+                            "`{}` exited with {}",
+                            exec::command_line(&cmd),
+                            status
+                        ))),
+                    }
+                }
+            };
+            res.map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!(
+                        "Post-build hook {} failed for {}: {}",
+                        index,
+                        self.output.display(),
+                        e
+                    ),
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Make sure the Emscripten compiler can be found before building
+    fn check_emscripten(&self) -> io::Result<()> {
+        let cxx = self.compiler_name();
+        let found = if Path::new(&cxx).components().count() > 1 {
+            Path::new(&cxx).is_file()
+        } else {
+            env::var_os("PATH").is_some_and(|path| {
+                env::split_paths(&path).any(|dir| {
+                    dir.join(&cxx).is_file() || dir.join(format!("{}.bat", cxx)).is_file()
+                })
+            })
+        };
+
+        if !found {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} not found in PATH, install Emscripten and run `source emsdk_env.sh`",
+                    cxx
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Warn when `strip` is set but has no effect, or removes requested debug info
+    fn check_strip(&self) {
+        if self.output_kind != OutputKind::Executable {
+            self.events.emit(Event::Warning(
+                "strip only applies to executables and is ignored".to_string(),
+            ));
+            return;
+        }
+
+        let debug = self
+            .cxxflags
+            .iter()
+            .flat_map(|flags| args::split_flags(flags))
+            .chain(
+                self.build_args
+                    .iter()
+                    .filter_map(|arg| arg.to_str())
+                    .map(str::to_string),
+            )
+            .any(|flag| flag.starts_with("-g") && flag != "-g0");
+        if debug {
+            self.events.emit(Event::Warning(
+                "strip removes the debug info requested using -g".to_string(),
+            ));
+        }
+    }
+
+    /// Combine the dependency files of each object into `<output>.d`
+    fn merge_depfiles(&self) -> io::Result<()> {
+        let mut deps: Vec<PathBuf> = vec![];
+        for object in self.intermediate_objects() {
+            for dep in depfile::read(object.with_extension("d"))? {
+                if !deps.contains(&dep) {
+                    deps.push(dep);
+                }
+            }
+        }
+
+        depfile::write(depfile::path(&self.output), &self.output, &deps)
+    }
+
+    /// Run a compile command, adding its resource usage to `usage`
+    ///
+    /// When `capture` is set the output is added to `outcomes` instead, and when
+    /// `structured_diagnostics` is set the diagnostics in it are sent as events and added to
+    /// `diagnostics`
+    fn status(
+        &self,
+        cmd: &mut Command,
+        usage: &mut Option<ResourceUsage>,
+        outcomes: &mut Vec<Outcome>,
+        diagnostics: &mut Vec<Diagnostic>,
+    ) -> io::Result<bool> {
+        if self.capture || self.tee || self.structured_diagnostics {
+            let outcome = self.capture_command(cmd, true, None)?;
+            let success = outcome.success();
+            if self.structured_diagnostics {
+                let found = diagnostics::parse_compiler(&String::from_utf8_lossy(&outcome.stderr));
+                for diagnostic in &found {
+                    self.events.emit(Event::Diagnostic(diagnostic.clone()));
+                }
+                diagnostics.extend(found);
+            }
+            if self.capture || self.tee {
+                outcomes.push(outcome);
+            }
+            return Ok(success);
+        }
+
+        let (status, used) = self
+            .executor
+            .status_with_usage(cmd, &self.interrupt, true, None)?;
+        exec::add_usage(usage, used);
+        exec::check_killed(cmd, status, usage.as_ref())?;
+        Ok(status.success())
+    }
+
+    /// The language standard, C++20 when `modules` is set
+    fn std(&self) -> &'static str {
+        match self.language {
+            Language::Cxx if self.modules => "c++20",
+            language => language.std(),
+        }
+    }
+
+    /// Flags enabling modules, nothing is added for compilers without module support since
+    /// `check_modules` fails the build for them
+    fn module_args(&self, cmd: &mut Command) {
+        let (flag, map_flag) = match self.compiler_family() {
+            Ok(version::CompilerFamily::Clang) => ("-fmodules", "-fmodule-map-file="),
+            Ok(version::CompilerFamily::Gcc) => ("-fmodules-ts", "-fmodule-mapper="),
+            _ => return,
+        };
+        cmd.arg(flag);
+        if let Some(map) = &self.module_map {
+            let mut arg = OsString::from(map_flag);
+            arg.push(map);
+            cmd.arg(arg);
+        }
+    }
+
+    /// Pick the first compiler from `compilers` that compiles an empty program
+    fn select_compiler(&self) -> io::Result<()> {
+        let ext = match self.language {
+            Language::Cxx => "cpp",
+            Language::C => "c",
+        };
+        let dir = env::temp_dir();
+        let base = format!("halide-build-probe-{}", std::process::id());
+        let source = dir.join(format!("{}.{}", base, ext));
+        let object = dir.join(format!("{}.o", base));
+        std::fs::write(&source, "int main(void) { return 0; }\n")?;
+
+        let mut rejected = vec![];
+        for compiler in &self.compilers {
+            let mut cmd = Command::new(compiler);
+            cmd.arg(format!("-std={}", self.std()))
+                .arg("-c")
+                .arg(&source)
+                .arg("-o")
+                .arg(&object);
+            match self.executor.output(&mut cmd) {
+                Ok(output) if output.status.success() => {
+                    let _ = remove_file(&source);
+                    let _ = remove_file(&object);
+                    let _ = self.selected_compiler.set(compiler.to_string());
+                    self.events.emit(Event::CompilerSelected {
+                        compiler: compiler.to_string(),
+                        rejected,
+                    });
+                    return Ok(());
+                }
+                Ok(output) => {
+                    let stderr = String::from_utf8_lossy(&output.stderr);
+                    let reason = stderr.lines().next().unwrap_or("failed").to_string();
+                    rejected.push((compiler.to_string(), reason));
+                }
+                Err(e) => rejected.push((compiler.to_string(), e.to_string())),
+            }
+        }
+        let _ = remove_file(&source);
+
+        let reasons: Vec<String> = rejected
+            .iter()
+            .map(|(compiler, reason)| format!("{}: {}", compiler, reason))
+            .collect();
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No usable compiler found ({})", reasons.join(", ")),
+        ))
+    }
+
+    /// The compiler, the one picked from `compilers` when set, defaulting to `CXX` or `c++`,
+    /// or `CC` or `cc` for C builds
+    fn compiler_name(&self) -> String {
+        if let Some(compiler) = self.selected_compiler.get() {
+            return compiler.clone();
+        }
+        if let Some(compiler) = self.compilers.first() {
+            return compiler.to_string();
+        }
+        match &self.cxx {
+            Some(cxx) => cxx.to_string(),
+            None => env::var(self.language.compiler_var())
+                .unwrap_or_else(|_| self.language.default_compiler().to_string()),
+        }
+    }
+
+    /// The output of `<compiler> --version`, run using the executor
+    ///
+    /// The compiler is only run once, clones of the build share the result
+    fn compiler_version_output(&self) -> io::Result<String> {
+        let cxx = self.compiler_name();
+        let mut cache = self.compiler_version_output.lock().unwrap();
+        if let Some((compiler, output)) = &*cache {
+            if *compiler == cxx {
+                return Ok(output.clone());
+            }
+        }
+
+        let output = version::version_output(&*self.executor, &cxx)?;
+        *cache = Some((cxx, output.clone()));
+        Ok(output)
+    }
+
+    /// The compiler's family, from its name when it's recognized, otherwise from its
+    /// `--version` output
+    fn compiler_family(&self) -> io::Result<version::CompilerFamily> {
+        match version::CompilerFamily::from_name(&self.compiler_name()) {
+            version::CompilerFamily::Unknown => Ok(version::CompilerFamily::from_version_output(
+                &self.compiler_version_output()?,
+            )),
+            family => Ok(family),
+        }
+    }
+
+    /// The compiler's version, from its `--version` output
+    fn compiler_version(&self) -> io::Result<Option<version::Version>> {
+        Ok(version::compiler_version_from_output(
+            &self.compiler_version_output()?,
+        ))
+    }
+
+    /// Create a compiler command with the language standard, include paths, warning flags and
+    /// `cxxflags`
+    fn compiler_command(&self) -> Command {
+        let mut cmd = Command::new(self.compiler_name());
+
+        cmd.arg(format!("-std={}", self.std()));
+        if self.modules {
+            self.module_args(&mut cmd);
+        }
+
+        if self.colorize_diagnostics && env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) {
+            match self.compiler_family() {
+                Ok(version::CompilerFamily::Clang) => {
+                    cmd.arg("-fcolor-diagnostics");
+                }
+                Ok(version::CompilerFamily::Gcc) => {
+                    cmd.arg("-fdiagnostics-color=always");
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(toolchain) = &self.mobile {
+            cmd.args(toolchain.args());
+        } else if cfg!(target_os = "macos") {
+            for arch in &self.archs {
+                cmd.arg("-arch").arg(arch.name());
+            }
+        }
+
+        let layout = self.halide_layout();
+        cmd.arg("-I")
+            .arg(layout.include)
+            .arg("-I")
+            .arg(layout.tools)
+            .args(self.image_flags().compile_args());
+        // For `#include <Halide/Halide.h>`
+        if let Some(framework) = self.framework() {
+            cmd.arg("-F").arg(framework_dir(&framework));
+        }
+
+        if self.output_kind == OutputKind::SharedLibrary {
+            cmd.arg("-fPIC");
+        }
+
+        // Before `cxxflags`, so the warnings can be adjusted there
+        if self.warnings != WarningLevel::Default || self.warnings_as_errors {
+            let family = self
+                .compiler_family()
+                .unwrap_or(version::CompilerFamily::Unknown);
+            let halide = version::header_version(self.halide_layout().include);
+            cmd.args(warnings::flags(
+                self.warnings,
+                family,
+                self.warnings_as_errors,
+                halide,
+            ));
+        }
+
+        if self.structured_diagnostics {
+            let family = self
+                .compiler_family()
+                .unwrap_or(version::CompilerFamily::Unknown);
+            let version = || self.compiler_version().ok().flatten();
+            if let Some(flag) = diagnostics::json_flag(family, version) {
+                cmd.arg(flag);
+            }
+        }
+
+        if self.language == Language::Cxx {
+            if self.no_rtti {
+                cmd.arg("-fno-rtti");
+            }
+            if self.no_exceptions {
+                cmd.arg("-fno-exceptions");
+            }
+        }
+
+        if let Some(flags) = &self.cxxflags {
+            cmd.args(args::split_flags(flags));
+        }
+
+        if self.reproducible {
+            self.reproducible_args(&mut cmd);
+        }
+
+        cmd
+    }
+
+    /// Create an `ar` command archiving `objects` into the output, `AR` may be used to select
+    /// the archiver
+    fn archive_command(&self, objects: &[PathBuf]) -> GroupedCommand {
+        let mut cmd = Command::new(env::var("AR").unwrap_or_else(|_| "ar".to_string()));
+        cmd.arg(if self.reproducible { "rcsD" } else { "rcs" });
+        let mut cmd = GroupedCommand::new(cmd, ArgGroup::Mode);
+        cmd.group(ArgGroup::Output).arg(self.command_output());
+        cmd.group(ArgGroup::Inputs).args(objects);
+        cmd
+    }
+
+    fn source_date_epoch_value(&self) -> String {
+        match self.source_date_epoch {
+            Some(epoch) => epoch.to_string(),
+            None => env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| "0".to_string()),
+        }
+    }
+
+    /// Add flags and environment that keep build paths and timestamps out of the output
+    fn reproducible_args(&self, cmd: &mut Command) {
+        if let Ok(dir) = env::current_dir() {
+            let dir = dir.to_string_lossy();
+            cmd.arg(format!("-ffile-prefix-map={}=.", dir))
+                .arg(format!("-fdebug-prefix-map={}=.", dir));
+        }
+
+        let mut seed = hash::Fnv::default();
+        seed.write_str(
+            &self
+                .output
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+        );
+        cmd.arg(format!("-frandom-seed={}", hash::hex(seed.finish())));
+        cmd.env("SOURCE_DATE_EPOCH", self.source_date_epoch_value());
+    }
+
+    /// Flags for the image libraries, found using pkg-config when `pkg_config` is set
+    ///
+    /// pkg-config is only run once for each set of libraries, clones of the build share the
+    /// result
+    fn image_flags(&self) -> pkg_config::Flags {
+        if self.image_libs.is_empty() || self.emscripten || self.mobile.is_some() {
+            return pkg_config::Flags::default();
+        }
+
+        if !self.pkg_config {
+            return pkg_config::Flags {
+                libs: self.image_libs.iter().map(|lib| lib.to_string()).collect(),
+                ..pkg_config::Flags::default()
+            };
+        }
+
+        let mut cache = self.image_flags.lock().unwrap();
+        if let Some((libs, flags)) = &*cache {
+            if *libs == self.image_libs {
+                return flags.clone();
+            }
+        }
+
+        let flags = pkg_config::find(&*self.executor, &self.image_libs);
+        let libs = self.image_libs.iter().map(|lib| lib.to_string()).collect();
+        *cache = Some((libs, flags.clone()));
+        flags
+    }
+
+    /// Libraries linked with every executable
+    ///
+    /// The system libraries needed by a static libHalide are reported by `llvm_config` when
+    /// it's set, or found by `halide_system_libs`, otherwise `-lncurses`, which `TERMINFO`
+    /// may replace, `-ldl` and `-lz` are linked
+    fn default_libs(&self) -> Vec<String> {
+        let mut libs = match self.framework() {
+            Some(_) => vec!["-framework".to_string(), "Halide".to_string()],
+            None => vec!["-lHalide".to_string()],
+        };
+        libs.extend(self.image_flags().link_args());
+        libs.push("-lpthread".to_string());
+        if let Some(llvm) = self.llvm_libs() {
+            libs.extend(llvm.into_iter().filter(|lib| lib != "-lpthread"));
+            return libs;
+        }
+        match self.halide_system_libs() {
+            Some(system) => libs.extend(
+                system
+                    .iter()
+                    .filter(|lib| *lib != "pthread")
+                    .map(|lib| format!("-l{}", lib)),
+            ),
+            None => libs.extend([
+                std::env::var("TERMINFO").unwrap_or_else(|_| "-lncurses".to_string()),
+                "-ldl".to_string(),
+                "-lz".to_string(),
+            ]),
+        }
+        libs
+    }
+
+    /// The static libHalide, `None` when there's a shared library, which the linker prefers
+    fn static_halide(&self) -> Option<PathBuf> {
+        let lib = self.halide_layout().lib;
+        let archive = lib.join("libHalide.a");
+        let shared = std::fs::read_dir(&lib)
+            .ok()?
+            .filter_map(Result::ok)
+            .any(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                name.starts_with("libHalide.so")
+                    || (name.starts_with("libHalide.") && name.ends_with(".dylib"))
+            });
+        (!shared && archive.exists()).then_some(archive)
+    }
+
+    /// The flags reported by `llvm_config` for a static libHalide, see
+    /// `system_libs::llvm_config_libs`
+    ///
+    /// `None` when it isn't set, libHalide is shared or `llvm-config` fails, which is reported
+    /// as a warning. `llvm-config` is only run once, clones of the build share the result
+    fn llvm_libs(&self) -> Option<Vec<String>> {
+        let llvm_config = self.llvm_config.as_ref()?;
+        self.static_halide()?;
+        let mut cache = self.llvm_libs.lock().unwrap();
+        if let Some((path, libs)) = &*cache {
+            if path == llvm_config {
+                return libs.clone();
+            }
+        }
+
+        let libs = match system_libs::llvm_config_libs(&*self.executor, llvm_config) {
+            Ok(libs) => Some(libs),
+            Err(e) => {
+                self.events.emit(Event::Warning(format!(
+                    "{}, finding the system libraries from the symbols of libHalide",
+                    e
+                )));
+                None
+            }
+        };
+        *cache = Some((llvm_config.clone(), libs.clone()));
+        libs
+    }
+
+    /// The system libraries needed by a static libHalide, see `system_libs`
+    ///
+    /// `None` when libHalide is shared or its symbols can't be listed. `nm` is only run once
+    /// for each set of hints, clones of the build share the result
+    fn halide_system_libs(&self) -> Option<Vec<String>> {
+        let archive = self.static_halide()?;
+        let mut cache = self.system_libs.lock().unwrap();
+        if let Some((hints, libs)) = &*cache {
+            if *hints == self.symbol_lib_hints {
+                return libs.clone();
+            }
+        }
+
+        let libs = self.find_system_libs(&archive);
+        *cache = Some((self.symbol_lib_hints.clone(), libs.clone()));
+        libs
+    }
+
+    fn find_system_libs(&self, archive: &Path) -> Option<Vec<String>> {
+        let symbols = match system_libs::archive_symbols(&*self.executor, archive) {
+            Some(symbols) => symbols,
+            None => {
+                self.events.emit(Event::Warning(format!(
+                    "Unable to list the symbols of {}, linking the default system libraries",
+                    archive.display()
+                )));
+                return None;
+            }
+        };
+
+        let mut hints: Vec<(String, String)> = system_libs::DEFAULT_HINTS
+            .iter()
+            .map(|(prefix, lib)| (prefix.to_string(), lib.to_string()))
+            .collect();
+        hints.extend(self.symbol_lib_hints.iter().cloned());
+
+        let compiler = self.compiler_name();
+        let libs = system_libs::resolve(&symbols, &hints)
+            .into_iter()
+            .filter_map(|alternatives| match &alternatives[..] {
+                [lib] => Some(lib.clone()),
+                _ => alternatives
+                    .iter()
+                    .find(|lib| system_libs::library_exists(&*self.executor, &compiler, lib))
+                    .or(alternatives.first())
+                    .cloned(),
+            })
+            .collect();
+        Some(libs)
+    }
+
+    /// Add the Halide libraries and `ldflags` to a link command
+    fn link_args(&self, cmd: &mut Command) {
+        // The Halide libraries are built for the host, so they can't be linked into
+        // WebAssembly or a device binary
+        if let Some(toolchain) = &self.mobile {
+            cmd.args(toolchain.libs());
+        } else if let Some(framework) = self.framework() {
+            // Frameworks are installed as `@rpath/Halide.framework/...`
+            let dir = framework_dir(&framework);
+            cmd.arg("-F")
+                .arg(&dir)
+                .args(["-Xlinker", "-rpath", "-Xlinker"])
+                .arg(&dir)
+                .args(self.default_libs());
+        } else if !self.emscripten {
+            cmd.arg("-L")
+                .arg(self.halide_layout().lib)
+                .args(self.default_libs());
+        }
+
+        for dir in &self.lib_dirs {
+            cmd.arg("-L").arg(dir);
+        }
+
+        for lib in &self.libs {
+            cmd.arg(format!("-l{}", lib));
+        }
+
+        for framework in &self.frameworks {
+            cmd.arg("-framework").arg(framework);
+        }
+
+        if let Some(flags) = &self.ldflags {
+            cmd.args(args::split_flags(flags));
+        }
+
+        if self.static_stdcxx
+            && !cfg!(target_os = "macos")
+            && !self.emscripten
+            && version::CompilerFamily::from_name(&self.compiler_name())
+                != version::CompilerFamily::Msvc
+        {
+            cmd.arg("-static-libstdc++").arg("-static-libgcc");
+        }
+
+        if let Some(path) = &self.link_map {
+            self.link_map_args(cmd, path);
+        }
+
+        if self.strip && self.output_kind == OutputKind::Executable {
+            // Apple's linker doesn't support `-s`
+            if cfg!(target_os = "macos") {
+                cmd.arg("-Wl,-S,-x");
+            } else {
+                cmd.arg("-s");
+            }
+        }
+    }
+
+    /// Ask the linker to write a map file to `path`
+    ///
+    /// `-Xlinker` passes the path as a separate argument, since `-Wl,` would split it at
+    /// commas
+    fn link_map_args(&self, cmd: &mut Command, path: &Path) {
+        let family = version::CompilerFamily::from_name(&self.compiler_name());
+        if self.emscripten || family == version::CompilerFamily::Msvc {
+            self.events.emit(Event::Warning(
+                "Linker maps are only supported by GNU ld, lld and Apple's linker".to_string(),
+            ));
+            return;
+        }
+
+        // Apple's linker spells the option in lowercase
+        let option = if cfg!(target_os = "macos") {
+            "-map"
+        } else {
+            "-Map"
+        };
+        cmd.args(["-Xlinker", option, "-Xlinker"]).arg(path);
+    }
+
+    /// The source of the generator `main`, see `gengen`
+    pub fn gengen_file(&self) -> PathBuf {
+        self.gengen
+            .clone()
+            .unwrap_or_else(|| self.halide_layout().tools.join("GenGen.cpp"))
+    }
+
+    /// Path of the intermediate object file for the source at `index`
+    fn object_path(&self, index: usize, src: &Path) -> PathBuf {
+        let name = self
+            .output
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let stem = src.file_stem().unwrap_or_default().to_string_lossy();
+        self.output
+            .with_file_name(format!("{}.{}.{}.o", name, index, stem))
+    }
+
+    /// Execute the run step
+    ///
+    /// Fails unless the output was built successfully by `build`, set `force` to run an
+    /// existing output
+    pub fn run(&self) -> io::Result<bool> {
+        self.run_report().map(|report| report.success)
+    }
+
+    /// Execute the run step, see `run`
+    pub fn run_report(&self) -> io::Result<RunReport> {
+        self.validate()?;
+        if let Some(toolchain) = &self.mobile {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} is built for {} and can't run on the host, copy it to a device instead",
+                    self.output.display(),
+                    toolchain.halide_target()
+                ),
+            ));
+        }
+        if !self.force && !self.built.get() {
+            return Err(io::Error::other(format!(
+                "{} was not built successfully, build it first or set force to run it anyway",
+                self.output.display()
+            )));
+        }
+
         if !self.output.exists() {
-            return Ok(false);
+            return Ok(RunReport::default());
+        }
+
+        let hook = match &self.pre_run {
+            Some(hook) => hook.call(&self.output),
+            None => Ok(()),
+        };
+
+        let wants_capture = self.capture || self.tee || self.expect_stdout.is_some();
+        if wants_capture && !self.run_under.is_empty() {
+            self.events.emit(Event::Warning(format!(
+                "Output isn't captured or checked when running under {}",
+                self.run_under.join(" ")
+            )));
         }
+        let capture = wants_capture && self.run_under.is_empty();
 
-        let res = Command::new(&self.output)
-            .args(&self.run_args)
-            .env("LD_LIBRARY_PATH", self.halide_path.join("lib"))
-            .status()
-            .map(|status| status.success());
+        let outputs_before: Vec<Option<SystemTime>> = self
+            .run_output_paths()
+            .iter()
+            .map(|path| path.metadata().and_then(|m| m.modified()).ok())
+            .collect();
+
+        // The executable stays in the terminal's process group, since it may be interactive
+        let res = hook
+            .and_then(|_| self.run_command())
+            .and_then(|(mut cmd, input)| {
+                if capture {
+                    let outcome = self.capture_command(&mut cmd, false, input)?;
+                    if let (Some(expect), true) = (&self.expect_stdout, outcome.success()) {
+                        golden::check(
+                            expect,
+                            &self.expect_normalize,
+                            &outcome.stdout,
+                            self.update_golden,
+                        )?;
+                    }
+                    return Ok(RunReport {
+                        success: outcome.success(),
+                        outcome: Some(outcome),
+                        ..RunReport::default()
+                    });
+                }
+
+                // Generator output is captured to report its diagnostics
+                if self.generator && self.run_under.is_empty() {
+                    let tee = self.tee.then_some(&self.events);
+                    return diagnostics::status(
+                        &*self.executor,
+                        &mut cmd,
+                        &self.interrupt,
+                        false,
+                        input,
+                        tee,
+                    )
+                    .map(|success| RunReport {
+                        success,
+                        ..RunReport::default()
+                    });
+                }
+
+                let (status, usage) =
+                    self.executor
+                        .status_with_usage(&mut cmd, &self.interrupt, false, input)?;
+                exec::check_killed(&cmd, status, usage.as_ref())?;
+                Ok(RunReport {
+                    success: status.success(),
+                    resource_usage: usage.filter(|_| self.resource_usage),
+                    ..RunReport::default()
+                })
+            })
+            .and_then(|report| {
+                if !report.success || self.run_outputs.is_empty() {
+                    return Ok(report);
+                }
+                Ok(RunReport {
+                    outputs: self.check_run_outputs(&outputs_before)?,
+                    ..report
+                })
+            });
 
         if !self.keep {
             let _ = remove_file(&self.output);
+            if self.emscripten {
+                let _ = remove_file(self.output.with_extension("wasm"));
+            }
         }
 
         res
     }
+
+    /// Run the built generator without `-g` and parse the names of its registered
+    /// generators from the error it prints
+    ///
+    /// The generator must already be built, fails with `io::ErrorKind::InvalidData` when the
+    /// list can't be found in its output
+    pub fn list_generators(&self) -> io::Result<Vec<String>> {
+        if !self.output.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist, build it first", self.output.display()),
+            ));
+        }
+
+        let mut cmd = Command::new(std::path::absolute(&self.output)?);
+        cmd.arg("-o").arg(env::temp_dir());
+        self.run_env().apply(&mut cmd);
+        let output = self.executor.output(&mut cmd)?;
+
+        let text =
+            String::from_utf8_lossy(&output.stderr) + String::from_utf8_lossy(&output.stdout);
+        gen::parse_generator_list(&text).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Unable to find the list of generators in the output of {}",
+                    self.output.display()
+                ),
+            )
+        })
+    }
+
+    /// The environment used to run the output
+    ///
+    /// Includes the Halide library directory and extra library directories, `run_paths`,
+    /// `HL_JIT_TARGET` for JIT programs when a target is set, and `run_vars`
+    pub fn run_env(&self) -> RunEnv {
+        // Paths are made absolute, since the working directory may be changed by `run_cwd`
+        let absolute = |path: PathBuf| std::path::absolute(&path).unwrap_or(path);
+
+        let mut run_env = RunEnv::new().lib_path(absolute(self.halide_layout().lib));
+        for dir in &self.lib_dirs {
+            run_env = run_env.lib_path(absolute(dir.clone()));
+        }
+        for dir in &self.run_paths {
+            run_env = run_env.exe_path(absolute(dir.clone()));
+        }
+
+        // For programs linked without the rpath, like prebuilt generators
+        if let Some(framework) = self.framework() {
+            let dir = absolute(framework_dir(&framework));
+            run_env = run_env.var("DYLD_FRAMEWORK_PATH", dir.display().to_string());
+        }
+
+        if let Some(target) = self.target_string() {
+            if !self.generator {
+                run_env = run_env.var("HL_JIT_TARGET", target);
+            }
+        }
+
+        for (name, value) in &self.run_vars {
+            run_env = run_env.var(name, value);
+        }
+        run_env
+    }
+
+    /// A command starting `program` under `run_under`
+    pub(crate) fn wrapped_command(&self, program: impl AsRef<std::ffi::OsStr>) -> Command {
+        match self.run_under.split_first() {
+            Some((wrapper, args)) => {
+                let mut cmd = Command::new(wrapper);
+                cmd.args(args).arg(program);
+                cmd
+            }
+            None => Command::new(program),
+        }
+    }
+
+    /// The command used to run the executable, and any bytes to write to its input
+    fn run_command(&self) -> io::Result<(Command, Option<&[u8]>)> {
+        // Relative paths would be resolved against `run_cwd` by the child
+        let output = std::path::absolute(&self.output)?;
+        let mut cmd = if self.emscripten {
+            let mut cmd = self.wrapped_command("node");
+            cmd.arg(output);
+            cmd
+        } else {
+            self.wrapped_command(output)
+        };
+        cmd.args(&self.run_args).args(&self.file_run_args);
+        self.run_env().apply(&mut cmd);
+
+        if let Some(cwd) = &self.run_cwd {
+            cmd.current_dir(cwd);
+        }
+
+        let input = match &self.stdin {
+            StdinSource::Inherit => None,
+            StdinSource::Null => {
+                cmd.stdin(Stdio::null());
+                None
+            }
+            StdinSource::File(path) => {
+                cmd.stdin(File::open(path)?);
+                None
+            }
+            StdinSource::Bytes(bytes) => {
+                cmd.stdin(Stdio::piped());
+                Some(bytes.as_slice())
+            }
+        };
+
+        if let Some(target) = self.target_string() {
+            let has_target = self
+                .run_args
+                .iter()
+                .filter_map(|arg| arg.to_str())
+                .chain(self.file_run_args.iter().map(String::as_str))
+                .any(|arg| arg.starts_with("target="));
+            if self.generator && !has_target {
+                cmd.arg(format!("target={}", target));
+            }
+        }
+
+        if self.nice.is_some() || !self.cpu_affinity.is_empty() {
+            if cfg!(target_os = "linux") {
+                process::set_scheduling(&mut cmd, self.nice, &self.cpu_affinity)?;
+            } else {
+                self.events.emit(Event::Warning(
+                    "nice and cpu_affinity are only supported on Linux".to_string(),
+                ));
+            }
+        }
+
+        Ok((cmd, input))
+    }
 }
 
 /// Source is used to maintain the Halide source directory
@@ -240,37 +3735,398 @@ pub struct Source {
     pub branch: String,
     pub make: String,
     pub make_flags: Vec<String>,
+
+    /// Only fetch the most recent commit when downloading
+    pub shallow: bool,
+
+    /// Stash local changes before updating instead of failing
+    pub stash: bool,
+
+    /// Number of parallel build jobs, defaults to the number of available cores
+    pub jobs: Option<usize>,
+
+    /// `llvm-config` used to find LLVM, defaults to `LLVM_CONFIG` or `llvm-config`
+    pub llvm_config: Option<PathBuf>,
+
+    /// Patches applied in order after downloading, see `apply_patches`
+    pub patches: Vec<PathBuf>,
+
+    /// Source control backend, detected automatically when `None`
+    pub scm: Option<Box<dyn scm::ScmBackend>>,
+
+    /// Used to interrupt the Halide build
+    pub interrupt: InterruptToken,
+
+    /// Receives `Event::SourceProgress` for each line of build output and `Event::SourceTick`
+    /// while a step is running, output is passed straight through when no callback is set
+    pub events: EventHandler,
+
+    /// Also send each line of build output to `events` as `Event::ChildStdoutLine` or
+    /// `Event::ChildStderrLine`, including standard error, which is otherwise passed through
+    pub tee: bool,
+
+    /// Runs the Halide build
+    pub(crate) executor: Arc<dyn exec::Executor>,
 }
 
 impl Source {
-    /// Download Halide source for the first time
+    /// Create a new source for the main branch of the upstream Halide repository
+    pub fn new<P: AsRef<std::path::Path>>(halide_path: P) -> Source {
+        Source {
+            halide_path: winpath::strip_verbatim(halide_path),
+            repo: "https://github.com/halide/halide".to_string(),
+            branch: "main".to_string(),
+            make: "make".to_string(),
+            make_flags: vec![],
+            shallow: false,
+            stash: false,
+            jobs: None,
+            llvm_config: None,
+            patches: vec![],
+            scm: None,
+            interrupt: InterruptToken::new(),
+            events: EventHandler::default(),
+            tee: false,
+            executor: Arc::new(exec::System),
+        }
+    }
+
+    /// Run the Halide build using `executor` instead of starting child processes
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_executor(mut self, executor: impl exec::Executor + 'static) -> Self {
+        self.executor = Arc::new(executor);
+        self
+    }
+
+    fn with_scm<T>(&self, f: impl FnOnce(&dyn scm::ScmBackend) -> io::Result<T>) -> io::Result<T> {
+        match &self.scm {
+            Some(backend) => f(backend.as_ref()),
+            None => f(scm::detect(self.executor.clone(), &self.interrupt)?.as_ref()),
+        }
+    }
+
+    /// Run `f`, emitting `Event::SourceTick` every `SOURCE_TICK` until it returns
+    fn with_ticks<T>(&self, step: SourceStep, f: impl FnOnce() -> T) -> T {
+        if !self.events.is_set() {
+            return f();
+        }
+
+        let start = Instant::now();
+        let events = &self.events;
+        let (done, ticks) = mpsc::channel::<()>();
+        std::thread::scope(|scope| {
+            scope.spawn(move || {
+                while let Err(mpsc::RecvTimeoutError::Timeout) = ticks.recv_timeout(SOURCE_TICK) {
+                    events.emit(Event::SourceTick {
+                        step,
+                        elapsed: start.elapsed(),
+                    });
+                }
+            });
+
+            let res = f();
+            drop(done);
+            res
+        })
+    }
+
+    /// Download Halide source for the first time, then apply `patches`
     pub fn download(&self) -> io::Result<bool> {
-        Command::new("git")
-            .arg("clone")
-            .args(&["-b", self.branch.as_str()])
-            .arg(&self.repo)
-            .arg(&self.halide_path)
-            .status()
-            .map(|status| status.success())
+        let success = self.with_ticks(SourceStep::Download, || {
+            self.with_scm(|scm| {
+                scm.clone_repo(&self.repo, &self.branch, &self.halide_path, self.shallow)
+            })
+        })?;
+        if success {
+            self.apply_patches()?;
+        }
+        Ok(success)
     }
 
-    /// Update Halide source
-    pub fn update(&self) -> io::Result<bool> {
-        Command::new("git")
-            .current_dir(&self.halide_path)
-            .arg("pull")
-            .arg("origin")
-            .arg(&self.branch)
-            .status()
-            .map(|status| status.success())
+    /// Apply `patches` to the Halide source in order
+    ///
+    /// Patches are applied using `git apply` in a git checkout and `patch -p1` otherwise.
+    /// Patches that are already applied are skipped, so this can be called again after
+    /// `update` or `checkout`. Stops at the first patch that doesn't apply, naming it in the
+    /// error
+    pub fn apply_patches(&self) -> io::Result<()> {
+        let git = self.halide_path.join(".git").exists();
+        for patch in &self.patches {
+            if !patch.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Patch {} not found", patch.display()),
+                ));
+            }
+            // Commands run in the source directory
+            let path = if patch.is_absolute() {
+                patch.clone()
+            } else {
+                env::current_dir()?.join(patch)
+            };
+
+            let applied = self
+                .executor
+                .output(&mut self.patch_command(git, &path, true))?;
+            if applied.status.success() {
+                self.events.emit(Event::PatchApplied {
+                    patch: patch.clone(),
+                    skipped: true,
+                });
+                continue;
+            }
+
+            // `git apply` applies nothing when a hunk fails, `patch` needs a dry run first
+            let dry_run = match git {
+                true => None,
+                false => Some(
+                    self.executor
+                        .output(self.patch_command(git, &path, false).arg("--dry-run"))?,
+                ),
+            };
+            let output = match dry_run {
+                Some(output) if !output.status.success() => output,
+                _ => self
+                    .executor
+                    .output(&mut self.patch_command(git, &path, false))?,
+            };
+            if !output.status.success() {
+                let mut msg = String::from_utf8_lossy(&output.stderr).trim().to_string();
+                if msg.is_empty() {
+                    msg = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                }
+                return Err(io::Error::other(format!(
+                    "Unable to apply patch {}: {}",
+                    patch.display(),
+                    msg
+                )));
+            }
+            self.events.emit(Event::PatchApplied {
+                patch: patch.clone(),
+                skipped: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// Apply `patch`, or only check that it's already applied when `check_applied` is set
+    fn patch_command(&self, git: bool, patch: &Path, check_applied: bool) -> Command {
+        let mut cmd = if git {
+            let mut cmd = Command::new("git");
+            cmd.arg("apply");
+            if check_applied {
+                cmd.args(["--check", "--reverse"]);
+            }
+            cmd.arg(patch);
+            cmd
+        } else {
+            let mut cmd = Command::new("patch");
+            cmd.args(["-p1", "-t", "-N"]);
+            if check_applied {
+                cmd.args(["--dry-run", "-R"]);
+            }
+            cmd.arg("-i").arg(patch);
+            cmd
+        };
+        cmd.current_dir(&self.halide_path);
+        cmd
+    }
+
+    /// Update Halide source, fast-forwarding to the latest commit on `branch`
+    ///
+    /// Applied `patches` are local changes, so `stash` must be set to update a patched
+    /// checkout, then `apply_patches` applies them again
+    pub fn update(&self) -> io::Result<scm::UpdateStatus> {
+        self.with_ticks(SourceStep::Update, || {
+            self.with_scm(|scm| scm.update(&self.halide_path, &self.branch, self.stash))
+        })
+    }
+
+    /// Check out a branch, tag or commit in the Halide source
+    pub fn checkout(&self, rev: &str) -> io::Result<bool> {
+        self.with_scm(|scm| scm.checkout(&self.halide_path, rev))
+    }
+
+    /// Create a linked worktree of the Halide source at `path` with `rev` checked out
+    ///
+    /// Worktrees share the object store of the main checkout, so several Halide versions can
+    /// be built without cloning again. Build one by creating a `Source` for the worktree path
+    pub fn add_worktree(&self, path: impl AsRef<Path>, rev: &str) -> io::Result<bool> {
+        self.with_scm(|scm| scm.add_worktree(&self.halide_path, path.as_ref(), rev))
+    }
+
+    /// Remove a worktree created by `add_worktree`, failing if it has local changes
+    pub fn remove_worktree(&self, path: impl AsRef<Path>) -> io::Result<bool> {
+        self.with_scm(|scm| scm.remove_worktree(&self.halide_path, path.as_ref()))
+    }
+
+    /// Number of parallel jobs used to build Halide
+    ///
+    /// Defaults to the number of available cores, an explicit `jobs` setting is capped at
+    /// the same count
+    pub fn build_jobs(&self) -> usize {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        self.jobs.map(|jobs| jobs.clamp(1, cores)).unwrap_or(cores)
+    }
+
+    /// The `llvm-config` executable used by the build
+    pub fn llvm_config_path(&self) -> PathBuf {
+        self.llvm_config
+            .clone()
+            .or_else(|| env::var_os("LLVM_CONFIG").map(PathBuf::from))
+            .unwrap_or_else(|| PathBuf::from("llvm-config"))
+    }
+
+    /// Check that the LLVM found by `llvm-config` is known to build this Halide version
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` when it's outside the supported range, and
+    /// `io::ErrorKind::NotFound` when `llvm-config` can't be run. Halide versions without a
+    /// known range only emit a warning
+    pub fn verify_llvm(&self) -> io::Result<()> {
+        let llvm_config = self.llvm_config_path();
+        let output = self
+            .executor
+            .output(Command::new(&llvm_config).arg("--version"))
+            .ok()
+            .filter(|output| output.status.success())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Unable to run {} --version", llvm_config.display()),
+                )
+            })?;
+        let llvm =
+            version::Version::find(&String::from_utf8_lossy(&output.stdout)).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Unable to read the LLVM version from {}",
+                        llvm_config.display()
+                    ),
+                )
+            })?;
+
+        let halide = match version::source_version(&self.halide_path) {
+            Some(halide) => halide,
+            None => {
+                self.events.emit(Event::Warning(
+                    "Unable to detect the Halide version, skipping the LLVM check".to_string(),
+                ));
+                return Ok(());
+            }
+        };
+
+        let (min, max) = match version::llvm_range(halide) {
+            Some(range) => range,
+            None => {
+                self.events.emit(Event::Warning(format!(
+                    "No known LLVM versions for Halide {}, using LLVM {}",
+                    halide, llvm
+                )));
+                return Ok(());
+            }
+        };
+
+        if llvm.major < min || llvm.major > max {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "Halide {} requires LLVM {} to {}, but {} is LLVM {}, use --llvm-config or LLVM_CONFIG to select another",
+                    halide,
+                    min,
+                    max,
+                    llvm_config.display(),
+                    llvm
+                ),
+            ));
+        }
+        Ok(())
     }
 
-    /// Build Halide source
+    /// Build Halide source, recording the compiler in `halide-build-info.json` next to the
+    /// library when it succeeds, see `build_info`
     pub fn build(&self) -> io::Result<bool> {
-        Command::new(&self.make)
-            .current_dir(&self.halide_path)
-            .args(&self.make_flags)
-            .status()
+        let success = self.with_ticks(SourceStep::Build, || self.run_build())?;
+        if success {
+            let lib_dir = build_info::lib_dir(&self.halide_path);
+            if let Err(e) =
+                BuildInfo::for_source(&*self.executor, &self.halide_path).write(&lib_dir)
+            {
+                self.events.emit(Event::Warning(format!(
+                    "Unable to write {}: {}",
+                    lib_dir.join(build_info::FILE_NAME).display(),
+                    e
+                )));
+            }
+        }
+        Ok(success)
+    }
+
+    fn run_build(&self) -> io::Result<bool> {
+        let mut cmd = Command::new(&self.make);
+        cmd.current_dir(&self.halide_path).args(&self.make_flags);
+        if let Some(llvm_config) = &self.llvm_config {
+            cmd.env("LLVM_CONFIG", llvm_config);
+        }
+
+        // Flags passed explicitly in `make_flags` take precedence
+        let has_jobs = self.make_flags.iter().any(|flag| {
+            flag.starts_with("-j") || flag.starts_with("--jobs") || flag.starts_with("--parallel")
+        });
+        if !has_jobs {
+            let jobs = self.build_jobs();
+            if Path::new(&self.make).file_stem() == Some("cmake".as_ref()) {
+                cmd.arg("--parallel").arg(jobs.to_string());
+            } else {
+                cmd.arg(format!("-j{}", jobs));
+            }
+        }
+
+        if !self.events.is_set() {
+            return self
+                .executor
+                .status(&mut cmd, &self.interrupt, true, None)
+                .map(|s| s.success());
+        }
+
+        // Plain Makefiles don't report progress, so it's estimated from the number of
+        // compiled sources
+        let total_sources = std::fs::read_dir(self.halide_path.join("src"))
+            .ok()
+            .map(|entries| {
+                entries
+                    .filter_map(Result::ok)
+                    .filter(|entry| entry.path().extension() == Some("cpp".as_ref()))
+                    .count()
+            });
+        let mut parser = progress::Parser::new(total_sources);
+        let streams: &[exec::Stream] = if self.tee {
+            &[exec::Stream::Stdout, exec::Stream::Stderr]
+        } else {
+            &[exec::Stream::Stdout]
+        };
+        self.executor
+            .status_with_lines(
+                &mut cmd,
+                &self.interrupt,
+                true,
+                None,
+                streams,
+                &mut |stream, line| {
+                    if stream == exec::Stream::Stdout {
+                        let line = exec::line_text(line);
+                        self.events.emit(Event::SourceProgress {
+                            percent: parser.line(&line),
+                            line: line.into_owned(),
+                        });
+                    }
+                    if self.tee {
+                        self.events.emit(exec::line_event(stream, line));
+                    }
+                },
+            )
             .map(|status| status.success())
     }
 }
@@ -0,0 +1,623 @@
+//! A machine-readable inventory of emitted pipelines
+//!
+//! The manifest is a JSON file, usually `pipelines.json`, with one entry per emitted function:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "pipelines": [
+//!     {
+//!       "function": "brighten",
+//!       "symbol": "brighten",
+//!       "generator": "brighten",
+//!       "sources": ["brighten_generator.cpp"],
+//!       "targets": ["host"],
+//!       "halide_version": "16.0.0",
+//!       "files": [{ "path": "out/brighten.a", "hash": "..." }],
+//!       "arguments": [{ "name": "input", "kind": "buffer", "type": "halide_buffer_t" }]
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::gen::{Emit, GenBuilder};
+use crate::{hash, version};
+
+/// Current manifest schema version
+pub const VERSION: u32 = 1;
+
+/// Whether a pipeline argument is a buffer or a scalar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArgumentKind {
+    Buffer,
+    Scalar,
+}
+
+/// A pipeline argument, parsed from the emitted header
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Argument {
+    pub name: String,
+    pub kind: ArgumentKind,
+
+    /// The C type of the argument
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+/// An emitted file and its content hash
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct File {
+    pub path: PathBuf,
+    pub hash: String,
+}
+
+/// A single emitted pipeline
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pipeline {
+    /// Name of the emitted function, including any namespace
+    pub function: String,
+
+    /// Name of the linker symbol of the function, C++ mangled when it's in a namespace
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub symbol: Option<String>,
+
+    /// Name of the generator used to emit the function
+    pub generator: String,
+
+    /// Sources of the generator executable
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<PathBuf>,
+
+    pub targets: Vec<String>,
+
+    #[serde(default)]
+    pub halide_version: Option<String>,
+
+    pub files: Vec<File>,
+
+    #[serde(default)]
+    pub arguments: Vec<Argument>,
+
+    /// Headers copied to an include directory by `GenBuilder::stage_headers`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub staged_headers: Vec<File>,
+}
+
+impl Pipeline {
+    /// Describe the files emitted by a generator
+    pub fn from_generator(gen: &GenBuilder) -> io::Result<Pipeline> {
+        let mut files = vec![];
+        let mut arguments = vec![];
+        let header_ext = Emit::CHeader.extension();
+
+        for path in gen.emitted_files() {
+            if !path.exists() {
+                continue;
+            }
+
+            if path.to_string_lossy().ends_with(header_ext) {
+                let header = fs::read_to_string(&path)?;
+                arguments = parse_header_arguments(&header, gen.function()).unwrap_or_default();
+            }
+
+            files.push(File {
+                hash: hash::hex(hash::file(&path)?),
+                path,
+            });
+        }
+
+        let targets: Vec<String> = gen.target().split(',').map(str::to_string).collect();
+        Ok(Pipeline {
+            function: gen.function().to_string(),
+            symbol: symbol_name(gen.function(), &arguments, &targets[0]),
+            generator: gen.generator_name.clone(),
+            sources: gen.build.src.clone(),
+            targets,
+            halide_version: version::halide_version(&gen.build.halide_path).map(|v| v.to_string()),
+            files,
+            arguments,
+            staged_headers: vec![],
+        })
+    }
+
+    /// Returns true if the emitted files are identical
+    pub fn same_files(&self, other: &Pipeline) -> bool {
+        self.files == other.files
+    }
+}
+
+/// Returned when merging manifests that contain different pipelines with the same function
+/// name
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub existing: Pipeline,
+    pub new: Pipeline,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Conflicting pipelines for function {}: generator {} and generator {} emitted different files",
+            self.existing.function, self.existing.generator, self.new.generator
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+/// A collection of emitted pipelines
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub pipelines: Vec<Pipeline>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            version: VERSION,
+            pipelines: vec![],
+        }
+    }
+}
+
+impl Manifest {
+    /// Find a pipeline by function name
+    pub fn get(&self, function: &str) -> Option<&Pipeline> {
+        self.pipelines.iter().find(|p| p.function == function)
+    }
+
+    /// Add a pipeline, replacing any existing entry for the same function
+    pub fn insert(&mut self, pipeline: Pipeline) {
+        match self
+            .pipelines
+            .iter_mut()
+            .find(|p| p.function == pipeline.function)
+        {
+            Some(existing) => *existing = pipeline,
+            None => self.pipelines.push(pipeline),
+        }
+    }
+}
+
+/// Combine two manifests
+///
+/// Pipelines with the same function name must have identical files, otherwise a `Conflict`
+/// is returned
+pub fn merge(mut a: Manifest, b: Manifest) -> Result<Manifest, Box<Conflict>> {
+    for pipeline in b.pipelines {
+        if let Some(existing) = a.get(&pipeline.function) {
+            if !existing.same_files(&pipeline) {
+                return Err(Box::new(Conflict {
+                    existing: existing.clone(),
+                    new: pipeline,
+                }));
+            }
+            continue;
+        }
+
+        a.pipelines.push(pipeline);
+    }
+
+    Ok(a)
+}
+
+/// Load a manifest file, or `pipelines.json` when given a directory
+pub fn load(path: impl AsRef<Path>) -> io::Result<Manifest> {
+    let mut path = path.as_ref().to_path_buf();
+    if path.is_dir() {
+        path.push("pipelines.json");
+    }
+
+    let manifest: Manifest = serde_json::from_str(&fs::read_to_string(&path)?)?;
+    if manifest.version > VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Unsupported manifest version {} in {}, the latest supported version is {}",
+                manifest.version,
+                path.display(),
+                VERSION
+            ),
+        ));
+    }
+
+    Ok(manifest)
+}
+
+/// Write a manifest file
+pub fn write(path: impl AsRef<Path>, manifest: &Manifest) -> io::Result<()> {
+    fs::write(path, serde_json::to_string_pretty(manifest)?)
+}
+
+/// Parse the arguments of `function` from the prototype in an emitted header
+///
+/// Namespaces are ignored, since the prototype is declared inside the namespace block
+pub fn parse_header_arguments(header: &str, function: &str) -> Option<Vec<Argument>> {
+    let name = function.rsplit("::").next()?;
+    let prototype = format!("int {}(", name);
+    let start = header.find(&prototype)? + prototype.len();
+    let end = start + header[start..].find(')')?;
+
+    let args = header[start..end]
+        .split(',')
+        .map(str::trim)
+        .filter(|arg| !arg.is_empty() && *arg != "void")
+        .map(|arg| {
+            let split = arg
+                .rfind(|c: char| !(c.is_alphanumeric() || c == '_'))
+                .map(|i| i + 1)
+                .unwrap_or(0);
+            let (ty, name) = arg.split_at(split);
+            let name = name.trim_start_matches('_');
+
+            if ty.contains("halide_buffer_t") {
+                Argument {
+                    name: name.strip_suffix("_buffer").unwrap_or(name).to_string(),
+                    kind: ArgumentKind::Buffer,
+                    ty: "halide_buffer_t".to_string(),
+                }
+            } else {
+                Argument {
+                    name: name.to_string(),
+                    kind: ArgumentKind::Scalar,
+                    ty: ty.trim().to_string(),
+                }
+            }
+        })
+        .collect();
+
+    Some(args)
+}
+
+/// The linker symbol of `function` emitted for `target`
+///
+/// Functions without a namespace have C linkage. Halide gives namespaced functions C++
+/// linkage, so their symbols are mangled using the Itanium ABI, which includes the argument
+/// types. Returns `None` when an argument type isn't known or the target uses the MSVC ABI
+pub fn symbol_name(function: &str, arguments: &[Argument], target: &str) -> Option<String> {
+    let mut segments: Vec<&str> = function.split("::").collect();
+    let name = segments.pop()?;
+    if segments.is_empty() {
+        return Some(name.to_string());
+    }
+
+    let features: Vec<&str> = target.split('-').collect();
+    let windows = match features.first() {
+        Some(&"host") => cfg!(windows),
+        _ => features.contains(&"windows"),
+    };
+    if windows {
+        return None;
+    }
+    let apple = match features.first() {
+        Some(&"host") => cfg!(target_vendor = "apple"),
+        _ => features.contains(&"osx") || features.contains(&"ios"),
+    };
+
+    // Each namespace prefix can be referred to by later substitutions
+    let mut subs = vec![];
+    let mut symbol = "_ZN".to_string();
+    for segment in &segments {
+        symbol.push_str(&source_name(segment));
+        subs.push(symbol[3..].to_string());
+    }
+    symbol.push_str(&source_name(name));
+    symbol.push('E');
+
+    if arguments.is_empty() {
+        symbol.push('v');
+    }
+    for arg in arguments {
+        let ty = match arg.kind {
+            ArgumentKind::Buffer => "struct halide_buffer_t *",
+            ArgumentKind::Scalar => &arg.ty,
+        };
+        symbol.push_str(&mangle_type(ty, apple, &mut subs)?);
+    }
+    Some(symbol)
+}
+
+fn source_name(name: &str) -> String {
+    format!("{}{}", name.len(), name)
+}
+
+/// Mangle a C type like `int32_t` or `const struct halide_buffer_t *`, recording the
+/// substitution candidates in `subs`
+fn mangle_type(ty: &str, apple: bool, subs: &mut Vec<String>) -> Option<String> {
+    let pointers = ty.matches('*').count();
+    let mut is_const = false;
+    let mut is_struct = false;
+    let mut words = vec![];
+    let ty = ty.replace('*', " ");
+    for word in ty.split_whitespace() {
+        match word {
+            "const" => is_const = true,
+            "struct" => is_struct = true,
+            word => words.push(word),
+        }
+    }
+
+    // The canonical encoding is compared, the emitted one may use substitutions
+    let base = match (words.join(" ").as_str(), apple) {
+        ("void", _) => "v",
+        ("bool", _) => "b",
+        ("char", _) => "c",
+        ("int8_t" | "signed char", _) => "a",
+        ("uint8_t" | "unsigned char", _) => "h",
+        ("int16_t" | "short", _) => "s",
+        ("uint16_t" | "unsigned short", _) => "t",
+        ("int32_t" | "int", _) => "i",
+        ("uint32_t" | "unsigned" | "unsigned int", _) => "j",
+        ("int64_t", false) | ("long", _) => "l",
+        ("uint64_t", false) | ("unsigned long", _) => "m",
+        ("int64_t", true) | ("long long", _) => "x",
+        ("uint64_t", true) | ("unsigned long long", _) => "y",
+        ("float", _) => "f",
+        ("double", _) => "d",
+        (name, _) if is_struct && crate::validate::is_identifier(name) => "",
+        _ => return None,
+    };
+    let (mut canonical, mut emitted) = match base {
+        "" => {
+            let name = source_name(words[0]);
+            (name.clone(), substitute(name, subs))
+        }
+        base => (base.to_string(), base.to_string()),
+    };
+
+    let qualifiers = ["K"].into_iter().filter(|_| is_const && pointers > 0);
+    for prefix in qualifiers.chain(std::iter::repeat_n("P", pointers)) {
+        canonical.insert_str(0, prefix);
+        emitted = match subs.contains(&canonical) {
+            true => substitute(canonical.clone(), subs),
+            false => {
+                subs.push(canonical.clone());
+                format!("{}{}", prefix, emitted)
+            }
+        };
+    }
+    Some(emitted)
+}
+
+/// Refer to an earlier candidate, or record a new one
+fn substitute(canonical: String, subs: &mut Vec<String>) -> String {
+    match subs.iter().position(|s| *s == canonical) {
+        Some(0) => "S_".to_string(),
+        Some(n) => format!("S{}_", base36(n - 1)),
+        None => {
+            subs.push(canonical.clone());
+            canonical
+        }
+    }
+}
+
+fn base36(mut n: usize) -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+    let mut s = vec![];
+    loop {
+        s.push(DIGITS[n % 36]);
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    s.reverse();
+    String::from_utf8(s).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::Mock;
+    use crate::gen::Emit;
+    use crate::Build;
+
+    /// The header Halide emits for `namespace demo { int brighten(...); }`
+    const HEADER: &str = "#ifndef HALIDE_brighten_h
+#define HALIDE_brighten_h
+#include <stdint.h>
+
+struct halide_buffer_t;
+struct halide_filter_metadata_t;
+
+namespace demo {
+
+HALIDE_FUNCTION_ATTRS
+int brighten(struct halide_buffer_t *_input_buffer, float _scale, int32_t _offset, struct halide_buffer_t *_output_buffer);
+
+HALIDE_FUNCTION_ATTRS
+int brighten_argv(void **args);
+
+}  // namespace demo
+#endif
+";
+
+    /// A generator run using `mock` that emits a header and a static library into `dir`
+    fn generator<'a>(dir: &Path, mock: &Mock, name: &str, function: &str) -> GenBuilder<'a> {
+        let build = Build::new("/opt/halide", dir.join(name))
+            .source_file(format!("{}.cpp", name))
+            .atomic_outputs(false)
+            .with_executor(mock.clone());
+        GenBuilder::new(build, name, dir.join("out"))
+            .function_name(function)
+            .emit(Emit::StaticLibrary)
+            .emit(Emit::CHeader)
+            .manifest(dir.join("pipelines.json"))
+    }
+
+    /// Write the files `gen` emits, the mock generator doesn't write them
+    fn emit(gen: &GenBuilder, contents: &str) {
+        fs::create_dir_all(&gen.output_dir).unwrap();
+        for path in gen.emitted_files() {
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    fn pipeline(function: &str, hash: &str) -> Pipeline {
+        Pipeline {
+            function: function.to_string(),
+            symbol: Some(function.to_string()),
+            generator: function.to_string(),
+            sources: vec![],
+            targets: vec!["host".to_string()],
+            halide_version: None,
+            files: vec![File {
+                path: PathBuf::from(format!("out/{}.a", function)),
+                hash: hash.to_string(),
+            }],
+            arguments: vec![],
+            staged_headers: vec![],
+        }
+    }
+
+    #[test]
+    fn newer_schema_versions_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pipelines.json");
+        let mut m = Manifest::default();
+        m.insert(pipeline("brighten", "1"));
+        write(&path, &m).unwrap();
+        assert_eq!(load(dir.path()).unwrap(), m);
+
+        m.version = VERSION + 1;
+        write(&path, &m).unwrap();
+        let err = load(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err
+            .to_string()
+            .starts_with(&format!("Unsupported manifest version {}", VERSION + 1)));
+    }
+
+    #[test]
+    fn generator_runs_accumulate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let brighten = generator(dir.path(), &mock, "brighten", "brighten");
+        let blur = generator(dir.path(), &mock, "blur", "blur");
+        emit(
+            &brighten,
+            "int brighten(struct halide_buffer_t *_input_buffer);",
+        );
+        emit(&blur, "int blur(float _sigma);");
+
+        assert!(brighten.run_generator().unwrap());
+        assert!(blur.run_generator().unwrap());
+        let m = load(dir.path().join("pipelines.json")).unwrap();
+        let functions: Vec<&str> = m.pipelines.iter().map(|p| p.function.as_str()).collect();
+        assert_eq!(functions, ["brighten", "blur"]);
+        assert_eq!(m.get("blur").unwrap().arguments[0].name, "sigma");
+        assert_eq!(
+            m.get("brighten").unwrap().sources,
+            [Path::new("brighten.cpp")]
+        );
+
+        // Running a generator again replaces its entry
+        emit(
+            &brighten,
+            "int brighten(struct halide_buffer_t *_image_buffer);",
+        );
+        assert!(brighten.run_generator().unwrap());
+        let again = load(dir.path().join("pipelines.json")).unwrap();
+        assert_eq!(again.pipelines.len(), 2);
+        assert_eq!(again.get("brighten").unwrap().arguments[0].name, "image");
+        assert_ne!(
+            again.get("brighten").unwrap().files,
+            m.get("brighten").unwrap().files
+        );
+        assert_eq!(again.get("blur"), m.get("blur"));
+    }
+
+    #[test]
+    fn conflicting_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new();
+        let brighten = generator(dir.path(), &mock, "brighten", "f");
+        emit(&brighten, "");
+        assert!(brighten.run_generator().unwrap());
+
+        // Another generator can't emit the same function into the manifest
+        let other = generator(dir.path(), &mock, "blur", "f");
+        let err = other.run_generator().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(mock.calls().len(), 1);
+
+        // Merging keeps identical entries once and rejects different ones
+        let a = Manifest {
+            pipelines: vec![pipeline("brighten", "1"), pipeline("blur", "2")],
+            ..Manifest::default()
+        };
+        let b = Manifest {
+            pipelines: vec![pipeline("blur", "2"), pipeline("sharpen", "3")],
+            ..Manifest::default()
+        };
+        let merged = merge(a.clone(), b).unwrap();
+        let functions: Vec<&str> = merged
+            .pipelines
+            .iter()
+            .map(|p| p.function.as_str())
+            .collect();
+        assert_eq!(functions, ["brighten", "blur", "sharpen"]);
+
+        let mut changed = pipeline("blur", "4");
+        changed.generator = "blur2".to_string();
+        let conflict = merge(
+            a,
+            Manifest {
+                pipelines: vec![changed],
+                ..Manifest::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(conflict.existing.files[0].hash, "2");
+        assert_eq!(
+            conflict.to_string(),
+            "Conflicting pipelines for function blur: generator blur and generator blur2 emitted different files"
+        );
+    }
+
+    #[test]
+    fn header_arguments() {
+        let args = parse_header_arguments(HEADER, "demo::brighten").unwrap();
+        assert_eq!(
+            args,
+            [
+                Argument {
+                    name: "input".to_string(),
+                    kind: ArgumentKind::Buffer,
+                    ty: "halide_buffer_t".to_string(),
+                },
+                Argument {
+                    name: "scale".to_string(),
+                    kind: ArgumentKind::Scalar,
+                    ty: "float".to_string(),
+                },
+                Argument {
+                    name: "offset".to_string(),
+                    kind: ArgumentKind::Scalar,
+                    ty: "int32_t".to_string(),
+                },
+                Argument {
+                    name: "output".to_string(),
+                    kind: ArgumentKind::Buffer,
+                    ty: "halide_buffer_t".to_string(),
+                },
+            ]
+        );
+        assert_eq!(
+            symbol_name("demo::brighten", &args, "x86-64-linux").as_deref(),
+            Some("_ZN4demo8brightenEP15halide_buffer_tfiS1_")
+        );
+        assert_eq!(parse_header_arguments("int f(void);", "f").unwrap(), []);
+        assert_eq!(parse_header_arguments(HEADER, "blur"), None);
+    }
+}
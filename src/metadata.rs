@@ -0,0 +1,366 @@
+//! Read the metadata Halide embeds in emitted pipelines
+//!
+//! Every AOT pipeline has a `<function>_metadata()` function returning a
+//! `halide_filter_metadata_t`, which describes the arguments more precisely than the
+//! prototype in the header. A small probe program calls it and prints the metadata as JSON:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "name": "brighten",
+//!   "target": "x86-64-linux-avx-sse41",
+//!   "arguments": [
+//!     {
+//!       "name": "offset",
+//!       "kind": "input_scalar",
+//!       "dimensions": 0,
+//!       "type": { "code": "uint", "bits": 8, "lanes": 1 },
+//!       "default": 1, "min": 0, "max": 255, "estimate": null
+//!     }
+//!   ]
+//! }
+//! ```
+
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+/// Whether an argument is a scalar, an input buffer or an output buffer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MetadataKind {
+    InputScalar,
+    InputBuffer,
+    OutputBuffer,
+}
+
+/// The `halide_type_code_t` of an argument
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TypeCode {
+    Int,
+    Uint,
+    Float,
+    Handle,
+    Bfloat,
+}
+
+/// The element type of a buffer or the type of a scalar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScalarType {
+    pub code: TypeCode,
+    pub bits: u8,
+    pub lanes: u16,
+}
+
+/// A scalar default, bound or estimate
+///
+/// Floats always include a decimal point or exponent, so they're never read as integers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScalarValue {
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+}
+
+/// A pipeline argument described by the metadata
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MetadataArgument {
+    pub name: String,
+    pub kind: MetadataKind,
+
+    /// Number of dimensions of buffers, 0 for scalars
+    pub dimensions: i32,
+
+    #[serde(rename = "type")]
+    pub ty: ScalarType,
+
+    /// Default, bounds and estimate of scalar arguments, `None` when unset, for buffers and
+    /// for values that aren't finite
+    #[serde(default)]
+    pub default: Option<ScalarValue>,
+    #[serde(default)]
+    pub min: Option<ScalarValue>,
+    #[serde(default)]
+    pub max: Option<ScalarValue>,
+    #[serde(default)]
+    pub estimate: Option<ScalarValue>,
+}
+
+/// The metadata of an emitted pipeline, see `GenBuilder::pipeline_metadata`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PipelineMetadata {
+    /// Version of `halide_filter_metadata_t`
+    pub version: i32,
+
+    pub name: String,
+
+    /// The target the pipeline was compiled for, with `host` resolved
+    pub target: String,
+
+    pub arguments: Vec<MetadataArgument>,
+}
+
+impl PipelineMetadata {
+    pub fn argument(&self, name: &str) -> Option<&MetadataArgument> {
+        self.arguments.iter().find(|arg| arg.name == name)
+    }
+}
+
+/// Parse the JSON printed by the probe program
+pub fn parse(json: &str) -> io::Result<PipelineMetadata> {
+    serde_json::from_str(json).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid pipeline metadata: {}", e),
+        )
+    })
+}
+
+/// Source of a program printing the metadata of `function` as JSON
+///
+/// `header` is the header emitted for the pipeline, it's included to declare the metadata
+/// function, which is in the same namespace as `function`
+pub fn probe_source(function: &str, header: &str) -> String {
+    format!(
+        r#"// Generated by halide-build, do not edit
+#include <cinttypes>
+#include <cmath>
+#include <cstdio>
+#include <cstring>
+
+#include "HalideRuntime.h"
+#include "{header}"
+
+static void print_string(const char *s) {{
+    putchar('"');
+    for (; s != nullptr && *s != 0; s++) {{
+        unsigned char c = (unsigned char)*s;
+        if (c == '"' || c == '\\') {{
+            printf("\\%c", c);
+        }} else if (c < 0x20) {{
+            printf("\\u%04x", c);
+        }} else {{
+            putchar(c);
+        }}
+    }}
+    putchar('"');
+}}
+
+static void print_float(double d) {{
+    if (!std::isfinite(d)) {{
+        printf("null");
+        return;
+    }}
+    // Always include a decimal point, so the value isn't read as an integer
+    char buf[64];
+    snprintf(buf, sizeof(buf), "%.17g", d);
+    printf("%s%s", buf, strpbrk(buf, ".e") != nullptr ? "" : ".0");
+}}
+
+static void print_scalar(const halide_type_t &t, const halide_scalar_value_t *v) {{
+    if (v == nullptr) {{
+        printf("null");
+        return;
+    }}
+    switch (t.code) {{
+    case halide_type_int:
+        switch (t.bits) {{
+        case 8: printf("%d", v->u.i8); return;
+        case 16: printf("%d", v->u.i16); return;
+        case 32: printf("%" PRId32, v->u.i32); return;
+        case 64: printf("%" PRId64, v->u.i64); return;
+        }}
+        break;
+    case halide_type_uint:
+        switch (t.bits) {{
+        case 1: fputs(v->u.b ? "true" : "false", stdout); return;
+        case 8: printf("%u", v->u.u8); return;
+        case 16: printf("%u", v->u.u16); return;
+        case 32: printf("%" PRIu32, v->u.u32); return;
+        case 64: printf("%" PRIu64, v->u.u64); return;
+        }}
+        break;
+    case halide_type_float:
+        switch (t.bits) {{
+        case 32: print_float(v->u.f32); return;
+        case 64: print_float(v->u.f64); return;
+        }}
+        break;
+    default:
+        break;
+    }}
+    printf("null");
+}}
+
+static const char *kind_name(int32_t kind) {{
+    switch (kind) {{
+    case halide_argument_kind_input_scalar: return "input_scalar";
+    case halide_argument_kind_input_buffer: return "input_buffer";
+    default: return "output_buffer";
+    }}
+}}
+
+static const char *code_name(uint8_t code) {{
+    switch (code) {{
+    case halide_type_int: return "int";
+    case halide_type_uint: return "uint";
+    case halide_type_float: return "float";
+    case halide_type_handle: return "handle";
+    default: return "bfloat";
+    }}
+}}
+
+int main() {{
+    const halide_filter_metadata_t *md = {function}_metadata();
+    printf("{{\"version\": %d, \"name\": ", md->version);
+    print_string(md->name);
+    printf(", \"target\": ");
+    print_string(md->target);
+    printf(", \"arguments\": [");
+    for (int32_t i = 0; i < md->num_arguments; i++) {{
+        const halide_filter_argument_t &arg = md->arguments[i];
+        fputs(i == 0 ? "{{\"name\": " : ", {{\"name\": ", stdout);
+        print_string(arg.name);
+        printf(", \"kind\": \"%s\", \"dimensions\": %d", kind_name(arg.kind), arg.dimensions);
+        printf(", \"type\": {{\"code\": \"%s\", \"bits\": %d, \"lanes\": %d}}",
+               code_name(arg.type.code), arg.type.bits, arg.type.lanes);
+        printf(", \"default\": ");
+        print_scalar(arg.type, arg.scalar_def);
+        printf(", \"min\": ");
+        print_scalar(arg.type, arg.scalar_min);
+        printf(", \"max\": ");
+        print_scalar(arg.type, arg.scalar_max);
+        printf(", \"estimate\": ");
+        print_scalar(arg.type, arg.scalar_estimate);
+        printf("}}");
+    }}
+    printf("]}}\n");
+    return 0;
+}}
+"#,
+        function = function,
+        header = header.replace('\\', "/"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+    use crate::gen::{Emit, GenBuilder};
+    use crate::Build;
+
+    const JSON: &str = r#"{"version": 1, "name": "brighten", "target": "x86-64-linux-avx-sse41", "arguments": [{"name": "input", "kind": "input_buffer", "dimensions": 3, "type": {"code": "uint", "bits": 8, "lanes": 1}, "default": null, "min": null, "max": null, "estimate": null}, {"name": "offset", "kind": "input_scalar", "dimensions": 0, "type": {"code": "uint", "bits": 8, "lanes": 1}, "default": 1, "min": 0, "max": 255, "estimate": null}, {"name": "scale", "kind": "input_scalar", "dimensions": 0, "type": {"code": "float", "bits": 32, "lanes": 1}, "default": 1.0, "min": -1e+30, "max": null, "estimate": 0.5}, {"name": "flag", "kind": "input_scalar", "dimensions": 0, "type": {"code": "uint", "bits": 1, "lanes": 1}, "default": true, "min": null, "max": null, "estimate": null}, {"name": "seed", "kind": "input_scalar", "dimensions": 0, "type": {"code": "uint", "bits": 64, "lanes": 1}, "default": 18446744073709551615, "min": -5, "max": null, "estimate": null}, {"name": "output", "kind": "output_buffer", "dimensions": 3, "type": {"code": "uint", "bits": 8, "lanes": 1}, "default": null, "min": null, "max": null, "estimate": null}]}"#;
+
+    #[test]
+    fn parse_probe_output() {
+        let md = parse(JSON).unwrap();
+        assert_eq!(md.name, "brighten");
+        assert_eq!(md.target, "x86-64-linux-avx-sse41");
+        assert_eq!(md.arguments.len(), 6);
+
+        let input = md.argument("input").unwrap();
+        assert_eq!(input.kind, MetadataKind::InputBuffer);
+        assert_eq!(input.dimensions, 3);
+        assert_eq!(input.default, None);
+
+        let offset = md.argument("offset").unwrap();
+        assert_eq!(
+            offset.ty,
+            ScalarType {
+                code: TypeCode::Uint,
+                bits: 8,
+                lanes: 1
+            }
+        );
+        assert_eq!(offset.default, Some(ScalarValue::Int(1)));
+        assert_eq!(offset.max, Some(ScalarValue::Int(255)));
+
+        // Floats stay floats even when they're whole numbers
+        let scale = md.argument("scale").unwrap();
+        assert_eq!(scale.default, Some(ScalarValue::Float(1.0)));
+        assert_eq!(scale.min, Some(ScalarValue::Float(-1e30)));
+        assert_eq!(scale.estimate, Some(ScalarValue::Float(0.5)));
+
+        assert_eq!(
+            md.argument("flag").unwrap().default,
+            Some(ScalarValue::Bool(true))
+        );
+        let seed = md.argument("seed").unwrap();
+        assert_eq!(seed.default, Some(ScalarValue::Uint(u64::MAX)));
+        assert_eq!(seed.min, Some(ScalarValue::Int(-5)));
+        assert_eq!(
+            md.argument("output").unwrap().kind,
+            MetadataKind::OutputBuffer
+        );
+        assert!(md.argument("missing").is_none());
+
+        // Serializing gives the same metadata back
+        assert_eq!(parse(&serde_json::to_string(&md).unwrap()).unwrap(), md);
+
+        let err = parse(r#"{"version": 1, "name": "f"}"#).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().starts_with("Invalid pipeline metadata: "));
+    }
+
+    #[test]
+    fn probe_calls_the_metadata_function() {
+        let source = probe_source("demo::brighten", r"C:\out\brighten.h");
+        assert!(source.contains("#include \"C:/out/brighten.h\"\n"));
+        assert!(source.contains("= demo::brighten_metadata();"));
+    }
+
+    /// Write the files the mock generator would have emitted
+    fn emit(gen: &GenBuilder) {
+        std::fs::create_dir_all(&gen.output_dir).unwrap();
+        for path in gen.emitted_files() {
+            std::fs::write(path, "").unwrap();
+        }
+    }
+
+    #[test]
+    fn pipeline_metadata_compiles_and_runs_the_probe() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let mock = Mock::new()
+            .on("c++", Response::ok().output_file("probe"))
+            .on("brighten_metadata_probe", Response::ok().stdout(JSON));
+        let build = Build::new("/opt/halide", dir.path().join("gen"))
+            .compiler("c++")
+            .with_image_io(false)
+            .with_executor(mock.clone());
+        let mut gen = GenBuilder::new(build, "brighten", &out)
+            .emit(Emit::StaticLibrary)
+            .emit(Emit::CHeader);
+
+        let err = gen.pipeline_metadata().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(mock.calls().is_empty());
+
+        emit(&gen);
+        assert_eq!(gen.pipeline_metadata().unwrap(), parse(JSON).unwrap());
+        let calls = mock.calls_to("c++");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(mock.calls_to("brighten_metadata_probe").len(), 1);
+        assert!(calls[0].has_arg(&out.join("brighten_metadata_probe.cpp").to_string_lossy()));
+        assert!(calls[0].has_arg(&out.join("brighten.a").to_string_lossy()));
+        let probe = std::fs::read_to_string(out.join("brighten_metadata_probe.cpp")).unwrap();
+        assert!(probe.contains("brighten_metadata();"));
+
+        let mock = Mock::new()
+            .on("c++", Response::ok().output_file("probe"))
+            .on(
+                "brighten_metadata_probe",
+                Response::exit(1).stderr("segfault\n"),
+            );
+        gen.build = gen.build.with_executor(mock);
+        let err = gen.pipeline_metadata().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Metadata probe for brighten failed: segfault"
+        );
+    }
+}
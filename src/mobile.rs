@@ -0,0 +1,375 @@
+//! Cross-compile kernels for Android and iOS
+//!
+//! A `MobileToolchain` describes the Android NDK or iOS SDK used to compile and link for a
+//! device. `Build::mobile` compiles with it and sets the Halide target, `GenBuilder::mobile`
+//! only sets the target, since the generator itself runs on the host. Binaries built for a
+//! device aren't run on the host, `Build::run` fails with `io::ErrorKind::Unsupported`
+
+use std::env;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Android API level used by `android_from_env`
+pub const DEFAULT_API_LEVEL: u32 = 24;
+
+/// Mobile operating systems
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MobileOs {
+    Android,
+    Ios,
+}
+
+/// Device architectures
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Abi {
+    Arm64,
+    Arm32,
+    X86_64,
+    X86,
+}
+
+impl Abi {
+    /// Parse an Android ABI name, like `arm64-v8a`
+    pub fn from_name(name: &str) -> Option<Abi> {
+        match name {
+            "arm64-v8a" | "arm64" | "aarch64" => Some(Abi::Arm64),
+            "armeabi-v7a" | "arm" => Some(Abi::Arm32),
+            "x86_64" => Some(Abi::X86_64),
+            "x86" => Some(Abi::X86),
+            _ => None,
+        }
+    }
+
+    /// The Android ABI name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Abi::Arm64 => "arm64-v8a",
+            Abi::Arm32 => "armeabi-v7a",
+            Abi::X86_64 => "x86_64",
+            Abi::X86 => "x86",
+        }
+    }
+}
+
+/// An Android NDK or iOS SDK, see the module documentation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MobileToolchain {
+    pub os: MobileOs,
+
+    /// NDK root for Android, SDK root like `iPhoneOS.sdk` for iOS
+    pub sdk: PathBuf,
+
+    /// API level for Android, like `24`, minimum OS version for iOS, like `15.0`
+    pub min_version: String,
+
+    pub abi: Abi,
+}
+
+impl MobileToolchain {
+    pub fn android(ndk: impl AsRef<Path>, api_level: u32, abi: Abi) -> MobileToolchain {
+        MobileToolchain {
+            os: MobileOs::Android,
+            sdk: ndk.as_ref().to_path_buf(),
+            min_version: api_level.to_string(),
+            abi,
+        }
+    }
+
+    /// The NDK at `ANDROID_NDK_HOME` or `ANDROID_NDK_ROOT`, for arm64 and `DEFAULT_API_LEVEL`
+    ///
+    /// Fails with `io::ErrorKind::NotFound` when neither is set or the NDK has no LLVM
+    /// toolchain, see `check`
+    pub fn android_from_env() -> io::Result<MobileToolchain> {
+        let ndk = ["ANDROID_NDK_HOME", "ANDROID_NDK_ROOT"]
+            .iter()
+            .find_map(|var| env::var_os(var).filter(|v| !v.is_empty()))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "ANDROID_NDK_HOME is not set, install the Android NDK and set it to the NDK root",
+                )
+            })?;
+
+        let toolchain = MobileToolchain::android(ndk, DEFAULT_API_LEVEL, Abi::Arm64);
+        toolchain.check()?;
+        Ok(toolchain)
+    }
+
+    /// iOS devices, `min_version` is the minimum iOS version like `15.0`
+    pub fn ios(sdk: impl AsRef<Path>, min_version: impl Into<String>) -> MobileToolchain {
+        MobileToolchain {
+            os: MobileOs::Ios,
+            sdk: sdk.as_ref().to_path_buf(),
+            min_version: min_version.into(),
+            abi: Abi::Arm64,
+        }
+    }
+
+    /// Set the Android API level
+    pub fn api_level(mut self, level: u32) -> Self {
+        self.min_version = level.to_string();
+        self
+    }
+
+    /// Set the architecture, `X86_64` on iOS targets the simulator
+    pub fn abi(mut self, abi: Abi) -> Self {
+        self.abi = abi;
+        self
+    }
+
+    /// The Halide target, like `arm-64-android`
+    pub fn halide_target(&self) -> &'static str {
+        match (self.os, self.abi) {
+            (MobileOs::Android, Abi::Arm64) => "arm-64-android",
+            (MobileOs::Android, Abi::Arm32) => "arm-32-android",
+            (MobileOs::Android, Abi::X86_64) => "x86-64-android",
+            (MobileOs::Android, Abi::X86) => "x86-32-android",
+            (MobileOs::Ios, Abi::Arm64) => "arm-64-ios",
+            (MobileOs::Ios, Abi::Arm32) => "arm-32-ios",
+            (MobileOs::Ios, Abi::X86_64) => "x86-64-ios",
+            (MobileOs::Ios, Abi::X86) => "x86-32-ios",
+        }
+    }
+
+    /// The clang target triple, like `aarch64-linux-android24`
+    pub fn triple(&self) -> String {
+        match self.os {
+            MobileOs::Android => {
+                let arch = match self.abi {
+                    Abi::Arm64 => "aarch64-linux-android",
+                    Abi::Arm32 => "armv7a-linux-androideabi",
+                    Abi::X86_64 => "x86_64-linux-android",
+                    Abi::X86 => "i686-linux-android",
+                };
+                format!("{}{}", arch, self.min_version)
+            }
+            MobileOs::Ios => {
+                let (arch, suffix) = match self.abi {
+                    Abi::Arm64 => ("arm64", ""),
+                    Abi::Arm32 => ("armv7", ""),
+                    Abi::X86_64 => ("x86_64", "-simulator"),
+                    Abi::X86 => ("i386", "-simulator"),
+                };
+                format!("{}-apple-ios{}{}", arch, self.min_version, suffix)
+            }
+        }
+    }
+
+    /// The NDK's prebuilt LLVM toolchain directory
+    fn ndk_llvm(&self) -> PathBuf {
+        // The NDK only ships x86_64 hosts, which run under Rosetta on Apple silicon
+        let host = if cfg!(target_os = "macos") {
+            "darwin-x86_64"
+        } else if cfg!(windows) {
+            "windows-x86_64"
+        } else {
+            "linux-x86_64"
+        };
+        self.sdk
+            .join("toolchains")
+            .join("llvm")
+            .join("prebuilt")
+            .join(host)
+    }
+
+    /// The C++ compiler, the NDK's clang for Android and `clang++` from the PATH for iOS
+    pub fn compiler(&self) -> PathBuf {
+        match self.os {
+            MobileOs::Android => {
+                let exe = if cfg!(windows) {
+                    "clang++.exe"
+                } else {
+                    "clang++"
+                };
+                self.ndk_llvm().join("bin").join(exe)
+            }
+            MobileOs::Ios => PathBuf::from("clang++"),
+        }
+    }
+
+    /// The directory containing the device's headers and libraries
+    pub fn sysroot(&self) -> PathBuf {
+        match self.os {
+            MobileOs::Android => self.ndk_llvm().join("sysroot"),
+            MobileOs::Ios => self.sdk.clone(),
+        }
+    }
+
+    /// Make sure the compiler and sysroot exist, fails with `io::ErrorKind::NotFound`
+    pub fn check(&self) -> io::Result<()> {
+        let compiler = self.compiler();
+        if self.os == MobileOs::Android && !compiler.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "{} is not an Android NDK, {} does not exist",
+                    self.sdk.display(),
+                    compiler.display()
+                ),
+            ));
+        }
+        if !self.sysroot().is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Sysroot {} does not exist", self.sysroot().display()),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Arguments selecting the device for both compiling and linking
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args = vec![OsString::from(format!("--target={}", self.triple()))];
+        match self.os {
+            MobileOs::Android => {
+                let mut sysroot = OsString::from("--sysroot=");
+                sysroot.push(self.sysroot());
+                args.push(sysroot);
+            }
+            MobileOs::Ios => {
+                args.push("-isysroot".into());
+                args.push(self.sysroot().into());
+            }
+        }
+        args
+    }
+
+    /// System libraries needed by the Halide runtime on the device
+    pub fn libs(&self) -> &'static [&'static str] {
+        match self.os {
+            // The runtime prints using the Android log
+            MobileOs::Android => &["-llog", "-ldl"],
+            MobileOs::Ios => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// An NDK with the compiler and sysroot, the files are empty
+    fn fake_ndk(root: &Path) -> MobileToolchain {
+        let toolchain = MobileToolchain::android(root, 26, Abi::Arm64);
+        fs::create_dir_all(toolchain.sysroot()).unwrap();
+        fs::create_dir_all(toolchain.compiler().parent().unwrap()).unwrap();
+        fs::write(toolchain.compiler(), "").unwrap();
+        toolchain
+    }
+
+    #[test]
+    fn android_ndk_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let toolchain = MobileToolchain::android(dir.path(), 26, Abi::Arm64);
+        let err = toolchain.check().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("is not an Android NDK"));
+
+        let toolchain = fake_ndk(dir.path());
+        toolchain.check().unwrap();
+        let llvm = toolchain
+            .compiler()
+            .parent()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        assert!(llvm.starts_with(dir.path().join("toolchains/llvm/prebuilt")));
+        assert_eq!(toolchain.sysroot(), llvm.join("sysroot"));
+
+        let mut sysroot = OsString::from("--sysroot=");
+        sysroot.push(toolchain.sysroot());
+        assert_eq!(
+            toolchain.args(),
+            [OsString::from("--target=aarch64-linux-android26"), sysroot]
+        );
+        assert_eq!(toolchain.libs(), ["-llog", "-ldl"]);
+
+        // A compiler without a sysroot is still incomplete
+        fs::remove_dir(toolchain.sysroot()).unwrap();
+        let err = toolchain.check().unwrap_err();
+        assert!(err.to_string().contains("Sysroot"));
+    }
+
+    #[test]
+    fn ios_sdk() {
+        let dir = tempfile::tempdir().unwrap();
+        let toolchain = MobileToolchain::ios(dir.path(), "15.0");
+        toolchain.check().unwrap();
+        assert_eq!(toolchain.compiler(), PathBuf::from("clang++"));
+        assert_eq!(
+            toolchain.args(),
+            [
+                OsString::from("--target=arm64-apple-ios15.0"),
+                "-isysroot".into(),
+                dir.path().into()
+            ]
+        );
+        assert!(toolchain.libs().is_empty());
+
+        let missing = MobileToolchain::ios(dir.path().join("missing"), "15.0");
+        assert_eq!(missing.check().unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn targets_and_triples() {
+        let table = [
+            (
+                MobileOs::Android,
+                Abi::Arm64,
+                "arm-64-android",
+                "aarch64-linux-android24",
+            ),
+            (
+                MobileOs::Android,
+                Abi::Arm32,
+                "arm-32-android",
+                "armv7a-linux-androideabi24",
+            ),
+            (
+                MobileOs::Android,
+                Abi::X86_64,
+                "x86-64-android",
+                "x86_64-linux-android24",
+            ),
+            (
+                MobileOs::Android,
+                Abi::X86,
+                "x86-32-android",
+                "i686-linux-android24",
+            ),
+            (MobileOs::Ios, Abi::Arm64, "arm-64-ios", "arm64-apple-ios24"),
+            (
+                MobileOs::Ios,
+                Abi::X86_64,
+                "x86-64-ios",
+                "x86_64-apple-ios24-simulator",
+            ),
+        ];
+        for (os, abi, target, triple) in table {
+            let toolchain = match os {
+                MobileOs::Android => MobileToolchain::android("ndk", 24, abi),
+                MobileOs::Ios => MobileToolchain::ios("sdk", "24").abi(abi),
+            };
+            assert_eq!(toolchain.halide_target(), target);
+            assert_eq!(toolchain.triple(), triple);
+        }
+        assert_eq!(
+            MobileToolchain::android("ndk", 24, Abi::Arm64)
+                .api_level(30)
+                .triple(),
+            "aarch64-linux-android30"
+        );
+    }
+
+    #[test]
+    fn abi_names() {
+        for abi in [Abi::Arm64, Abi::Arm32, Abi::X86_64, Abi::X86] {
+            assert_eq!(Abi::from_name(abi.name()), Some(abi));
+        }
+        assert_eq!(Abi::from_name("aarch64"), Some(Abi::Arm64));
+        assert_eq!(Abi::from_name("mips"), None);
+    }
+}
@@ -0,0 +1,151 @@
+//! File names of build outputs for each platform
+//!
+//! Executables have no extension on Unix and `.exe` on Windows, static libraries are
+//! `lib<name>.a` or `<name>.lib`, shared libraries are `lib<name>.so`, `lib<name>.dylib` or
+//! `<name>.dll` and objects are `<name>.o` or `<name>.obj`
+
+use std::path::{Path, PathBuf};
+
+use crate::shared_library::{self, Platform};
+use crate::OutputKind;
+
+/// Extensions removed by `base_name`
+const EXTENSIONS: &[&str] = &[".exe", ".a", ".lib", ".so", ".dylib", ".dll", ".o", ".obj"];
+
+/// A description of `kind` for messages
+pub(crate) fn describe(kind: OutputKind) -> &'static str {
+    match kind {
+        OutputKind::Executable => "an executable",
+        OutputKind::Object => "an object",
+        OutputKind::StaticLibrary => "a static library",
+        OutputKind::SharedLibrary => "a shared library",
+    }
+}
+
+/// The file name of an artifact named `base` on `platform`
+pub fn artifact_name(kind: OutputKind, base: &str, platform: Platform) -> String {
+    match (kind, platform) {
+        (OutputKind::Executable, Platform::Windows) => format!("{}.exe", base),
+        (OutputKind::Executable, _) => base.to_string(),
+        (OutputKind::Object, Platform::Windows) => format!("{}.obj", base),
+        (OutputKind::Object, _) => format!("{}.o", base),
+        (OutputKind::StaticLibrary, Platform::Windows) => format!("{}.lib", base),
+        (OutputKind::StaticLibrary, _) => format!("lib{}.a", base),
+        (OutputKind::SharedLibrary, Platform::Windows) => format!("{}.dll", base),
+        (OutputKind::SharedLibrary, platform) => format!("lib{}.{}", base, platform.extension()),
+    }
+}
+
+/// The name of an artifact without its `lib` prefix or extension, `filter` for
+/// `libfilter.a` or `filter.exe`
+pub fn base_name(file_name: &str) -> &str {
+    // Versioned shared libraries like `libfoo.so.1`
+    let name = match file_name.find(".so.") {
+        Some(i) => &file_name[..i + 3],
+        None => file_name,
+    };
+    match EXTENSIONS.iter().find_map(|ext| name.strip_suffix(ext)) {
+        Some(name) => name.strip_prefix("lib").unwrap_or(name),
+        None => name,
+    }
+}
+
+/// `path` with its file name replaced by the conventional name for `kind` on `platform`
+pub fn normalize(kind: OutputKind, path: impl AsRef<Path>, platform: Platform) -> PathBuf {
+    let path = path.as_ref();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(artifact_name(kind, base_name(&file_name), platform))
+}
+
+/// Returns true if `path` has the conventional name for `kind` on `platform`, versioned
+/// shared libraries like `libfoo.so.1` are accepted
+pub fn is_conventional(kind: OutputKind, path: impl AsRef<Path>, platform: Platform) -> bool {
+    let path = path.as_ref();
+    let path = match shared_library::unversioned(path) {
+        Some(unversioned) if kind == OutputKind::SharedLibrary => unversioned,
+        _ => path.to_path_buf(),
+    };
+    normalize(kind, &path, platform) == path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KINDS: [OutputKind; 4] = [
+        OutputKind::Executable,
+        OutputKind::Object,
+        OutputKind::StaticLibrary,
+        OutputKind::SharedLibrary,
+    ];
+
+    const PLATFORMS: [Platform; 3] = [Platform::Linux, Platform::MacOs, Platform::Windows];
+
+    /// The name of `brighten` for each kind, on Linux, macOS and Windows
+    const NAMES: [[&str; 3]; 4] = [
+        ["brighten", "brighten", "brighten.exe"],
+        ["brighten.o", "brighten.o", "brighten.obj"],
+        ["libbrighten.a", "libbrighten.a", "brighten.lib"],
+        ["libbrighten.so", "libbrighten.dylib", "brighten.dll"],
+    ];
+
+    #[test]
+    fn artifact_names() {
+        for (kind, names) in KINDS.iter().zip(NAMES) {
+            for (platform, name) in PLATFORMS.iter().zip(names) {
+                assert_eq!(artifact_name(*kind, "brighten", *platform), name);
+                assert_eq!(base_name(name), "brighten");
+                assert!(
+                    is_conventional(*kind, Path::new("out").join(name), *platform),
+                    "{} for {:?} on {:?}",
+                    name,
+                    kind,
+                    platform
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn unconventional_names() {
+        for (kind, names) in KINDS.iter().zip(NAMES) {
+            for (platform, name) in PLATFORMS.iter().zip(names) {
+                // Names used by the other kinds and platforms are normalized to this one
+                for other in NAMES.iter().flatten().filter(|other| **other != name) {
+                    let path = Path::new("out").join(other);
+                    assert!(
+                        !is_conventional(*kind, &path, *platform),
+                        "{} for {:?} on {:?}",
+                        other,
+                        kind,
+                        platform
+                    );
+                    assert_eq!(
+                        normalize(*kind, &path, *platform),
+                        Path::new("out").join(name)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn versioned_shared_libraries() {
+        assert!(is_conventional(
+            OutputKind::SharedLibrary,
+            "libbrighten.so.1.2",
+            Platform::Linux
+        ));
+        assert!(is_conventional(
+            OutputKind::SharedLibrary,
+            "libbrighten.1.dylib",
+            Platform::MacOs
+        ));
+        assert!(!is_conventional(
+            OutputKind::StaticLibrary,
+            "libbrighten.so.1",
+            Platform::Linux
+        ));
+        assert_eq!(base_name("libbrighten.so.1"), "brighten");
+    }
+}
@@ -0,0 +1,400 @@
+//! Write Ninja build files describing builds and generator runs
+//!
+//! The compiler and flags are written as top-level `cxx`, `cxxflags`, `ldflags` and `ar`
+//! variables, so they can be changed in the generated file or overridden by a file that
+//! includes it. Builds with different settings override the variables on their own edges.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::exec::shell_quote;
+use crate::{gen::GenBuilder, ArgPosition, Build, OutputKind};
+
+/// Escape a path used as an input or output of a build edge
+pub fn escape_path(path: &Path) -> String {
+    let mut s = String::new();
+    for c in path.to_string_lossy().chars() {
+        if matches!(c, '$' | ' ' | ':') {
+            s.push('$');
+        }
+        s.push(c);
+    }
+    s
+}
+
+/// Escape the value of a variable
+pub fn escape(value: &str) -> String {
+    value.replace('$', "$$")
+}
+
+fn command_line<'a>(args: impl IntoIterator<Item = &'a OsStr>) -> String {
+    args.into_iter()
+        .map(shell_quote)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The compiler, compile flags and link flags of a build
+fn toolchain_vars(build: &Build) -> [(&'static str, String); 3] {
+    let compile = build.compiler_command();
+    let mut link = Command::new("");
+    build.link_args(&mut link);
+    [
+        ("cxx", shell_quote(compile.get_program())),
+        ("cxxflags", command_line(compile.get_args())),
+        ("ldflags", command_line(link.get_args())),
+    ]
+}
+
+/// Writes a Ninja build file
+///
+/// `new` writes the variables and rules, then each build or generator is added as a set of
+/// build edges
+#[derive(Debug, Clone)]
+pub struct NinjaWriter {
+    out: String,
+    vars: [(&'static str, String); 3],
+
+    /// Paths and values containing line breaks, which Ninja has no way to escape
+    unescapable: Vec<String>,
+}
+
+impl NinjaWriter {
+    /// Start a build file, using the compiler and flags of `build` for the top-level variables
+    pub fn new(build: &Build) -> NinjaWriter {
+        let mut w = NinjaWriter {
+            out: String::new(),
+            vars: toolchain_vars(build),
+            unescapable: vec![],
+        };
+
+        let _ = writeln!(w.out, "# Generated by halide-build, do not edit");
+        let _ = writeln!(w.out);
+        for (name, value) in w.vars.clone() {
+            w.variable(name, &value);
+        }
+        w.variable("ar", &env::var("AR").unwrap_or_else(|_| "ar".to_string()));
+        let _ = writeln!(w.out);
+
+        w.rule(
+            "cxx",
+            "$cxx $cxxflags -MMD -MF $out.d -c $flags $before $in $after -o $out $end",
+            "CXX $out",
+            Some("$out.d"),
+        );
+        w.rule(
+            "link",
+            "$cxx $cxxflags $before $in $after -o $out $ldflags $end",
+            "LINK $out",
+            None,
+        );
+        w.rule(
+            "shared",
+            "$cxx $cxxflags -shared $before $in $after -o $out $ldflags $end",
+            "SHARED $out",
+            None,
+        );
+        w.rule("ar", "rm -f $out && $ar rcs $out $in", "AR $out", None);
+        w.rule(
+            "generate",
+            "mkdir -p $outdir && $env $gen $args",
+            "GENERATE $out",
+            None,
+        );
+        w
+    }
+
+    /// Write a top-level variable, `value` is escaped
+    pub fn variable(&mut self, name: &str, value: &str) {
+        self.check(value);
+        let _ = writeln!(self.out, "{} = {}", name, escape(value));
+    }
+
+    /// Write a rule, `command` and `description` may refer to variables
+    pub fn rule(&mut self, name: &str, command: &str, description: &str, depfile: Option<&str>) {
+        let _ = writeln!(self.out, "rule {}", name);
+        let _ = writeln!(self.out, "  command = {}", command);
+        let _ = writeln!(self.out, "  description = {}", description);
+        if let Some(depfile) = depfile {
+            let _ = writeln!(self.out, "  depfile = {}", depfile);
+            let _ = writeln!(self.out, "  deps = gcc");
+        }
+        let _ = writeln!(self.out);
+    }
+
+    /// Write a build edge, variable values are escaped
+    pub fn build(
+        &mut self,
+        outputs: &[PathBuf],
+        rule: &str,
+        inputs: &[PathBuf],
+        vars: &[(&str, String)],
+    ) {
+        for path in outputs.iter().chain(inputs) {
+            self.check(&path.to_string_lossy());
+        }
+        for (_, value) in vars {
+            self.check(value);
+        }
+
+        let paths = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|p| escape_path(p))
+                .collect::<Vec<_>>()
+                .join(" ")
+        };
+        let _ = writeln!(
+            self.out,
+            "build {}: {} {}",
+            paths(outputs),
+            rule,
+            paths(inputs)
+        );
+        for (name, value) in vars {
+            let _ = writeln!(self.out, "  {} = {}", name, escape(value));
+        }
+        let _ = writeln!(self.out);
+    }
+
+    /// Add the edges that compile, link or archive the output of `build`
+    pub fn add_build(&mut self, build: &Build) {
+        let mut overrides: Vec<(&str, String)> = toolchain_vars(build)
+            .into_iter()
+            .filter(|var| !self.vars.contains(var))
+            .collect();
+        for (name, position) in [
+            ("before", ArgPosition::BeforeSources),
+            ("after", ArgPosition::AfterSources),
+            ("end", ArgPosition::End),
+        ] {
+            let args: Vec<&OsStr> = build
+                .raw_args
+                .iter()
+                .filter(|(p, _)| *p == position)
+                .map(|(_, arg)| OsStr::new(*arg))
+                .collect();
+            if !args.is_empty() {
+                overrides.push((name, command_line(args)));
+            }
+        }
+        let flags = |extra: &[&str]| {
+            command_line(
+                build
+                    .build_args
+                    .iter()
+                    .map(OsString::as_os_str)
+                    .chain(extra.iter().map(OsStr::new)),
+            )
+        };
+
+        if build.output_kind == OutputKind::Object {
+            let extra: Vec<&str> = build
+                .src
+                .iter()
+                .filter_map(|src| build.src_flags.get(src))
+                .flatten()
+                .copied()
+                .collect();
+            let mut vars = overrides;
+            vars.push(("flags", flags(&extra)));
+            self.build(
+                std::slice::from_ref(&build.output),
+                "cxx",
+                &build.src,
+                &vars,
+            );
+            return;
+        }
+
+        // Every source is compiled separately, so Ninja can rebuild and parallelize them
+        let objects = build.intermediate_objects();
+        for ((src, extra), object) in build.object_sources().into_iter().zip(&objects) {
+            let mut vars = overrides.clone();
+            vars.push(("flags", flags(extra)));
+            self.build(std::slice::from_ref(object), "cxx", &[src], &vars);
+        }
+
+        let mut inputs = objects;
+        inputs.extend(build.objects.iter().cloned());
+        if build.output_kind != OutputKind::StaticLibrary {
+            inputs.extend(build.archives.iter().cloned());
+        }
+        let rule = match build.output_kind {
+            OutputKind::StaticLibrary => "ar",
+            OutputKind::SharedLibrary => "shared",
+            _ => "link",
+        };
+        self.build(
+            std::slice::from_ref(&build.output),
+            rule,
+            &inputs,
+            &overrides,
+        );
+    }
+
+    /// Add the edges that build the generator, then run it to emit the pipeline
+    ///
+    /// The manifest isn't updated by the emit step
+    pub fn add_generator(&mut self, gen: &GenBuilder) {
+        self.add_build(&gen.build);
+
+        let cmd = gen.emit_command();
+        let env = cmd
+            .get_envs()
+            .filter_map(|(name, value)| {
+                let mut var = name.to_os_string();
+                var.push("=");
+                var.push(value?);
+                Some(var)
+            })
+            .collect::<Vec<_>>();
+        let env = match env.is_empty() {
+            true => String::new(),
+            false => format!(
+                "env {}",
+                command_line(env.iter().map(|var| var.as_os_str()))
+            ),
+        };
+
+        // A bare file name would be looked up in `PATH` by the shell
+        let mut generator = gen.build.output.clone();
+        if generator.components().count() == 1 {
+            generator = Path::new(".").join(generator);
+        }
+
+        self.build(
+            &gen.emitted_files(),
+            "generate",
+            std::slice::from_ref(&gen.build.output),
+            &[
+                ("gen", shell_quote(generator.as_os_str())),
+                ("args", command_line(cmd.get_args())),
+                ("env", env),
+                ("outdir", shell_quote(gen.output_dir.as_os_str())),
+            ],
+        );
+    }
+
+    /// Set the targets built when Ninja is run without arguments
+    pub fn default(&mut self, targets: &[PathBuf]) {
+        for target in targets {
+            self.check(&target.to_string_lossy());
+        }
+        let targets: Vec<String> = targets.iter().map(|p| escape_path(p)).collect();
+        let _ = writeln!(self.out, "default {}", targets.join(" "));
+    }
+
+    /// The contents of the build file
+    pub fn as_str(&self) -> &str {
+        &self.out
+    }
+
+    /// Record a value that can't be written to the build file
+    fn check(&mut self, value: &str) {
+        if value.contains(['\n', '\r']) {
+            self.unescapable.push(value.to_string());
+        }
+    }
+
+    /// Write the build file to `path`
+    ///
+    /// Fails with `io::ErrorKind::InvalidInput` when a path or value contains a line break
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        if let Some(value) = self.unescapable.first() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{:?} contains a line break, which Ninja can't escape",
+                    value
+                ),
+            ));
+        }
+        fs::write(path, &self.out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer() -> NinjaWriter {
+        NinjaWriter::new(&Build::new("/opt/halide", "out").compiler("c++"))
+    }
+
+    #[test]
+    fn escapes_paths() {
+        assert_eq!(
+            escape_path(Path::new("out dir/a:b$c.o")),
+            "out$ dir/a$:b$$c.o"
+        );
+        assert_eq!(
+            escape_path(Path::new("C:\\src\\brighten.cpp")),
+            "C$:\\src\\brighten.cpp"
+        );
+        assert_eq!(escape_path(Path::new("plain/path.o")), "plain/path.o");
+    }
+
+    #[test]
+    fn escapes_values() {
+        assert_eq!(escape("-DHOME=$HOME -I a b"), "-DHOME=$$HOME -I a b");
+        assert_eq!(escape("$$"), "$$$$");
+    }
+
+    #[test]
+    fn build_edges_are_escaped() {
+        let mut w = writer();
+        w.build(
+            &[PathBuf::from("build dir/my:out.o")],
+            "cxx",
+            &[PathBuf::from("src/$brighten.cpp"), PathBuf::from("a b.cpp")],
+            &[("flags", "-DPRICE=$5".to_string())],
+        );
+        w.default(&[PathBuf::from("build dir/my:out.o")]);
+        let out = w.as_str();
+        assert!(out.contains(
+            "build build$ dir/my$:out.o: cxx src/$$brighten.cpp a$ b.cpp\n  flags = -DPRICE=$$5\n"
+        ));
+        assert!(out.ends_with("default build$ dir/my$:out.o\n"));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.ninja");
+        w.write(&path).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), out);
+    }
+
+    #[test]
+    fn line_breaks_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("build.ninja");
+
+        let mut w = writer();
+        w.build(
+            &[PathBuf::from("out.o")],
+            "cxx",
+            &[PathBuf::from("src/a\nb.cpp")],
+            &[],
+        );
+        let err = w.write(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+
+        let mut w = writer();
+        w.build(
+            &[PathBuf::from("out.o")],
+            "cxx",
+            &[],
+            &[("flags", "-DA=\"a\r\nb\"".to_string())],
+        );
+        assert!(w.write(&path).is_err());
+
+        let mut w = writer();
+        w.variable("cxxflags", "-O3\n-g");
+        assert!(w.write(&path).is_err());
+    }
+}
@@ -0,0 +1,362 @@
+//! Find compile and link flags for libraries using pkg-config
+//!
+//! Used for the image libraries, whose headers may be in a versioned directory like
+//! `/usr/include/libpng16` or a Homebrew prefix that isn't searched by default
+
+use std::path::PathBuf;
+use std::process::Command;
+
+use crate::args::split_flags;
+use crate::exec::Executor;
+
+/// Compile and link flags for a set of libraries
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Flags {
+    pub include_dirs: Vec<PathBuf>,
+    pub lib_dirs: Vec<PathBuf>,
+
+    /// Library names, without `-l`
+    pub libs: Vec<String>,
+
+    /// Other compile flags, like `-D` definitions
+    pub cflags: Vec<String>,
+
+    /// Other link flags, like `-Wl,` options or frameworks
+    pub ldflags: Vec<String>,
+}
+
+fn push_unique<T: PartialEq>(v: &mut Vec<T>, x: T) {
+    if !v.contains(&x) {
+        v.push(x)
+    }
+}
+
+impl Flags {
+    /// Parse the output of `pkg-config --cflags --libs`
+    pub fn parse(output: &str) -> Flags {
+        let mut flags = Flags::default();
+        // pkg-config escapes spaces in paths with a backslash
+        let words = split_flags(output);
+        let mut words = words.iter().map(String::as_str);
+        while let Some(word) = words.next() {
+            if let Some(dir) = word.strip_prefix("-I") {
+                push_unique(&mut flags.include_dirs, PathBuf::from(dir));
+            } else if let Some(dir) = word.strip_prefix("-L") {
+                push_unique(&mut flags.lib_dirs, PathBuf::from(dir));
+            } else if let Some(lib) = word.strip_prefix("-l") {
+                push_unique(&mut flags.libs, lib.to_string());
+            } else if word == "-isystem" || word == "-include" {
+                flags.cflags.push(word.to_string());
+                flags.cflags.extend(words.next().map(str::to_string));
+            } else if word == "-framework" {
+                flags.ldflags.push(word.to_string());
+                flags.ldflags.extend(words.next().map(str::to_string));
+            } else if ["-D", "-U", "-f", "-m", "-std"]
+                .iter()
+                .any(|prefix| word.starts_with(prefix))
+            {
+                push_unique(&mut flags.cflags, word.to_string());
+            } else if word == "-pthread" {
+                push_unique(&mut flags.cflags, word.to_string());
+                push_unique(&mut flags.ldflags, word.to_string());
+            } else {
+                push_unique(&mut flags.ldflags, word.to_string());
+            }
+        }
+        flags
+    }
+
+    /// Add the flags of `other`, skipping directories, libraries and flags already present
+    pub fn merge(&mut self, other: Flags) {
+        for dir in other.include_dirs {
+            push_unique(&mut self.include_dirs, dir);
+        }
+        for dir in other.lib_dirs {
+            push_unique(&mut self.lib_dirs, dir);
+        }
+        for lib in other.libs {
+            push_unique(&mut self.libs, lib);
+        }
+
+        // Flags with arguments, like `-framework Foo`, can't be deduplicated word by word
+        if !other.cflags.iter().all(|flag| self.cflags.contains(flag)) {
+            self.cflags.extend(other.cflags);
+        }
+        if !other.ldflags.iter().all(|flag| self.ldflags.contains(flag)) {
+            self.ldflags.extend(other.ldflags);
+        }
+    }
+
+    /// Arguments for a compile command
+    pub fn compile_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for dir in &self.include_dirs {
+            args.push(format!("-I{}", dir.display()));
+        }
+        args.extend(self.cflags.iter().cloned());
+        args
+    }
+
+    /// Arguments for a link command
+    pub fn link_args(&self) -> Vec<String> {
+        let mut args = vec![];
+        for dir in &self.lib_dirs {
+            args.push(format!("-L{}", dir.display()));
+        }
+        args.extend(self.libs.iter().map(|lib| format!("-l{}", lib)));
+        args.extend(self.ldflags.iter().cloned());
+        args
+    }
+}
+
+/// pkg-config packages that may provide a library, in order of preference
+pub fn package_names(lib: &str) -> Vec<String> {
+    match lib {
+        "png" => vec!["libpng".to_string(), "libpng16".to_string()],
+        "jpeg" => vec!["libjpeg".to_string()],
+        lib => vec![format!("lib{}", lib), lib.to_string()],
+    }
+}
+
+/// Query pkg-config for `package`, returns `None` when pkg-config isn't installed or doesn't
+/// know the package
+pub fn query(executor: &dyn Executor, package: &str) -> Option<Flags> {
+    let pkg_config = std::env::var("PKG_CONFIG").unwrap_or_else(|_| "pkg-config".to_string());
+    let mut cmd = Command::new(pkg_config);
+    cmd.args(["--cflags", "--libs", package]);
+    stdout(executor, &mut cmd).map(|output| Flags::parse(&output))
+}
+
+/// The Homebrew prefix of `formula`, if Homebrew is installed and the formula is present
+pub fn brew_prefix(executor: &dyn Executor, formula: &str) -> Option<PathBuf> {
+    let mut cmd = Command::new("brew");
+    cmd.args(["--prefix", formula]);
+    let prefix = stdout(executor, &mut cmd)?;
+    let prefix = PathBuf::from(prefix.trim());
+    prefix.exists().then_some(prefix)
+}
+
+/// The output of a successful command
+fn stdout(executor: &dyn Executor, cmd: &mut Command) -> Option<String> {
+    let output = executor.output(cmd).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Flags for linking `libs`, using pkg-config, then Homebrew on macOS, and falling back to
+/// `-l<lib>` when neither knows the library
+pub fn find(executor: &dyn Executor, libs: &[&str]) -> Flags {
+    let mut flags = Flags::default();
+    for lib in libs {
+        let found = package_names(lib)
+            .iter()
+            .filter_map(|package| query(executor, package))
+            .find(|found| !found.libs.is_empty());
+        if let Some(found) = found {
+            flags.merge(found);
+            continue;
+        }
+
+        if cfg!(target_os = "macos") {
+            let formula = if *lib == "png" {
+                "libpng".to_string()
+            } else {
+                lib.to_string()
+            };
+            if let Some(prefix) = brew_prefix(executor, &formula) {
+                flags.merge(Flags {
+                    include_dirs: vec![prefix.join("include")],
+                    lib_dirs: vec![prefix.join("lib")],
+                    ..Flags::default()
+                });
+            }
+        }
+        push_unique(&mut flags.libs, lib.to_string());
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// The program `query` runs
+    fn pkg_config() -> String {
+        std::env::var("PKG_CONFIG").unwrap_or_else(|_| "pkg-config".to_string())
+    }
+
+    #[test]
+    fn parse_distribution_output() {
+        // Ubuntu's libpng16 and libjpeg-turbo
+        assert_eq!(
+            Flags::parse("-I/usr/include/libpng16 -lpng16\n"),
+            Flags {
+                include_dirs: vec![PathBuf::from("/usr/include/libpng16")],
+                libs: strings(&["png16"]),
+                ..Flags::default()
+            }
+        );
+        assert_eq!(
+            Flags::parse("-ljpeg \n"),
+            Flags {
+                libs: strings(&["jpeg"]),
+                ..Flags::default()
+            }
+        );
+        assert_eq!(Flags::parse("\n"), Flags::default());
+    }
+
+    #[test]
+    fn parse_homebrew_output() {
+        let flags = Flags::parse(
+            "-I/opt/homebrew/Cellar/libpng/1.6.43/include/libpng16 -L/opt/homebrew/Cellar/libpng/1.6.43/lib -lpng16 -lz\n",
+        );
+        assert_eq!(
+            flags.include_dirs,
+            [PathBuf::from(
+                "/opt/homebrew/Cellar/libpng/1.6.43/include/libpng16"
+            )]
+        );
+        assert_eq!(
+            flags.lib_dirs,
+            [PathBuf::from("/opt/homebrew/Cellar/libpng/1.6.43/lib")]
+        );
+        assert_eq!(flags.libs, strings(&["png16", "z"]));
+    }
+
+    #[test]
+    fn parse_other_flags() {
+        let flags = Flags::parse(
+            r"-pthread -DPNG_STATIC -I/Users/me/My\ Libs/include -isystem /opt/include -std=c99 -L/Users/me/My\ Libs/lib -lpng -lpng -framework CoreFoundation -Wl,-rpath,/opt/lib -pthread",
+        );
+        assert_eq!(
+            flags,
+            Flags {
+                include_dirs: vec![PathBuf::from("/Users/me/My Libs/include")],
+                lib_dirs: vec![PathBuf::from("/Users/me/My Libs/lib")],
+                libs: strings(&["png"]),
+                cflags: strings(&[
+                    "-pthread",
+                    "-DPNG_STATIC",
+                    "-isystem",
+                    "/opt/include",
+                    "-std=c99"
+                ]),
+                ldflags: strings(&[
+                    "-pthread",
+                    "-framework",
+                    "CoreFoundation",
+                    "-Wl,-rpath,/opt/lib"
+                ]),
+            }
+        );
+        assert_eq!(
+            flags.compile_args(),
+            strings(&[
+                "-I/Users/me/My Libs/include",
+                "-pthread",
+                "-DPNG_STATIC",
+                "-isystem",
+                "/opt/include",
+                "-std=c99",
+            ])
+        );
+        assert_eq!(
+            flags.link_args(),
+            strings(&[
+                "-L/Users/me/My Libs/lib",
+                "-lpng",
+                "-pthread",
+                "-framework",
+                "CoreFoundation",
+                "-Wl,-rpath,/opt/lib",
+            ])
+        );
+    }
+
+    #[test]
+    fn merge() {
+        let mut flags = Flags::parse("-I/usr/include/libpng16 -pthread -lpng16 -lz");
+        flags.merge(Flags::parse(
+            "-I/usr/include/libpng16 -I/usr/include -lz -ljpeg -pthread",
+        ));
+        assert_eq!(
+            flags.include_dirs,
+            [
+                PathBuf::from("/usr/include/libpng16"),
+                PathBuf::from("/usr/include")
+            ]
+        );
+        assert_eq!(flags.libs, strings(&["png16", "z", "jpeg"]));
+        assert_eq!(flags.cflags, strings(&["-pthread"]));
+        assert_eq!(flags.ldflags, strings(&["-pthread"]));
+
+        // Flags with arguments are kept together
+        let mut flags = Flags::parse("-framework CoreFoundation");
+        flags.merge(Flags::parse("-framework Accelerate"));
+        flags.merge(Flags::parse("-framework CoreFoundation"));
+        assert_eq!(
+            flags.ldflags,
+            strings(&["-framework", "CoreFoundation", "-framework", "Accelerate"])
+        );
+    }
+
+    #[test]
+    fn package_names_for_image_libraries() {
+        assert_eq!(package_names("png"), ["libpng", "libpng16"]);
+        assert_eq!(package_names("jpeg"), ["libjpeg"]);
+        assert_eq!(package_names("tiff"), ["libtiff", "tiff"]);
+    }
+
+    #[test]
+    fn find_merges_packages() {
+        let pkg_config = pkg_config();
+        let mock = Mock::new()
+            .on(&pkg_config, Response::exit(1))
+            .on(
+                &pkg_config,
+                Response::ok().stdout("-I/usr/include/libpng16 -lpng16 -lz\n"),
+            )
+            .on(
+                &pkg_config,
+                Response::ok().stdout("-I/usr/include -ljpeg -lz\n"),
+            );
+        let flags = find(&mock, &["png", "jpeg"]);
+        assert_eq!(
+            flags.compile_args(),
+            ["-I/usr/include/libpng16", "-I/usr/include"]
+        );
+        assert_eq!(flags.link_args(), ["-lpng16", "-lz", "-ljpeg"]);
+
+        let calls = mock.calls_to(&pkg_config);
+        let packages: Vec<&str> = calls.iter().map(|call| call.args[2].as_str()).collect();
+        assert_eq!(packages, ["libpng", "libpng16", "libjpeg"]);
+        assert_eq!(calls[0].args[..2], ["--cflags", "--libs"]);
+    }
+
+    #[cfg(not(target_os = "macos"))]
+    #[test]
+    fn find_falls_back_to_library_names() {
+        // pkg-config isn't installed
+        let mock = Mock::new().on(&pkg_config(), Response::exit(127));
+        let flags = find(&mock, &["png", "jpeg"]);
+        assert_eq!(
+            flags,
+            Flags {
+                libs: strings(&["png", "jpeg"]),
+                ..Flags::default()
+            }
+        );
+
+        // Packages without libraries are skipped
+        let mock = Mock::new().on(&pkg_config(), Response::ok().stdout("-DPNG_STATIC\n"));
+        assert_eq!(find(&mock, &["png"]).link_args(), ["-lpng"]);
+    }
+}
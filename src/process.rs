@@ -0,0 +1,327 @@
+//! Child process execution shared by the build, run and source steps
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+use crate::exec::ResourceUsage;
+
+/// How long an interrupted child is given to exit before it is killed
+pub(crate) const INTERRUPT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A flag that can be set from another thread, or a signal handler, to interrupt running
+/// child processes
+///
+/// Interrupted steps return an error with `io::ErrorKind::Interrupted` after any temporary
+/// files have been removed
+#[derive(Debug, Clone, Default)]
+pub struct InterruptToken(Arc<AtomicBool>);
+
+impl InterruptToken {
+    pub fn new() -> InterruptToken {
+        InterruptToken::default()
+    }
+
+    /// Request that running child processes are stopped
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::SeqCst)
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Run a command to completion, stopping it if `token` is interrupted, and writing `input` to
+/// the child's standard input, which must be piped
+///
+/// When `group` is set the child is started in its own process group on Unix, so the
+/// interrupt is forwarded to any processes it spawns, like the compiler driver's subprocesses.
+/// Commands that may need the terminal should not use their own group.
+pub(crate) fn status_with_input(
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    let mut child = spawn(cmd, token, group)?;
+    let writer = write_input(&mut child, input);
+    let res = wait(&mut child, token, group)?;
+    join_input(writer)?;
+    Ok(res)
+}
+
+/// Write `input` to the child's standard input from another thread, so a child that doesn't
+/// read its input, or fills its output pipes first, can't block us
+fn write_input(
+    child: &mut Child,
+    input: Option<&[u8]>,
+) -> Option<thread::JoinHandle<io::Result<()>>> {
+    match (input, child.stdin.take()) {
+        (Some(input), Some(mut stdin)) => {
+            let input = input.to_vec();
+            Some(thread::spawn(move || match stdin.write_all(&input) {
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                res => res,
+            }))
+        }
+        _ => None,
+    }
+}
+
+fn join_input(writer: Option<thread::JoinHandle<io::Result<()>>>) -> io::Result<()> {
+    match writer {
+        Some(writer) => writer.join().expect("stdin writer panicked"),
+        None => Ok(()),
+    }
+}
+
+/// An output stream of a child process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Like `status_with_input`, calling `on_line` with each line the child writes to the given
+/// streams, other streams are inherited
+///
+/// Lines are read on other threads and passed to `on_line` on the calling thread in the
+/// order they arrive. They're passed as written, including the line ending, the last line
+/// may not have one
+pub(crate) fn status_with_lines(
+    cmd: &mut Command,
+    token: &InterruptToken,
+    group: bool,
+    input: Option<&[u8]>,
+    streams: &[Stream],
+    on_line: &mut dyn FnMut(Stream, &[u8]),
+) -> io::Result<ExitStatus> {
+    for stream in streams {
+        match stream {
+            Stream::Stdout => cmd.stdout(Stdio::piped()),
+            Stream::Stderr => cmd.stderr(Stdio::piped()),
+        };
+    }
+    let mut child = spawn(cmd, token, group)?;
+    let writer = write_input(&mut child, input);
+
+    let (tx, rx) = mpsc::channel();
+    let mut readers = vec![];
+    if let Some(stdout) = child.stdout.take() {
+        readers.push(read_lines(stdout, Stream::Stdout, tx.clone()));
+    }
+    if let Some(stderr) = child.stderr.take() {
+        readers.push(read_lines(stderr, Stream::Stderr, tx.clone()));
+    }
+    drop(tx);
+
+    let status = loop {
+        while let Ok((stream, line)) = rx.try_recv() {
+            on_line(stream, &line);
+        }
+
+        if let Some((status, _)) = try_wait(&mut child)? {
+            break status;
+        }
+
+        if token.is_interrupted() {
+            stop(&mut child, group)?;
+            return Err(interrupted());
+        }
+
+        sleep(POLL_INTERVAL);
+    };
+
+    // Forward anything written just before the child exited
+    for (stream, line) in rx {
+        on_line(stream, &line);
+    }
+    for reader in readers {
+        reader.join().expect("output reader panicked")?;
+    }
+    join_input(writer)?;
+    Ok(status)
+}
+
+fn read_lines(
+    output: impl io::Read + Send + 'static,
+    stream: Stream,
+    tx: mpsc::Sender<(Stream, Vec<u8>)>,
+) -> thread::JoinHandle<io::Result<()>> {
+    thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        loop {
+            let mut line = vec![];
+            if output.read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if tx.send((stream, line)).is_err() {
+                return Ok(());
+            }
+        }
+    })
+}
+
+fn spawn(cmd: &mut Command, token: &InterruptToken, group: bool) -> io::Result<Child> {
+    if token.is_interrupted() {
+        return Err(interrupted());
+    }
+
+    #[cfg(unix)]
+    if group {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+
+    #[cfg(not(unix))]
+    let _ = group;
+
+    cmd.spawn()
+}
+
+fn wait(
+    child: &mut Child,
+    token: &InterruptToken,
+    group: bool,
+) -> io::Result<(ExitStatus, Option<ResourceUsage>)> {
+    loop {
+        if let Some(res) = try_wait(child)? {
+            return Ok(res);
+        }
+
+        if token.is_interrupted() {
+            stop(child, group)?;
+            return Err(interrupted());
+        }
+
+        sleep(POLL_INTERVAL);
+    }
+}
+
+/// Check if the child has exited, collecting its resource usage on Unix
+///
+/// The child is reaped using `wait4`, so `Child::wait` and `Child::try_wait` must not be
+/// called once this returns a status
+#[cfg(unix)]
+fn try_wait(child: &mut Child) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    let pid = child.id() as libc::pid_t;
+    match unsafe { libc::wait4(pid, &mut status, libc::WNOHANG, &mut usage) } {
+        0 => Ok(None),
+        -1 => match io::Error::last_os_error() {
+            e if e.kind() == io::ErrorKind::Interrupted => Ok(None),
+            e => Err(e),
+        },
+        _ => Ok(Some((
+            ExitStatus::from_raw(status),
+            Some(ResourceUsage::from_rusage(&usage)),
+        ))),
+    }
+}
+
+#[cfg(not(unix))]
+fn try_wait(child: &mut Child) -> io::Result<Option<(ExitStatus, Option<ResourceUsage>)>> {
+    Ok(child.try_wait()?.map(|status| (status, None)))
+}
+
+/// Forward an interrupt to the child, then kill it if it doesn't exit in time
+fn stop(child: &mut Child, group: bool) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let pid = child.id() as libc::pid_t;
+        let target = if group { -pid } else { pid };
+        unsafe {
+            libc::kill(target, libc::SIGINT);
+        }
+
+        let start = Instant::now();
+        while start.elapsed() < INTERRUPT_GRACE_PERIOD {
+            if child.try_wait()?.is_some() {
+                return Ok(());
+            }
+            sleep(POLL_INTERVAL);
+        }
+
+        if group {
+            unsafe {
+                libc::kill(target, libc::SIGKILL);
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = (group, Instant::now(), INTERRUPT_GRACE_PERIOD);
+
+    let _ = child.kill();
+    child.wait().map(|_| ())
+}
+
+/// Set the niceness and CPU affinity of the child before it starts
+///
+/// Only async-signal-safe calls are made between fork and exec, and the CPU set is built
+/// before forking
+#[cfg(target_os = "linux")]
+pub(crate) fn set_scheduling(
+    cmd: &mut Command,
+    nice: Option<i32>,
+    cpus: &[usize],
+) -> io::Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let max = 8 * std::mem::size_of::<libc::cpu_set_t>();
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Invalid CPU index {}", cpu),
+            ));
+        }
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    let affinity = !cpus.is_empty();
+
+    unsafe {
+        cmd.pre_exec(move || {
+            if let Some(increment) = nice {
+                // -1 is a valid niceness, so errors are detected using errno
+                *libc::__errno_location() = 0;
+                if libc::nice(increment) == -1 && *libc::__errno_location() != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+
+            if affinity
+                && libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(())
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_scheduling(
+    _cmd: &mut Command,
+    _nice: Option<i32>,
+    _cpus: &[usize],
+) -> io::Result<()> {
+    Ok(())
+}
+
+pub(crate) fn interrupted() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "Interrupted")
+}
@@ -0,0 +1,152 @@
+//! Estimate progress from the output of make, CMake and ninja builds
+
+/// Parse CMake's Makefile progress prefix, like `[ 42%] Building CXX object ...`
+pub fn parse_percent(line: &str) -> Option<f32> {
+    let rest = line.trim_start().strip_prefix('[')?;
+    let (value, _) = rest.split_once("%]")?;
+    value.trim().parse().ok()
+}
+
+/// Parse ninja's step prefix, like `[12/345] Building CXX object ...`
+pub fn parse_steps(line: &str) -> Option<(u32, u32)> {
+    let rest = line.trim_start().strip_prefix('[')?;
+    let (steps, _) = rest.split_once(']')?;
+    let (n, total) = steps.split_once('/')?;
+    Some((n.trim().parse().ok()?, total.trim().parse().ok()?))
+}
+
+/// Returns true for a compiler invocation that produces an object file, as echoed by make
+pub fn is_compile_line(line: &str) -> bool {
+    let mut words = line.split_whitespace();
+    let mut compile = false;
+    let mut object = false;
+    while let Some(word) = words.next() {
+        match word {
+            "-c" => compile = true,
+            "-o" => object = words.next().is_some_and(|out| out.ends_with(".o")),
+            _ => (),
+        }
+    }
+    compile && object
+}
+
+/// Tracks progress across the lines of a build
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    /// Number of sources compiled by a plain Makefile, used to estimate progress from
+    /// compile lines
+    pub total_sources: Option<usize>,
+
+    compiled: usize,
+}
+
+impl Parser {
+    pub fn new(total_sources: Option<usize>) -> Parser {
+        Parser {
+            total_sources,
+            compiled: 0,
+        }
+    }
+
+    /// The progress after `line` in percent, if it can be determined
+    pub fn line(&mut self, line: &str) -> Option<f32> {
+        if let Some(percent) = parse_percent(line) {
+            return Some(percent);
+        }
+
+        if let Some((n, total)) = parse_steps(line) {
+            if total > 0 {
+                return Some(100.0 * n as f32 / total as f32);
+            }
+            return None;
+        }
+
+        if is_compile_line(line) {
+            self.compiled += 1;
+            let total = self.total_sources.filter(|total| *total > 0)?;
+            return Some((100.0 * self.compiled as f32 / total as f32).min(99.0));
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cmake_makefile_output() {
+        assert_eq!(
+            parse_percent(
+                "[  0%] Building CXX object src/CMakeFiles/Halide.dir/AddAtomicMutex.cpp.o"
+            ),
+            Some(0.0)
+        );
+        assert_eq!(
+            parse_percent("[ 42%] Building CXX object src/CMakeFiles/Halide.dir/Func.cpp.o"),
+            Some(42.0)
+        );
+        assert_eq!(
+            parse_percent("[100%] Linking CXX shared library libHalide.so"),
+            Some(100.0)
+        );
+        assert_eq!(
+            parse_percent("Scanning dependencies of target Halide"),
+            None
+        );
+        assert_eq!(parse_percent("[ninja] no percent"), None);
+    }
+
+    #[test]
+    fn ninja_output() {
+        assert_eq!(
+            parse_steps("[12/345] Building CXX object src/CMakeFiles/Halide.dir/Func.cpp.o"),
+            Some((12, 345))
+        );
+        assert_eq!(
+            parse_steps("[345/345] Linking CXX shared library src/libHalide.so.16.0.0"),
+            Some((345, 345))
+        );
+        assert_eq!(parse_steps("[ 42%] Building CXX object"), None);
+        assert_eq!(parse_steps("ninja: no work to do."), None);
+    }
+
+    #[test]
+    fn make_compile_lines() {
+        assert!(is_compile_line(
+            "g++ -std=c++17 -O3 -fno-rtti -Iinclude -c src/Func.cpp -o build/Func.o"
+        ));
+        assert!(!is_compile_line(
+            "g++ -shared build/Func.o build/Var.o -o lib/libHalide.so"
+        ));
+        assert!(!is_compile_line(
+            "g++ -c src/Func.cpp -o build/Func.ll -emit-llvm"
+        ));
+        assert!(!is_compile_line(
+            "make[1]: Entering directory '/src/halide'"
+        ));
+    }
+
+    #[test]
+    fn parser() {
+        let mut parser = Parser::default();
+        assert_eq!(
+            parser.line("[ 50%] Building CXX object a.cpp.o"),
+            Some(50.0)
+        );
+        assert_eq!(parser.line("[3/4] Linking CXX executable gen"), Some(75.0));
+        assert_eq!(parser.line("[0/0] nothing"), None);
+        // Compile lines need the number of sources
+        assert_eq!(parser.line("c++ -c a.cpp -o a.o"), None);
+
+        let mut parser = Parser::new(Some(4));
+        assert_eq!(parser.line("c++ -c a.cpp -o a.o"), Some(25.0));
+        assert_eq!(parser.line("c++ -c b.cpp -o b.o"), Some(50.0));
+        assert_eq!(parser.line("ar rcs libHalide.a a.o b.o"), None);
+        assert_eq!(parser.line("c++ -c c.cpp -o c.o"), Some(75.0));
+        assert_eq!(parser.line("c++ -c d.cpp -o d.o"), Some(99.0));
+        // More sources than expected stay below 100%
+        assert_eq!(parser.line("c++ -c e.cpp -o e.o"), Some(99.0));
+    }
+}
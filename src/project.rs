@@ -0,0 +1,185 @@
+//! Project layout and the `halide.toml` project file
+//!
+//! A project file lists the kernels in a project:
+//!
+//! ```toml
+//! [[kernel]]
+//! name = "brighten"
+//! sources = ["brighten_generator.cpp"]
+//! target = "host"
+//! output_dir = "build"
+//!
+//! [kernel.params]
+//! scale = "2"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::template::{self, TemplateVars};
+
+/// Default project file name
+pub const FILE_NAME: &str = "halide.toml";
+
+/// A kernel emitted by a generator
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Kernel {
+    /// Name of the emitted function
+    pub name: String,
+
+    /// Generator sources, relative to the project file
+    pub sources: Vec<PathBuf>,
+
+    /// Name of the registered generator, defaults to `name`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub generator: Option<String>,
+
+    /// Halide target, defaults to `host`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+
+    /// Directory for emitted files, defaults to `build`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output_dir: Option<PathBuf>,
+
+    /// Generator parameters
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, String>,
+}
+
+impl Kernel {
+    pub fn generator_name(&self) -> &str {
+        self.generator.as_deref().unwrap_or(&self.name)
+    }
+
+    pub fn output_dir(&self) -> &Path {
+        self.output_dir.as_deref().unwrap_or(Path::new("build"))
+    }
+}
+
+/// The contents of a project file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Project {
+    #[serde(default, rename = "kernel")]
+    pub kernels: Vec<Kernel>,
+}
+
+impl Project {
+    pub fn parse(s: &str) -> io::Result<Project> {
+        toml::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+/// Load a project file, or `halide.toml` when given a directory
+pub fn load(path: impl AsRef<Path>) -> io::Result<Project> {
+    let mut path = path.as_ref().to_path_buf();
+    if path.is_dir() {
+        path.push(FILE_NAME);
+    }
+
+    Project::parse(&fs::read_to_string(&path)?).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Invalid project file {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// Source for a new generator, `class` is the C++ class name and `name` the registered name
+pub fn generator_source(class: &str, name: &str) -> String {
+    template::render(template::DEFAULT, &TemplateVars::new(class, name))
+        .expect("Invalid default template")
+}
+
+/// Create a new project in `dir` with a generator, a project file and a README
+///
+/// Fails if `dir` exists and isn't empty, unless `force` is set
+pub fn init(dir: impl AsRef<Path>, force: bool) -> io::Result<()> {
+    let dir = dir.as_ref();
+    if !force && dir.exists() && fs::read_dir(dir)?.next().is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} is not empty, use force to overwrite existing files",
+                dir.display()
+            ),
+        ));
+    }
+
+    fs::create_dir_all(dir)?;
+
+    let name = identifier(
+        &dir.canonicalize()?
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy(),
+    );
+    let class: String = name
+        .split('_')
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            chars
+                .next()
+                .map(|c| c.to_ascii_uppercase().to_string() + chars.as_str())
+                .unwrap_or_default()
+        })
+        .collect();
+    let source = format!("{}_generator.cpp", name);
+
+    fs::write(dir.join(&source), generator_source(&class, &name))?;
+
+    let project = Project {
+        kernels: vec![Kernel {
+            name: name.clone(),
+            sources: vec![PathBuf::from(&source)],
+            generator: None,
+            target: Some("host".to_string()),
+            output_dir: Some(PathBuf::from("build")),
+            params: BTreeMap::new(),
+        }],
+    };
+    let toml = toml::to_string_pretty(&project).map_err(io::Error::other)?;
+    fs::write(dir.join(FILE_NAME), toml)?;
+
+    fs::write(
+        dir.join("README.md"),
+        format!(
+            "# {name}
+
+A Halide generator project.
+
+- `{source}` defines the `{name}` generator
+- `{file}` lists the kernels emitted by the project
+
+Build and run the generator with:
+
+```
+halide build -g {name}_gen {source}
+./{name}_gen -g {name} -o build target=host
+```
+",
+            file = FILE_NAME
+        ),
+    )?;
+
+    Ok(())
+}
+
+/// Convert a directory name into a C++ identifier
+fn identifier(name: &str) -> String {
+    let mut id: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if id.is_empty() || id.starts_with(|c: char| c.is_ascii_digit()) {
+        id.insert_str(0, "kernel_");
+    }
+    id.to_ascii_lowercase()
+}
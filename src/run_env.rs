@@ -0,0 +1,252 @@
+//! The environment used to run built executables and generators
+
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Library search path entries, executable search path entries and environment variables for
+/// running an executable
+///
+/// Search paths are kept in the order they were added, without duplicates, and are prepended
+/// to any existing value of the platform's library path variable or `PATH`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunEnv {
+    pub lib_paths: Vec<PathBuf>,
+    pub exe_paths: Vec<PathBuf>,
+    pub vars: Vec<(String, String)>,
+}
+
+impl RunEnv {
+    pub fn new() -> RunEnv {
+        RunEnv::default()
+    }
+
+    /// Add a library search path, ignored if it has already been added
+    pub fn lib_path(mut self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        if !self.lib_paths.contains(&path) {
+            self.lib_paths.push(path);
+        }
+        self
+    }
+
+    /// Add an executable search path, ignored if it has already been added
+    pub fn exe_path(mut self, path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        if !self.exe_paths.contains(&path) {
+            self.exe_paths.push(path);
+        }
+        self
+    }
+
+    /// Set an environment variable, replacing any earlier value
+    pub fn var(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        let name = name.into();
+        let value = value.into();
+        match self.vars.iter_mut().find(|(n, _)| *n == name) {
+            Some(var) => var.1 = value,
+            None => self.vars.push((name, value)),
+        }
+        self
+    }
+
+    /// The library search path variable for the current platform
+    pub fn lib_path_var() -> &'static str {
+        lib_path_var_for(env::consts::OS)
+    }
+
+    /// The value of the library path variable, including the inherited value
+    ///
+    /// On Windows, where libraries are found using `PATH`, this includes `exe_paths`
+    pub fn lib_path_value(&self) -> Option<OsString> {
+        if Self::lib_path_var() == "PATH" {
+            return self.path_value();
+        }
+        prepend(Self::lib_path_var(), &self.lib_paths)
+    }
+
+    /// The value of `PATH`, with `exe_paths` prepended to the inherited value
+    ///
+    /// On Windows this includes `lib_paths`
+    pub fn path_value(&self) -> Option<OsString> {
+        let paths = match Self::lib_path_var() {
+            "PATH" => [&self.exe_paths[..], &self.lib_paths[..]].concat(),
+            _ => self.exe_paths.clone(),
+        };
+        prepend("PATH", &paths)
+    }
+
+    /// Set the variables on a command
+    pub fn apply(&self, cmd: &mut Command) {
+        if let Some(value) = self.lib_path_value() {
+            cmd.env(Self::lib_path_var(), value);
+        }
+
+        if let Some(value) = self.path_value() {
+            cmd.env("PATH", value);
+        }
+
+        for (name, value) in &self.vars {
+            cmd.env(name, value);
+        }
+    }
+
+    /// Render the environment as `export` lines for a POSIX shell
+    pub fn to_shell(&self) -> String {
+        let mut s = String::new();
+        if !self.lib_paths.is_empty() {
+            let var = Self::lib_path_var();
+            let sep = if cfg!(windows) { ";" } else { ":" };
+            let paths: Vec<String> = self
+                .lib_paths
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect();
+            s.push_str(&format!(
+                "export {}={}${{{}:+{}${}}}\n",
+                var,
+                shell_quote(&paths.join(sep)),
+                var,
+                sep,
+                var
+            ));
+        }
+
+        if !self.exe_paths.is_empty() {
+            let sep = if cfg!(windows) { ";" } else { ":" };
+            let paths: Vec<String> = self
+                .exe_paths
+                .iter()
+                .map(|p| p.to_string_lossy().into_owned())
+                .collect();
+            s.push_str(&format!(
+                "export PATH={}${{PATH:+{}$PATH}}\n",
+                shell_quote(&paths.join(sep)),
+                sep
+            ));
+        }
+
+        for (name, value) in &self.vars {
+            s.push_str(&format!("export {}={}\n", name, shell_quote(value)));
+        }
+        s
+    }
+}
+
+/// The library search path variable used on `os`, as named by `std::env::consts::OS`
+pub fn lib_path_var_for(os: &str) -> &'static str {
+    match os {
+        "macos" | "ios" => "DYLD_LIBRARY_PATH",
+        "windows" => "PATH",
+        _ => "LD_LIBRARY_PATH",
+    }
+}
+
+/// `paths` followed by the inherited value of `var`, without duplicates, `None` when `paths` is
+/// empty
+fn prepend(var: &str, paths: &[PathBuf]) -> Option<OsString> {
+    if paths.is_empty() {
+        return None;
+    }
+
+    join_paths(paths, &env::var_os(var).unwrap_or_default())
+}
+
+/// `paths` followed by the entries of `existing`, skipping duplicates and empty entries
+fn join_paths(paths: &[PathBuf], existing: &OsString) -> Option<OsString> {
+    let mut joined: Vec<PathBuf> = vec![];
+    // An empty entry would add the current directory
+    for path in paths.iter().cloned().chain(env::split_paths(existing)) {
+        if !path.as_os_str().is_empty() && !joined.contains(&path) {
+            joined.push(path);
+        }
+    }
+    env::join_paths(joined).ok()
+}
+
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn search_paths_are_deduplicated_in_order() {
+        let run_env = RunEnv::new()
+            .lib_path("b")
+            .lib_path("a")
+            .lib_path("b")
+            .exe_path("bin")
+            .exe_path("bin");
+        assert_eq!(run_env.lib_paths, [PathBuf::from("b"), PathBuf::from("a")]);
+        assert_eq!(run_env.exe_paths, [PathBuf::from("bin")]);
+    }
+
+    #[test]
+    fn vars_replace_earlier_values() {
+        let run_env = RunEnv::new().var("A", "1").var("B", "2").var("A", "3");
+        assert_eq!(
+            run_env.vars,
+            [
+                ("A".to_string(), "3".to_string()),
+                ("B".to_string(), "2".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn platform_lib_path_var() {
+        assert_eq!(lib_path_var_for("linux"), "LD_LIBRARY_PATH");
+        assert_eq!(lib_path_var_for("freebsd"), "LD_LIBRARY_PATH");
+        assert_eq!(lib_path_var_for("macos"), "DYLD_LIBRARY_PATH");
+        assert_eq!(lib_path_var_for("ios"), "DYLD_LIBRARY_PATH");
+        assert_eq!(lib_path_var_for("windows"), "PATH");
+        assert_eq!(RunEnv::lib_path_var(), lib_path_var_for(env::consts::OS));
+    }
+
+    #[test]
+    fn paths_are_prepended_to_the_inherited_value() {
+        let inherited = env::join_paths(["/usr/lib", "", "a", "/opt/lib"]).unwrap();
+        let paths = [PathBuf::from("b"), PathBuf::from("a")];
+        let joined = join_paths(&paths, &inherited).unwrap();
+        assert_eq!(
+            env::split_paths(&joined).collect::<Vec<_>>(),
+            ["b", "a", "/usr/lib", "/opt/lib"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn apply_sets_every_variable() {
+        let run_env = RunEnv::new().lib_path("lib").exe_path("bin").var("A", "1");
+        let mut cmd = Command::new("true");
+        run_env.apply(&mut cmd);
+        let envs: Vec<_> = cmd
+            .get_envs()
+            .map(|(k, v)| (k.to_owned(), v.map(|v| v.to_owned())))
+            .collect();
+        let get = |name: &str| {
+            envs.iter()
+                .find(|(k, _)| k == name)
+                .and_then(|(_, v)| v.clone())
+        };
+
+        let lib = get(RunEnv::lib_path_var()).unwrap();
+        assert_eq!(env::split_paths(&lib).next(), Some(PathBuf::from("lib")));
+        let path = get("PATH").unwrap();
+        assert_eq!(env::split_paths(&path).next(), Some(PathBuf::from("bin")));
+        assert_eq!(get("A"), Some("1".into()));
+        assert!(RunEnv::new().lib_path_value().is_none());
+    }
+
+    #[test]
+    fn shell_exports() {
+        let run_env = RunEnv::new().exe_path("/a b").var("X", "it's");
+        assert_eq!(
+            run_env.to_shell(),
+            "export PATH='/a b'${PATH:+:$PATH}\nexport X='it'\\''s'\n"
+        );
+    }
+}
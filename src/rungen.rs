@@ -0,0 +1,327 @@
+//! Build one RunGen executable that can run any of several emitted pipelines
+//!
+//! Each pipeline must be emitted with `Emit::Registration` and `Emit::StaticLibrary`, and
+//! with the `no_runtime` target feature, so the pipelines can share a single runtime emitted
+//! by `GenBuilder::emit_runtime`. The executable accepts the usual RunGen arguments, plus:
+//!
+//! - `--list` to print the names of the registered pipelines
+//! - `--pipeline=<name>` to select the pipeline to run, optional when only one is registered
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::gen::Emit;
+use crate::manifest::Pipeline;
+use crate::{Build, OutputKind, TargetFeature};
+
+/// `main` for the executable
+///
+/// RunGen only accepts a single registered pipeline, so `RunGenMain.cpp` is included with its
+/// `main` and registration function renamed, and the selected pipeline is registered with it
+/// before calling its `main`
+pub fn main_source() -> String {
+    r#"// Generated by halide-build, do not edit
+#include <cstdio>
+#include <cstring>
+#include <vector>
+
+#include "RunGen.h"
+
+#define main halide_rungen_main
+#define halide_register_argv_and_metadata halide_rungen_register
+#include "RunGenMain.cpp"
+#undef main
+#undef halide_register_argv_and_metadata
+
+namespace {
+
+struct Registered {
+    int (*call)(void **);
+    const struct halide_filter_metadata_t *metadata;
+    const char *const *extra;
+};
+
+// Filled by the registration files during static initialization
+std::vector<Registered> &registered() {
+    static std::vector<Registered> pipelines;
+    return pipelines;
+}
+
+}  // namespace
+
+extern "C" void halide_register_argv_and_metadata(
+    int (*call)(void **),
+    const struct halide_filter_metadata_t *metadata,
+    const char *const *extra) {
+    registered().push_back({call, metadata, extra});
+}
+
+int main(int argc, char **argv) {
+    const char *name = nullptr;
+    std::vector<char *> args = {argv[0]};
+    for (int i = 1; i < argc; i++) {
+        if (strcmp(argv[i], "--list") == 0) {
+            for (const Registered &r : registered()) {
+                printf("%s\n", r.metadata->name);
+            }
+            return 0;
+        } else if (strncmp(argv[i], "--pipeline=", 11) == 0) {
+            name = argv[i] + 11;
+        } else {
+            args.push_back(argv[i]);
+        }
+    }
+
+    if (name == nullptr && registered().size() == 1) {
+        name = registered()[0].metadata->name;
+    }
+
+    for (const Registered &r : registered()) {
+        if (name != nullptr && strcmp(r.metadata->name, name) == 0) {
+            halide_rungen_register(r.call, r.metadata, r.extra);
+            int n = (int)args.size();
+            args.push_back(nullptr);
+            return halide_rungen_main(n, args.data());
+        }
+    }
+
+    if (name == nullptr) {
+        fprintf(stderr, "Select a pipeline using --pipeline=<name>, see --list\n");
+    } else {
+        fprintf(stderr, "Unknown pipeline %s, see --list\n", name);
+    }
+    return 1;
+}
+"#
+    .to_string()
+}
+
+/// Make sure none of the pipelines were emitted with their own runtime
+///
+/// Fails with `io::ErrorKind::InvalidInput` naming the pipelines whose targets are missing
+/// the `no_runtime` feature, since their runtimes would conflict with the shared one
+pub fn check_runtimes(pipelines: &[Pipeline]) -> io::Result<()> {
+    let no_runtime = TargetFeature::NoRuntime.name();
+    let conflicting: Vec<&str> = pipelines
+        .iter()
+        .filter(|p| {
+            p.targets
+                .iter()
+                .any(|target| !target.split('-').any(|feature| feature == no_runtime))
+        })
+        .map(|p| p.function.as_str())
+        .collect();
+
+    if conflicting.is_empty() {
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "Emitted with their own runtime: {}, emit them with the {} target feature to share one",
+            conflicting.join(", "),
+            no_runtime
+        ),
+    ))
+}
+
+/// The registration files and static libraries of `pipelines`, in order
+///
+/// Fails with `io::ErrorKind::NotFound` when a pipeline wasn't emitted with both
+pub fn inputs(pipelines: &[Pipeline]) -> io::Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+    let find = |pipeline: &Pipeline, emit: Emit| {
+        pipeline
+            .files
+            .iter()
+            .map(|file| &file.path)
+            .find(|path| path.to_string_lossy().ends_with(emit.extension()))
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Pipeline {} has no {} file, emit it with Emit::{:?}",
+                        pipeline.function,
+                        emit.name(),
+                        emit
+                    ),
+                )
+            })
+    };
+
+    let mut registrations = vec![];
+    let mut archives = vec![];
+    for pipeline in pipelines {
+        registrations.push(find(pipeline, Emit::Registration)?);
+        archives.push(find(pipeline, Emit::StaticLibrary)?);
+    }
+    Ok((registrations, archives))
+}
+
+/// Builds a RunGen executable for several pipelines
+#[derive(Debug, Clone)]
+pub struct MultiRunGen<'a> {
+    /// Builds the executable, `build.output` is the executable path
+    pub build: Build<'a>,
+
+    /// Runtime object shared by the pipelines, see `GenBuilder::emit_runtime`
+    pub runtime: PathBuf,
+}
+
+impl<'a> MultiRunGen<'a> {
+    /// Create a new builder, `build` supplies the output path, compiler and flags
+    pub fn new(build: Build<'a>, runtime: impl AsRef<Path>) -> MultiRunGen<'a> {
+        MultiRunGen {
+            build: build.generator(false).output_kind(OutputKind::Executable),
+            runtime: runtime.as_ref().to_path_buf(),
+        }
+    }
+
+    /// The build for the executable, compiling `RunGenMain.cpp` and the registration files
+    /// and linking the static libraries and the shared runtime
+    pub fn executable(&self, pipelines: &[Pipeline]) -> io::Result<Build<'a>> {
+        if pipelines.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "At least one pipeline is required",
+            ));
+        }
+        check_runtimes(pipelines)?;
+        let (registrations, archives) = inputs(pipelines)?;
+
+        let mut build = self
+            .build
+            .clone()
+            .source_string("rungen_main.cpp", &main_source())?
+            .object(&self.runtime);
+        build.src.extend(registrations);
+        build.archives.extend(archives);
+        Ok(build)
+    }
+
+    /// Compile the executable, see `executable`
+    pub fn build(&self, pipelines: &[Pipeline]) -> io::Result<bool> {
+        self.executable(pipelines)?.build()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::File;
+
+    fn pipeline(function: &str, targets: &[&str], emits: &[Emit]) -> Pipeline {
+        Pipeline {
+            function: function.to_string(),
+            symbol: None,
+            generator: function.to_string(),
+            sources: vec![],
+            targets: targets.iter().map(|t| t.to_string()).collect(),
+            halide_version: None,
+            files: emits
+                .iter()
+                .map(|emit| File {
+                    path: PathBuf::from(format!("out/{}{}", function, emit.extension())),
+                    hash: String::new(),
+                })
+                .collect(),
+            arguments: vec![],
+            staged_headers: vec![],
+        }
+    }
+
+    #[test]
+    fn runtime_conflicts() {
+        // Targets of each pipeline and the pipelines expected to conflict
+        let table: &[(&[&[&str]], &[&str])] = &[
+            (
+                &[&["host-no_runtime"], &["x86-64-linux-no_runtime-avx2"]],
+                &[],
+            ),
+            (&[&["host"], &["host-no_runtime"]], &["p0"]),
+            // Every target of a multi-target pipeline needs the feature
+            (&[&["host-no_runtime", "x86-64-linux"]], &["p0"]),
+            // A feature with the same prefix isn't enough
+            (&[&["host-no_runtime_x"], &["host"]], &["p0", "p1"]),
+            (&[], &[]),
+        ];
+        for (targets, conflicting) in table {
+            let pipelines: Vec<Pipeline> = targets
+                .iter()
+                .enumerate()
+                .map(|(i, targets)| pipeline(&format!("p{}", i), targets, &[]))
+                .collect();
+            let res = check_runtimes(&pipelines);
+            if conflicting.is_empty() {
+                assert!(res.is_ok(), "{:?}", targets);
+                continue;
+            }
+            let err = res.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(
+                err.to_string().starts_with(&format!(
+                    "Emitted with their own runtime: {},",
+                    conflicting.join(", ")
+                )),
+                "{:?}: {}",
+                targets,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn registration_and_archive_inputs() {
+        let both = [Emit::Registration, Emit::StaticLibrary];
+        let pipelines = [
+            pipeline("a", &[], &both),
+            pipeline("b", &[], &[Emit::StaticLibrary, Emit::Registration]),
+        ];
+        let (registrations, archives) = inputs(&pipelines).unwrap();
+        assert_eq!(
+            registrations,
+            [
+                PathBuf::from("out/a.registration.cpp"),
+                PathBuf::from("out/b.registration.cpp")
+            ]
+        );
+        assert_eq!(
+            archives,
+            [
+                PathBuf::from(format!("out/a{}", Emit::StaticLibrary.extension())),
+                PathBuf::from(format!("out/b{}", Emit::StaticLibrary.extension()))
+            ]
+        );
+
+        let err = inputs(&[pipeline("c", &[], &[Emit::StaticLibrary])]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err.to_string().contains("Emit::Registration"));
+    }
+
+    #[test]
+    fn executable_inputs() {
+        let rungen = MultiRunGen::new(Build::new("/opt/halide", "out/rungen"), "out/runtime.o");
+        let err = rungen.executable(&[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let both = [Emit::Registration, Emit::StaticLibrary];
+        let conflicting = [pipeline("a", &["host"], &both)];
+        let err = rungen.executable(&conflicting).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let build = rungen
+            .executable(&[pipeline("a", &["host-no_runtime"], &both)])
+            .unwrap();
+        assert_eq!(build.output_kind, OutputKind::Executable);
+        assert_eq!(build.src.len(), 2);
+        assert!(build.src[0]
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with("rungen_main-"));
+        assert_eq!(build.src[1], PathBuf::from("out/a.registration.cpp"));
+        assert_eq!(build.objects, [PathBuf::from("out/runtime.o")]);
+        assert_eq!(build.archives.len(), 1);
+    }
+}
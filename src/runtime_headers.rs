@@ -0,0 +1,300 @@
+//! Vendor the Halide runtime headers for crates that only link prebuilt pipelines
+//!
+//! Code that calls an emitted pipeline needs `HalideRuntime.h` and `HalideBuffer.h`, and the
+//! headers they include, but not the rest of Halide. `vendor` copies just those headers, so a
+//! build script can point bindgen at its `OUT_DIR` instead of a Halide install
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::gen::RUNTIME_HEADERS;
+use crate::hash;
+use crate::layout::Layout;
+
+/// An `#include` directive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include {
+    /// The included path, as written
+    pub path: String,
+
+    /// True for `<...>` includes, false for `"..."` includes
+    pub angled: bool,
+}
+
+/// The `#include` directives in a source file, in order
+pub fn includes(source: &str) -> Vec<Include> {
+    source
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim_start().strip_prefix('#')?.trim_start();
+            let rest = rest.strip_prefix("include")?.trim_start();
+            let (close, angled) = match rest.chars().next()? {
+                '"' => ('"', false),
+                '<' => ('>', true),
+                _ => return None,
+            };
+            let (path, _) = rest[1..].split_once(close)?;
+            Some(Include {
+                path: path.to_string(),
+                angled,
+            })
+        })
+        .collect()
+}
+
+/// Find the file an include refers to within `include_dir`
+///
+/// Quoted includes are looked up next to the including file first. Includes found outside
+/// `include_dir`, like system headers, return `None`
+pub fn resolve(include_dir: &Path, from: &Path, include: &Include) -> Option<PathBuf> {
+    let mut candidates = vec![];
+    if !include.angled {
+        if let Some(dir) = from.parent() {
+            candidates.push(dir.join(&include.path));
+        }
+    }
+    candidates.push(include_dir.join(&include.path));
+
+    candidates
+        .into_iter()
+        .map(|path| normalize(&path))
+        .find(|path| path.starts_with(include_dir) && path.is_file())
+}
+
+/// Remove `.` and `..` components without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                out.pop();
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// The headers in `include_dir` reachable from `roots` by following includes, relative to
+/// `include_dir` and sorted
+pub fn closure(include_dir: impl AsRef<Path>, roots: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let include_dir = normalize(include_dir.as_ref());
+    let mut found = vec![];
+    let mut queue = VecDeque::new();
+    for root in roots {
+        let path = include_dir.join(root);
+        if !path.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            ));
+        }
+        queue.push_back(path);
+    }
+
+    while let Some(path) = queue.pop_front() {
+        if found.contains(&path) {
+            continue;
+        }
+        let source = fs::read_to_string(&path)?;
+        for include in includes(&source) {
+            if let Some(dep) = resolve(&include_dir, &path, &include) {
+                queue.push_back(dep);
+            }
+        }
+        found.push(path);
+    }
+
+    let mut relative: Vec<PathBuf> = found
+        .iter()
+        .filter_map(|path| path.strip_prefix(&include_dir).ok())
+        .map(Path::to_path_buf)
+        .collect();
+    relative.sort();
+    Ok(relative)
+}
+
+/// Copy the runtime headers of the Halide install at `halide_path`, and the headers they
+/// include, into `dest_dir`, returning the copied paths
+///
+/// Files are only written when their contents change, so build scripts don't rerun bindgen
+/// needlessly. Fails with `io::ErrorKind::InvalidData` when the copies aren't self-contained,
+/// see `check`
+pub fn vendor(
+    halide_path: impl AsRef<Path>,
+    dest_dir: impl AsRef<Path>,
+) -> io::Result<Vec<PathBuf>> {
+    vendor_from(&Layout::install(halide_path), dest_dir)
+}
+
+/// Like `vendor`, using the include directory of `layout`
+pub fn vendor_from(layout: &Layout, dest_dir: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let dest_dir = dest_dir.as_ref();
+    let mut copied = vec![];
+    for header in closure(&layout.include, RUNTIME_HEADERS)? {
+        let src = layout.include.join(&header);
+        let dest = dest_dir.join(&header);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let contents = fs::read(&src)?;
+        let unchanged = dest.exists() && hash::file(&dest)? == hash::bytes(&contents);
+        if !unchanged {
+            fs::write(&dest, &contents)?;
+        }
+        copied.push(dest);
+    }
+
+    check(&layout.include, dest_dir, &copied)?;
+    Ok(copied)
+}
+
+/// Make sure `headers` in `dest_dir` don't depend on anything in `include_dir` that wasn't
+/// copied
+///
+/// Fails with `io::ErrorKind::InvalidData` listing the includes that would still be found in
+/// `include_dir`
+pub fn check(
+    include_dir: impl AsRef<Path>,
+    dest_dir: impl AsRef<Path>,
+    headers: &[PathBuf],
+) -> io::Result<()> {
+    let include_dir = normalize(include_dir.as_ref());
+    let dest_dir = normalize(dest_dir.as_ref());
+
+    let mut missing = vec![];
+    for header in headers {
+        let header = normalize(header);
+        let source = fs::read_to_string(&header)?;
+        let original = header
+            .strip_prefix(&dest_dir)
+            .map(|rel| include_dir.join(rel))
+            .unwrap_or_else(|_| header.clone());
+
+        for include in includes(&source) {
+            // Absolute includes into `include_dir` resolve there but not in `dest_dir`
+            if resolve(&include_dir, &original, &include).is_some()
+                && resolve(&dest_dir, &header, &include).is_none()
+            {
+                missing.push(format!("{} in {}", include.path, header.display()));
+            }
+        }
+    }
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Vendored headers in {} still depend on {}: {}",
+            dest_dir.display(),
+            include_dir.display(),
+            missing.join(", ")
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runtime headers including each other in a cycle through a subdirectory, along with a
+    /// system header and a header that doesn't exist
+    fn install(dir: &Path) -> Layout {
+        for (path, contents) in [
+            (
+                "include/HalideRuntime.h",
+                "#include <stdint.h>\n#include \"runtime/common.h\"\n#include \"missing.h\"\n",
+            ),
+            (
+                "include/HalideBuffer.h",
+                "#pragma once\n  #  include <HalideRuntime.h>\n// #include \"Halide.h\"\n",
+            ),
+            (
+                "include/runtime/common.h",
+                "#include \"../HalideBuffer.h\"\n",
+            ),
+            ("include/Halide.h", "#include \"HalideRuntime.h\"\n"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        Layout::install(dir)
+    }
+
+    #[test]
+    fn include_directives() {
+        assert_eq!(
+            includes(
+                "#include <a.h>\n # include \"b/c.h\" // comment\n#define X\n#include MACRO\n"
+            ),
+            [
+                Include {
+                    path: "a.h".to_string(),
+                    angled: true,
+                },
+                Include {
+                    path: "b/c.h".to_string(),
+                    angled: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn closure_follows_cycles_and_skips_missing_includes() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = install(dir.path());
+        assert_eq!(
+            closure(&layout.include, &["HalideRuntime.h"]).unwrap(),
+            [
+                Path::new("HalideBuffer.h"),
+                Path::new("HalideRuntime.h"),
+                Path::new("runtime/common.h"),
+            ]
+        );
+
+        let err = closure(&layout.include, &["missing.h"]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn vendored_headers_are_self_contained() {
+        let dir = tempfile::tempdir().unwrap();
+        let layout = install(&dir.path().join("halide"));
+        let dest = dir.path().join("out");
+
+        let copied = vendor_from(&layout, &dest).unwrap();
+        assert_eq!(copied.len(), 3);
+        assert!(dest.join("runtime/common.h").is_file());
+        assert!(!dest.join("Halide.h").exists());
+
+        // Unchanged headers aren't written again
+        let past = std::time::SystemTime::UNIX_EPOCH;
+        let header = dest.join("HalideBuffer.h");
+        fs::File::options()
+            .write(true)
+            .open(&header)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        assert_eq!(vendor_from(&layout, &dest).unwrap(), copied);
+        assert_eq!(header.metadata().unwrap().modified().unwrap(), past);
+
+        // Headers that include something left behind are reported
+        fs::remove_file(dest.join("runtime/common.h")).unwrap();
+        let err = check(&layout.include, &dest, &[dest.join("HalideRuntime.h")]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().ends_with(&format!(
+            "runtime/common.h in {}",
+            dest.join("HalideRuntime.h").display()
+        )));
+    }
+}
@@ -0,0 +1,691 @@
+//! Source control backends used to fetch the Halide source
+
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::exec::{self, Executor, InterruptToken};
+
+/// The result of updating a checkout
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The checkout already matched the remote branch
+    UpToDate,
+
+    /// The checkout was fast-forwarded
+    Updated,
+}
+
+/// Operations needed to download and update a Halide checkout
+pub trait ScmBackend {
+    /// Clone `repo` into `dest`, checking out `branch`, which may also be a tag or commit
+    fn clone_repo(&self, repo: &str, branch: &str, dest: &Path, shallow: bool) -> io::Result<bool>;
+
+    /// Fetch `branch` from the origin remote and fast-forward the checkout at `path`
+    ///
+    /// Fails if the working tree has local changes, unless `stash` is set, or if the checkout
+    /// can't be fast-forwarded
+    fn update(&self, path: &Path, branch: &str, stash: bool) -> io::Result<UpdateStatus>;
+
+    /// Check out a branch, tag or commit
+    fn checkout(&self, path: &Path, rev: &str) -> io::Result<bool>;
+
+    /// Create a linked worktree at `dest` sharing the object store of the checkout at `path`,
+    /// with `rev` checked out as a detached HEAD
+    fn add_worktree(&self, path: &Path, dest: &Path, rev: &str) -> io::Result<bool> {
+        let _ = (path, dest, rev);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Worktrees are not supported by this backend",
+        ))
+    }
+
+    /// Remove a linked worktree created by `add_worktree`, failing if it has local changes
+    fn remove_worktree(&self, path: &Path, dest: &Path) -> io::Result<bool> {
+        let _ = (path, dest);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "Worktrees are not supported by this backend",
+        ))
+    }
+}
+
+/// Uses the system `git` executable
+#[derive(Debug, Clone)]
+pub struct GitCli {
+    /// Path to the git executable
+    pub git: String,
+
+    /// Used to interrupt git
+    pub interrupt: InterruptToken,
+
+    /// Runs git
+    pub(crate) executor: Arc<dyn Executor>,
+}
+
+impl Default for GitCli {
+    fn default() -> Self {
+        GitCli::new(Arc::new(exec::System), InterruptToken::new())
+    }
+}
+
+impl GitCli {
+    pub(crate) fn new(executor: Arc<dyn Executor>, interrupt: InterruptToken) -> GitCli {
+        GitCli {
+            git: "git".to_string(),
+            interrupt,
+            executor,
+        }
+    }
+
+    /// Run git with its output passed through, it may prompt for credentials so it stays in
+    /// the terminal's process group
+    fn status(&self, cmd: &mut Command) -> io::Result<bool> {
+        self.executor
+            .status(cmd, &self.interrupt, false, None)
+            .map(|status| status.success())
+    }
+
+    /// Run git in `path`, returning stdout or an error containing stderr
+    fn output(&self, path: &Path, args: &[&str]) -> io::Result<String> {
+        let mut cmd = Command::new(&self.git);
+        cmd.current_dir(path).args(args);
+        let output = exec::capture(&*self.executor, &mut cmd, &self.interrupt, false, None)?;
+
+        if !output.success() {
+            return Err(io::Error::other(format!(
+                "git {} failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Returns true when `name` is a branch or tag of `repo`, otherwise it's treated as a
+    /// commit
+    fn is_remote_ref(&self, repo: &str, name: &str) -> io::Result<bool> {
+        let mut cmd = Command::new(&self.git);
+        cmd.args(["ls-remote", "--exit-code", repo])
+            .arg(format!("refs/heads/{}", name))
+            .arg(format!("refs/tags/{}", name));
+        exec::capture(&*self.executor, &mut cmd, &self.interrupt, false, None)
+            .map(|output| output.success())
+    }
+
+    /// Clone `repo` into `dest` with `rev`, a commit, checked out as a detached HEAD
+    ///
+    /// `clone -b` only accepts branches and tags, so shallow clones fetch the single commit
+    /// into an empty repository instead
+    fn clone_commit(&self, repo: &str, rev: &str, dest: &Path, shallow: bool) -> io::Result<bool> {
+        if !shallow {
+            let mut clone = Command::new(&self.git);
+            clone.args(["clone", "--no-checkout", repo]).arg(dest);
+            return Ok(self.status(&mut clone)?
+                && self.status(
+                    Command::new(&self.git)
+                        .current_dir(dest)
+                        .args(["checkout", "--detach", rev]),
+                )?);
+        }
+
+        if !self.status(Command::new(&self.git).arg("init").arg(dest))? {
+            return Ok(false);
+        }
+        for args in [
+            &["remote", "add", "origin", repo][..],
+            &["fetch", "--depth", "1", "origin", rev],
+            &["checkout", "--detach", "FETCH_HEAD"],
+        ] {
+            if !self.status(Command::new(&self.git).current_dir(dest).args(args))? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns true when the git executable can be run
+    pub fn is_available(&self) -> bool {
+        self.executor
+            .output(Command::new(&self.git).arg("--version"))
+            .is_ok_and(|output| output.status.success())
+    }
+}
+
+impl ScmBackend for GitCli {
+    fn clone_repo(&self, repo: &str, branch: &str, dest: &Path, shallow: bool) -> io::Result<bool> {
+        if !self.is_remote_ref(repo, branch)? {
+            return self.clone_commit(repo, branch, dest, shallow);
+        }
+
+        let mut cmd = Command::new(&self.git);
+        cmd.arg("clone").args(["-b", branch]);
+        if shallow {
+            cmd.args(["--depth", "1"]);
+        }
+        cmd.arg(repo).arg(dest);
+        self.status(&mut cmd)
+    }
+
+    fn update(&self, path: &Path, branch: &str, stash: bool) -> io::Result<UpdateStatus> {
+        let changes = self.output(path, &["status", "--porcelain", "--untracked-files=no"])?;
+        if !changes.trim().is_empty() {
+            if !stash {
+                return Err(dirty_error(path));
+            }
+            self.output(path, &["stash", "push", "-m", "halide-build update"])?;
+        }
+
+        self.output(path, &["fetch", "origin", branch])?;
+
+        let before = self.output(path, &["rev-parse", "HEAD"])?;
+        self.output(path, &["merge", "--ff-only", "FETCH_HEAD"])
+            .map_err(|_| fast_forward_error(path, branch))?;
+        let after = self.output(path, &["rev-parse", "HEAD"])?;
+
+        if before == after {
+            Ok(UpdateStatus::UpToDate)
+        } else {
+            Ok(UpdateStatus::Updated)
+        }
+    }
+
+    fn checkout(&self, path: &Path, rev: &str) -> io::Result<bool> {
+        self.status(
+            Command::new(&self.git)
+                .current_dir(path)
+                .arg("checkout")
+                .arg(rev),
+        )
+    }
+
+    fn add_worktree(&self, path: &Path, dest: &Path, rev: &str) -> io::Result<bool> {
+        self.status(
+            Command::new(&self.git)
+                .current_dir(path)
+                .args(["worktree", "add", "--detach"])
+                .arg(std::path::absolute(dest)?)
+                .arg(rev),
+        )
+    }
+
+    fn remove_worktree(&self, path: &Path, dest: &Path) -> io::Result<bool> {
+        self.status(
+            Command::new(&self.git)
+                .current_dir(path)
+                .args(["worktree", "remove"])
+                .arg(std::path::absolute(dest)?),
+        )
+    }
+}
+
+fn dirty_error(path: &Path) -> io::Error {
+    io::Error::other(format!(
+        "Halide source in {} has local changes, commit or stash them before updating",
+        path.display()
+    ))
+}
+
+fn fast_forward_error(path: &Path, branch: &str) -> io::Error {
+    io::Error::other(format!(
+        "Halide source in {} can't be fast-forwarded to origin/{}",
+        path.display(),
+        branch
+    ))
+}
+
+/// Uses libgit2, for systems without a git executable
+#[cfg(feature = "git2")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Git2;
+
+#[cfg(feature = "git2")]
+impl Git2 {
+    fn checkout_ref(repo: &git2::Repository, name: &str) -> Result<(), git2::Error> {
+        let remote_name = format!("origin/{}", name);
+        if let Ok(remote) = repo.find_branch(&remote_name, git2::BranchType::Remote) {
+            let commit = remote.get().peel_to_commit()?;
+            let mut local = match repo.find_branch(name, git2::BranchType::Local) {
+                Ok(branch) => branch,
+                Err(_) => repo.branch(name, &commit, false)?,
+            };
+            local.set_upstream(Some(&remote_name))?;
+            repo.checkout_tree(commit.as_object(), None)?;
+            return repo.set_head(&format!("refs/heads/{}", name));
+        }
+
+        let obj = repo.revparse_single(name)?;
+        repo.checkout_tree(&obj, None)?;
+        repo.set_head_detached(obj.peel_to_commit()?.id())
+    }
+
+    fn is_dirty(repo: &git2::Repository) -> Result<bool, git2::Error> {
+        let mut options = git2::StatusOptions::new();
+        options.include_untracked(false).include_ignored(false);
+        Ok(!repo.statuses(Some(&mut options))?.is_empty())
+    }
+
+    /// Find the worktree of `repo` checked out at `dest`
+    fn find_worktree(repo: &git2::Repository, dest: &Path) -> io::Result<git2::Worktree> {
+        let dest = dest.canonicalize()?;
+        for name in repo.worktrees().map_err(git2_error)?.iter().flatten() {
+            let worktree = repo.find_worktree(name).map_err(git2_error)?;
+            if worktree.path().canonicalize().ok().as_deref() == Some(dest.as_path()) {
+                return Ok(worktree);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not a worktree", dest.display()),
+        ))
+    }
+}
+
+#[cfg(feature = "git2")]
+fn git2_error(err: git2::Error) -> io::Error {
+    io::Error::other(err)
+}
+
+#[cfg(feature = "git2")]
+impl ScmBackend for Git2 {
+    fn clone_repo(&self, repo: &str, branch: &str, dest: &Path, shallow: bool) -> io::Result<bool> {
+        let mut fetch = git2::FetchOptions::new();
+        if shallow {
+            fetch.depth(1);
+        }
+
+        let repository = git2::build::RepoBuilder::new()
+            .fetch_options(fetch)
+            .clone(repo, dest)
+            .map_err(git2_error)?;
+        Git2::checkout_ref(&repository, branch).map_err(git2_error)?;
+        Ok(true)
+    }
+
+    fn update(&self, path: &Path, branch: &str, stash: bool) -> io::Result<UpdateStatus> {
+        let mut repo = git2::Repository::open(path).map_err(git2_error)?;
+
+        if Git2::is_dirty(&repo).map_err(git2_error)? {
+            if !stash {
+                return Err(dirty_error(path));
+            }
+
+            let signature = repo
+                .signature()
+                .or_else(|_| git2::Signature::now("halide-build", "halide-build@localhost"))
+                .map_err(git2_error)?;
+            repo.stash_save(&signature, "halide-build update", None)
+                .map_err(git2_error)?;
+        }
+
+        let mut remote = repo.find_remote("origin").map_err(git2_error)?;
+        remote.fetch(&[branch], None, None).map_err(git2_error)?;
+
+        let fetch_head = repo.find_reference("FETCH_HEAD").map_err(git2_error)?;
+        let commit = repo
+            .reference_to_annotated_commit(&fetch_head)
+            .map_err(git2_error)?;
+        let (analysis, _) = repo.merge_analysis(&[&commit]).map_err(git2_error)?;
+
+        if analysis.is_up_to_date() {
+            return Ok(UpdateStatus::UpToDate);
+        }
+
+        if !analysis.is_fast_forward() {
+            return Err(fast_forward_error(path, branch));
+        }
+
+        // The working tree is updated before HEAD moves, so it's compared with the old HEAD
+        let target = repo.find_commit(commit.id()).map_err(git2_error)?;
+        repo.checkout_tree(
+            target.as_object(),
+            Some(git2::build::CheckoutBuilder::new().safe()),
+        )
+        .map_err(git2_error)?;
+
+        // A detached HEAD, such as a pinned commit, stays detached at the fetched commit
+        if repo.head_detached().map_err(git2_error)? {
+            repo.set_head_detached(commit.id()).map_err(git2_error)?;
+            return Ok(UpdateStatus::Updated);
+        }
+
+        let refname = format!("refs/heads/{}", branch);
+        match repo.find_reference(&refname) {
+            Ok(mut reference) => reference
+                .set_target(commit.id(), "fast-forward")
+                .map_err(git2_error)?,
+            Err(_) => repo
+                .reference(&refname, commit.id(), true, "fast-forward")
+                .map_err(git2_error)?,
+        };
+        repo.set_head(&refname).map_err(git2_error)?;
+        Ok(UpdateStatus::Updated)
+    }
+
+    fn checkout(&self, path: &Path, rev: &str) -> io::Result<bool> {
+        let repo = git2::Repository::open(path).map_err(git2_error)?;
+        Git2::checkout_ref(&repo, rev).map_err(git2_error)?;
+        Ok(true)
+    }
+
+    fn add_worktree(&self, path: &Path, dest: &Path, rev: &str) -> io::Result<bool> {
+        let repo = git2::Repository::open(path).map_err(git2_error)?;
+        let commit = repo
+            .revparse_single(rev)
+            .and_then(|obj| obj.peel_to_commit())
+            .map_err(git2_error)?;
+        let name = dest
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid worktree path"))?
+            .to_string_lossy()
+            .into_owned();
+
+        // libgit2 creates worktrees on a branch, so the worktree is detached from a temporary
+        // branch that is then deleted
+        let mut branch = repo.branch(&name, &commit, false).map_err(git2_error)?;
+        let mut options = git2::WorktreeAddOptions::new();
+        options.reference(Some(branch.get()));
+        let worktree = repo
+            .worktree(&name, dest, Some(&options))
+            .map_err(git2_error)?;
+        let checkout = git2::Repository::open_from_worktree(&worktree).map_err(git2_error)?;
+        checkout
+            .set_head_detached(commit.id())
+            .map_err(git2_error)?;
+        branch.delete().map_err(git2_error)?;
+        Ok(true)
+    }
+
+    fn remove_worktree(&self, path: &Path, dest: &Path) -> io::Result<bool> {
+        let repo = git2::Repository::open(path).map_err(git2_error)?;
+        let worktree = Git2::find_worktree(&repo, dest)?;
+
+        let checkout = git2::Repository::open_from_worktree(&worktree).map_err(git2_error)?;
+        if Git2::is_dirty(&checkout).map_err(git2_error)? {
+            return Err(io::Error::other(format!(
+                "Worktree {} has local changes",
+                dest.display()
+            )));
+        }
+
+        worktree
+            .prune(Some(
+                git2::WorktreePruneOptions::new()
+                    .valid(true)
+                    .working_tree(true),
+            ))
+            .map_err(git2_error)?;
+        Ok(true)
+    }
+}
+
+/// Select a backend: the system git when available, otherwise libgit2 if the `git2` feature
+/// is enabled
+///
+/// git is run using `executor`, and stopped when `interrupt` is interrupted
+pub fn detect(
+    executor: Arc<dyn Executor>,
+    interrupt: &InterruptToken,
+) -> io::Result<Box<dyn ScmBackend>> {
+    let git = GitCli::new(executor, interrupt.clone());
+    if git.is_available() {
+        return Ok(Box::new(git));
+    }
+
+    #[cfg(feature = "git2")]
+    {
+        Ok(Box::new(Git2))
+    }
+
+    #[cfg(not(feature = "git2"))]
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "Unable to find git executable: install git or enable the git2 feature",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    /// Records the operations it's asked to perform
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    impl ScmBackend for Recorder {
+        fn clone_repo(
+            &self,
+            repo: &str,
+            branch: &str,
+            dest: &Path,
+            shallow: bool,
+        ) -> io::Result<bool> {
+            self.0.lock().unwrap().push(format!(
+                "clone {} {} {} {}",
+                repo,
+                branch,
+                dest.display(),
+                shallow
+            ));
+            Ok(true)
+        }
+
+        fn update(&self, path: &Path, branch: &str, stash: bool) -> io::Result<UpdateStatus> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("update {} {} {}", path.display(), branch, stash));
+            Ok(UpdateStatus::Updated)
+        }
+
+        fn checkout(&self, path: &Path, rev: &str) -> io::Result<bool> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("checkout {} {}", path.display(), rev));
+            Ok(false)
+        }
+    }
+
+    #[test]
+    fn source_uses_its_backend() {
+        let recorder = Recorder::default();
+        let mut source = crate::Source::new("/tmp/halide");
+        source.repo = "https://example.com/halide.git".to_string();
+        source.branch = "release/17.x".to_string();
+        source.shallow = true;
+        source.stash = true;
+        source.scm = Some(Box::new(recorder.clone()));
+
+        assert!(source.download().unwrap());
+        assert_eq!(source.update().unwrap(), UpdateStatus::Updated);
+        assert!(!source.checkout("v17.0.0").unwrap());
+        let err = source.add_worktree("/tmp/v17", "v17.0.0").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = source.remove_worktree("/tmp/v17").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "clone https://example.com/halide.git release/17.x /tmp/halide true",
+                "update /tmp/halide release/17.x true",
+                "checkout /tmp/halide v17.0.0",
+            ]
+        );
+    }
+
+    /// The arguments of each git call
+    fn git_calls(mock: &Mock) -> Vec<String> {
+        mock.calls_to("git")
+            .iter()
+            .map(|call| call.args.join(" "))
+            .collect()
+    }
+
+    #[test]
+    fn detect_prefers_system_git() {
+        let mock = Mock::new().on("git", Response::ok());
+        let scm = detect(Arc::new(mock.clone()), &InterruptToken::new()).unwrap();
+        assert!(scm.checkout(Path::new("/tmp/halide"), "main").unwrap());
+
+        let calls = mock.calls_to("git");
+        assert_eq!(git_calls(&mock), ["--version", "checkout main"]);
+        assert_eq!(calls[1].cwd.as_deref(), Some(Path::new("/tmp/halide")));
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn detect_falls_back_to_git2() {
+        let dir = tempfile::tempdir().unwrap();
+        let mock = Mock::new().on("git", Response::exit(127));
+        let scm = detect(Arc::new(mock.clone()), &InterruptToken::new()).unwrap();
+
+        // libgit2 fails to open the empty directory without running git
+        assert!(scm.checkout(dir.path(), "main").is_err());
+        assert_eq!(git_calls(&mock), ["--version"]);
+    }
+
+    #[cfg(not(feature = "git2"))]
+    #[test]
+    fn detect_fails_without_git() {
+        let mock = Mock::new().on("git", Response::exit(127));
+        let err = detect(Arc::new(mock), &InterruptToken::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(err
+            .to_string()
+            .contains("install git or enable the git2 feature"));
+    }
+
+    #[test]
+    fn clone_branch_or_commit() {
+        let repo = "https://example.com/halide.git";
+        let dest = PathBuf::from("/tmp/halide");
+        let clone = |ls_remote: Response, shallow: bool| {
+            let mock = Mock::new().on("git", ls_remote).on("git", Response::ok());
+            let git = GitCli::new(Arc::new(mock.clone()), InterruptToken::new());
+            let sha = "0123456789abcdef0123456789abcdef01234567";
+            assert!(git.clone_repo(repo, sha, &dest, shallow).unwrap());
+            let calls = mock.calls_to("git");
+            assert!(calls[1..]
+                .iter()
+                .filter(|call| call.args[0] != "clone" && call.args[0] != "init")
+                .all(|call| call.cwd.as_deref() == Some(dest.as_path())));
+            git_calls(&mock)
+        };
+        let ls_remote = format!(
+            "ls-remote --exit-code {} refs/heads/{sha} refs/tags/{sha}",
+            repo,
+            sha = "0123456789abcdef0123456789abcdef01234567"
+        );
+
+        // Branches and tags are cloned directly
+        assert_eq!(
+            clone(Response::ok(), true),
+            [
+                ls_remote.clone(),
+                format!(
+                    "clone -b 0123456789abcdef0123456789abcdef01234567 --depth 1 {} /tmp/halide",
+                    repo
+                ),
+            ]
+        );
+
+        // Commits are checked out after cloning, or fetched alone for shallow clones
+        assert_eq!(
+            clone(Response::exit(2), false),
+            [
+                ls_remote.clone(),
+                format!("clone --no-checkout {} /tmp/halide", repo),
+                "checkout --detach 0123456789abcdef0123456789abcdef01234567".to_string(),
+            ]
+        );
+        assert_eq!(
+            clone(Response::exit(2), true),
+            [
+                ls_remote,
+                "init /tmp/halide".to_string(),
+                format!("remote add origin {}", repo),
+                "fetch --depth 1 origin 0123456789abcdef0123456789abcdef01234567".to_string(),
+                "checkout --detach FETCH_HEAD".to_string(),
+            ]
+        );
+    }
+
+    #[cfg(feature = "git2")]
+    #[test]
+    fn git2_update_keeps_a_detached_head() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin_path = dir.path().join("origin");
+        let origin = git2::Repository::init(&origin_path).unwrap();
+        let signature = git2::Signature::now("test", "test@localhost").unwrap();
+        let commit = |message: &str| {
+            std::fs::write(origin_path.join("file"), message).unwrap();
+            let mut index = origin.index().unwrap();
+            index.add_path(Path::new("file")).unwrap();
+            index.write().unwrap();
+            let tree = origin.find_tree(index.write_tree().unwrap()).unwrap();
+            let parent = origin
+                .head()
+                .ok()
+                .map(|head| head.peel_to_commit().unwrap());
+            origin
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    message,
+                    &tree,
+                    parent.as_ref().into_iter().collect::<Vec<_>>().as_slice(),
+                )
+                .unwrap()
+        };
+        let first = commit("first");
+        let branch = origin.head().unwrap().shorthand().unwrap().to_string();
+
+        let checkout = dir.path().join("checkout");
+        let url = origin_path.to_str().unwrap();
+        assert!(Git2
+            .clone_repo(url, &first.to_string(), &checkout, false)
+            .unwrap());
+        let repo = git2::Repository::open(&checkout).unwrap();
+        assert!(repo.head_detached().unwrap());
+
+        let second = commit("second");
+        assert_eq!(
+            Git2.update(&checkout, &branch, false).unwrap(),
+            UpdateStatus::Updated
+        );
+        assert!(repo.head_detached().unwrap());
+        assert_eq!(repo.head().unwrap().target(), Some(second));
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("file")).unwrap(),
+            "second"
+        );
+
+        // A checkout of the branch stays on it
+        let checkout = dir.path().join("branch");
+        assert!(Git2.clone_repo(url, &branch, &checkout, false).unwrap());
+        let third = commit("third");
+        assert_eq!(
+            Git2.update(&checkout, &branch, false).unwrap(),
+            UpdateStatus::Updated
+        );
+        let repo = git2::Repository::open(&checkout).unwrap();
+        let head = repo.head().unwrap();
+        assert_eq!(head.shorthand(), Some(branch.as_str()));
+        assert_eq!(head.target(), Some(third));
+        assert_eq!(
+            std::fs::read_to_string(checkout.join("file")).unwrap(),
+            "third"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Compile shared libraries using the naming and linker conventions of each platform
+//!
+//! Outputs without an extension get `.so`, `.dylib` or `.dll`, and explicit extensions are
+//! kept. Versioned outputs like `libfoo.so.1.2` or `libfoo.1.2.dylib` also get an unversioned
+//! symlink, so they can be linked using `-lfoo`
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Arc;
+
+use crate::exec::{self, Executor, InterruptToken};
+
+/// Platforms with different shared library conventions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// Linux and other ELF platforms
+    Linux,
+    MacOs,
+    Windows,
+}
+
+impl Platform {
+    /// The platform being compiled on
+    pub fn host() -> Platform {
+        if cfg!(target_os = "macos") {
+            Platform::MacOs
+        } else if cfg!(windows) {
+            Platform::Windows
+        } else {
+            Platform::Linux
+        }
+    }
+
+    /// Shared library extension, without the dot
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Platform::Linux => "so",
+            Platform::MacOs => "dylib",
+            Platform::Windows => "dll",
+        }
+    }
+
+    /// Compiler flags needed for position independent code
+    pub fn pic_flags(&self) -> &'static [&'static str] {
+        match self {
+            Platform::Windows => &[],
+            _ => &["-fPIC"],
+        }
+    }
+
+    /// Linker flags recording `soname` as the library's name, empty on Windows
+    pub fn soname_flags(&self, soname: &str) -> Vec<String> {
+        match self {
+            Platform::Linux => vec![format!("-Wl,-soname,{}", soname)],
+            Platform::MacOs => vec![format!("-Wl,-install_name,{}", soname)],
+            Platform::Windows => vec![],
+        }
+    }
+
+    /// `output` with the shared library extension added when it has none
+    pub fn output_path(&self, output: impl AsRef<Path>) -> PathBuf {
+        let output = output.as_ref();
+        if output.extension().is_some() {
+            return output.to_path_buf();
+        }
+        let mut s = output.as_os_str().to_owned();
+        s.push(".");
+        s.push(self.extension());
+        PathBuf::from(s)
+    }
+}
+
+/// The unversioned name of a versioned shared library, `libfoo.so` for `libfoo.so.1.2` and
+/// `libfoo.dylib` for `libfoo.1.2.dylib`
+pub fn unversioned(path: impl AsRef<Path>) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let name = path.file_name()?.to_str()?;
+    let is_version = |s: &str| !s.is_empty() && s.split('.').all(|n| n.parse::<u32>().is_ok());
+
+    let unversioned = if let Some((base, version)) = name.split_once(".so.") {
+        is_version(version).then(|| format!("{}.so", base))?
+    } else {
+        let stem = name.strip_suffix(".dylib")?;
+        let (base, version) = stem.split_once('.')?;
+        is_version(version).then(|| format!("{}.dylib", base))?
+    };
+    Some(path.with_file_name(unversioned))
+}
+
+/// Builds a shared library from C++ sources or objects
+#[derive(Debug, Clone)]
+pub struct SharedLibrary<'a> {
+    /// C++ compiler, defaults to `CXX` or `c++`
+    pub compiler: Option<&'a str>,
+
+    /// Output path, the platform's extension is added when it has none
+    pub output: PathBuf,
+
+    /// Sources, objects and other compiler arguments
+    pub args: Vec<&'a str>,
+
+    /// Name recorded in the library, using `-soname` on Linux and `-install_name` on macOS
+    pub soname: Option<String>,
+
+    /// Platform whose conventions are used, defaults to the host
+    pub platform: Platform,
+
+    executor: Arc<dyn Executor>,
+}
+
+impl<'a> SharedLibrary<'a> {
+    pub fn new(output: impl AsRef<Path>) -> SharedLibrary<'a> {
+        SharedLibrary {
+            compiler: None,
+            output: output.as_ref().to_path_buf(),
+            args: vec![],
+            soname: None,
+            platform: Platform::host(),
+            executor: Arc::new(exec::System),
+        }
+    }
+
+    pub fn compiler(mut self, compiler: &'a str) -> Self {
+        self.compiler = Some(compiler);
+        self
+    }
+
+    pub fn arg(mut self, arg: &'a str) -> Self {
+        self.args.push(arg);
+        self
+    }
+
+    pub fn args(mut self, args: impl AsRef<[&'a str]>) -> Self {
+        self.args.extend(args.as_ref());
+        self
+    }
+
+    pub fn soname(mut self, soname: impl Into<String>) -> Self {
+        self.soname = Some(soname.into());
+        self
+    }
+
+    /// Set the macOS install name, the same as `soname`
+    pub fn install_name(self, name: impl Into<String>) -> Self {
+        self.soname(name)
+    }
+
+    pub fn platform(mut self, platform: Platform) -> Self {
+        self.platform = platform;
+        self
+    }
+
+    /// Run commands using `executor` instead of starting child processes
+    #[cfg(any(test, feature = "testing"))]
+    pub fn with_executor(mut self, executor: impl Executor + 'static) -> Self {
+        self.executor = Arc::new(executor);
+        self
+    }
+
+    /// The path of the compiled library
+    pub fn output_path(&self) -> PathBuf {
+        self.platform.output_path(&self.output)
+    }
+
+    /// The compiler command
+    pub fn command(&self) -> Command {
+        let cxx = std::env::var("CXX").unwrap_or_else(|_| "c++".to_owned());
+        let mut cmd = Command::new(self.compiler.unwrap_or(&cxx));
+        cmd.arg("-std=c++17")
+            .args(self.platform.pic_flags())
+            .arg("-shared");
+        if let Some(soname) = &self.soname {
+            cmd.args(self.platform.soname_flags(soname));
+        }
+        cmd.arg("-o").arg(self.output_path()).args(&self.args);
+        cmd
+    }
+
+    /// Compile the library, then create the unversioned symlink for versioned outputs
+    pub fn compile(&self) -> io::Result<bool> {
+        let status =
+            self.executor
+                .status(&mut self.command(), &InterruptToken::new(), false, None)?;
+        if !status.success() {
+            return Ok(false);
+        }
+
+        if self.platform != Platform::Windows {
+            let output = self.output_path();
+            if let Some(link) = unversioned(&output) {
+                symlink(&output, &link)?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Point `link` at `target`, replacing an existing link
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    match fs::remove_file(link) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+
+    // Relative, so the pair can be moved together
+    let target = target.file_name().unwrap_or_default();
+
+    #[cfg(unix)]
+    return std::os::unix::fs::symlink(target, link);
+
+    #[cfg(not(unix))]
+    return fs::copy(link.with_file_name(target), link).map(|_| ());
+}
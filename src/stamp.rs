@@ -0,0 +1,190 @@
+//! Stamp files mark a finished build for external build systems
+//!
+//! A stamp is written next to the output as `<output>.stamp` and contains simple `key=value`
+//! lines:
+//!
+//! ```text
+//! version=1
+//! output=path/to/output
+//! hash=<hex content hash of the output>
+//! fingerprint=<hex hash of the compiler, flags and inputs>
+//! ```
+//!
+//! The stamp is only rewritten when its contents change, so its mtime can be used as a
+//! dependency that only changes when the artifact does.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Current stamp file format version
+pub const VERSION: u32 = 1;
+
+/// The contents of a stamp file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stamp {
+    /// Path to the artifact
+    pub output: PathBuf,
+
+    /// Content hash of the artifact
+    pub hash: String,
+
+    /// Hash of the configuration used to build the artifact
+    pub fingerprint: String,
+}
+
+impl fmt::Display for Stamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "version={}", VERSION)?;
+        writeln!(f, "output={}", self.output.display())?;
+        writeln!(f, "hash={}", self.hash)?;
+        writeln!(f, "fingerprint={}", self.fingerprint)
+    }
+}
+
+impl Stamp {
+    /// Parse a stamp from `key=value` lines, unknown keys are ignored
+    pub fn parse(s: &str) -> io::Result<Stamp> {
+        let mut output = None;
+        let mut hash = None;
+        let mut fingerprint = None;
+
+        for line in s.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("Invalid stamp line: {}", line)))?;
+            match key {
+                "version" if value.parse::<u32>().ok() != Some(VERSION) => {
+                    return Err(invalid(format!("Unsupported stamp version: {}", value)));
+                }
+                "output" => output = Some(PathBuf::from(value)),
+                "hash" => hash = Some(value.to_string()),
+                "fingerprint" => fingerprint = Some(value.to_string()),
+                _ => (),
+            }
+        }
+
+        Ok(Stamp {
+            output: output.ok_or_else(|| invalid("Missing stamp key: output"))?,
+            hash: hash.ok_or_else(|| invalid("Missing stamp key: hash"))?,
+            fingerprint: fingerprint.ok_or_else(|| invalid("Missing stamp key: fingerprint"))?,
+        })
+    }
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Get the stamp path for an output file
+pub fn path(output: impl AsRef<Path>) -> PathBuf {
+    let output = output.as_ref();
+    let mut name = output.file_name().unwrap_or_default().to_owned();
+    name.push(".stamp");
+    output.with_file_name(name)
+}
+
+/// Read a stamp file
+pub fn read(path: impl AsRef<Path>) -> io::Result<Stamp> {
+    Stamp::parse(&fs::read_to_string(path)?)
+}
+
+/// Write a stamp file, leaving it untouched if the contents are unchanged
+///
+/// Returns true when the file was written
+pub fn write(path: impl AsRef<Path>, stamp: &Stamp) -> io::Result<bool> {
+    let path = path.as_ref();
+    let contents = stamp.to_string();
+    if let Ok(existing) = fs::read_to_string(path) {
+        if existing == contents {
+            return Ok(false);
+        }
+    }
+
+    fs::write(path, contents)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    fn stamp() -> Stamp {
+        Stamp {
+            output: PathBuf::from("out/libbrighten.a"),
+            hash: "85944171f73967e8".to_string(),
+            fingerprint: "af63dc4c8601ec8c".to_string(),
+        }
+    }
+
+    #[test]
+    fn format() {
+        assert_eq!(
+            stamp().to_string(),
+            "version=1\noutput=out/libbrighten.a\nhash=85944171f73967e8\nfingerprint=af63dc4c8601ec8c\n"
+        );
+        assert_eq!(Stamp::parse(&stamp().to_string()).unwrap(), stamp());
+    }
+
+    #[test]
+    fn parse() {
+        // Unknown keys, blank lines and surrounding whitespace are allowed
+        let s = "\n  hash=85944171f73967e8\nfingerprint=af63dc4c8601ec8c\nextra=1\noutput=out/libbrighten.a\n";
+        assert_eq!(Stamp::parse(s).unwrap(), stamp());
+
+        for s in [
+            "version=2\noutput=a\nhash=b\nfingerprint=c\n",
+            "version=x\noutput=a\nhash=b\nfingerprint=c\n",
+            "output=a\nhash=b\n",
+            "output=a\nhash\nfingerprint=c\n",
+            "",
+        ] {
+            let e = Stamp::parse(s).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::InvalidData, "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(
+            path("out/libbrighten.a"),
+            PathBuf::from("out/libbrighten.a.stamp")
+        );
+        assert_eq!(path("brighten"), PathBuf::from("brighten.stamp"));
+    }
+
+    #[test]
+    fn identical_stamps_are_not_rewritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("libbrighten.a.stamp");
+        assert!(write(&path, &stamp()).unwrap());
+        assert_eq!(read(&path).unwrap(), stamp());
+
+        let past = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(past)
+            .unwrap();
+        let modified = || path.metadata().unwrap().modified().unwrap();
+
+        assert!(!write(&path, &stamp()).unwrap());
+        assert_eq!(modified(), past);
+
+        let changed = Stamp {
+            hash: "0000000000000000".to_string(),
+            ..stamp()
+        };
+        assert!(write(&path, &changed).unwrap());
+        assert!(modified() > past);
+        assert_eq!(read(&path).unwrap(), changed);
+    }
+}
@@ -0,0 +1,422 @@
+//! Run a generator across a grid of parameter values and benchmark every variant
+//!
+//! Each variant is emitted into its own subdirectory of the generator's output directory,
+//! named by `variant_name`, and benchmarked using the program from `harness`. Variants are
+//! emitted and compiled in parallel, but benchmarked one at a time so they don't compete for
+//! the CPU. The results are written to `sweep.json` and `sweep.csv` in the output directory.
+
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::gen::{Emit, GenBuilder};
+use crate::harness::BenchmarkOptions;
+
+/// Generator parameters and the values to try for each of them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParamGrid {
+    /// Parameter names and their values, in the order they're enumerated
+    pub params: Vec<(String, Vec<String>)>,
+
+    /// Only keep the first `limit` variants, after shuffling
+    pub limit: Option<usize>,
+
+    /// Shuffle the variants with this seed, so a limited sweep samples the whole grid
+    pub shuffle: Option<u64>,
+
+    /// Number of variants emitted and compiled in parallel, defaults to the number of
+    /// available cores
+    pub jobs: Option<usize>,
+}
+
+impl ParamGrid {
+    pub fn new() -> ParamGrid {
+        ParamGrid::default()
+    }
+
+    pub fn param<T: ToString>(mut self, name: impl Into<String>, values: impl AsRef<[T]>) -> Self {
+        let values = values.as_ref().iter().map(T::to_string).collect();
+        self.params.push((name.into(), values));
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn shuffle(mut self, seed: u64) -> Self {
+        self.shuffle = Some(seed);
+        self
+    }
+
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Number of parallel jobs, an explicit `jobs` setting is capped at the number of cores
+    pub fn build_jobs(&self) -> usize {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        self.jobs.map(|jobs| jobs.clamp(1, cores)).unwrap_or(cores)
+    }
+
+    /// The variants to run, each paired with its index in the unshuffled grid
+    ///
+    /// The cartesian product is enumerated with the last parameter changing fastest. A grid
+    /// without parameters has a single variant using the generator's own parameters
+    pub fn variants(&self) -> Vec<(usize, Vec<(String, String)>)> {
+        let mut variants: Vec<Vec<(String, String)>> = vec![vec![]];
+        for (name, values) in &self.params {
+            variants = variants
+                .into_iter()
+                .flat_map(|variant| {
+                    values.iter().map(move |value| {
+                        let mut variant = variant.clone();
+                        variant.push((name.clone(), value.clone()));
+                        variant
+                    })
+                })
+                .collect();
+        }
+
+        let mut variants: Vec<_> = variants.into_iter().enumerate().collect();
+        if let Some(seed) = self.shuffle {
+            shuffle(&mut variants, seed);
+        }
+        if let Some(limit) = self.limit {
+            variants.truncate(limit);
+        }
+        variants
+    }
+}
+
+/// Fisher-Yates shuffle using a SplitMix64 generator, so a seed gives the same order on
+/// every platform
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Name of the subdirectory a variant is emitted into, like `0003_tile=32,vector=8`
+///
+/// `index` is the variant's position in the unshuffled grid, which keeps names unique when
+/// values only differ in characters that are replaced with `_`
+pub fn variant_name(index: usize, params: &[(String, String)]) -> String {
+    let params = params
+        .iter()
+        .map(|(name, value)| {
+            let value: String = value
+                .chars()
+                .map(|c| match c {
+                    'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' | '_' => c,
+                    _ => '_',
+                })
+                .collect();
+            format!("{}={}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    if params.is_empty() {
+        format!("{:04}", index)
+    } else {
+        format!("{:04}_{}", index, params)
+    }
+}
+
+/// The benchmark result of one variant
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SweepResult {
+    /// Parameters of the variant, added to the generator's own parameters
+    pub params: Vec<(String, String)>,
+
+    /// Directory the variant was emitted into
+    pub dir: PathBuf,
+
+    /// `None` when the variant failed, see `error`
+    pub seconds_per_iteration: Option<f64>,
+
+    /// Why the variant couldn't be emitted, compiled or benchmarked
+    pub error: Option<String>,
+}
+
+/// The timing printed by the benchmark program
+#[derive(Deserialize)]
+struct Timing {
+    seconds_per_iteration: f64,
+}
+
+/// Emit and benchmark every variant of `grid`, see the module documentation
+///
+/// The generator is compiled once before the sweep. Failures of a single variant are recorded
+/// in its `SweepResult`, errors are only returned when the generator can't be compiled or
+/// the summary can't be written
+pub fn run(
+    gen: &GenBuilder,
+    grid: &ParamGrid,
+    bench: &BenchmarkOptions,
+) -> io::Result<Vec<SweepResult>> {
+    for (name, _) in &grid.params {
+        crate::validate::param_name(name)?;
+    }
+    if !gen.build_generator()? {
+        return Err(io::Error::other(format!(
+            "Unable to compile generator {}",
+            gen.build.output.display()
+        )));
+    }
+
+    let variants = grid.variants();
+    let next = AtomicUsize::new(0);
+    let built = Mutex::new(vec![]);
+    std::thread::scope(|scope| {
+        for _ in 0..grid.build_jobs().min(variants.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let (index, params) = match variants.get(i) {
+                    Some(variant) => variant,
+                    None => break,
+                };
+                let res = build_variant(gen, *index, params, bench);
+                built.lock().unwrap().push((i, res));
+            });
+        }
+    });
+
+    let mut built = built.into_inner().unwrap();
+    built.sort_by_key(|(i, _)| *i);
+    let results: Vec<SweepResult> = built
+        .into_iter()
+        .map(|(i, res)| {
+            let (index, params) = &variants[i];
+            let dir = gen.output_dir.join(variant_name(*index, params));
+            let timing = res.and_then(|benchmark| benchmark_variant(&benchmark));
+            SweepResult {
+                params: params.clone(),
+                dir,
+                seconds_per_iteration: timing.as_ref().ok().copied(),
+                error: timing.err().map(|e| e.to_string()),
+            }
+        })
+        .collect();
+
+    let json = serde_json::to_string_pretty(&results).map_err(io::Error::other)?;
+    fs::write(gen.output_dir.join("sweep.json"), json)?;
+    fs::write(gen.output_dir.join("sweep.csv"), to_csv(grid, &results))?;
+    Ok(results)
+}
+
+/// Emit a variant and compile its benchmark program
+fn build_variant<'a>(
+    gen: &GenBuilder<'a>,
+    index: usize,
+    params: &[(String, String)],
+    bench: &BenchmarkOptions,
+) -> io::Result<crate::Build<'a>> {
+    let mut variant = GenBuilder::new(
+        gen.build.clone(),
+        &gen.generator_name,
+        gen.output_dir.join(variant_name(index, params)),
+    )
+    .emit(Emit::StaticLibrary)
+    .emit(Emit::CHeader);
+    variant.function_name = gen.function_name.clone();
+    variant.params = gen
+        .params
+        .iter()
+        .filter(|(name, _)| !params.iter().any(|(n, _)| n == name))
+        .chain(params)
+        .cloned()
+        .collect();
+
+    if !variant.run_generator()? {
+        return Err(io::Error::other("Generator failed"));
+    }
+    let benchmark = variant.benchmark_harness(bench)?.capture(true);
+    if !benchmark.build()? {
+        return Err(io::Error::other("Unable to compile the benchmark"));
+    }
+    Ok(benchmark)
+}
+
+fn benchmark_variant(benchmark: &crate::Build) -> io::Result<f64> {
+    let report = benchmark.run_report()?;
+    match report.outcome {
+        Some(outcome) if outcome.success() => serde_json::from_slice::<Timing>(&outcome.stdout)
+            .map(|timing| timing.seconds_per_iteration)
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid benchmark output: {}", e),
+                )
+            }),
+        Some(outcome) => Err(io::Error::other(format!(
+            "Benchmark failed: {}",
+            String::from_utf8_lossy(&outcome.stderr).trim()
+        ))),
+        None => Err(io::Error::other("Benchmark was not run")),
+    }
+}
+
+/// One row per variant, with a column per parameter of the grid followed by `dir`,
+/// `seconds_per_iteration` and `error`
+pub fn to_csv(grid: &ParamGrid, results: &[SweepResult]) -> String {
+    let field = |s: &str| {
+        if s.contains([',', '"', '\n', '\r']) {
+            format!("\"{}\"", s.replace('"', "\"\""))
+        } else {
+            s.to_string()
+        }
+    };
+
+    let mut s = String::new();
+    let mut header: Vec<String> = grid.params.iter().map(|(name, _)| field(name)).collect();
+    header.extend(["dir", "seconds_per_iteration", "error"].map(str::to_string));
+    let _ = writeln!(s, "{}", header.join(","));
+
+    for result in results {
+        let mut row: Vec<String> = grid
+            .params
+            .iter()
+            .map(|(name, _)| {
+                let value = result.params.iter().find(|(n, _)| n == name);
+                field(value.map(|(_, value)| value.as_str()).unwrap_or_default())
+            })
+            .collect();
+        row.push(field(&result.dir.to_string_lossy()));
+        row.push(
+            result
+                .seconds_per_iteration
+                .map(|seconds| seconds.to_string())
+                .unwrap_or_default(),
+        );
+        row.push(field(result.error.as_deref().unwrap_or_default()));
+        let _ = writeln!(s, "{}", row.join(","));
+    }
+    s
+}
+
+/// Read the results written to `sweep.json` by `run`
+pub fn load(path: impl AsRef<Path>) -> io::Result<Vec<SweepResult>> {
+    let json = fs::read_to_string(path)?;
+    serde_json::from_str(&json).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid sweep results: {}", e),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(variant: &[(String, String)]) -> Vec<(&str, &str)> {
+        variant
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn grid_enumeration() {
+        let grid = ParamGrid::new()
+            .param("tile", [16, 32])
+            .param("vector", ["4", "8", "16"]);
+        let variants = grid.variants();
+        assert_eq!(variants.len(), 6);
+        assert_eq!(variants[0].0, 0);
+        assert_eq!(params(&variants[0].1), [("tile", "16"), ("vector", "4")]);
+        assert_eq!(params(&variants[1].1), [("tile", "16"), ("vector", "8")]);
+        assert_eq!(params(&variants[3].1), [("tile", "32"), ("vector", "4")]);
+        assert_eq!(params(&variants[5].1), [("tile", "32"), ("vector", "16")]);
+
+        // Shuffling keeps every variant and its index, and is the same for a seed
+        let shuffled = grid.clone().shuffle(7).variants();
+        assert_eq!(shuffled, grid.clone().shuffle(7).variants());
+        assert_ne!(shuffled, variants);
+        let mut sorted = shuffled.clone();
+        sorted.sort_by_key(|(index, _)| *index);
+        assert_eq!(sorted, variants);
+
+        let limited = grid.clone().shuffle(7).limit(2).variants();
+        assert_eq!(limited, shuffled[..2]);
+        assert_eq!(grid.limit(10).variants().len(), 6);
+
+        // No parameters is one variant, a parameter without values is none
+        assert_eq!(ParamGrid::new().variants(), [(0, vec![])]);
+        assert!(ParamGrid::new()
+            .param::<&str>("tile", [])
+            .variants()
+            .is_empty());
+    }
+
+    #[test]
+    fn variant_names() {
+        let params = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        assert_eq!(variant_name(0, &[]), "0000");
+        assert_eq!(
+            variant_name(3, &params(&[("tile", "32"), ("vector", "8")])),
+            "0003_tile=32,vector=8"
+        );
+        assert_eq!(
+            variant_name(
+                12345,
+                &params(&[("target", "x86-64-linux/avx 2"), ("f", "1.5e-3")])
+            ),
+            "12345_target=x86-64-linux_avx_2,f=1.5e-3"
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let grid = ParamGrid::new()
+            .param("tile", [16, 32])
+            .param("label", ["a,b"]);
+        let results = [
+            SweepResult {
+                params: vec![
+                    ("tile".to_string(), "16".to_string()),
+                    ("label".to_string(), "a,b".to_string()),
+                ],
+                dir: PathBuf::from("out/0000_tile=16,label=a_b"),
+                seconds_per_iteration: Some(0.25),
+                error: None,
+            },
+            SweepResult {
+                params: vec![("tile".to_string(), "32".to_string())],
+                dir: PathBuf::from("out/0001"),
+                seconds_per_iteration: None,
+                error: Some("Benchmark failed: \"oops\"".to_string()),
+            },
+        ];
+        assert_eq!(
+            to_csv(&grid, &results),
+            "tile,label,dir,seconds_per_iteration,error\n\
+             16,\"a,b\",\"out/0000_tile=16,label=a_b\",0.25,\n\
+             32,,out/0001,,\"Benchmark failed: \"\"oops\"\"\"\n"
+        );
+    }
+}
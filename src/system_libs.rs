@@ -0,0 +1,265 @@
+//! Find the system libraries needed to link a static libHalide
+//!
+//! A static libHalide built against the system LLVM depends on whatever that LLVM was
+//! configured with, which differs between distributions: Fedora and Arch need `-lzstd` and
+//! `-ltinfo`, Ubuntu needs `-lncurses` and sometimes `-lxml2`. The undefined symbols of the
+//! archive are listed using `nm`, and each one is matched against a table of symbol prefixes
+//! and the libraries that define them. Alternatively `llvm-config` can report the libraries
+//! of the LLVM that libHalide was built with, see `llvm_config_libs`
+
+use std::collections::BTreeSet;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use crate::exec::Executor;
+
+/// Symbol prefixes and the library defining them, see `resolve`
+///
+/// Several libraries for the same prefix are alternatives, in order of preference
+pub const DEFAULT_HINTS: &[(&str, &str)] = &[
+    ("setupterm", "tinfo"),
+    ("setupterm", "ncurses"),
+    ("tigetnum", "tinfo"),
+    ("tigetnum", "ncurses"),
+    ("set_curterm", "tinfo"),
+    ("set_curterm", "ncurses"),
+    ("del_curterm", "tinfo"),
+    ("del_curterm", "ncurses"),
+    ("ZSTD_", "zstd"),
+    ("compress2", "z"),
+    ("uncompress", "z"),
+    ("crc32", "z"),
+    ("deflate", "z"),
+    ("inflate", "z"),
+    ("xml", "xml2"),
+    ("ffi_", "ffi"),
+    ("dlopen", "dl"),
+    ("dlsym", "dl"),
+    ("dlerror", "dl"),
+    ("dladdr", "dl"),
+    ("pthread_", "pthread"),
+];
+
+/// The symbols used but not defined by an archive or object, from the output of `nm`
+///
+/// Symbols defined by another member of the archive aren't included. Version suffixes like
+/// `@GLIBC_2.34` are removed, the result is sorted
+pub fn undefined_symbols(nm_output: &str) -> Vec<String> {
+    let mut undefined = BTreeSet::new();
+    let mut defined = BTreeSet::new();
+    for line in nm_output.lines() {
+        // `[address] type name`, archive members are listed as `member.o:`
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let (kind, name) = match words[..] {
+            [kind, name] | [_, kind, name] if kind.len() == 1 => (kind, name),
+            _ => continue,
+        };
+        let name = name.split('@').next().unwrap_or(name).to_string();
+        match kind {
+            "U" => undefined.insert(name),
+            // Weak symbols may be left undefined
+            "w" | "v" => false,
+            _ => defined.insert(name),
+        };
+    }
+    undefined.difference(&defined).cloned().collect()
+}
+
+/// Whether `symbol` starts with `prefix`, ignoring the leading underscore added on macOS
+fn matches(symbol: &str, prefix: &str) -> bool {
+    symbol.starts_with(prefix)
+        || symbol
+            .strip_prefix('_')
+            .is_some_and(|symbol| symbol.starts_with(prefix))
+}
+
+/// The libraries needed by `symbols` according to `hints`, which pair symbol prefixes with
+/// libraries
+///
+/// Each entry lists the alternatives for one library, like `["tinfo", "ncurses"]`, in the
+/// order they first appear in `hints`
+pub fn resolve<S: AsRef<str>>(symbols: &[S], hints: &[(String, String)]) -> Vec<Vec<String>> {
+    let mut groups: Vec<Vec<String>> = vec![];
+    for (prefix, lib) in hints {
+        // Prefixes with the same alternatives, like `setupterm` and `tigetnum`, are one library
+        if groups.iter().any(|group| group.contains(lib)) {
+            continue;
+        }
+        if symbols
+            .iter()
+            .any(|symbol| matches(symbol.as_ref(), prefix))
+        {
+            groups.push(
+                hints
+                    .iter()
+                    .filter(|(p, _)| p == prefix)
+                    .map(|(_, lib)| lib.clone())
+                    .collect(),
+            );
+        }
+    }
+    groups
+}
+
+/// The undefined symbols of `archive`, listed using `nm` or `NM` when it's set
+pub fn archive_symbols(executor: &dyn Executor, archive: &Path) -> Option<Vec<String>> {
+    let nm = std::env::var("NM").unwrap_or_else(|_| "nm".to_string());
+    let output = executor.output(Command::new(nm).arg(archive)).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(undefined_symbols(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// The LLVM libraries and system libraries reported by `llvm-config --libs --system-libs`,
+/// preceded by `-L` for the LLVM library directory
+///
+/// Fails with `io::ErrorKind::NotFound` when `llvm_config` can't be run
+pub fn llvm_config_libs(executor: &dyn Executor, llvm_config: &Path) -> io::Result<Vec<String>> {
+    let run = |args: &[&str]| -> io::Result<String> {
+        let output = executor
+            .output(Command::new(llvm_config).args(args))
+            .ok()
+            .filter(|output| output.status.success())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!(
+                        "Unable to run {} {}, install LLVM or set LLVM_CONFIG",
+                        llvm_config.display(),
+                        args.join(" ")
+                    ),
+                )
+            })?;
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    };
+
+    let libdir = run(&["--libdir"])?;
+    let mut flags = vec![format!("-L{}", libdir.trim())];
+    flags.extend(
+        run(&["--libs", "--system-libs"])?
+            .split_whitespace()
+            .map(str::to_string),
+    );
+    Ok(flags)
+}
+
+/// Whether the compiler can find `lib`, by asking it for the path of the library file
+pub fn library_exists(executor: &dyn Executor, compiler: &str, lib: &str) -> bool {
+    ["so", "a", "dylib"].iter().any(|ext| {
+        let file = format!("lib{}.{}", lib, ext);
+        executor
+            .output(Command::new(compiler).arg(format!("-print-file-name={}", file)))
+            .map(|output| {
+                let path = String::from_utf8_lossy(&output.stdout).trim().to_string();
+                output.status.success() && path != file && Path::new(&path).exists()
+            })
+            .unwrap_or(false)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+
+    /// `nm libHalide.a` for an archive built against an LLVM using terminfo, zstd and zlib
+    const NM: &str = "
+Func.o:
+                 U _ZN4llvm5errsEv
+0000000000000000 T _ZN6Halide4FuncC1Ev
+                 U setupterm
+                 U ZSTD_compress@ZSTD_1.0
+                 w __gmon_start__
+                 U pthread_create@GLIBC_2.34
+
+Lower.o:
+                 U _ZN6Halide4FuncC1Ev
+                 U tigetnum
+                 U compress2
+0000000000000010 t helper
+";
+
+    fn default_hints() -> Vec<(String, String)> {
+        DEFAULT_HINTS
+            .iter()
+            .map(|(prefix, lib)| (prefix.to_string(), lib.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn nm_output() {
+        assert_eq!(
+            undefined_symbols(NM),
+            [
+                "ZSTD_compress",
+                "_ZN4llvm5errsEv",
+                "compress2",
+                "pthread_create",
+                "setupterm",
+                "tigetnum",
+            ]
+        );
+        assert!(undefined_symbols("").is_empty());
+    }
+
+    #[test]
+    fn symbols_map_to_libraries() {
+        assert_eq!(
+            resolve(&undefined_symbols(NM), &default_hints()),
+            [
+                vec!["tinfo", "ncurses"],
+                vec!["zstd"],
+                vec!["z"],
+                vec!["pthread"]
+            ]
+        );
+
+        // macOS adds a leading underscore
+        assert_eq!(
+            resolve(&["_xmlReadFile", "_ffi_call"], &default_hints()),
+            [vec!["xml2"], vec!["ffi"]]
+        );
+        assert!(resolve(&["_ZN4llvm5errsEv"], &default_hints()).is_empty());
+    }
+
+    #[test]
+    fn archive_symbols_run_nm() {
+        // `NM` overrides the program, the mock matches it by file name
+        let nm = std::env::var("NM").unwrap_or_else(|_| "nm".to_string());
+        let nm = Path::new(&nm).file_name().unwrap().to_string_lossy();
+        let mock = Mock::new()
+            .on(&nm, Response::ok().stdout(NM))
+            .on(&nm, Response::exit(1).stderr("nm: libHalide.a: no symbols"));
+        let archive = Path::new("/opt/halide/lib/libHalide.a");
+
+        let symbols = archive_symbols(&mock, archive).unwrap();
+        assert_eq!(symbols, undefined_symbols(NM));
+        assert_eq!(mock.calls()[0].args, ["/opt/halide/lib/libHalide.a"]);
+        assert_eq!(archive_symbols(&mock, archive), None);
+    }
+
+    #[test]
+    fn llvm_config() {
+        let mock = Mock::new()
+            .on(
+                "llvm-config",
+                Response::ok().stdout("/usr/lib/llvm-17/lib\n"),
+            )
+            .on(
+                "llvm-config",
+                Response::ok().stdout("-lLLVM-17 -lz -lzstd\n"),
+            );
+        assert_eq!(
+            llvm_config_libs(&mock, Path::new("llvm-config")).unwrap(),
+            ["-L/usr/lib/llvm-17/lib", "-lLLVM-17", "-lz", "-lzstd"]
+        );
+        let args: Vec<_> = mock.calls().into_iter().map(|call| call.args).collect();
+        assert_eq!(args, [vec!["--libdir"], vec!["--libs", "--system-libs"]]);
+
+        let mock = Mock::new().on("llvm-config", Response::exit(1));
+        let err = llvm_config_libs(&mock, Path::new("llvm-config")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
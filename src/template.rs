@@ -0,0 +1,297 @@
+//! Generator source templates used by `halide new`
+//!
+//! Templates are C++ sources with `{{name}}` style placeholders, see `TemplateVars` for the
+//! available names. Only identifiers are substituted, so brace initializers like
+//! `input{{"input", 3}}` are left alone, and `\{{` writes a literal `{{`
+//!
+//! Named templates are read from the directory in `HALIDE_TEMPLATE_DIR`
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::validate;
+
+/// Environment variable holding the directory of named templates
+pub const DIR_VAR: &str = "HALIDE_TEMPLATE_DIR";
+
+/// The default generator template
+pub const DEFAULT: &str = "
+#include <Halide.h>
+using namespace Halide;
+
+class {{name}}: public Generator<{{name}}> {
+public:
+    Var x, y, c;
+    Input<Buffer<{{input_type}}>> input{\"input\", {{dims}}};
+    Output<Buffer<{{input_type}}>> output{\"output\", {{dims}}};
+    void generate(){
+
+    }
+
+    void schedule(){
+
+    }
+};
+
+HALIDE_REGISTER_GENERATOR({{name}}, {{registered_name}});";
+
+/// Values substituted into a template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateVars {
+    /// C++ class name, `{{name}}`
+    pub name: String,
+
+    /// Name the generator is registered as, `{{registered_name}}`
+    pub registered_name: String,
+
+    /// Element type of the input and output buffers, `{{input_type}}`, defaults to `float`
+    pub input_type: String,
+
+    /// Number of buffer dimensions, `{{dims}}`, defaults to 3
+    pub dims: usize,
+}
+
+impl TemplateVars {
+    /// Placeholder names
+    pub const KEYS: [&'static str; 4] = ["name", "registered_name", "input_type", "dims"];
+
+    pub fn new(name: impl Into<String>, registered_name: impl Into<String>) -> TemplateVars {
+        TemplateVars {
+            name: name.into(),
+            registered_name: registered_name.into(),
+            input_type: "float".to_string(),
+            dims: 3,
+        }
+    }
+
+    pub fn input_type(mut self, ty: impl Into<String>) -> Self {
+        self.input_type = ty.into();
+        self
+    }
+
+    pub fn dims(mut self, dims: usize) -> Self {
+        self.dims = dims;
+        self
+    }
+
+    /// The value of a placeholder
+    pub fn get(&self, key: &str) -> Option<String> {
+        match key {
+            "name" => Some(self.name.clone()),
+            "registered_name" => Some(self.registered_name.clone()),
+            "input_type" => Some(self.input_type.clone()),
+            "dims" => Some(self.dims.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Substitute the placeholders in `template`
+///
+/// Fails with `io::ErrorKind::InvalidData` for unknown placeholders
+pub fn render(template: &str, vars: &TemplateVars) -> io::Result<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        if rest[..start].ends_with('\\') {
+            out.push_str(&rest[..start - 1]);
+            out.push_str("{{");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let key = after
+            .find("}}")
+            .map(|end| (end, after[..end].trim()))
+            .filter(|(_, key)| validate::is_identifier(key));
+        let (end, key) = match key {
+            Some(key) => key,
+            None => {
+                out.push_str("{{");
+                rest = after;
+                continue;
+            }
+        };
+
+        match vars.get(key) {
+            Some(value) => out.push_str(&value),
+            None => {
+                let offset = template.len() - rest.len() + start;
+                let line = template[..offset].matches('\n').count() + 1;
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Unknown placeholder {{{{{}}}}} on line {}, valid placeholders are: {}",
+                        key,
+                        line,
+                        TemplateVars::KEYS.join(", ")
+                    ),
+                ));
+            }
+        }
+        rest = &after[end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Load a named template from `HALIDE_TEMPLATE_DIR`, `name` may omit the `.cpp` extension
+pub fn load(name: &str) -> io::Result<String> {
+    let dir = env::var_os(DIR_VAR).map(PathBuf::from).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not set, unable to find template {}", DIR_VAR, name),
+        )
+    })?;
+
+    for path in [dir.join(name), dir.join(format!("{}.cpp", name))] {
+        if path.is_file() {
+            return fs::read_to_string(path);
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "No template named {} in {}, available templates are: {}",
+            name,
+            dir.display(),
+            list(&dir)?.join(", ")
+        ),
+    ))
+}
+
+/// Names of the templates in `dir`
+fn list(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .map(|entry| {
+            let path = entry.path();
+            match path.extension() {
+                Some(ext) if ext == "cpp" => path.file_stem(),
+                _ => path.file_name(),
+            }
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()
+        })
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars() -> TemplateVars {
+        TemplateVars::new("Brighten", "brighten")
+    }
+
+    #[test]
+    fn render_default() {
+        let source = render(DEFAULT, &vars().input_type("uint8_t").dims(2)).unwrap();
+        assert!(source.contains("class Brighten: public Generator<Brighten> {"));
+        assert!(source.contains("Input<Buffer<uint8_t>> input{\"input\", 2};"));
+        assert!(source.contains("Output<Buffer<uint8_t>> output{\"output\", 2};"));
+        assert!(source.ends_with("HALIDE_REGISTER_GENERATOR(Brighten, brighten);"));
+        assert!(!source.contains("{{"));
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            render(
+                "{{name}} {{ registered_name }}\n{{input_type}}{{dims}}",
+                &vars()
+            )
+            .unwrap(),
+            "Brighten brighten\nfloat3"
+        );
+        assert_eq!(render("", &vars()).unwrap(), "");
+        assert_eq!(
+            render("no placeholders", &vars()).unwrap(),
+            "no placeholders"
+        );
+    }
+
+    #[test]
+    fn literal_braces() {
+        for (template, expected) in [
+            (r"\{{name}}", "{{name}}"),
+            (r"a \{{ b {{name}}", "a {{ b Brighten"),
+            ("Buffer<int> f{{\"f\", 2}};", "Buffer<int> f{{\"f\", 2}};"),
+            (
+                "int a[2][2] = {{1, 2}, {3, 4}};",
+                "int a[2][2] = {{1, 2}, {3, 4}};",
+            ),
+            ("{{}}", "{{}}"),
+            ("{{name", "{{name"),
+            ("}} {{name}}", "}} Brighten"),
+        ] {
+            assert_eq!(render(template, &vars()).unwrap(), expected, "{}", template);
+        }
+    }
+
+    #[test]
+    fn unknown_placeholders() {
+        let e = render("// {{license}}\n\n{{ namespace }}", &vars()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            e.to_string(),
+            "Unknown placeholder {{license}} on line 1, valid placeholders are: name, registered_name, input_type, dims"
+        );
+
+        let e = render("{{name}}\n\n{{ namespace }}", &vars()).unwrap_err();
+        assert!(e
+            .to_string()
+            .starts_with("Unknown placeholder {{namespace}} on line 3"));
+    }
+
+    #[test]
+    fn vars_lookup() {
+        for key in TemplateVars::KEYS {
+            assert!(vars().get(key).is_some(), "{}", key);
+        }
+        assert_eq!(vars().get("Name"), None);
+    }
+
+    #[test]
+    fn named_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("team.cpp"), "// team\n{{name}}").unwrap();
+        fs::write(dir.path().join("plain"), "{{name}}").unwrap();
+        fs::create_dir(dir.path().join("nested.cpp")).unwrap();
+
+        let saved = env::var_os(DIR_VAR);
+        env::set_var(DIR_VAR, dir.path());
+        let team = load("team");
+        let team_cpp = load("team.cpp");
+        let plain = load("plain");
+        let nested = load("nested");
+        env::remove_var(DIR_VAR);
+        let unset = load("team");
+        if let Some(saved) = saved {
+            env::set_var(DIR_VAR, saved);
+        }
+
+        assert_eq!(team.unwrap(), "// team\n{{name}}");
+        assert_eq!(team_cpp.unwrap(), "// team\n{{name}}");
+        assert_eq!(plain.unwrap(), "{{name}}");
+
+        let e = nested.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e
+            .to_string()
+            .ends_with("available templates are: plain, team"));
+
+        let e = unset.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::NotFound);
+        assert!(e.to_string().starts_with("HALIDE_TEMPLATE_DIR is not set"));
+    }
+}
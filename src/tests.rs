@@ -0,0 +1,1666 @@
+//! Tests of the build, run, generator and source steps, run using `exec::Mock` so they don't
+//! need a compiler or a Halide install
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::exec::{Call, Mock, Response};
+use crate::*;
+
+/// The Halide path used by the tests, it doesn't need to exist
+const HALIDE: &str = "/opt/halide";
+
+/// A build of `brighten.cpp` into `dir` that doesn't depend on the environment
+fn build(dir: &Path, mock: &Mock) -> Build<'static> {
+    Build::new(HALIDE, dir.join("brighten"))
+        .source_file("brighten.cpp")
+        .compiler("c++")
+        .with_image_io(false)
+        .with_executor(mock.clone())
+}
+
+fn args(call: &Call) -> Vec<&str> {
+    call.args.iter().map(String::as_str).collect()
+}
+
+/// A handler collecting the events it receives
+fn events() -> (
+    Arc<Mutex<Vec<Event>>>,
+    impl Fn(&Event) + Send + Sync + 'static,
+) {
+    let events = Arc::new(Mutex::new(vec![]));
+    let sink = events.clone();
+    (events, move |event: &Event| {
+        sink.lock().unwrap().push(event.clone())
+    })
+}
+
+#[test]
+fn compile_object_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let output = dir.path().join("brighten.o");
+    let build = Build::new(HALIDE, &output)
+        .source_file("brighten.cpp")
+        .compiler("c++")
+        .cxxflags("-O3 -DNAME=\"a b\"")
+        .build_arg("-g")
+        .output_kind(OutputKind::Object)
+        .with_image_io(false)
+        .atomic_outputs(false)
+        .with_executor(mock.clone());
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0].program, "c++");
+    assert_eq!(
+        args(&calls[0]),
+        [
+            "-std=c++17",
+            "-I",
+            "/opt/halide/include",
+            "-I",
+            "/opt/halide/tools",
+            "-O3",
+            "-DNAME=a b",
+            "-c",
+            "-g",
+            "brighten.cpp",
+            "-o",
+            output.to_str().unwrap(),
+        ]
+    );
+    assert!(calls[0].group);
+    assert_eq!(calls[0].cwd, None);
+}
+
+/// `-o` and the output are followed by the link arguments of `build`
+fn link_args(build: &Build, extra: &[&str]) -> Vec<String> {
+    let mut link = vec!["-L".to_string(), "/opt/halide/lib".to_string()];
+    link.extend(build.default_libs());
+    link.extend(extra.iter().map(|arg| arg.to_string()));
+    link
+}
+
+#[test]
+fn stamp_only_changes_with_the_output() {
+    use std::time::{Duration, SystemTime};
+
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new()
+        .on("c++", Response::ok().output_file("exe"))
+        .on("c++", Response::ok().output_file("exe"))
+        .on("c++", Response::ok().output_file("changed"));
+    let build = build(dir.path(), &mock).stamp(true);
+    let path = stamp::path(&build.output);
+    assert!(build.build().unwrap());
+
+    let stamp = stamp::read(&path).unwrap();
+    assert_eq!(stamp.output, build.output);
+    assert_eq!(stamp.hash, hash::hex(hash::bytes(b"exe")));
+    assert_eq!(stamp.fingerprint, hash::hex(build.fingerprint()));
+
+    // Rebuilding the same output leaves the stamp alone
+    let past = SystemTime::now() - Duration::from_secs(3600);
+    let set_modified = || {
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(past)
+            .unwrap()
+    };
+    let modified = || path.metadata().unwrap().modified().unwrap();
+    set_modified();
+    assert!(build.build().unwrap());
+    assert_eq!(modified(), past);
+
+    assert!(build.build().unwrap());
+    assert!(modified() > past);
+    assert_eq!(
+        stamp::read(&path).unwrap().hash,
+        hash::hex(hash::bytes(b"changed"))
+    );
+
+    // The fingerprint is stable, and follows the flags
+    assert_eq!(build.fingerprint(), build.clone().fingerprint());
+    assert_ne!(
+        build.fingerprint(),
+        build.clone().build_arg("-O3").fingerprint()
+    );
+
+    // Failed builds don't write a stamp
+    let mock = Mock::new().on("c++", Response::exit(1));
+    let failed = build.clone().with_executor(mock);
+    fs::remove_file(&path).unwrap();
+    assert!(!failed.build().unwrap());
+    assert!(!path.exists());
+}
+
+#[test]
+fn link_executable_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("c++", Response::ok().output_file("exe"));
+    let build = build(dir.path(), &mock)
+        .extra_lib("m")
+        .ldflags("-Wl,--as-needed");
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls_to("c++");
+    assert_eq!(calls.len(), 1);
+    let args = args(&calls[0]);
+    let o = args.iter().position(|arg| *arg == "-o").unwrap();
+    assert_eq!(args[o - 1], "brighten.cpp");
+
+    // The output is written next to the final path, then moved into place
+    let staging = format!("{}.tmp.{}", build.output.display(), std::process::id());
+    assert_eq!(args[o + 1], staging);
+    assert!(!Path::new(&staging).exists());
+    assert_eq!(fs::read(&build.output).unwrap(), b"exe");
+
+    assert_eq!(
+        args[o + 2..],
+        link_args(&build, &["-lm", "-Wl,--as-needed"])
+    );
+}
+
+#[test]
+fn argument_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let output = dir.path().join("brighten");
+    let object = dir.path().join("prebuilt.o");
+    let archive = dir.path().join("libprebuilt.a");
+    fs::write(&object, "").unwrap();
+    fs::write(&archive, "").unwrap();
+    let build = Build::new(HALIDE, &output)
+        .compiler("c++")
+        .cxxflags("-O3")
+        .ldflags("-Wl,--as-needed")
+        .build_arg("-g")
+        .source_file("a.cpp")
+        .source_file("b.cpp")
+        .object(&object)
+        .archive(&archive)
+        .extra_lib("m")
+        .raw_arg(ArgPosition::End, "-end1")
+        .raw_arg(ArgPosition::BeforeSources, "-before1")
+        .raw_arg(ArgPosition::AfterSources, "-after")
+        .raw_arg(ArgPosition::BeforeSources, "-before2")
+        .raw_arg(ArgPosition::End, "-end2")
+        .with_image_io(false)
+        .atomic_outputs(false)
+        .with_executor(mock.clone());
+    assert!(build.build().unwrap());
+
+    let mut expected: Vec<String> = [
+        "-std=c++17",
+        "-I",
+        "/opt/halide/include",
+        "-I",
+        "/opt/halide/tools",
+        "-O3",
+        "-g",
+        "-before1",
+        "-before2",
+        "a.cpp",
+        "b.cpp",
+        object.to_str().unwrap(),
+        archive.to_str().unwrap(),
+        "-after",
+        "-o",
+        output.to_str().unwrap(),
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect();
+    expected.extend(link_args(&build, &["-lm", "-Wl,--as-needed"]));
+    expected.extend(["-end1".to_string(), "-end2".to_string()]);
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 1);
+    assert_eq!(args(&calls[0]), expected);
+}
+
+#[test]
+fn argument_order_with_per_source_flags() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let output = dir.path().join("brighten");
+    let object = dir.path().join("prebuilt.o");
+    let archive = dir.path().join("libprebuilt.a");
+    fs::write(&object, "").unwrap();
+    fs::write(&archive, "").unwrap();
+    let build = Build::new(HALIDE, &output)
+        .compiler("c++")
+        .build_arg("-g")
+        .source_file_with_flags("a.cpp", ["-DA"])
+        .source_file("b.cpp")
+        .object(&object)
+        .archive(&archive)
+        .raw_arg(ArgPosition::BeforeSources, "-before")
+        .raw_arg(ArgPosition::AfterSources, "-after")
+        .raw_arg(ArgPosition::End, "-end")
+        .with_image_io(false)
+        .atomic_outputs(false)
+        .with_executor(mock.clone());
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 3);
+    let objects = build.intermediate_objects();
+    let includes = [
+        "-std=c++17",
+        "-I",
+        "/opt/halide/include",
+        "-I",
+        "/opt/halide/tools",
+    ];
+
+    // Each source is compiled with its own flags after the build arguments
+    let sources = [("a.cpp", &["-DA"][..]), ("b.cpp", &[])];
+    for ((call, (src, flags)), compiled) in calls.iter().zip(sources).zip(&objects) {
+        let mut expected: Vec<&str> = includes.to_vec();
+        expected.push("-c");
+        expected.push("-g");
+        expected.extend(flags);
+        expected.extend(["-before", src, "-after", "-o"]);
+        expected.push(compiled.to_str().unwrap());
+        expected.push("-end");
+        assert_eq!(args(call), expected);
+    }
+
+    // Objects are linked before the prebuilt objects and archives
+    let mut expected: Vec<String> = includes.iter().map(|arg| arg.to_string()).collect();
+    expected.push("-g".to_string());
+    expected.push("-before".to_string());
+    expected.extend(objects.iter().map(|o| o.display().to_string()));
+    expected.extend([object.display().to_string(), archive.display().to_string()]);
+    expected.extend(["-after", "-o"].map(String::from));
+    expected.push(output.display().to_string());
+    expected.extend(link_args(&build, &[]));
+    expected.push("-end".to_string());
+    assert_eq!(args(&calls[2]), expected);
+}
+
+#[test]
+fn per_source_flags_are_isolated() {
+    let dir = tempfile::tempdir().unwrap();
+    let gengen = dir.path().join("GenGen.cpp");
+    fs::write(&gengen, "").unwrap();
+    let mock = Mock::new();
+    let build = Build::new(HALIDE, dir.path().join("filter_gen"))
+        .compiler("c++")
+        .source_file("filter_gen.cpp")
+        .source_file("common.cpp")
+        .source_file_with_flags("schedules_avx2.cpp", ["-mavx2"])
+        .source_file_with_flags("src/common.cpp", ["-DSECOND", "-O1"])
+        .generator(true)
+        .gengen_path(&gengen)
+        .with_image_io(false)
+        .atomic_outputs(false)
+        .with_executor(mock.clone());
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 6);
+    let sources = [
+        ("filter_gen.cpp", &[][..]),
+        ("common.cpp", &[]),
+        ("schedules_avx2.cpp", &["-mavx2"]),
+        ("src/common.cpp", &["-DSECOND", "-O1"]),
+        (gengen.to_str().unwrap(), &[]),
+    ];
+    let objects = build.intermediate_objects();
+    for (i, (src, flags)) in sources.iter().enumerate() {
+        let args = args(&calls[i]);
+        let c = args.iter().position(|arg| *arg == "-c").unwrap();
+        let o = args.iter().position(|arg| *arg == "-o").unwrap();
+        assert_eq!(args[c + 1..o - 1], **flags, "{}", src);
+        assert_eq!(args[o - 1], *src);
+        assert_eq!(Path::new(args[o + 1]), objects[i]);
+    }
+
+    // Sources with the same name get their own objects
+    assert_eq!(objects[1].file_name().unwrap(), "filter_gen.1.common.o");
+    assert_eq!(objects[3].file_name().unwrap(), "filter_gen.3.common.o");
+
+    // None of the flags reach the link
+    let link = args(&calls[5]);
+    for flag in ["-mavx2", "-DSECOND", "-O1"] {
+        assert!(!link.contains(&flag), "{}", flag);
+    }
+    let o = link.iter().position(|arg| *arg == "-o").unwrap();
+    let linked: Vec<&Path> = link[..o]
+        .iter()
+        .map(Path::new)
+        .filter(|arg| objects.iter().any(|object| object == arg))
+        .collect();
+    assert_eq!(
+        linked,
+        objects.iter().map(PathBuf::as_path).collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn source_string_names_stay_next_to_the_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .source_string("expr.cpp", "int main() {}")
+        .unwrap();
+    let path = build.src.last().unwrap();
+    assert_eq!(path.parent(), Some(dir.path()));
+    assert!(path
+        .file_name()
+        .unwrap()
+        .to_string_lossy()
+        .starts_with("expr-"));
+
+    for name in [
+        "",
+        ".",
+        "..",
+        "../expr",
+        "sub/expr",
+        "/tmp/expr",
+        "..\\expr",
+    ] {
+        let err = build
+            .clone()
+            .source_string(name, "int main() {}")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "{:?}", name);
+    }
+}
+
+#[test]
+fn syntax_check_per_source_flags() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .source_file("common.cpp")
+        .source_file_with_flags("schedules_avx2.cpp", ["-mavx2"]);
+    let commands = build.syntax_check_commands();
+    let args = |cmd: &std::process::Command| -> Vec<String> {
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    };
+    assert_eq!(commands.len(), 2);
+
+    // Sources without flags are checked together
+    let plain = args(&commands[0]);
+    assert!(plain.ends_with(&["brighten.cpp".to_string(), "common.cpp".to_string()]));
+    assert!(!plain.contains(&"-mavx2".to_string()));
+    assert!(args(&commands[1]).ends_with(&["-mavx2".to_string(), "schedules_avx2.cpp".to_string()]));
+}
+
+#[test]
+fn generator_links_gengen_first() {
+    let dir = tempfile::tempdir().unwrap();
+    let gengen = dir.path().join("GenGen.cpp");
+    fs::write(&gengen, "").unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .generator(true)
+        .gengen_path(&gengen)
+        .atomic_outputs(false);
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls();
+    let args = args(&calls[0]);
+    let gengen = args
+        .iter()
+        .position(|arg| Path::new(arg) == gengen)
+        .unwrap();
+    assert_eq!(args[gengen + 1], "brighten.cpp");
+}
+
+#[test]
+fn failed_compile_removes_the_staged_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on(
+        "c++",
+        Response::exit(1)
+            .stderr("brighten.cpp:1:1: error: expected expression\n")
+            .output_file("partial"),
+    );
+    let build = build(dir.path(), &mock);
+    assert!(!build.build().unwrap());
+    assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+
+    // The build didn't succeed, so it can't be run
+    assert!(build.run().is_err());
+}
+
+#[test]
+fn failed_compile_keeps_the_previous_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("c++", Response::exit(1).output_file("partial"));
+    let build = build(dir.path(), &mock).force(true);
+    fs::write(&build.output, "previous").unwrap();
+    assert!(!build.build().unwrap());
+
+    // The compiler wrote to the staged output, which was removed
+    assert_eq!(fs::read(&build.output).unwrap(), b"previous");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn without_atomic_outputs_a_failed_compile_leaves_partial_output() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("c++", Response::exit(1).output_file("partial"));
+    let build = build(dir.path(), &mock).force(true).atomic_outputs(false);
+    fs::write(&build.output, "previous").unwrap();
+    assert!(!build.build().unwrap());
+    assert_eq!(fs::read(&build.output).unwrap(), b"partial");
+}
+
+#[test]
+fn copy_replace_replaces_the_destination() {
+    let dir = tempfile::tempdir().unwrap();
+    let from = dir.path().join("from");
+    let to = dir.path().join("to");
+    fs::write(&from, "new").unwrap();
+    fs::write(&to, "old").unwrap();
+    copy_replace(&from, &to).unwrap();
+    assert_eq!(fs::read(&to).unwrap(), b"new");
+    assert!(!from.exists());
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    // A failed copy leaves the destination and source alone
+    let missing = dir.path().join("missing");
+    assert!(copy_replace(&missing, &to).is_err());
+    assert_eq!(fs::read(&to).unwrap(), b"new");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+}
+
+#[test]
+fn capture_records_outcomes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on(
+        "c++",
+        Response::ok()
+            .stderr("brighten.cpp:3:5: warning: unused variable 'x'\n")
+            .output_file("exe"),
+    );
+    let report = build(dir.path(), &mock)
+        .capture(true)
+        .build_report()
+        .unwrap();
+    assert!(report.success);
+    assert_eq!(report.outcomes.len(), 1);
+    assert!(report.outcomes[0].command.starts_with("c++ -std=c++17 "));
+    assert_eq!(
+        report.outcomes[0].stderr,
+        b"brighten.cpp:3:5: warning: unused variable 'x'\n"
+    );
+}
+
+#[test]
+fn compiler_fallback() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new()
+        .on(
+            "broken-c++",
+            Response::exit(1).stderr("broken-c++: not installed\n"),
+        )
+        .on("c++", Response::ok().output_file("exe"));
+    let (events, handler) = events();
+    let build = Build::new(HALIDE, dir.path().join("brighten"))
+        .source_file("brighten.cpp")
+        .compilers(&["broken-c++", "c++"])
+        .with_image_io(false)
+        .on_event(handler)
+        .with_executor(mock.clone());
+    assert!(build.build().unwrap());
+
+    // Each compiler is probed in order, then the build uses the first that works
+    let programs: Vec<String> = mock.calls().into_iter().map(|call| call.program).collect();
+    assert_eq!(programs, ["broken-c++", "c++", "c++"]);
+    assert!(events.lock().unwrap().contains(&Event::CompilerSelected {
+        compiler: "c++".to_string(),
+        rejected: vec![(
+            "broken-c++".to_string(),
+            "broken-c++: not installed".to_string()
+        )],
+    }));
+}
+
+#[test]
+fn no_usable_compiler() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("c++", Response::exit(1));
+    let err = build(dir.path(), &mock)
+        .compilers(&["c++"])
+        .build()
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(mock.calls().len(), 1);
+}
+
+#[test]
+fn interrupted_build() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("c++", Response::interrupted().output_file("partial"));
+    let err = build(dir.path(), &mock).build().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+    assert!(fs::read_dir(dir.path()).unwrap().next().is_none());
+}
+
+#[test]
+fn interrupted_before_starting() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock);
+    build.interrupt_token().interrupt();
+    assert_eq!(
+        build.build().unwrap_err().kind(),
+        io::ErrorKind::Interrupted
+    );
+    assert!(mock.calls().is_empty());
+}
+
+#[test]
+fn mobile_build_uses_the_ndk() {
+    use crate::mobile::{Abi, MobileToolchain};
+
+    let dir = tempfile::tempdir().unwrap();
+    let toolchain = MobileToolchain::android(dir.path().join("ndk"), 26, Abi::Arm64);
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .atomic_outputs(false)
+        .mobile(&toolchain);
+    assert_eq!(build.build().unwrap_err().kind(), io::ErrorKind::NotFound);
+    assert!(mock.calls().is_empty());
+
+    fs::create_dir_all(toolchain.sysroot()).unwrap();
+    fs::create_dir_all(toolchain.compiler().parent().unwrap()).unwrap();
+    fs::write(toolchain.compiler(), "").unwrap();
+    assert!(build.build().unwrap());
+
+    let calls = mock.calls_to("clang++");
+    assert_eq!(Path::new(&calls[0].program), toolchain.compiler());
+    let call = calls.last().unwrap();
+    let args = args(call);
+    assert!(args.contains(&"--target=aarch64-linux-android26"));
+    let sysroot = format!("--sysroot={}", toolchain.sysroot().display());
+    assert!(args.contains(&sysroot.as_str()));
+    assert!(args.contains(&"-llog"));
+    // The host Halide libraries aren't linked
+    assert!(!args.contains(&"/opt/halide/lib"));
+    assert_eq!(build.target_string().as_deref(), Some("arm-64-android"));
+
+    let err = build.force(true).run().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn run_arguments_env_and_cwd() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .run_args(["input.png", "out put.png"])
+        .run_var("HL_NUM_THREADS", "4")
+        .run_cwd(dir.path())
+        .target("host-cuda")
+        .keep(true)
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    assert!(build.run().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 1);
+    let call = &calls[0];
+    assert_eq!(Path::new(&call.program), build.output);
+    assert_eq!(args(call), ["input.png", "out put.png"]);
+    assert_eq!(call.cwd.as_deref(), Some(dir.path()));
+    assert_eq!(call.env("HL_NUM_THREADS"), Some("4"));
+    assert_eq!(call.env("HL_JIT_TARGET"), Some("host-cuda"));
+    let lib_path = call.env(RunEnv::lib_path_var()).unwrap();
+    assert!(lib_path.starts_with("/opt/halide/lib"));
+    assert!(!call.group);
+    assert_eq!(call.input, None);
+}
+
+#[test]
+fn run_under_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .run_under(["gdb", "--args"])
+        .run_args(["input.png"])
+        .run_var("HL_NUM_THREADS", "4")
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    assert!(build.run().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 1);
+    let call = &calls[0];
+    assert_eq!(call.program, "gdb");
+    let output = std::path::absolute(&build.output).unwrap();
+    assert_eq!(
+        args(call),
+        ["--args", output.to_str().unwrap(), "input.png"]
+    );
+
+    // The environment applies to the wrapped process
+    assert_eq!(call.env("HL_NUM_THREADS"), Some("4"));
+    assert!(call.env(RunEnv::lib_path_var()).is_some());
+    assert!(!call.group);
+
+    // The output is only removed once the wrapper exits
+    assert!(!build.output.exists());
+}
+
+#[test]
+fn run_under_forces_passthrough() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("valgrind", Response::ok().stdout("captured\n"));
+    let (events, handler) = events();
+    let build = build(dir.path(), &mock)
+        .run_under(["valgrind", "--leak-check=full"])
+        .capture(true)
+        .force(true)
+        .on_event(handler);
+    fs::write(&build.output, "").unwrap();
+    let report = build.run_report().unwrap();
+    assert!(report.success);
+    assert!(report.outcome.is_none());
+    assert!(events.lock().unwrap().iter().any(|event| matches!(
+        event,
+        Event::Warning(message) if message.contains("valgrind --leak-check=full")
+    )));
+    assert_eq!(mock.calls()[0].program, "valgrind");
+}
+
+#[test]
+fn run_capture_outcome() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("brighten", Response::ok().stdout("a\r\nb").stderr("c\n"));
+    let build = build(dir.path(), &mock)
+        .run_arg("--verbose")
+        .capture(true)
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    let outcome = build.run_report().unwrap().outcome.unwrap();
+    assert!(outcome.success());
+    assert_eq!(
+        outcome.command,
+        format!("{} --verbose", build.output.display())
+    );
+    assert_eq!(outcome.stdout, b"a\r\nb");
+    assert_eq!(outcome.stderr, b"c\n");
+}
+
+#[test]
+fn run_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .run_stdin("1 2 3\n")
+        .keep(true)
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    assert!(build.run().unwrap());
+    assert_eq!(mock.calls()[0].input.as_deref(), Some(&b"1 2 3\n"[..]));
+
+    // Generators report diagnostics, they still get the input
+    assert!(build.clone().generator(true).run().unwrap());
+    assert_eq!(mock.calls()[1].input.as_deref(), Some(&b"1 2 3\n"[..]));
+}
+
+#[test]
+fn run_stdin_with_capture() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("brighten", Response::ok().stdout("6\n"));
+    let build = build(dir.path(), &mock)
+        .run_stdin("1 2 3\n")
+        .capture(true)
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    let report = build.run_report().unwrap();
+    assert_eq!(report.outcome.unwrap().stdout, b"6\n");
+    assert_eq!(mock.calls()[0].input.as_deref(), Some(&b"1 2 3\n"[..]));
+}
+
+#[test]
+fn run_expect_stdout() {
+    let dir = tempfile::tempdir().unwrap();
+    let golden = dir.path().join("brighten.golden");
+    fs::write(&golden, "min 0\nmax 255\n").unwrap();
+    let mock = Mock::new()
+        .on("brighten", Response::ok().stdout("min 0\r\nmax 255\r\n"))
+        .on("brighten", Response::ok().stdout("min 0\nmax 254\n"));
+    let build = build(dir.path(), &mock)
+        .expect_stdout(&golden)
+        .keep(true)
+        .force(true);
+    fs::write(&build.output, "").unwrap();
+    assert!(build.run().unwrap());
+
+    let e = build.run().unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    let mismatch = e.get_ref().unwrap().downcast_ref::<golden::Mismatch>();
+    assert!(mismatch.unwrap().diff.contains("-max 255\n+max 254"));
+
+    // Updating writes the captured bytes as they are
+    assert!(build.clone().update_golden(true).run().unwrap());
+    assert_eq!(fs::read(&golden).unwrap(), b"min 0\nmax 254\n");
+    assert!(build.run().unwrap());
+}
+
+/// A script run as the output, a build with it can be run without building
+#[cfg(unix)]
+fn script(dir: &Path, body: &str) -> Build<'static> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let build = Build::new(HALIDE, dir.join("script"))
+        .force(true)
+        .keep(true);
+    fs::write(&build.output, format!("#!/bin/sh\n{}", body)).unwrap();
+    fs::set_permissions(&build.output, fs::Permissions::from_mode(0o755)).unwrap();
+    build
+}
+
+#[cfg(unix)]
+#[test]
+fn run_stdin_with_tee() {
+    let dir = tempfile::tempdir().unwrap();
+    let (events, handler) = events();
+
+    // More input than fits in a pipe, echoed back before the script exits
+    let input: String = (0..20000).map(|i| format!("line {}\n", i)).collect();
+    let build = script(dir.path(), "wc -l\necho done >&2\n")
+        .run_stdin(input)
+        .tee(true)
+        .on_event(handler);
+    let outcome = build.run_report().unwrap().outcome.unwrap();
+    assert!(outcome.success());
+    assert_eq!(String::from_utf8_lossy(&outcome.stdout).trim(), "20000");
+    assert_eq!(outcome.stderr, b"done\n");
+
+    let events = events.lock().unwrap();
+    assert!(events.contains(&Event::ChildStdoutLine(
+        String::from_utf8_lossy(&outcome.stdout)
+            .trim_end()
+            .to_string()
+    )));
+    assert!(events.contains(&Event::ChildStderrLine("done".to_string())));
+}
+
+#[cfg(unix)]
+#[test]
+fn run_tee_streams_lines_in_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let marker = dir.path().join("seen");
+
+    // The script only continues once the first line has been seen as an event, so it fails
+    // unless lines are sent while it's running
+    let body = format!(
+        "echo first
+i=0
+while [ ! -e '{}' ]; do
+  i=$((i + 1)); [ $i -gt 100 ] && exit 3
+  sleep 0.05
+done
+echo warning >&2
+sleep 0.05
+printf 'second\\r\\n'
+sleep 0.05
+printf 'error\\n' >&2
+sleep 0.05
+printf 'partial'
+",
+        marker.display()
+    );
+    let events = Arc::new(Mutex::new(vec![]));
+    let sink = events.clone();
+    let build = script(dir.path(), &body)
+        .tee(true)
+        .on_event(move |event: &Event| {
+            if let Event::ChildStdoutLine(_) | Event::ChildStderrLine(_) = event {
+                fs::write(&marker, "").unwrap();
+                sink.lock().unwrap().push(event.clone());
+            }
+        });
+    let outcome = build.run_report().unwrap().outcome.unwrap();
+    assert!(outcome.success());
+    assert_eq!(outcome.stdout, b"first\nsecond\r\npartial");
+    assert_eq!(outcome.stderr, b"warning\nerror\n");
+    assert_eq!(
+        *events.lock().unwrap(),
+        [
+            Event::ChildStdoutLine("first".to_string()),
+            Event::ChildStderrLine("warning".to_string()),
+            Event::ChildStdoutLine("second".to_string()),
+            Event::ChildStderrLine("error".to_string()),
+            Event::ChildStdoutLine("partial".to_string()),
+        ]
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn run_scheduling() {
+    let dir = tempfile::tempdir().unwrap();
+
+    // Fields 19 and 39 of `/proc/self/stat` are the niceness and the CPU last run on
+    let stat = |build: Build| {
+        let outcome = build.tee(true).run_report().unwrap().outcome.unwrap();
+        assert!(outcome.success());
+        String::from_utf8(outcome.stdout)
+            .unwrap()
+            .trim()
+            .to_string()
+    };
+    let build = script(dir.path(), "cut -d' ' -f39 /proc/self/stat\n");
+    assert_eq!(stat(build.clone().cpu_affinity(&[0])), "0");
+
+    let nice = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+    let build = script(dir.path(), "cut -d' ' -f19,39 /proc/self/stat\n");
+    assert_eq!(
+        stat(build.nice(5).cpu_affinity(&[0])),
+        format!("{} 0", (nice + 5).min(19))
+    );
+}
+
+#[test]
+fn run_removes_the_output_unless_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on("brighten", Response::exit(2));
+    let build = build(dir.path(), &mock).force(true);
+    fs::write(&build.output, "").unwrap();
+    assert!(!build.run().unwrap());
+    assert!(!build.output.exists());
+}
+
+#[test]
+fn generator_emit_command() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock).target("x86-64-linux-avx2");
+    let gen = GenBuilder::new(build, "brighten", &out)
+        .function_name("ns::brighten_fast")
+        .emit(Emit::StaticLibrary)
+        .emit(Emit::CHeader)
+        .param("scale", "2 -o /etc")
+        .param("autoscheduler", "Mullapudi2016");
+    assert!(gen.run_generator().unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 1);
+    let call = &calls[0];
+    assert_eq!(Path::new(&call.program), gen.build.output);
+    let args = args(call);
+
+    // Files are emitted into a staging directory inside the output directory
+    let staging = out.join(format!(".ns::brighten_fast.tmp.{}", std::process::id()));
+    assert_eq!(
+        args,
+        [
+            "-g",
+            "brighten",
+            "-o",
+            staging.to_str().unwrap(),
+            "-f",
+            "ns::brighten_fast",
+            "-e",
+            "static_library,c_header",
+            "target=x86-64-linux-avx2",
+            "scale=2 -o /etc",
+            "autoscheduler=Mullapudi2016",
+        ]
+    );
+    assert!(!staging.exists());
+    assert!(call.group);
+    assert!(call
+        .env(RunEnv::lib_path_var())
+        .unwrap()
+        .starts_with("/opt/halide/lib"));
+    // Generators get the target as an argument, not from the environment
+    assert_eq!(call.env("HL_JIT_TARGET"), None);
+}
+
+#[test]
+fn emit_command_never_splits_param_values() {
+    let dir = tempfile::tempdir().unwrap();
+    let values = [
+        "2 -o /etc",
+        "\"quoted\" 'value'",
+        "a;rm -rf /",
+        "$(touch pwned)",
+        "line\nbreak",
+        "",
+    ];
+    let mut gen = GenBuilder::new(build(dir.path(), &Mock::new()), "brighten", dir.path());
+    for (i, value) in values.iter().enumerate() {
+        gen = gen.param(format!("p{}", i), *value);
+    }
+
+    let cmd = gen.emit_command();
+    let args: Vec<String> = cmd
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let params: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|arg| arg.starts_with('p'))
+        .collect();
+    let expected: Vec<String> = values
+        .iter()
+        .enumerate()
+        .map(|(i, value)| format!("p{}={}", i, value))
+        .collect();
+    assert_eq!(params, expected);
+}
+
+#[test]
+fn invalid_generator_names_are_rejected_before_running() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let gens = [
+        GenBuilder::new(build(dir.path(), &mock), "-o", dir.path()),
+        GenBuilder::new(build(dir.path(), &mock), "brighten", dir.path()).function_name("f;g"),
+        GenBuilder::new(build(dir.path(), &mock), "brighten", dir.path()).param("-o /etc", "1"),
+        GenBuilder::new(
+            build(&dir.path().join("sandbox"), &mock).sandbox(dir.path().join("sandbox")),
+            "brighten",
+            dir.path().join("sandbox/../out"),
+        ),
+    ];
+    fs::create_dir_all(dir.path().join("sandbox")).unwrap();
+    for gen in gens {
+        let err = gen.run_generator().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+    assert!(mock.calls().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn generator_manifest_records_the_moved_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("out");
+    let manifest_path = dir.path().join("pipelines.json");
+
+    // Writes the library and header into the directory passed using `-o`
+    let build = script(
+        dir.path(),
+        "while [ \"$1\" != -o ]; do shift; done\n\
+         echo new > \"$2/brighten.a\"\n\
+         echo 'int brighten(struct halide_buffer_t *_input_buffer);' > \"$2/brighten.h\"\n",
+    );
+    fs::create_dir_all(&out).unwrap();
+    fs::write(out.join("brighten.a"), "old").unwrap();
+
+    let gen = GenBuilder::new(build, "brighten", &out)
+        .emit(Emit::StaticLibrary)
+        .emit(Emit::CHeader)
+        .manifest(&manifest_path);
+    assert!(gen.run_generator().unwrap());
+    assert_eq!(fs::read(out.join("brighten.a")).unwrap(), b"new\n");
+
+    let m = manifest::load(&manifest_path).unwrap();
+    let pipeline = m.get("brighten").unwrap();
+    assert_eq!(pipeline.files.len(), 2);
+    for file in &pipeline.files {
+        assert!(file.path.starts_with(&out));
+        assert_eq!(file.hash, hash::hex(hash::file(&file.path).unwrap()));
+    }
+    assert_eq!(pipeline.arguments[0].name, "input");
+
+    // Nothing is left in the staging directory
+    assert_eq!(fs::read_dir(&out).unwrap().count(), 2);
+}
+
+#[test]
+fn generator_run_under() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let build = build(dir.path(), &mock)
+        .run_under(["gdb", "--args"])
+        .atomic_outputs(false)
+        .target("host");
+    let gen = GenBuilder::new(build, "brighten", dir.path());
+    assert!(gen.run_generator().unwrap());
+    assert!(gen.emit_runtime("halide_runtime").unwrap());
+
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 2);
+    let output = gen.build.output.to_str().unwrap();
+    let out = dir.path().to_str().unwrap();
+    for call in &calls {
+        assert_eq!(call.program, "gdb");
+        assert!(!call.group);
+        assert!(call.env(RunEnv::lib_path_var()).is_some());
+    }
+    assert_eq!(
+        args(&calls[0])[..6],
+        ["--args", output, "-g", "brighten", "-o", out]
+    );
+    assert_eq!(
+        args(&calls[1]),
+        [
+            "--args",
+            output,
+            "-r",
+            "halide_runtime",
+            "-o",
+            out,
+            "-e",
+            "object",
+            "target=host"
+        ]
+    );
+}
+
+#[test]
+fn generator_failure_reports_diagnostics() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on(
+        "brighten",
+        Response::exit(1).stderr("Error: Input buffer input is accessed at -1\n"),
+    );
+    let gen = GenBuilder::new(
+        build(dir.path(), &mock).atomic_outputs(false),
+        "brighten",
+        dir.path(),
+    );
+    let err = gen.run_generator().unwrap_err();
+    let failure = err
+        .get_ref()
+        .and_then(|e| e.downcast_ref::<diagnostics::Failure>())
+        .unwrap();
+    assert_eq!(
+        failure.diagnostics[0].message,
+        "Input buffer input is accessed at -1"
+    );
+}
+
+#[test]
+fn shared_library_arguments() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("libkernels");
+    let mock = Mock::new();
+    assert!(compile_shared_library_with(
+        mock.clone(),
+        Some("clang++"),
+        output.to_str().unwrap(),
+        &["a.cpp", "b.o"],
+    )
+    .unwrap());
+
+    let platform = Platform::host();
+    let mut expected = vec!["-std=c++17"];
+    expected.extend(platform.pic_flags());
+    let output = platform.output_path(&output);
+    expected.extend(["-shared", "-o", output.to_str().unwrap(), "a.cpp", "b.o"]);
+
+    let calls = mock.calls_to("clang++");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(args(&calls[0]), expected);
+}
+
+#[test]
+fn shared_library_failure() {
+    let dir = tempfile::tempdir().unwrap();
+    let output = dir.path().join("libkernels.so.1.2");
+    let mock = Mock::new().on("c++", Response::exit(1));
+    let res = compile_shared_library_with(mock, Some("c++"), output.to_str().unwrap(), &[]);
+    assert!(!res.unwrap());
+
+    // The unversioned link is only created for successful builds
+    assert!(!dir.path().join("libkernels.so").exists());
+}
+
+#[test]
+fn source_build_arguments_and_progress() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new().on(
+        "make",
+        Response::exit(2).stdout("[ 50%] Building CXX object src/Func.cpp.o\n"),
+    );
+    let (events, handler) = events();
+    let mut source = Source::new(dir.path()).with_executor(mock.clone());
+    source.make_flags = vec!["-j3".to_string(), "distrib".to_string()];
+    source.llvm_config = Some(PathBuf::from("/opt/llvm/bin/llvm-config"));
+    source.events = EventHandler::new(handler);
+    assert!(!source.build().unwrap());
+
+    let calls = mock.calls_to("make");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(args(&calls[0]), ["-j3", "distrib"]);
+    assert_eq!(calls[0].cwd.as_deref(), Some(dir.path()));
+    assert_eq!(
+        calls[0].env("LLVM_CONFIG"),
+        Some("/opt/llvm/bin/llvm-config")
+    );
+    assert!(events.lock().unwrap().contains(&Event::SourceProgress {
+        percent: Some(50.0),
+        line: "[ 50%] Building CXX object src/Func.cpp.o".to_string(),
+    }));
+}
+
+#[test]
+fn source_build_jobs() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new()
+        .on("make", Response::exit(1))
+        .on("cmake", Response::exit(1));
+    let mut source = Source::new(dir.path()).with_executor(mock.clone());
+
+    // At least one job is run
+    source.jobs = Some(0);
+    assert!(!source.build().unwrap());
+    assert_eq!(args(&mock.calls_to("make")[0]), ["-j1"]);
+
+    source.make = "cmake".to_string();
+    source.make_flags = vec!["--build".to_string(), "build".to_string()];
+    assert!(!source.build().unwrap());
+    assert_eq!(
+        args(&mock.calls_to("cmake")[0]),
+        ["--build", "build", "--parallel", "1"]
+    );
+}
+
+#[test]
+fn source_runs_git_using_the_executor() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new()
+        .on("git", Response::ok().stdout("git version 2.43.0\n"))
+        .on("git", Response::ok());
+    let mut source = Source::new(dir.path()).with_executor(mock.clone());
+    source.branch = "release/17.x".to_string();
+    assert!(source.download().unwrap());
+
+    let calls = mock.calls_to("git");
+    assert_eq!(args(&calls[0]), ["--version"]);
+    let clone = calls.last().unwrap();
+    assert_eq!(args(clone)[..3], ["clone", "-b", "release/17.x"]);
+    assert!(!clone.group);
+
+    // `rev-parse` returns the same output before and after the merge
+    assert_eq!(source.update().unwrap(), scm::UpdateStatus::UpToDate);
+    let update: Vec<_> = mock.calls_to("git")[calls.len()..]
+        .iter()
+        .filter(|call| call.args[0] != "--version")
+        .map(|call| {
+            assert_eq!(call.cwd.as_deref(), Some(dir.path()));
+            call.args[0].clone()
+        })
+        .collect();
+    assert_eq!(
+        update,
+        ["status", "fetch", "rev-parse", "merge", "rev-parse"]
+    );
+}
+
+/// Serializes tests that change environment variables
+static ENV: Mutex<()> = Mutex::new(());
+
+/// Run `f` with the variables set, or removed when `None`, restoring them afterwards
+fn with_env<T>(vars: &[(&str, Option<&str>)], f: impl FnOnce() -> T) -> T {
+    let _lock = ENV.lock().unwrap_or_else(|e| e.into_inner());
+    let saved: Vec<_> = vars
+        .iter()
+        .map(|(name, _)| (*name, std::env::var_os(name)))
+        .collect();
+    for (name, value) in vars {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+    let res = f();
+    for (name, value) in saved {
+        match value {
+            Some(value) => std::env::set_var(name, value),
+            None => std::env::remove_var(name),
+        }
+    }
+    res
+}
+
+fn provenance(build: &Build, name: &str) -> Provenance {
+    build.explain().get(name).unwrap().provenance.clone()
+}
+
+#[test]
+fn info_snapshot() {
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    for (path, contents) in [
+        (
+            "include/Halide.h",
+            "#define HALIDE_VERSION_MAJOR 16\n#define HALIDE_VERSION_MINOR 0\n#define HALIDE_VERSION_PATCH 1\n",
+        ),
+        ("tools/GenGen.cpp", ""),
+        ("lib/libHalide.so.16.0.1", ""),
+        ("lib/libHalide.a", ""),
+        ("lib/libHalide.cmake", ""),
+        ("lib/libpng.so", ""),
+    ] {
+        fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+        fs::write(root.join(path), contents).unwrap();
+    }
+    let mock = Mock::new().on(
+        "c++",
+        Response::ok()
+            .stdout("c++ (GCC) 12.2.0\nCopyright (C) 2022 Free Software Foundation, Inc.\n"),
+    );
+
+    let info = with_env(&[("CXX", None)], || Info::collect(&mock, root));
+    let target = info::host_target();
+    assert_eq!(
+        info.to_string(),
+        format!(
+            "halide path: {root}
+layout:      install
+include:     {root}/include
+lib:         {root}/lib
+tools:       {root}/tools
+library:     libHalide.a (static)
+library:     libHalide.so.16.0.1 (shared)
+version:     16.0.1
+compiler:    c++ (gcc 12.2.0)
+target:      {target}
+",
+            root = root.display(),
+        )
+    );
+
+    let json: serde_json::Value = serde_json::to_value(&info).unwrap();
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "version": info::VERSION,
+            "halide_path": root,
+            "layout": "install",
+            "include": root.join("include"),
+            "lib": root.join("lib"),
+            "tools": root.join("tools"),
+            "libraries": [
+                { "name": "libHalide.a", "kind": "static" },
+                { "name": "libHalide.so.16.0.1", "kind": "shared" },
+            ],
+            "halide_version": "16.0.1",
+            "compiler": { "name": "c++", "family": "gcc", "version": "12.2.0" },
+            "target": target,
+        })
+    );
+    assert_eq!(serde_json::from_value::<Info>(json).unwrap(), info);
+
+    // An empty directory reports missing pieces rather than failing
+    let empty = tempfile::tempdir().unwrap();
+    let info = with_env(&[("CXX", Some("c++"))], || {
+        Info::collect(&Mock::new(), empty.path())
+    });
+    assert_eq!(info.layout, "unknown");
+    assert!(info.libraries.is_empty());
+    assert_eq!(info.halide_version, None);
+    assert_eq!(info.compiler.family, "unknown");
+    assert_eq!(info.compiler.version, None);
+}
+
+#[test]
+fn explain_defaults() {
+    let dir = tempfile::tempdir().unwrap();
+    with_env(&[("CXX", None), ("TERMINFO", None)], || {
+        let build = Build::new(HALIDE, dir.path().join("brighten")).with_executor(Mock::new());
+        assert_eq!(provenance(&build, "compiler"), Provenance::Default);
+        assert_eq!(provenance(&build, "cxxflags"), Provenance::Default);
+        assert_eq!(provenance(&build, "ldflags"), Provenance::Default);
+        assert_eq!(provenance(&build, "target"), Provenance::Default);
+        assert_eq!(provenance(&build, "libs"), Provenance::Default);
+        assert_eq!(provenance(&build, "std"), Provenance::Default);
+    });
+}
+
+#[test]
+fn explain_env() {
+    let dir = tempfile::tempdir().unwrap();
+    let vars = [
+        ("HALIDE_PATH", Some(HALIDE)),
+        ("CXX", Some("clang++")),
+        ("CXXFLAGS", Some("-O2")),
+        ("LDFLAGS", Some("-s")),
+        ("TERMINFO", Some("-ltinfo")),
+    ];
+    with_env(&vars, || {
+        let build = Build::from_env(dir.path().join("brighten")).with_executor(Mock::new());
+        let explanation = build.explain();
+        for (setting, var) in [
+            ("halide_path", "HALIDE_PATH"),
+            ("compiler", "CXX"),
+            ("cxxflags", "CXXFLAGS"),
+            ("ldflags", "LDFLAGS"),
+            ("libs", "TERMINFO"),
+        ] {
+            assert_eq!(
+                explanation.get(setting).unwrap().provenance,
+                Provenance::Env(var.to_string()),
+                "{}",
+                setting
+            );
+        }
+        assert_eq!(explanation.get("compiler").unwrap().value, "clang++");
+        assert!(explanation
+            .get("libs")
+            .unwrap()
+            .value
+            .split(' ')
+            .any(|lib| lib == "-ltinfo"));
+
+        // Changing a setting after it was read from the environment makes it a builder setting
+        let build = build.cxxflags("-O3");
+        assert_eq!(provenance(&build, "cxxflags"), Provenance::Builder);
+        assert_eq!(
+            provenance(&build, "ldflags"),
+            Provenance::Env("LDFLAGS".to_string())
+        );
+    });
+}
+
+#[test]
+fn explain_builder() {
+    let dir = tempfile::tempdir().unwrap();
+    with_env(&[("CXX", Some("clang++")), ("TERMINFO", None)], || {
+        let build = Build::new(HALIDE, dir.path().join("brighten"))
+            .compiler("g++")
+            .cxxflags("-O3")
+            .target("host-cuda")
+            .extra_lib("foo")
+            .with_executor(Mock::new());
+        for setting in ["halide_path", "compiler", "cxxflags", "target", "libs"] {
+            assert_eq!(
+                provenance(&build, setting),
+                Provenance::Builder,
+                "{}",
+                setting
+            );
+        }
+
+        // Image libraries are part of the linked libraries
+        let build = Build::new(HALIDE, dir.path().join("brighten"))
+            .with_image_io(false)
+            .with_executor(Mock::new());
+        assert_eq!(provenance(&build, "libs"), Provenance::Builder);
+    });
+}
+
+#[test]
+fn explain_llvm_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let halide = dir.path().join("halide");
+    fs::create_dir_all(halide.join("lib")).unwrap();
+    fs::write(halide.join("lib").join("libHalide.a"), "").unwrap();
+    let mock = Mock::new()
+        .on("llvm-config", Response::ok().stdout("/usr/lib/llvm\n"))
+        .on("llvm-config", Response::ok().stdout("-lLLVM -lz\n"));
+
+    with_env(&[("LLVM_CONFIG", Some("llvm-config"))], || {
+        let build = Build::new(&halide, dir.path().join("brighten"))
+            .llvm_system_libs(true)
+            .with_executor(mock.clone());
+        let libs = build.explain().get("libs").unwrap().clone();
+        assert_eq!(libs.provenance, Provenance::Env("LLVM_CONFIG".to_string()));
+        assert!(libs.value.contains("-L/usr/lib/llvm -lLLVM -lz"));
+
+        let build = build.llvm_config("/opt/llvm/bin/llvm-config");
+        assert_eq!(provenance(&build, "libs"), Provenance::Builder);
+    });
+}
+
+#[test]
+fn language_selects_the_compiler_and_std() {
+    let dir = tempfile::tempdir().unwrap();
+    let compile = |language: Language, src: &str| {
+        let mock = Mock::new();
+        let build = Build::new(HALIDE, dir.path().join("kernel.o"))
+            .source_file(src)
+            .language(language)
+            .output_kind(OutputKind::Object)
+            .with_image_io(false)
+            .atomic_outputs(false)
+            .with_executor(mock.clone());
+        assert!(build.build().unwrap());
+        mock.calls().remove(0)
+    };
+
+    with_env(&[("CC", None), ("CXX", None)], || {
+        let call = compile(Language::C, "kernel.c");
+        assert_eq!(call.program, "cc");
+        assert_eq!(args(&call)[0], "-std=c11");
+        assert!(args(&call).contains(&"kernel.c"));
+        assert!(!args(&call).contains(&"-x"));
+
+        let call = compile(Language::Cxx, "kernel.cpp");
+        assert_eq!(call.program, "c++");
+        assert_eq!(args(&call)[0], "-std=c++17");
+
+        // The compiler would pick C++ from the extension
+        let call = compile(Language::C, "kernel.cpp");
+        let args = args(&call);
+        let i = args.iter().position(|arg| *arg == "kernel.cpp").unwrap();
+        assert_eq!(args[i - 2..i + 3], ["-x", "c", "kernel.cpp", "-x", "none"]);
+    });
+
+    with_env(&[("CC", Some("clang")), ("CXX", Some("clang++"))], || {
+        assert_eq!(compile(Language::C, "kernel.c").program, "clang");
+        assert_eq!(compile(Language::Cxx, "kernel.cpp").program, "clang++");
+    });
+}
+
+#[test]
+fn c_source_library_compiles_as_c() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let gen = GenBuilder::new(
+        build(dir.path(), &mock).cxxflags("-O3"),
+        "brighten",
+        dir.path(),
+    )
+    .function_name("ns::brighten");
+    fs::write(gen.runtime_path("halide_runtime"), "").unwrap();
+    let lib = gen.c_source_library("halide_runtime").atomic_outputs(false);
+    assert_eq!(lib.language, Language::C);
+    assert_eq!(lib.output_kind, OutputKind::StaticLibrary);
+
+    with_env(&[("CC", None), ("AR", None)], || {
+        assert!(lib.build().unwrap());
+    });
+    let calls = mock.calls();
+    assert_eq!(calls.len(), 2);
+
+    // The generator's C++ compiler and flags aren't used
+    assert_eq!(calls[0].program, "cc");
+    let source = dir.path().join("brighten.halide_generated.cpp");
+    let compile = args(&calls[0]);
+    assert_eq!(compile[0], "-std=c11");
+    assert!(!compile.contains(&"-O3"));
+    assert!(compile
+        .windows(3)
+        .any(|w| w == ["-x", "c", source.to_str().unwrap()]));
+
+    assert_eq!(calls[1].program, "ar");
+    let runtime = gen.runtime_path("halide_runtime");
+    assert!(args(&calls[1]).contains(&runtime.to_str().unwrap()));
+}
+
+/// A generator for `c_source_library_end_to_end`
+const BRIGHTEN_GENERATOR: &str = r#"#include "Halide.h"
+
+class Brighten : public Halide::Generator<Brighten> {
+public:
+    Input<Buffer<uint8_t, 2>> input{"input"};
+    Output<Buffer<uint8_t, 2>> output{"output"};
+
+    void generate() {
+        Var x, y;
+        output(x, y) = Halide::saturating_cast<uint8_t>(input(x, y) + 10);
+    }
+};
+
+HALIDE_REGISTER_GENERATOR(Brighten, brighten)
+"#;
+
+/// Emit C source with a real Halide install and build a static library from it, skipped
+/// unless `HALIDE_PATH` is set
+#[test]
+fn c_source_library_end_to_end() {
+    let halide = match std::env::var("HALIDE_PATH") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let dir = tempfile::tempdir().unwrap();
+    let source = dir.path().join("brighten_generator.cpp");
+    fs::write(&source, BRIGHTEN_GENERATOR).unwrap();
+
+    let build = Build::new(&halide, dir.path().join("brighten.generator"))
+        .source_file(&source)
+        .generator(true)
+        .with_image_io(false);
+    assert!(build.build().unwrap());
+
+    let gen = GenBuilder::new(build, "brighten", dir.path().join("out"))
+        .emit(Emit::CSource)
+        .emit(Emit::CHeader);
+    assert!(gen.run_generator().unwrap());
+    assert!(gen.emit_runtime("halide_runtime").unwrap());
+
+    let lib = gen.c_source_library("halide_runtime");
+    assert!(lib.build().unwrap());
+    assert!(lib.output.exists());
+}
+
+#[test]
+fn check_naming_warns_or_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new();
+    let (events, handler) = events();
+    let output = dir.path().join("brighten.so.lib");
+    let build = Build::new(HALIDE, &output)
+        .source_file("brighten.cpp")
+        .output_kind(OutputKind::StaticLibrary)
+        .with_executor(mock.clone())
+        .on_event(handler);
+    let expected = naming::normalize(OutputKind::StaticLibrary, &output, Platform::host());
+
+    build.check_naming().unwrap();
+    let message = events
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|event| match event {
+            Event::Warning(message) => Some(message.clone()),
+            _ => None,
+        })
+        .unwrap();
+    assert!(message.ends_with(&format!("expected {}", expected.display())));
+
+    let err = build.strict_naming(true).build().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(mock.calls().is_empty());
+}
+
+#[test]
+fn image_io_uses_pkg_config() {
+    let dir = tempfile::tempdir().unwrap();
+    let mock = Mock::new()
+        .on(
+            "pkg-config-test",
+            Response::ok().stdout("-I/usr/include/libpng16 -lpng16\n"),
+        )
+        .on(
+            "pkg-config-test",
+            Response::ok().stdout("-I/opt/jpeg/include -L/opt/jpeg/lib -ljpeg\n"),
+        );
+    let build = build(dir.path(), &mock)
+        .with_image_io(true)
+        .atomic_outputs(false);
+    with_env(&[("PKG_CONFIG", Some("pkg-config-test"))], || {
+        assert!(build.build().unwrap())
+    });
+
+    let calls = mock.calls_to("c++");
+    let argv = args(&calls[0]);
+    let includes: Vec<&str> = argv
+        .iter()
+        .filter(|arg| arg.starts_with("-I/"))
+        .copied()
+        .collect();
+    assert_eq!(includes, ["-I/usr/include/libpng16", "-I/opt/jpeg/include"]);
+    let o = argv.iter().position(|arg| *arg == "-o").unwrap();
+    let l = argv
+        .iter()
+        .position(|arg| *arg == "-L/opt/jpeg/lib")
+        .unwrap();
+    assert!(l > o);
+    assert_eq!(argv[l..l + 3], ["-L/opt/jpeg/lib", "-lpng16", "-ljpeg"]);
+
+    // pkg-config is only queried once for clones of the build
+    assert!(build.clone().build().unwrap());
+    assert_eq!(mock.calls_to("pkg-config-test").len(), 2);
+
+    // Without pkg-config the libraries are linked by name
+    let mock = Mock::new();
+    let build = build.clone().pkg_config(false).with_executor(mock.clone());
+    assert!(build.build().unwrap());
+    assert!(mock.calls_to("pkg-config-test").is_empty());
+    let calls = mock.calls_to("c++");
+    let plain = args(&calls[0]);
+    assert!(plain.contains(&"-lpng") && plain.contains(&"-ljpeg"));
+    assert!(!plain.contains(&"-I/usr/include/libpng16"));
+}
+
+#[test]
+fn compiler_version_is_run_using_the_executor() {
+    let dir = tempfile::tempdir().unwrap();
+    let gcc =
+        "c++ (Ubuntu 13.2.0-23ubuntu4) 13.2.0\nCopyright (C) 2023 Free Software Foundation, Inc.\n";
+    let mock = Mock::new().on("c++", Response::ok().stdout(gcc));
+    let build = build(dir.path(), &mock)
+        .warnings(WarningLevel::Strict)
+        .warnings_as_errors(true)
+        .colorize_diagnostics(true)
+        .structured_diagnostics(true);
+    let flags: Vec<String> = build
+        .dump_command()
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    for flag in ["-Wall", "-Werror", "-fdiagnostics-format=json"] {
+        assert!(flags.iter().any(|arg| arg == flag), "{}", flag);
+    }
+    if std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) {
+        assert!(flags.iter().any(|arg| arg == "-fdiagnostics-color=always"));
+    }
+
+    // The compiler is only asked once, clones share the answer
+    build.fingerprint();
+    build.clone().summary();
+    let calls = mock.calls_to("c++");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(args(&calls[0]), ["--version"]);
+
+    // Changing the compiler asks again, compilers named after their family aren't run
+    let _ = build.clone().compiler("c++-17").dump_command();
+    assert_eq!(mock.calls_to("c++-17").len(), 1);
+    let named = build.clone().compiler("clang++");
+    let flags: Vec<String> = named
+        .dump_command()
+        .get_args()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    assert!(flags.iter().any(|arg| arg == "-Wall"));
+    assert!(!flags.iter().any(|arg| arg == "-fdiagnostics-format=json"));
+    assert!(mock.calls_to("clang++").is_empty());
+}
@@ -0,0 +1,466 @@
+//! Pin the compiler, Halide version and flags used to build kernels
+//!
+//! A toolchain file is usually checked in as `halide-toolchain.toml`:
+//!
+//! ```toml
+//! target_features = ["avx2"]
+//! cxxflags = "-O3"
+//!
+//! [compiler]
+//! cxx = "clang++"
+//! min_version = "14"
+//! max_version = "17"
+//!
+//! [halide]
+//! version = "16.0"
+//! commit = "c5bd1d2"
+//! ```
+//!
+//! Version bounds are inclusive and only compared up to the components they specify, so a
+//! `max_version` of `17` allows `17.0.6`
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use serde::{Deserialize, Serialize};
+
+use crate::exec::Executor;
+use crate::version::{self, Version};
+
+/// Default toolchain file name, read by the CLI from the current directory
+pub const FILE_NAME: &str = "halide-toolchain.toml";
+
+/// Compiler pins
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compiler {
+    /// Compiler name or path
+    pub cxx: Option<String>,
+
+    pub min_version: Option<String>,
+    pub max_version: Option<String>,
+}
+
+/// Halide pins
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Halide {
+    /// Version reported by the Halide headers, compared up to the components given
+    pub version: Option<String>,
+
+    /// Commit of the Halide source checkout, may be abbreviated
+    pub commit: Option<String>,
+}
+
+/// A toolchain description
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Toolchain {
+    /// Halide target features that are always enabled
+    #[serde(default)]
+    pub target_features: Vec<String>,
+
+    /// Default compile flags, used when no flags are set on the build
+    pub cxxflags: Option<String>,
+
+    /// Default link flags, used when no flags are set on the build
+    pub ldflags: Option<String>,
+
+    #[serde(default)]
+    pub compiler: Compiler,
+
+    #[serde(default)]
+    pub halide: Halide,
+}
+
+impl Toolchain {
+    /// Parse a toolchain description, checking that version bounds are valid
+    pub fn parse(s: &str) -> io::Result<Toolchain> {
+        let toolchain: Toolchain =
+            toml::from_str(s).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let versions = [
+            ("compiler.min_version", &toolchain.compiler.min_version),
+            ("compiler.max_version", &toolchain.compiler.max_version),
+            ("halide.version", &toolchain.halide.version),
+        ];
+        for (name, value) in versions {
+            if let Some(value) = value {
+                if Version::parse(value).is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("Invalid version for {}: {:?}", name, value),
+                    ));
+                }
+            }
+        }
+
+        Ok(toolchain)
+    }
+
+    /// Compare the installed compiler and Halide against the pins
+    pub fn mismatches(
+        &self,
+        executor: &dyn Executor,
+        cxx: &str,
+        halide_path: &Path,
+    ) -> io::Result<Vec<Mismatch>> {
+        let mut mismatches = vec![];
+
+        if let Some(expected) = &self.compiler.cxx {
+            if expected != cxx {
+                mismatches.push(Mismatch::Compiler {
+                    expected: expected.clone(),
+                    found: cxx.to_string(),
+                });
+            }
+        }
+
+        let compiler = &self.compiler;
+        if compiler.min_version.is_some() || compiler.max_version.is_some() {
+            let found = version::compiler_version(executor, cxx).map_err(|e| {
+                io::Error::new(e.kind(), format!("Unable to run compiler {}: {}", cxx, e))
+            })?;
+            let in_range = found.is_some_and(|found| {
+                compiler
+                    .min_version
+                    .as_deref()
+                    .is_none_or(|min| at_least(found, min))
+                    && compiler
+                        .max_version
+                        .as_deref()
+                        .is_none_or(|max| at_most(found, max))
+            });
+            if !in_range {
+                mismatches.push(Mismatch::CompilerVersion {
+                    compiler: cxx.to_string(),
+                    found,
+                    min: compiler.min_version.clone(),
+                    max: compiler.max_version.clone(),
+                });
+            }
+        }
+
+        if let Some(expected) = &self.halide.version {
+            let found = version::halide_version(halide_path);
+            let matches =
+                found.is_some_and(|found| at_least(found, expected) && at_most(found, expected));
+            if !matches {
+                mismatches.push(Mismatch::HalideVersion {
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+        }
+
+        if let Some(expected) = &self.halide.commit {
+            let found = head_commit(executor, halide_path);
+            let matches = found.as_deref().is_some_and(|found| {
+                !expected.is_empty() && found.starts_with(&expected.to_lowercase())
+            });
+            if !matches {
+                mismatches.push(Mismatch::HalideCommit {
+                    expected: expected.clone(),
+                    found,
+                });
+            }
+        }
+
+        Ok(mismatches)
+    }
+}
+
+/// Load a toolchain file
+pub fn load(path: impl AsRef<Path>) -> io::Result<Toolchain> {
+    let path = path.as_ref();
+    Toolchain::parse(&fs::read_to_string(path)?).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Invalid toolchain file {}: {}", path.display(), e),
+        )
+    })
+}
+
+/// A difference between the environment and a toolchain pin
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mismatch {
+    Compiler {
+        expected: String,
+        found: String,
+    },
+    CompilerVersion {
+        compiler: String,
+        found: Option<Version>,
+        min: Option<String>,
+        max: Option<String>,
+    },
+    HalideVersion {
+        expected: String,
+        found: Option<Version>,
+    },
+    HalideCommit {
+        expected: String,
+        found: Option<String>,
+    },
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Mismatch::Compiler { expected, found } => {
+                write!(f, "compiler is {}, expected {}", found, expected)
+            }
+            Mismatch::CompilerVersion {
+                compiler,
+                found,
+                min,
+                max,
+            } => {
+                match found {
+                    Some(found) => write!(f, "{} version is {}", compiler, found)?,
+                    None => write!(f, "unable to detect {} version", compiler)?,
+                }
+                match (min, max) {
+                    (Some(min), Some(max)) => write!(f, ", expected {} to {}", min, max),
+                    (Some(min), None) => write!(f, ", expected at least {}", min),
+                    (None, Some(max)) => write!(f, ", expected at most {}", max),
+                    (None, None) => Ok(()),
+                }
+            }
+            Mismatch::HalideVersion { expected, found } => match found {
+                Some(found) => write!(f, "Halide version is {}, expected {}", found, expected),
+                None => write!(f, "unable to detect Halide version, expected {}", expected),
+            },
+            Mismatch::HalideCommit { expected, found } => match found {
+                Some(found) => write!(f, "Halide commit is {}, expected {}", found, expected),
+                None => write!(f, "unable to detect Halide commit, expected {}", expected),
+            },
+        }
+    }
+}
+
+/// Returned by `Build::check` when the environment doesn't satisfy the toolchain pins
+#[derive(Debug, Clone)]
+pub struct CheckError(pub Vec<Mismatch>);
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Toolchain mismatch:")?;
+        for mismatch in &self.0 {
+            write!(f, "\n  {}", mismatch)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+/// The components of `bound` that were specified, missing components match anything
+fn components(bound: &str) -> Vec<u32> {
+    let version = Version::parse(bound).unwrap_or_default();
+    let count = bound.trim().split('.').count().min(3);
+    [version.major, version.minor, version.patch][..count].to_vec()
+}
+
+fn truncate(version: Version, n: usize) -> Vec<u32> {
+    [version.major, version.minor, version.patch][..n].to_vec()
+}
+
+fn at_least(version: Version, min: &str) -> bool {
+    let min = components(min);
+    truncate(version, min.len()) >= min
+}
+
+fn at_most(version: Version, max: &str) -> bool {
+    let max = components(max);
+    truncate(version, max.len()) <= max
+}
+
+/// The commit checked out in the Halide source directory
+fn head_commit(executor: &dyn Executor, halide_path: &Path) -> Option<String> {
+    let output = executor
+        .output(
+            Command::new("git")
+                .arg("-C")
+                .arg(halide_path)
+                .args(["rev-parse", "HEAD"]),
+        )
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{Mock, Response};
+    use crate::Build;
+
+    const TOOLCHAIN: &str = r#"
+target_features = ["avx2", "sse41"]
+cxxflags = "-O3"
+ldflags = "-Wl,--as-needed"
+
+[compiler]
+cxx = "clang++"
+min_version = "14"
+max_version = "17"
+
+[halide]
+version = "16.0"
+commit = "C5BD1D2"
+"#;
+
+    #[test]
+    fn parse() {
+        let toolchain = Toolchain::parse(TOOLCHAIN).unwrap();
+        assert_eq!(
+            toolchain,
+            Toolchain {
+                target_features: vec!["avx2".to_string(), "sse41".to_string()],
+                cxxflags: Some("-O3".to_string()),
+                ldflags: Some("-Wl,--as-needed".to_string()),
+                compiler: Compiler {
+                    cxx: Some("clang++".to_string()),
+                    min_version: Some("14".to_string()),
+                    max_version: Some("17".to_string()),
+                },
+                halide: Halide {
+                    version: Some("16.0".to_string()),
+                    commit: Some("C5BD1D2".to_string()),
+                },
+            }
+        );
+        assert_eq!(Toolchain::parse("").unwrap(), Toolchain::default());
+
+        for (toml, message) in [
+            ("cflags = \"-O2\"", "unknown field `cflags`"),
+            (
+                "[compiler]\nmin_version = \"fourteen\"",
+                "Invalid version for compiler.min_version: \"fourteen\"",
+            ),
+            (
+                "[halide]\nversion = \"\"",
+                "Invalid version for halide.version: \"\"",
+            ),
+        ] {
+            let err = Toolchain::parse(toml).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn builder_flags_take_precedence() {
+        let toolchain = Toolchain::parse(TOOLCHAIN).unwrap();
+
+        let build = Build::new("/opt/halide", "out")
+            .compiler("g++")
+            .target_feature("avx2")
+            .with_toolchain(&toolchain);
+        assert_eq!(build.cxx.as_deref(), Some("clang++"));
+        assert_eq!(build.cxxflags.as_deref(), Some("-O3"));
+        assert_eq!(build.ldflags.as_deref(), Some("-Wl,--as-needed"));
+        assert_eq!(build.target_features, ["avx2", "sse41"]);
+
+        // Flags set on the builder are kept, before or after the toolchain is applied
+        let build = Build::new("/opt/halide", "out")
+            .cxxflags("-O1")
+            .with_toolchain(&toolchain)
+            .ldflags("-static")
+            .compiler("g++");
+        assert_eq!(build.cxxflags.as_deref(), Some("-O1"));
+        assert_eq!(build.ldflags.as_deref(), Some("-static"));
+        assert_eq!(build.cxx.as_deref(), Some("g++"));
+    }
+
+    #[test]
+    fn compiler_version_bounds() {
+        let toolchain = Toolchain::parse(
+            "[compiler]\ncxx = \"clang++\"\nmin_version = \"14.0.1\"\nmax_version = \"17\"",
+        )
+        .unwrap();
+        let mismatches = |output: &str| {
+            let mock = Mock::new().on("clang++", Response::ok().stdout(output));
+            let res = toolchain.mismatches(&mock, "clang++", Path::new("/opt/halide"));
+            assert_eq!(mock.calls()[0].args, ["--version"]);
+            res.unwrap()
+        };
+        let clang = |version: &str| format!("clang version {}\nTarget: x86_64\n", version);
+
+        for version in ["14.0.1", "15.0.0", "17.0.6"] {
+            assert_eq!(mismatches(&clang(version)), [], "{}", version);
+        }
+        for version in [Version::new(14, 0, 0), Version::new(18, 1, 8)] {
+            let found = mismatches(&clang(&version.to_string()));
+            assert_eq!(
+                found,
+                [Mismatch::CompilerVersion {
+                    compiler: "clang++".to_string(),
+                    found: Some(version),
+                    min: Some("14.0.1".to_string()),
+                    max: Some("17".to_string()),
+                }]
+            );
+            assert_eq!(
+                found[0].to_string(),
+                format!("clang++ version is {}, expected 14.0.1 to 17", version)
+            );
+        }
+
+        // Output without a version is a mismatch, failing to run the compiler is an error
+        assert_eq!(
+            mismatches("no version here")[0].to_string(),
+            "unable to detect clang++ version, expected 14.0.1 to 17"
+        );
+        let mock = Mock::new().on("clang++", Response::interrupted());
+        let err = toolchain
+            .mismatches(&mock, "clang++", Path::new("/opt/halide"))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Interrupted);
+        assert!(err
+            .to_string()
+            .starts_with("Unable to run compiler clang++"));
+
+        // A different compiler is reported along with its version
+        let mock = Mock::new().on("g++", Response::ok().stdout("g++ (GCC) 13.2.0\n"));
+        let found = toolchain
+            .mismatches(&mock, "g++", Path::new("/opt/halide"))
+            .unwrap();
+        assert_eq!(found[0].to_string(), "compiler is g++, expected clang++");
+        assert_eq!(
+            found[1].to_string(),
+            "g++ version is 13.2.0, expected 14.0.1 to 17"
+        );
+    }
+
+    #[test]
+    fn halide_commit() {
+        let toolchain = Toolchain::parse("[halide]\ncommit = \"C5BD1D2\"").unwrap();
+        let mock = Mock::new()
+            .on("git", Response::ok().stdout("c5bd1d2e4f\n"))
+            .on("git", Response::ok().stdout("0123456789\n"))
+            .on("git", Response::exit(128));
+        let halide = Path::new("/opt/halide");
+        assert_eq!(toolchain.mismatches(&mock, "c++", halide).unwrap(), []);
+        assert_eq!(
+            toolchain.mismatches(&mock, "c++", halide).unwrap()[0].to_string(),
+            "Halide commit is 0123456789, expected C5BD1D2"
+        );
+        assert_eq!(
+            toolchain.mismatches(&mock, "c++", halide).unwrap()[0].to_string(),
+            "unable to detect Halide commit, expected C5BD1D2"
+        );
+        assert_eq!(
+            mock.calls()[0].args,
+            ["-C", "/opt/halide", "rev-parse", "HEAD"]
+        );
+    }
+}
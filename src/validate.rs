@@ -0,0 +1,388 @@
+//! Validate names, targets and paths that may come from untrusted input
+//!
+//! Generator arguments are always passed as separate argv entries, these checks make sure
+//! a name can't be mistaken for a flag and that paths stay inside the sandbox set with
+//! `Build::sandbox`
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A value rejected by validation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// A generator, function or parameter name that isn't a valid identifier
+    InvalidName { kind: &'static str, name: String },
+
+    /// A Halide target string that can't be parsed
+    InvalidTarget { target: String, reason: String },
+
+    /// A path that resolves to a location outside of the sandbox root
+    OutsideSandbox { path: PathBuf, root: PathBuf },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidName { kind, name } => write!(f, "Invalid {} name {:?}", kind, name),
+            Error::InvalidTarget { target, reason } => {
+                write!(f, "Invalid target {:?}: {}", target, reason)
+            }
+            Error::OutsideSandbox { path, root } => write!(
+                f,
+                "{} is outside of the sandbox {}",
+                path.display(),
+                root.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+/// Returns true if `s` matches `[A-Za-z_][A-Za-z0-9_]*`
+pub fn is_identifier(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Check a generator name
+pub fn generator_name(name: &str) -> Result<(), Error> {
+    if is_identifier(name) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidName {
+        kind: "generator",
+        name: name.to_string(),
+    })
+}
+
+/// Check a file name used inside a build directory, which must not contain path separators
+/// or refer to a parent directory
+pub fn file_name(kind: &'static str, name: &str) -> Result<(), Error> {
+    if !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidName {
+        kind,
+        name: name.to_string(),
+    })
+}
+
+/// Check a function name, which may be qualified with C++ namespaces like `ns::f`
+pub fn function_name(name: &str) -> Result<(), Error> {
+    if name.split("::").all(is_identifier) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidName {
+        kind: "function",
+        name: name.to_string(),
+    })
+}
+
+/// Check a generator parameter name, which may have dotted parts like
+/// `autoscheduler.parallelism`
+///
+/// Values aren't checked, since they're passed in the same argv entry as the name
+pub fn param_name(name: &str) -> Result<(), Error> {
+    if name.split('.').all(is_identifier) {
+        return Ok(());
+    }
+
+    Err(Error::InvalidName {
+        kind: "parameter",
+        name: name.to_string(),
+    })
+}
+
+/// Parse a Halide target string into the features of each target
+///
+/// Targets are `-` separated lists of names like `x86-64-linux-avx2` or `host-cuda`, and
+/// several targets can be given separated by `,`
+pub fn target(target: &str) -> Result<Vec<Vec<&str>>, Error> {
+    let invalid = |reason: &str| Error::InvalidTarget {
+        target: target.to_string(),
+        reason: reason.to_string(),
+    };
+
+    if target.is_empty() {
+        return Err(invalid("target is empty"));
+    }
+
+    target
+        .split(',')
+        .map(|t| {
+            let parts: Vec<&str> = t.split('-').collect();
+            for part in &parts {
+                if part.is_empty() {
+                    return Err(invalid("empty component"));
+                }
+
+                if let Some(c) = part
+                    .chars()
+                    .find(|c| !c.is_ascii_alphanumeric() && *c != '_')
+                {
+                    return Err(invalid(&format!("unexpected character {:?}", c)));
+                }
+            }
+            Ok(parts)
+        })
+        .collect()
+}
+
+/// Symlinks followed when resolving dangling links, like the Linux `ELOOP` limit
+const MAX_LINKS: usize = 40;
+
+/// Resolve `path` and check that it's inside `root`, returning the resolved path
+///
+/// Symlinks are followed for the parts of the path that exist, the remaining parts can't
+/// contain `..`
+pub fn within(root: impl AsRef<Path>, path: impl AsRef<Path>) -> io::Result<PathBuf> {
+    let root = root.as_ref().canonicalize()?;
+    let path = std::path::absolute(path.as_ref())?;
+    let outside = || Error::OutsideSandbox {
+        path: path.clone(),
+        root: root.clone(),
+    };
+
+    let mut existing = path.clone();
+    let mut rest = vec![];
+    let mut links = 0;
+    let mut resolved = loop {
+        match existing.canonicalize() {
+            Ok(resolved) => break resolved,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+
+        // A dangling link would be followed when the path is written, so resolve it
+        // instead of treating it as a missing file
+        if existing.symlink_metadata().is_ok() {
+            if links == MAX_LINKS {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Too many levels of symbolic links in {}", path.display()),
+                ));
+            }
+            let target = existing.read_link()?;
+            existing = match existing.parent() {
+                Some(parent) => parent.join(target),
+                None => target,
+            };
+            links += 1;
+            continue;
+        }
+
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name.to_os_string());
+                existing = parent.to_path_buf();
+            }
+            _ => return Err(outside().into()),
+        }
+    };
+    resolved.extend(rest.iter().rev());
+
+    if !resolved.starts_with(&root) {
+        return Err(outside().into());
+    }
+
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_generator_names() {
+        for name in ["brighten", "_brighten", "Brighten2"] {
+            assert!(generator_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            "-o",
+            "--help",
+            "2brighten",
+            "bright en",
+            "a;rm",
+            "a::b",
+            "a.b",
+            "a=b",
+            "ünï",
+            "a\nb",
+        ] {
+            assert_eq!(
+                generator_name(name),
+                Err(Error::InvalidName {
+                    kind: "generator",
+                    name: name.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_function_names() {
+        for name in ["brighten", "ns::brighten", "a::b::c"] {
+            assert!(function_name(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "", "ns::", "::f", "ns:::f", "-f", "f-x", "f g", "f/../g", "ns:f",
+        ] {
+            assert!(function_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_param_names() {
+        for name in ["scale", "autoscheduler", "autoscheduler.parallelism"] {
+            assert!(param_name(name).is_ok(), "{}", name);
+        }
+        for name in ["", "a..b", ".a", "a.", "a=b", "-o", "a b", "target=host"] {
+            assert!(param_name(name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_targets() {
+        assert_eq!(
+            target("x86-64-linux-avx2,host-cuda").unwrap(),
+            [vec!["x86", "64", "linux", "avx2"], vec!["host", "cuda"]]
+        );
+        for t in [
+            "",
+            "x86--linux",
+            "host-",
+            "host,",
+            "x86-64 linux",
+            "host;rm",
+            "-o",
+        ] {
+            assert!(
+                matches!(target(t), Err(Error::InvalidTarget { .. })),
+                "{}",
+                t
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_sandbox_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(root.join("out")).unwrap();
+        let root_resolved = root.canonicalize().unwrap();
+
+        assert_eq!(
+            within(&root, root.join("out/brighten.a")).unwrap(),
+            root_resolved.join("out/brighten.a")
+        );
+        // Paths that don't exist yet are resolved against their existing parent
+        assert_eq!(
+            within(&root, root.join("new/dir/brighten.a")).unwrap(),
+            root_resolved.join("new/dir/brighten.a")
+        );
+
+        for path in [
+            dir.path().join("outside"),
+            root.join("../outside"),
+            root.join("out/../../outside"),
+            root.join("missing/../../outside"),
+            PathBuf::from("/etc/passwd"),
+        ] {
+            let err = within(&root, &path).unwrap_err();
+            assert_eq!(
+                err.kind(),
+                io::ErrorKind::InvalidInput,
+                "{}",
+                path.display()
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escapes() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, root.join("link")).unwrap();
+        symlink("../outside/file", root.join("file_link")).unwrap();
+        symlink(root.join("missing"), root.join("dangling")).unwrap();
+
+        for path in [
+            root.join("link"),
+            root.join("link/brighten.a"),
+            root.join("link/new/brighten.a"),
+            root.join("file_link"),
+        ] {
+            assert!(within(&root, &path).is_err(), "{}", path.display());
+        }
+
+        // Dangling links are followed too
+        assert_eq!(
+            within(&root, root.join("dangling")).unwrap(),
+            root.canonicalize().unwrap().join("missing")
+        );
+        symlink("loop", root.join("loop")).unwrap();
+        assert!(within(&root, root.join("loop")).is_err());
+
+        // Links that stay inside the root are followed
+        symlink(root.join("out"), root.join("inside")).unwrap();
+        std::fs::create_dir_all(root.join("out")).unwrap();
+        assert_eq!(
+            within(&root, root.join("inside/brighten.a")).unwrap(),
+            root.canonicalize().unwrap().join("out/brighten.a")
+        );
+
+        // The root itself may be a link
+        let root_link = dir.path().join("root_link");
+        symlink(&root, &root_link).unwrap();
+        assert!(within(&root_link, root.join("out/brighten.a")).is_ok());
+        assert!(within(&root_link, outside.join("brighten.a")).is_err());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn rejects_windows_escapes() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        std::fs::create_dir_all(&root).unwrap();
+        let drive = &root.to_str().unwrap()[..2];
+
+        for path in [
+            r"\\server\share\brighten.a".to_string(),
+            r"\\?\UNC\server\share\brighten.a".to_string(),
+            format!(r"{}..\..\brighten.a", drive),
+            format!(r"{}\Windows\brighten.a", drive),
+            r"\brighten.a".to_string(),
+        ] {
+            assert!(within(&root, &path).is_err(), "{}", path);
+        }
+        assert!(within(&root, root.join(r"out\brighten.a")).is_ok());
+    }
+
+    #[test]
+    fn errors_are_invalid_input() {
+        let err: io::Error = generator_name("-o").unwrap_err().into();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Invalid generator name \"-o\"");
+    }
+}
@@ -0,0 +1,472 @@
+//! Copy the parts of a built Halide that kernel builds need into a project
+//!
+//! `create` copies the headers, libHalide, the autoscheduler plugins and the tools used to
+//! build generators, laid out like an install, and records the Halide version and a hash of
+//! every file in `halide-vendor.json`. `LayoutKind::detect` recognizes the result, so it can
+//! be used as the Halide path of a `Build` and committed to a repository. `verify` checks
+//! that nothing was changed since
+//!
+//! Symbolic links between libraries, like `libHalide.so -> libHalide.so.16`, are kept as
+//! links on Unix so the library isn't copied several times
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::build_info;
+use crate::hash;
+use crate::layout::{Layout, LayoutKind};
+use crate::version;
+
+/// Name of the manifest written to the destination
+pub const FILE_NAME: &str = "halide-vendor.json";
+
+/// Current manifest schema version
+pub const VERSION: u32 = 1;
+
+/// Tools needed to build generators and run them, headers in the tools directory are always
+/// copied
+const TOOLS: &[&str] = &["GenGen.cpp", "RunGenMain.cpp"];
+
+/// What to copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VendorOptions {
+    /// Copy the autoscheduler plugins, enabled by default
+    pub autoschedulers: bool,
+}
+
+impl Default for VendorOptions {
+    fn default() -> Self {
+        VendorOptions {
+            autoschedulers: true,
+        }
+    }
+}
+
+impl VendorOptions {
+    pub fn new() -> VendorOptions {
+        VendorOptions::default()
+    }
+
+    pub fn autoschedulers(mut self, x: bool) -> Self {
+        self.autoschedulers = x;
+        self
+    }
+}
+
+/// A vendored file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VendoredFile {
+    /// Path relative to the destination, using `/` as the separator
+    pub path: String,
+
+    /// Content hash, empty for links
+    pub hash: String,
+
+    /// Size in bytes, 0 for links
+    pub size: u64,
+
+    /// Target of a symbolic link, relative to the link's directory
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// The contents of `halide-vendor.json`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VendorManifest {
+    /// Schema version, see `VERSION`
+    pub version: u32,
+
+    /// `None` when the headers don't define the version macros
+    pub halide_version: Option<String>,
+
+    /// The Halide path the files were copied from
+    pub source: PathBuf,
+
+    /// Sorted by path
+    pub files: Vec<VendoredFile>,
+}
+
+impl VendorManifest {
+    /// Total size of the vendored files in bytes
+    pub fn size(&self) -> u64 {
+        self.files.iter().map(|file| file.size).sum()
+    }
+
+    /// Read the manifest of a vendored directory
+    pub fn read(dest: impl AsRef<Path>) -> io::Result<VendorManifest> {
+        let path = dest.as_ref().join(FILE_NAME);
+        let json = fs::read_to_string(&path)?;
+        serde_json::from_str(&json).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid {}: {}", path.display(), e),
+            )
+        })
+    }
+}
+
+/// A difference between a vendored directory and its manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tampering {
+    Missing(String),
+    Modified(String),
+
+    /// A file that isn't in the manifest
+    Added(String),
+}
+
+impl fmt::Display for Tampering {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Tampering::Missing(path) => write!(f, "{} is missing", path),
+            Tampering::Modified(path) => write!(f, "{} was modified", path),
+            Tampering::Added(path) => write!(f, "{} isn't in {}", path, FILE_NAME),
+        }
+    }
+}
+
+/// The layout of the built Halide at `halide_path`
+fn source_layout(halide_path: &Path) -> io::Result<Layout> {
+    let kind = LayoutKind::detect(halide_path);
+    match kind {
+        LayoutKind::CMakeBuild => Layout::build_dir(halide_path),
+        LayoutKind::Release => Ok(Layout {
+            tools: halide_path.join("share/Halide/tools"),
+            ..Layout::install(halide_path)
+        }),
+        LayoutKind::SourceTree | LayoutKind::Unknown => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            kind.advice(halide_path).unwrap_or_default(),
+        )),
+        _ => Ok(Layout::install(halide_path)),
+    }
+}
+
+/// The files to copy from `layout`, as source paths and destination paths relative to the
+/// vendored directory
+fn select(layout: &Layout, options: &VendorOptions) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut files = vec![];
+
+    let mut headers = vec![];
+    walk(&layout.include, &mut headers)?;
+    for header in headers {
+        let rel = header.strip_prefix(&layout.include).unwrap_or(&header);
+        files.push((header.clone(), Path::new("include").join(rel)));
+    }
+
+    // CMake build trees keep the autoscheduler plugins in subdirectories
+    let mut libs = vec![];
+    walk_flat(&layout.lib, &mut libs)?;
+    walk(&layout.lib.join("autoschedulers"), &mut libs)?;
+    for lib in libs {
+        let name = lib.file_name().unwrap_or_default().to_string_lossy();
+        let halide = name.starts_with("libHalide.") || name.starts_with("Halide.");
+        let autoscheduler =
+            name.starts_with("libautoschedule_") || name.starts_with("autoschedule_");
+        if halide || (autoscheduler && options.autoschedulers) || name == build_info::FILE_NAME {
+            let dest = Path::new("lib").join(&*name);
+            files.push((lib, dest));
+        }
+    }
+    if !files.iter().any(|(_, dest)| dest.starts_with("lib")) {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No Halide library in {}", layout.lib.display()),
+        ));
+    }
+
+    let mut tools = vec![];
+    walk_flat(&layout.tools, &mut tools)?;
+    for tool in tools {
+        let name = tool.file_name().unwrap_or_default().to_string_lossy();
+        if TOOLS.contains(&&*name) || name.ends_with(".h") {
+            let dest = Path::new("tools").join(&*name);
+            files.push((tool, dest));
+        }
+    }
+    for tool in TOOLS {
+        if !layout.tools.join(tool).is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not found in {}", tool, layout.tools.display()),
+            ));
+        }
+    }
+
+    files.sort_by(|a, b| a.1.cmp(&b.1));
+    files.dedup_by(|a, b| a.1 == b.1);
+    Ok(files)
+}
+
+/// Files in `dir` and its subdirectories, without following links to directories, a missing
+/// directory has no files
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            walk(&entry.path(), files)?;
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Files directly in `dir`
+fn walk_flat(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// `path` relative to `base`, using `/` as the separator
+fn relative(base: &Path, path: &Path) -> String {
+    path.strip_prefix(base)
+        .unwrap_or(path)
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The target of a link to a file in the same directory, other links are copied
+fn local_link(path: &Path) -> Option<String> {
+    if !cfg!(unix) {
+        return None;
+    }
+    let target = fs::read_link(path).ok()?;
+    let target = target.to_str()?;
+    let resolved = path.with_file_name(target);
+    (!target.contains('/') && resolved.is_file()).then(|| target.to_string())
+}
+
+#[cfg(unix)]
+fn symlink(target: &str, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &str, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Links are only vendored on Unix",
+    ))
+}
+
+/// Copy what kernel builds need from the built Halide at `halide_path` into `dest`
+///
+/// `halide_path` may be an install, a binary release, a Makefile build or a CMake build tree.
+/// `dest` must be empty or a previous vendored directory, whose files are replaced. Fails with
+/// `io::ErrorKind::NotFound` when the library or tools are missing
+pub fn create(
+    halide_path: impl AsRef<Path>,
+    dest: impl AsRef<Path>,
+    options: &VendorOptions,
+) -> io::Result<VendorManifest> {
+    let halide_path = halide_path.as_ref();
+    let dest = dest.as_ref();
+    let layout = source_layout(halide_path)?;
+    let files = select(&layout, options)?;
+
+    let vendored = dest.join(FILE_NAME).is_file();
+    let empty = fs::read_dir(dest)
+        .map(|mut entries| entries.next().is_none())
+        .unwrap_or(true);
+    if !vendored && !empty {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{} isn't empty and wasn't created by vendoring Halide",
+                dest.display()
+            ),
+        ));
+    }
+    for dir in ["include", "lib", "tools"] {
+        match fs::remove_dir_all(dest.join(dir)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+    }
+
+    let mut manifest = VendorManifest {
+        version: VERSION,
+        halide_version: version::header_version(&layout.include).map(|v| v.to_string()),
+        source: halide_path.to_path_buf(),
+        files: vec![],
+    };
+    for (src, rel) in files {
+        let path = dest.join(&rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let rel = relative(Path::new(""), &rel);
+        if let Some(target) = local_link(&src) {
+            symlink(&target, &path)?;
+            manifest.files.push(VendoredFile {
+                path: rel,
+                hash: String::new(),
+                size: 0,
+                link: Some(target),
+            });
+            continue;
+        }
+
+        let size = fs::copy(&src, &path)?;
+        manifest.files.push(VendoredFile {
+            path: rel,
+            hash: hash::hex(hash::file(&path)?),
+            size,
+            link: None,
+        });
+    }
+
+    let json = serde_json::to_string_pretty(&manifest).map_err(io::Error::other)?;
+    fs::write(dest.join(FILE_NAME), json)?;
+    Ok(manifest)
+}
+
+/// Check the files of a vendored directory against its manifest, an empty list means nothing
+/// was changed
+pub fn verify(dest: impl AsRef<Path>) -> io::Result<Vec<Tampering>> {
+    let dest = dest.as_ref();
+    let manifest = VendorManifest::read(dest)?;
+
+    let mut tampering = vec![];
+    for file in &manifest.files {
+        let path = dest.join(&file.path);
+        let matches = match &file.link {
+            Some(target) => match fs::read_link(&path) {
+                Ok(link) => link == Path::new(target),
+                Err(_) if fs::symlink_metadata(&path).is_err() => {
+                    tampering.push(Tampering::Missing(file.path.clone()));
+                    continue;
+                }
+                Err(_) => false,
+            },
+            None if !path.is_file() => {
+                tampering.push(Tampering::Missing(file.path.clone()));
+                continue;
+            }
+            None => hash::hex(hash::file(&path)?) == file.hash,
+        };
+        if !matches {
+            tampering.push(Tampering::Modified(file.path.clone()));
+        }
+    }
+
+    let known: BTreeSet<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+    let mut present = vec![];
+    for dir in ["include", "lib", "tools"] {
+        walk(&dest.join(dir), &mut present)?;
+    }
+    present.sort();
+    for path in present {
+        let rel = relative(dest, &path);
+        if !known.contains(rel.as_str()) {
+            tampering.push(Tampering::Added(rel));
+        }
+    }
+    Ok(tampering)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An installed Halide 16.0.1 in `dir`
+    fn install(dir: &Path) {
+        for (path, contents) in [
+            ("include/Halide.h", "#include \"HalideRuntime.h\"\n"),
+            (
+                "include/HalideRuntime.h",
+                "#define HALIDE_VERSION_MAJOR 16\n#define HALIDE_VERSION_MINOR 0\n\
+                 #define HALIDE_VERSION_PATCH 1\n",
+            ),
+            ("lib/libHalide.so.16", "library"),
+            ("lib/libautoschedule_adams2019.so", "plugin"),
+            ("lib/libunrelated.so", "unrelated"),
+            ("tools/GenGen.cpp", "gengen"),
+            ("tools/RunGenMain.cpp", "rungen"),
+            ("tools/halide_image_io.h", "image io"),
+            ("tools/mex_halide.m", "matlab"),
+        ] {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("libHalide.so.16", dir.join("lib/libHalide.so")).unwrap();
+    }
+
+    #[test]
+    fn verify_reports_the_modified_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let halide = dir.path().join("halide");
+        let dest = dir.path().join("vendor");
+        install(&halide);
+
+        let manifest = create(&halide, &dest, &VendorOptions::new()).unwrap();
+        assert_eq!(manifest.halide_version.as_deref(), Some("16.0.1"));
+        let mut expected = vec![
+            "include/Halide.h",
+            "include/HalideRuntime.h",
+            "lib/libHalide.so.16",
+            "lib/libautoschedule_adams2019.so",
+            "tools/GenGen.cpp",
+            "tools/RunGenMain.cpp",
+            "tools/halide_image_io.h",
+        ];
+        if cfg!(unix) {
+            expected.insert(2, "lib/libHalide.so");
+            let link = &manifest.files[2];
+            assert_eq!(link.link.as_deref(), Some("libHalide.so.16"));
+        }
+        let paths: Vec<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(paths, expected);
+        assert_eq!(VendorManifest::read(&dest).unwrap(), manifest);
+        assert_eq!(LayoutKind::detect(&dest), LayoutKind::Vendored);
+        assert_eq!(verify(&dest).unwrap(), []);
+
+        fs::write(dest.join("tools/halide_image_io.h"), "patched").unwrap();
+        assert_eq!(
+            verify(&dest).unwrap(),
+            [Tampering::Modified("tools/halide_image_io.h".to_string())]
+        );
+
+        // Vendoring again replaces the files
+        let again = create(&halide, &dest, &VendorOptions::new().autoschedulers(false)).unwrap();
+        assert_eq!(again.files.len(), expected.len() - 1);
+        assert_eq!(verify(&dest).unwrap(), []);
+    }
+
+    #[test]
+    fn create_refuses_other_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let halide = dir.path().join("halide");
+        install(&halide);
+        fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let err = create(&halide, dir.path(), &VendorOptions::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+}
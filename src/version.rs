@@ -0,0 +1,445 @@
+//! Version numbers for Halide and the toolchain
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, SystemTime};
+
+use crate::exec::Executor;
+use crate::layout::Layout;
+
+/// How much newer the headers may be than the library before they're considered stale, when
+/// the versions can't be compared
+const MODIFIED_TOLERANCE: Duration = Duration::from_secs(10 * 60);
+
+/// A `major.minor.patch` version number
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Version {
+        Version {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// Parse a version like `16`, `16.0` or `16.0.1`, ignoring any suffix after the digits,
+    /// for example `16.0.1git`
+    pub fn parse(s: &str) -> Option<Version> {
+        let mut parts = s.trim().split('.').map(|part| {
+            let digits: String = part.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse::<u32>().ok()
+        });
+
+        let major = parts.next()??;
+        let minor = parts.next().flatten().unwrap_or(0);
+        let patch = parts.next().flatten().unwrap_or(0);
+        Some(Version::new(major, minor, patch))
+    }
+
+    /// Find the first dotted version number in some text, like the output of `c++ --version`
+    pub fn find(s: &str) -> Option<Version> {
+        s.split(|c: char| c.is_whitespace() || c == '(' || c == ')' || c == '-')
+            .filter(|word| word.starts_with(|c: char| c.is_ascii_digit()) && word.contains('.'))
+            .find_map(Version::parse)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Read the Halide version from the `HALIDE_VERSION_*` macros in the Halide headers
+pub fn halide_version(halide_path: impl AsRef<Path>) -> Option<Version> {
+    header_version(Layout::install(halide_path).include)
+}
+
+pub(crate) fn header_version(include: impl AsRef<Path>) -> Option<Version> {
+    ["HalideRuntime.h", "Halide.h"]
+        .iter()
+        .filter_map(|header| fs::read_to_string(include.as_ref().join(header)).ok())
+        .find_map(|contents| parse_version_macros(&contents))
+}
+
+/// Version components in a versioned library name, like `[16, 0, 1]` for
+/// `libHalide.so.16.0.1` or `libHalide.16.0.1.dylib`
+pub fn library_name_version(name: &str) -> Vec<u32> {
+    let version = match name.split_once(".so.") {
+        Some((_, version)) => version,
+        None => name
+            .strip_suffix(".dylib")
+            .and_then(|stem| stem.split_once('.'))
+            .map(|(_, version)| version)
+            .unwrap_or_default(),
+    };
+    version
+        .split('.')
+        .map_while(|part| part.parse().ok())
+        .take(3)
+        .collect()
+}
+
+/// The Halide headers and library found in a layout, compared by `check`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Installed {
+    /// Version from the `HALIDE_VERSION_*` macros
+    pub header_version: Option<Version>,
+
+    /// Version components from the name the library resolves to, see `library_name_version`
+    pub library_version: Vec<u32>,
+
+    pub header_modified: Option<SystemTime>,
+    pub library_modified: Option<SystemTime>,
+}
+
+impl Installed {
+    /// Read the versions and modification times of `Halide.h` and the Halide library
+    pub fn read(layout: &Layout) -> Installed {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let library = ["libHalide.so", "libHalide.dylib"]
+            .iter()
+            .map(|name| layout.lib.join(name))
+            .find(|path| path.exists());
+
+        Installed {
+            header_version: header_version(&layout.include),
+            library_version: library
+                .as_ref()
+                .and_then(|path| fs::canonicalize(path).ok())
+                .and_then(|path| Some(library_name_version(path.file_name()?.to_str()?)))
+                .unwrap_or_default(),
+            header_modified: modified(&layout.include.join("Halide.h")),
+            library_modified: library.as_deref().and_then(modified),
+        }
+    }
+
+    /// Make sure the headers and library are from the same build
+    ///
+    /// Versions are compared up to the components in the library name. When either is unknown,
+    /// headers modified well after the library are reported instead
+    pub fn check(&self) -> Result<(), Stale> {
+        if let (Some(header), false) = (self.header_version, self.library_version.is_empty()) {
+            let n = self.library_version.len();
+            if [header.major, header.minor, header.patch][..n] != self.library_version[..] {
+                return Err(Stale::Version {
+                    header,
+                    library: self.library_version.clone(),
+                });
+            }
+            return Ok(());
+        }
+
+        if let (Some(header), Some(library)) = (self.header_modified, self.library_modified) {
+            if let Ok(newer) = header.duration_since(library) {
+                if newer > MODIFIED_TOLERANCE {
+                    return Err(Stale::Modified { newer });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The Halide headers and library are from different builds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stale {
+    Version {
+        header: Version,
+        library: Vec<u32>,
+    },
+
+    /// The headers were modified `newer` after the library
+    Modified {
+        newer: Duration,
+    },
+}
+
+impl fmt::Display for Stale {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stale::Version { header, library } => {
+                let library: Vec<String> = library.iter().map(u32::to_string).collect();
+                write!(
+                    f,
+                    "Halide headers are version {} but libHalide is version {}",
+                    header,
+                    library.join(".")
+                )?;
+            }
+            Stale::Modified { newer } => write!(
+                f,
+                "Halide headers are {} minutes newer than libHalide",
+                newer.as_secs() / 60
+            )?,
+        }
+        write!(f, ", rebuild Halide using `halide src`")
+    }
+}
+
+impl std::error::Error for Stale {}
+
+/// LLVM major versions known to build each Halide major version, inclusive
+const LLVM_RANGES: &[(u32, u32, u32)] = &[
+    (14, 13, 15),
+    (15, 14, 16),
+    (16, 15, 17),
+    (17, 16, 18),
+    (18, 17, 19),
+    (19, 18, 20),
+];
+
+/// The range of LLVM major versions known to build a Halide version, `None` for versions
+/// that aren't in the table
+pub fn llvm_range(halide: Version) -> Option<(u32, u32)> {
+    LLVM_RANGES
+        .iter()
+        .find(|(major, _, _)| *major == halide.major)
+        .map(|(_, min, max)| (*min, *max))
+}
+
+/// Read the Halide version of a source checkout from `project(Halide VERSION ...)` in its
+/// `CMakeLists.txt`, or the runtime header's version macros
+pub fn source_version(halide_path: impl AsRef<Path>) -> Option<Version> {
+    let halide_path = halide_path.as_ref();
+    let cmake = fs::read_to_string(halide_path.join("CMakeLists.txt")).ok();
+    cmake
+        .and_then(|cmake| {
+            let start = cmake.find("project(Halide")?;
+            let project = &cmake[start..];
+            let project = &project[..project.find(')')?];
+            let mut words = project.split_whitespace();
+            words.find(|word| *word == "VERSION")?;
+            Version::parse(words.next()?)
+        })
+        .or_else(|| header_version(halide_path.join("src").join("runtime")))
+}
+
+/// Run `<cxx> --version` using `executor`, returning its standard output
+pub fn version_output(executor: &dyn Executor, cxx: &str) -> io::Result<String> {
+    let output = executor.output(Command::new(cxx).arg("--version"))?;
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Read the version of a C++ compiler from the first line of `<cxx> --version`
+pub fn compiler_version(executor: &dyn Executor, cxx: &str) -> io::Result<Option<Version>> {
+    Ok(compiler_version_from_output(&version_output(
+        executor, cxx,
+    )?))
+}
+
+/// Read the compiler version from the output of `<cxx> --version`
+pub fn compiler_version_from_output(s: &str) -> Option<Version> {
+    s.lines().next().and_then(Version::find)
+}
+
+/// Compilers that accept different spellings of some flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerFamily {
+    Gcc,
+    Clang,
+    Msvc,
+    Unknown,
+}
+
+impl CompilerFamily {
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompilerFamily::Gcc => "gcc",
+            CompilerFamily::Clang => "clang",
+            CompilerFamily::Msvc => "msvc",
+            CompilerFamily::Unknown => "unknown",
+        }
+    }
+
+    /// Guess the family from the compiler's name, like `clang++-17`, `g++` or `cl.exe`
+    pub fn from_name(cxx: &str) -> CompilerFamily {
+        let name = Path::new(cxx)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+        if name == "cl" || name == "cl.exe" {
+            CompilerFamily::Msvc
+        } else if name.contains("clang") || name.starts_with("em++") {
+            CompilerFamily::Clang
+        } else if name.contains("g++") || name.contains("gcc") {
+            CompilerFamily::Gcc
+        } else {
+            CompilerFamily::Unknown
+        }
+    }
+
+    /// Detect the family from the output of `<cxx> --version`
+    pub fn from_version_output(s: &str) -> CompilerFamily {
+        if s.contains("clang") {
+            CompilerFamily::Clang
+        } else if s.contains("Microsoft") {
+            CompilerFamily::Msvc
+        } else if s.contains("Free Software Foundation") || s.contains("GCC") {
+            CompilerFamily::Gcc
+        } else {
+            CompilerFamily::Unknown
+        }
+    }
+}
+
+/// Detect the family of a C++ compiler from its name, running `<cxx> --version` for generic
+/// names like `c++`
+pub fn compiler_family(executor: &dyn Executor, cxx: &str) -> io::Result<CompilerFamily> {
+    match CompilerFamily::from_name(cxx) {
+        CompilerFamily::Unknown => Ok(CompilerFamily::from_version_output(&version_output(
+            executor, cxx,
+        )?)),
+        family => Ok(family),
+    }
+}
+
+/// Parse `#define HALIDE_VERSION_MAJOR 16` style macros
+pub fn parse_version_macros(header: &str) -> Option<Version> {
+    let mut major = None;
+    let mut minor = None;
+    let mut patch = None;
+
+    for line in header.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("#define") {
+            continue;
+        }
+
+        let (name, value) = match (words.next(), words.next()) {
+            (Some(name), Some(value)) => (name, value.parse::<u32>().ok()),
+            _ => continue,
+        };
+
+        match name {
+            "HALIDE_VERSION_MAJOR" => major = value,
+            "HALIDE_VERSION_MINOR" => minor = value,
+            "HALIDE_VERSION_PATCH" => patch = value,
+            _ => (),
+        }
+    }
+
+    Some(Version::new(major?, minor.unwrap_or(0), patch.unwrap_or(0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed(header: Option<Version>, library: &[u32]) -> Installed {
+        Installed {
+            header_version: header,
+            library_version: library.to_vec(),
+            ..Installed::default()
+        }
+    }
+
+    #[test]
+    fn header_and_library_versions() {
+        let v = |major, minor, patch| Some(Version::new(major, minor, patch));
+        let table: &[(Option<Version>, &[u32], bool)] = &[
+            (v(16, 0, 1), &[16, 0, 1], true),
+            // Only the components in the library name are compared
+            (v(16, 0, 1), &[16], true),
+            (v(16, 0, 1), &[16, 0], true),
+            (v(16, 0, 1), &[17], false),
+            (v(16, 0, 1), &[16, 1], false),
+            (v(16, 0, 1), &[16, 0, 2], false),
+            // Unknown versions can't be compared
+            (None, &[16], true),
+            (v(16, 0, 0), &[], true),
+        ];
+        for (header, library, ok) in table {
+            let res = installed(*header, library).check();
+            assert_eq!(res.is_ok(), *ok, "{:?} {:?}", header, library);
+            if let Err(stale) = res {
+                assert_eq!(
+                    stale,
+                    Stale::Version {
+                        header: header.unwrap(),
+                        library: library.to_vec(),
+                    }
+                );
+            }
+        }
+
+        let stale = installed(v(16, 0, 1), &[17, 0]).check().unwrap_err();
+        assert_eq!(
+            stale.to_string(),
+            "Halide headers are version 16.0.1 but libHalide is version 17.0, rebuild Halide using `halide src`"
+        );
+    }
+
+    #[test]
+    fn modification_times_without_versions() {
+        let library = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let with_times = |header: SystemTime| Installed {
+            header_modified: Some(header),
+            library_modified: Some(library),
+            ..Installed::default()
+        };
+        assert_eq!(with_times(library + MODIFIED_TOLERANCE).check(), Ok(()));
+        assert_eq!(
+            with_times(library - Duration::from_secs(3600)).check(),
+            Ok(())
+        );
+        let newer = MODIFIED_TOLERANCE + Duration::from_secs(60);
+        assert_eq!(
+            with_times(library + newer).check(),
+            Err(Stale::Modified { newer })
+        );
+
+        // Matching versions take precedence over the times
+        let installed = Installed {
+            header_version: Some(Version::new(16, 0, 0)),
+            library_version: vec![16],
+            ..with_times(library + newer)
+        };
+        assert_eq!(installed.check(), Ok(()));
+    }
+
+    #[test]
+    fn parse_and_find() {
+        assert_eq!(Version::parse("16"), Some(Version::new(16, 0, 0)));
+        assert_eq!(Version::parse("16.1"), Some(Version::new(16, 1, 0)));
+        assert_eq!(Version::parse("16.0.1git"), Some(Version::new(16, 0, 1)));
+        assert_eq!(Version::parse("trunk"), None);
+        assert!(Version::new(16, 0, 1) < Version::new(16, 1, 0));
+        assert!(Version::new(9, 9, 9) < Version::new(10, 0, 0));
+        assert_eq!(
+            Version::find("clang version 17.0.6 (Fedora 17.0.6-2.fc39)"),
+            Some(Version::new(17, 0, 6))
+        );
+        assert_eq!(
+            Version::find("g++ (GCC) 12.2.0"),
+            Some(Version::new(12, 2, 0))
+        );
+    }
+
+    #[test]
+    fn library_names() {
+        assert_eq!(library_name_version("libHalide.so.16.0.1"), [16, 0, 1]);
+        assert_eq!(library_name_version("libHalide.so.16"), [16]);
+        assert_eq!(library_name_version("libHalide.16.0.1.dylib"), [16, 0, 1]);
+        assert!(library_name_version("libHalide.so").is_empty());
+        assert!(library_name_version("libHalide.dylib").is_empty());
+    }
+
+    #[test]
+    fn version_macros_and_llvm() {
+        let header = "#define HALIDE_VERSION_MAJOR 17\n#define HALIDE_VERSION_MINOR 0\n";
+        assert_eq!(parse_version_macros(header), Some(Version::new(17, 0, 0)));
+        assert_eq!(parse_version_macros("#define HALIDE_VERSION_MINOR 1"), None);
+        assert_eq!(llvm_range(Version::new(17, 0, 0)), Some((16, 18)));
+        assert_eq!(llvm_range(Version::new(12, 0, 0)), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Compiler warning flags for each warning level and compiler family
+//!
+//! `Strict` also suppresses the warnings the Halide headers are known to trigger, so kernels
+//! can be built warning-clean with `-Werror`
+
+use crate::version::{CompilerFamily, Version};
+
+/// How many warnings the compiler reports
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WarningLevel {
+    /// Disable all warnings
+    None,
+
+    /// The compiler's defaults
+    #[default]
+    Default,
+
+    /// `-Wall -Wextra -Wno-unused-parameter`, or `/W4` for MSVC
+    Strict,
+}
+
+impl WarningLevel {
+    pub fn from_name(name: &str) -> Option<WarningLevel> {
+        match name {
+            "none" => Some(WarningLevel::None),
+            "default" => Some(WarningLevel::Default),
+            "strict" => Some(WarningLevel::Strict),
+            _ => None,
+        }
+    }
+}
+
+/// Flags for each level and family, compilers of unknown families are assumed to accept GCC
+/// flags
+const LEVELS: &[(WarningLevel, CompilerFamily, &[&str])] = &[
+    (WarningLevel::None, CompilerFamily::Msvc, &["/w"]),
+    (WarningLevel::None, CompilerFamily::Gcc, &["-w"]),
+    (WarningLevel::Strict, CompilerFamily::Msvc, &["/W4"]),
+    (
+        WarningLevel::Strict,
+        CompilerFamily::Gcc,
+        &["-Wall", "-Wextra", "-Wno-unused-parameter"],
+    ),
+];
+
+/// Warnings triggered by the Halide headers with `Strict`, for the Halide major versions in
+/// the inclusive range
+const HALIDE_SUPPRESSIONS: &[(CompilerFamily, u32, u32, &[&str])] = &[
+    // Aggregate initializers of runtime structs in HalideBuffer.h leave fields out
+    (
+        CompilerFamily::Gcc,
+        0,
+        u32::MAX,
+        &["-Wno-missing-field-initializers"],
+    ),
+    (
+        CompilerFamily::Clang,
+        0,
+        u32::MAX,
+        &["-Wno-missing-field-initializers"],
+    ),
+    // Classes with user-declared copy constructors in Halide.h, before Halide 15
+    (CompilerFamily::Gcc, 0, 14, &["-Wno-deprecated-copy"]),
+    (CompilerFamily::Clang, 0, 14, &["-Wno-deprecated-copy"]),
+];
+
+/// Families that use GCC's flag spellings
+fn gcc_like(family: CompilerFamily) -> CompilerFamily {
+    match family {
+        CompilerFamily::Msvc => CompilerFamily::Msvc,
+        _ => CompilerFamily::Gcc,
+    }
+}
+
+/// The warning flags for `level` on a compiler of `family`, including the suppressions for
+/// Halide headers of version `halide` with `Strict`
+///
+/// When the Halide version is unknown the suppressions for every version are used
+pub fn flags(
+    level: WarningLevel,
+    family: CompilerFamily,
+    warnings_as_errors: bool,
+    halide: Option<Version>,
+) -> Vec<&'static str> {
+    let mut flags: Vec<&'static str> = LEVELS
+        .iter()
+        .filter(|(l, f, _)| *l == level && *f == gcc_like(family))
+        .flat_map(|(_, _, flags)| flags.iter().copied())
+        .collect();
+
+    if level == WarningLevel::Strict {
+        let family = match family {
+            CompilerFamily::Unknown => CompilerFamily::Gcc,
+            family => family,
+        };
+        for (_, _, _, suppressions) in HALIDE_SUPPRESSIONS.iter().filter(|(f, min, max, _)| {
+            *f == family && halide.is_none_or(|v| (*min..=*max).contains(&v.major))
+        }) {
+            for flag in suppressions.iter() {
+                if !flags.contains(flag) {
+                    flags.push(flag);
+                }
+            }
+        }
+    }
+
+    if warnings_as_errors {
+        flags.push(match family {
+            CompilerFamily::Msvc => "/WX",
+            _ => "-Werror",
+        });
+    }
+    flags
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CompilerFamily::{Clang, Gcc, Msvc, Unknown};
+    use WarningLevel::{Default, None, Strict};
+
+    type Case = (
+        WarningLevel,
+        CompilerFamily,
+        bool,
+        Option<Version>,
+        &'static [&'static str],
+    );
+
+    #[test]
+    fn flags_per_family_and_level() {
+        const GCC_STRICT: &[&str] = &[
+            "-Wall",
+            "-Wextra",
+            "-Wno-unused-parameter",
+            "-Wno-missing-field-initializers",
+        ];
+        let old_halide = Some(Version::new(14, 0, 0));
+        let new_halide = Some(Version::new(16, 0, 0));
+        let table: &[Case] = &[
+            (None, Gcc, false, new_halide, &["-w"]),
+            (None, Clang, false, new_halide, &["-w"]),
+            (None, Unknown, false, new_halide, &["-w"]),
+            (None, Msvc, false, new_halide, &["/w"]),
+            (Default, Gcc, false, new_halide, &[]),
+            (Default, Clang, true, new_halide, &["-Werror"]),
+            (Default, Msvc, true, new_halide, &["/WX"]),
+            (Strict, Gcc, false, new_halide, GCC_STRICT),
+            (Strict, Clang, false, new_halide, GCC_STRICT),
+            (Strict, Unknown, false, new_halide, GCC_STRICT),
+            (
+                Strict,
+                Gcc,
+                true,
+                old_halide,
+                &[
+                    "-Wall",
+                    "-Wextra",
+                    "-Wno-unused-parameter",
+                    "-Wno-missing-field-initializers",
+                    "-Wno-deprecated-copy",
+                    "-Werror",
+                ],
+            ),
+            // An unknown Halide version gets every suppression
+            (
+                Strict,
+                Clang,
+                false,
+                Option::None,
+                &[
+                    "-Wall",
+                    "-Wextra",
+                    "-Wno-unused-parameter",
+                    "-Wno-missing-field-initializers",
+                    "-Wno-deprecated-copy",
+                ],
+            ),
+            (Strict, Msvc, true, old_halide, &["/W4", "/WX"]),
+        ];
+        for (level, family, werror, halide, expected) in table {
+            assert_eq!(
+                flags(*level, *family, *werror, *halide),
+                *expected,
+                "{:?} {:?} {} {:?}",
+                level,
+                family,
+                werror,
+                halide
+            );
+        }
+    }
+
+    #[test]
+    fn level_names() {
+        assert_eq!(WarningLevel::from_name("none"), Some(None));
+        assert_eq!(WarningLevel::from_name("default"), Some(Default));
+        assert_eq!(WarningLevel::from_name("strict"), Some(Strict));
+        assert_eq!(WarningLevel::from_name("all"), Option::None);
+    }
+}
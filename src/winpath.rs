@@ -0,0 +1,86 @@
+//! Handle Windows extended-length paths
+//!
+//! `std::fs` already adds the `\\?\` prefix itself when a path is longer than `MAX_PATH`, so
+//! files are read and written without any help. Compilers, linkers and Cargo don't understand
+//! the prefix, which `fs::canonicalize` returns on Windows, so it's removed from paths passed
+//! to other programs
+
+use std::path::{Path, PathBuf};
+
+/// Longest path accepted by Windows APIs that don't support extended-length paths
+pub const MAX_PATH: usize = 260;
+
+/// `path` without a `\\?\` or `\\?\UNC\` prefix
+///
+/// Paths on every platform are accepted, since only the prefix is checked. Other verbatim
+/// forms, like `\\?\Volume{...}`, have no equivalent without the prefix and are unchanged
+pub fn strip_verbatim(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    let s = match path.to_str() {
+        Some(s) => s,
+        None => return path.to_path_buf(),
+    };
+
+    if let Some(rest) = s.strip_prefix(r"\\?\UNC\") {
+        return PathBuf::from(format!(r"\\{}", rest));
+    }
+    match s.strip_prefix(r"\\?\") {
+        Some(rest) if rest.as_bytes().get(1) == Some(&b':') => PathBuf::from(rest),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Whether `path` is too long for programs that don't support extended-length paths
+pub fn exceeds_max_path(path: impl AsRef<Path>) -> bool {
+    path.as_ref().as_os_str().len() >= MAX_PATH
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_drive_prefixes() {
+        assert_eq!(
+            strip_verbatim(r"\\?\C:\halide\lib\Halide.lib"),
+            PathBuf::from(r"C:\halide\lib\Halide.lib")
+        );
+        assert_eq!(strip_verbatim(r"\\?\d:\"), PathBuf::from(r"d:\"));
+    }
+
+    #[test]
+    fn strips_unc_prefixes() {
+        assert_eq!(
+            strip_verbatim(r"\\?\UNC\server\share\Halide.lib"),
+            PathBuf::from(r"\\server\share\Halide.lib")
+        );
+    }
+
+    #[test]
+    fn keeps_other_paths() {
+        for path in [
+            r"\\?\Volume{b75e2c83-0000-0000-0000-602f00000000}\Halide.lib",
+            r"\\?\GLOBALROOT\Device\Harddisk0",
+            r"\\server\share\Halide.lib",
+            r"\\.\C:\Halide.lib",
+            r"C:relative\Halide.lib",
+            r"C:\..\..\Halide.lib",
+            r"\Halide.lib",
+            r"..\Halide.lib",
+            "/opt/halide/lib/libHalide.a",
+            "",
+        ] {
+            assert_eq!(strip_verbatim(path), PathBuf::from(path), "{}", path);
+        }
+    }
+
+    #[test]
+    fn max_path() {
+        assert!(!exceeds_max_path(
+            "C:\\".to_string() + &"a".repeat(MAX_PATH - 4)
+        ));
+        assert!(exceeds_max_path(
+            "C:\\".to_string() + &"a".repeat(MAX_PATH - 3)
+        ));
+    }
+}